@@ -0,0 +1,472 @@
+use crate::{
+    app::{RepoState, ViewState},
+    git::{ReferenceId, SortType},
+    util::{glob_match, is_glob},
+};
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf},
+};
+
+const USAGE: &str = "\
+Usage: spit [OPTIONS] [REPO] [REVISION...] [-- PATH]
+
+Arguments:
+  [REPO]          Path to the repository. Defaults to the current directory when any revisions or
+                  options are given. Relative paths have to be a repository root or start with ./
+                  or ../, anything else is a revision
+  [REVISION...]   Reference names or globs to show (e.g. main 'feature/*'). Ranges of the form
+                  a..b and exclusions of the form ^a hide the history of the excluded side
+  [PATH]          Only show commits that modify the given path
+
+Options:
+  -C, --repo <REPO>   Path to the repository
+      --all           Show all references
+      --branches      Show all local branches
+      --remotes       Show all remote branches
+      --tags          Show all tags
+      --sort <TYPE>   Sort by \"committer\" (default) or \"author\" timestamp
+  -h, --help          Print this message
+";
+
+/// A reference selector as given on the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefPattern {
+    All,
+    Branches,
+    Remotes,
+    Tags,
+    /// A reference name or glob, matched against both the short and full reference names
+    Name(String),
+}
+
+impl RefPattern {
+    fn matches(&self, id: &ReferenceId) -> bool {
+        match self {
+            RefPattern::All => true,
+            RefPattern::Branches => matches!(id, ReferenceId::LocalBranch(_)),
+            RefPattern::Remotes => matches!(id, ReferenceId::RemoteBranch(_)),
+            RefPattern::Tags => matches!(id, ReferenceId::Tag(_)),
+            RefPattern::Name(name) => {
+                glob_match(name, &id.to_string())
+                    || id
+                        .reference_string()
+                        .map(|full_name| glob_match(name, &full_name))
+                        .unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Requested initial view of a repository. Unlike ViewState this holds patterns that can only be
+/// resolved once the references of the repository are known
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ViewSpec {
+    pub(crate) included: Vec<RefPattern>,
+    pub(crate) excluded: Vec<RefPattern>,
+    pub(crate) sort_type: SortType,
+    pub(crate) path_filter: Option<PathBuf>,
+}
+
+impl ViewSpec {
+    pub(crate) fn resolve(&self, repo_state: &RepoState) -> ViewState {
+        let resolve_patterns = |patterns: &[RefPattern]| -> HashSet<ReferenceId> {
+            let mut ret = HashSet::new();
+            for pattern in patterns {
+                let initial_len = ret.len();
+                ret.extend(
+                    repo_state
+                        .references
+                        .iter()
+                        .filter(|r| pattern.matches(&r.id))
+                        .map(|r| r.id.clone()),
+                );
+
+                if let RefPattern::Name(name) = pattern {
+                    if ret.len() == initial_len && !is_glob(name) {
+                        warn!("{} did not match any references", name);
+                    }
+                }
+            }
+            ret
+        };
+
+        let mut selected_references = resolve_patterns(&self.included);
+        if selected_references.is_empty() {
+            selected_references = FromIterator::from_iter([ReferenceId::head()]);
+        }
+
+        let path_filter = self
+            .path_filter
+            .as_ref()
+            .and_then(|p| repo_relative_path(p, &repo_state.repo));
+
+        ViewState {
+            selected_references,
+            excluded_references: resolve_patterns(&self.excluded),
+            sort_type: self.sort_type,
+            path_filter,
+        }
+    }
+
+    fn add_revision(&mut self, revision: &str) -> Result<()> {
+        // git treats a missing side of a range as HEAD
+        let or_head = |s: &str| {
+            if s.is_empty() {
+                "HEAD".to_string()
+            } else {
+                s.to_string()
+            }
+        };
+
+        if revision.contains("...") {
+            bail!("Symmetric difference ranges are not supported: {}", revision);
+        }
+
+        if let Some((from, to)) = revision.split_once("..") {
+            self.excluded.push(RefPattern::Name(or_head(from)));
+            self.included.push(RefPattern::Name(or_head(to)));
+        } else if let Some(excluded) = revision.strip_prefix('^') {
+            self.excluded.push(RefPattern::Name(excluded.to_string()));
+        } else {
+            self.included.push(RefPattern::Name(revision.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+fn repo_relative_path(path: &Path, repo_root: &Path) -> Option<PathBuf> {
+    if path.is_relative() {
+        return Some(path.to_path_buf());
+    }
+
+    match path.strip_prefix(repo_root) {
+        Ok(v) => Some(v.to_path_buf()),
+        Err(_) => {
+            warn!(
+                "Path filter {} is not in {}, ignoring",
+                path.display(),
+                repo_root.display()
+            );
+            None
+        }
+    }
+}
+
+/// What the command line asks for
+pub enum ParsedArgs {
+    Run(Args),
+    /// Print the contained usage text and exit
+    Help(&'static str),
+}
+
+pub struct Args {
+    pub repo: Option<PathBuf>,
+    pub view_spec: ViewSpec,
+}
+
+impl Args {
+    pub fn parse_env() -> Result<ParsedArgs> {
+        Args::parse(std::env::args_os().skip(1))
+    }
+
+    fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<ParsedArgs> {
+        use lexopt::prelude::*;
+
+        let mut args = args.into_iter().collect::<Vec<_>>();
+
+        // lexopt does not tell us where a -- was, split off the paths ourselves
+        let mut paths = match args.iter().position(|arg| arg == "--") {
+            Some(pos) => {
+                let paths = args.split_off(pos + 1);
+                args.pop();
+                paths
+            }
+            None => Vec::new(),
+        };
+
+        if paths.len() > 1 {
+            bail!("Only a single path filter is supported");
+        }
+
+        let mut repo: Option<PathBuf> = None;
+        let mut view_spec = ViewSpec::default();
+        let mut positional = Vec::new();
+
+        let mut parser = lexopt::Parser::from_args(args);
+        while let Some(arg) = parser.next()? {
+            match arg {
+                Short('C') | Long("repo") => {
+                    repo = Some(parser.value()?.into());
+                }
+                Long("all") => view_spec.included.push(RefPattern::All),
+                Long("branches") => view_spec.included.push(RefPattern::Branches),
+                Long("remotes") => view_spec.included.push(RefPattern::Remotes),
+                Long("tags") => view_spec.included.push(RefPattern::Tags),
+                Long("sort") => {
+                    view_spec.sort_type = parse_sort_type(&parser.value()?.string()?)?;
+                }
+                Short('h') | Long("help") => return Ok(ParsedArgs::Help(USAGE)),
+                Value(v) => positional.push(v),
+                _ => return Err(arg.unexpected().into()),
+            }
+        }
+
+        let mut positional = positional.into_iter().peekable();
+        if repo.is_none() {
+            // Keep `spit <repo>` working
+            repo = positional
+                .next_if(|arg| is_repo_arg(arg))
+                .map(PathBuf::from);
+        }
+
+        for revision in positional {
+            let revision = revision
+                .into_string()
+                .map_err(|_| anyhow::Error::msg("Revision is not valid utf8"))?;
+            view_spec.add_revision(&revision)?;
+        }
+
+        if let Some(path) = paths.pop() {
+            let path = PathBuf::from(path);
+            let path = if path.is_relative() {
+                std::env::current_dir()
+                    .context("failed to get cwd for relative path filter")?
+                    .join(path)
+            } else {
+                path
+            };
+            view_spec.path_filter = Some(path);
+        }
+
+        if repo.is_none() && view_spec != ViewSpec::default() {
+            repo = Some(".".into());
+        }
+
+        Ok(ParsedArgs::Run(Args { repo, view_spec }))
+    }
+}
+
+/// Whether a positional argument is the repository rather than a revision. A branch can share its
+/// name with a directory, so a path that could be a reference name has to be a repository itself
+fn is_repo_arg(arg: &OsStr) -> bool {
+    let path = Path::new(arg);
+    if !path.is_dir() {
+        return false;
+    }
+
+    // Reference names are never absolute and no component of them starts with a dot
+    let unambiguous = path.is_absolute()
+        || matches!(
+            path.components().next(),
+            Some(Component::CurDir | Component::ParentDir)
+        );
+    unambiguous
+        || git2::Repository::open_ext(
+            path,
+            git2::RepositoryOpenFlags::NO_SEARCH,
+            std::iter::empty::<&OsStr>(),
+        )
+        .is_ok()
+}
+
+fn parse_sort_type(s: &str) -> Result<SortType> {
+    let ret = match s {
+        "committer" => SortType::CommitterTimestamp,
+        "author" => SortType::AuthorTimestamp,
+        _ => bail!("Unknown sort type {}, expected committer or author", s),
+    };
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::Reference;
+    use tempfile::TempDir;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        match Args::parse(args.iter().map(OsString::from))? {
+            ParsedArgs::Run(args) => Ok(args),
+            ParsedArgs::Help(_) => bail!("Unexpected help request"),
+        }
+    }
+
+    fn repo_state_with_refs(repo: &Path, ids: &[ReferenceId]) -> Result<RepoState> {
+        Ok(RepoState {
+            repo: repo.to_path_buf(),
+            index: Default::default(),
+            head: ReferenceId::LocalBranch("main".into()),
+            references: ids
+                .iter()
+                .map(|id| -> Result<Reference> {
+                    Ok(Reference {
+                        id: id.clone(),
+                        head: "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse()?,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            shallow: Default::default(),
+            replace_refs: true,
+            replaced: Default::default(),
+            reference_commits: Default::default(),
+            ref_presets: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_no_args() -> Result<()> {
+        let args = parse(&[])?;
+        assert_eq!(args.repo, None);
+        assert_eq!(args.view_spec, ViewSpec::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repo_positional() -> Result<()> {
+        let repo_dir = TempDir::new()?;
+        let repo_s = repo_dir.path().to_str().unwrap();
+
+        let args = parse(&[repo_s, "main", "feature/*"])?;
+        assert_eq!(args.repo.as_deref(), Some(repo_dir.path()));
+        assert_eq!(
+            args.view_spec.included,
+            [
+                RefPattern::Name("main".into()),
+                RefPattern::Name("feature/*".into())
+            ]
+        );
+
+        // Non directories are revisions, and imply the current directory
+        let args = parse(&["main"])?;
+        assert_eq!(args.repo, Some(".".into()));
+        assert_eq!(args.view_spec.included, [RefPattern::Name("main".into())]);
+
+        let args = parse(&["-C", repo_s, "--all"])?;
+        assert_eq!(args.repo.as_deref(), Some(repo_dir.path()));
+        assert_eq!(args.view_spec.included, [RefPattern::All]);
+
+        // Tests run from the crate root, where src is a directory but not a repository
+        let args = parse(&["src"])?;
+        assert_eq!(args.repo, Some(".".into()));
+        assert_eq!(args.view_spec.included, [RefPattern::Name("src".into())]);
+
+        let args = parse(&["./src", "main"])?;
+        assert_eq!(args.repo, Some("./src".into()));
+        assert_eq!(args.view_spec.included, [RefPattern::Name("main".into())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_help() -> Result<()> {
+        for arg in ["-h", "--help"] {
+            let parsed = Args::parse([OsString::from("main"), OsString::from(arg)])?;
+            assert!(matches!(parsed, ParsedArgs::Help(USAGE)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_revision_ranges() -> Result<()> {
+        let args = parse(&["main..topic", "^old", "other"])?;
+        assert_eq!(
+            args.view_spec.included,
+            [
+                RefPattern::Name("topic".into()),
+                RefPattern::Name("other".into())
+            ]
+        );
+        assert_eq!(
+            args.view_spec.excluded,
+            [
+                RefPattern::Name("main".into()),
+                RefPattern::Name("old".into())
+            ]
+        );
+
+        let args = parse(&["main.."])?;
+        assert_eq!(args.view_spec.included, [RefPattern::Name("HEAD".into())]);
+        assert_eq!(args.view_spec.excluded, [RefPattern::Name("main".into())]);
+
+        assert!(parse(&["main...topic"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_and_path() -> Result<()> {
+        let args = parse(&["--sort", "author", "--", "/src/git"])?;
+        assert!(args.view_spec.sort_type == SortType::AuthorTimestamp);
+        assert_eq!(args.view_spec.path_filter, Some("/src/git".into()));
+        assert!(args.view_spec.included.is_empty());
+
+        assert!(parse(&["--sort", "unknown"]).is_err());
+        assert!(parse(&["--", "a", "b"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let repo_state = repo_state_with_refs(
+            Path::new("/repo"),
+            &[
+                ReferenceId::head(),
+                ReferenceId::LocalBranch("main".into()),
+                ReferenceId::LocalBranch("feature/a".into()),
+                ReferenceId::LocalBranch("feature/b".into()),
+                ReferenceId::RemoteBranch("origin/main".into()),
+                ReferenceId::Tag("v1.0".into()),
+            ],
+        )?;
+
+        let mut view_spec = ViewSpec::default();
+        view_spec.add_revision("main..feature/*")?;
+        view_spec.path_filter = Some("/repo/src".into());
+        let view_state = view_spec.resolve(&repo_state);
+        assert_eq!(
+            view_state.selected_references,
+            FromIterator::from_iter([
+                ReferenceId::LocalBranch("feature/a".into()),
+                ReferenceId::LocalBranch("feature/b".into())
+            ])
+        );
+        assert_eq!(
+            view_state.excluded_references,
+            FromIterator::from_iter([ReferenceId::LocalBranch("main".into())])
+        );
+        assert_eq!(view_state.path_filter, Some("src".into()));
+
+        let view_spec = ViewSpec {
+            included: vec![RefPattern::Remotes, RefPattern::Name("refs/tags/*".into())],
+            ..Default::default()
+        };
+        let view_state = view_spec.resolve(&repo_state);
+        assert_eq!(
+            view_state.selected_references,
+            FromIterator::from_iter([
+                ReferenceId::RemoteBranch("origin/main".into()),
+                ReferenceId::Tag("v1.0".into())
+            ])
+        );
+
+        // Nothing matched, fall back to HEAD
+        let view_spec = ViewSpec {
+            included: vec![RefPattern::Name("missing".into())],
+            ..Default::default()
+        };
+        let view_state = view_spec.resolve(&repo_state);
+        assert_eq!(
+            view_state.selected_references,
+            FromIterator::from_iter([ReferenceId::head()])
+        );
+
+        Ok(())
+    }
+}
@@ -1,14 +1,20 @@
+mod args;
 mod priority_queue;
 
+pub use args::{Args, ParsedArgs, ViewSpec};
+
 use crate::{
     app::priority_queue::PriorityQueue,
     git::{
-        self, build_git_history_graph, Commit, Diff, DiffTarget, HistoryGraph, Identifier,
-        ModifiedFiles, ObjectId, Reference, ReferenceId, RemoteRef, Repo, SortType,
+        self, build_git_history_graph, prepend_to_history_graph, rev_parse, Commit, Description,
+        Diff, DiffTarget, GraphPrepend, GraphSummary, HistoryGraph, Identifier, ModifiedFiles,
+        ObjectId, RefPreset, Reference, ReferenceId, RemoteRef, Repo, SortType,
     },
+    util::{CancellationToken, Cancelled},
 };
 
 use anyhow::{bail, Context, Error, Result};
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, warn};
 use notify::{self, Event, RecommendedWatcher, RecursiveMode, Watcher};
 use spiff::{DiffCollectionProcessor, DiffOptions};
@@ -36,12 +42,38 @@ pub(crate) struct IndexState {
     pub(crate) files: HashMap<PathBuf, ObjectId>,
 }
 
+/// Details of the commit a reference points at, for finding recently touched refs
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ReferenceCommit {
+    pub(crate) author_timestamp: DateTime<Utc>,
+    pub(crate) committer_timestamp: DateTime<Utc>,
+}
+
+/// How a local branch relates to the branch it tracks
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpstreamStatus {
+    pub(crate) upstream: ReferenceId,
+    /// Commits only on the local branch
+    pub(crate) ahead: usize,
+    /// Commits only on the upstream
+    pub(crate) behind: usize,
+}
+
 #[derive(Clone, Eq, PartialEq, Default)]
 pub struct RepoState {
     pub(crate) repo: PathBuf,
     pub(crate) index: IndexState,
     pub(crate) head: ReferenceId,
     pub(crate) references: Vec<Reference>,
+    /// Commits at which a shallow clone's history ends
+    pub(crate) shallow: HashSet<ObjectId>,
+    /// Whether refs/replace/* are applied
+    pub(crate) replace_refs: bool,
+    /// Commits whose history is rewritten by replace refs or grafts
+    pub(crate) replaced: HashSet<ObjectId>,
+    /// Head commit details keyed by reference head
+    pub(crate) reference_commits: HashMap<ObjectId, ReferenceCommit>,
+    pub(crate) ref_presets: Vec<RefPreset>,
 }
 
 impl RepoState {
@@ -58,7 +90,11 @@ impl RepoState {
 #[derive(Clone, Default, PartialEq, Eq)]
 pub struct ViewState {
     pub(crate) selected_references: HashSet<ReferenceId>,
+    /// History reachable from these references is hidden
+    pub(crate) excluded_references: HashSet<ReferenceId>,
     pub(crate) sort_type: SortType,
+    /// Repo relative path. Only commits that modify this path are shown
+    pub(crate) path_filter: Option<PathBuf>,
 }
 
 impl ViewState {
@@ -73,6 +109,9 @@ impl ViewState {
         if self.selected_references.is_empty() && had_any_branches {
             self.selected_references = FromIterator::from_iter([ReferenceId::head()]);
         }
+
+        self.excluded_references
+            .retain(|excluded| repo_state.references.iter().any(|b| &b.id == excluded));
     }
 }
 
@@ -89,8 +128,22 @@ pub struct DiffRequest {
     pub search_query: String,
 }
 
+/// How urgently the GUI needs the result of a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestPriority {
+    /// Speculative work, e.g. prefetching data that might be scrolled to
+    Background,
+    Normal,
+    /// Data the user is currently waiting on, e.g. the selected commit
+    UserVisible,
+}
+
+impl RequestPriority {
+    pub(crate) const NUM_LEVELS: usize = 3;
+}
+
 pub enum AppRequest {
-    OpenRepo(PathBuf),
+    OpenRepo(PathBuf, ViewSpec),
     GetCommitGraph {
         expected_repo: PathBuf,
         // Unique ID from the UI for preempting
@@ -100,20 +153,51 @@ pub enum AppRequest {
     Refresh {
         paths: Vec<PathBuf>,
     },
-    GetCommit {
+    GetCommits {
         expected_repo: PathBuf,
-        id: ObjectId,
+        ids: Vec<ObjectId>,
+        priority: RequestPriority,
     },
     GetDiffs {
         expected_repo: PathBuf,
+        viewer_id: String,
         reqs: Vec<DiffRequest>,
     },
+    /// Find the references that have id in their history
+    GetContainingRefs {
+        expected_repo: PathBuf,
+        id: ObjectId,
+    },
+    DescribeCommits {
+        expected_repo: PathBuf,
+        ids: Vec<ObjectId>,
+    },
+    /// Compare local branches with their upstreams. Walks history, so this is kept off of the
+    /// path that reports repo state changes
+    GetUpstreamStatuses {
+        expected_repo: PathBuf,
+    },
+    /// Author names of the given commits, only needed when refs are sorted by author
+    GetCommitAuthors {
+        expected_repo: PathBuf,
+        ids: Vec<ObjectId>,
+    },
+    GetMergeBase {
+        expected_repo: PathBuf,
+        a: ObjectId,
+        b: ObjectId,
+    },
     Search {
         expected_repo: PathBuf,
         viewer_id: String,
         commit_list: Vec<ObjectId>,
         search_string: String,
     },
+    ResolveRevision {
+        expected_repo: PathBuf,
+        viewer_id: String,
+        revision: String,
+    },
     Checkout(RepoState, Identifier),
     Delete(RepoState, ReferenceId),
     CherryPick(RepoState, ObjectId),
@@ -125,12 +209,20 @@ pub enum AppRequest {
     },
     FetchRemoteRef(PathBuf, RemoteRef),
     FetchAll(PathBuf),
+    SetReplaceRefs {
+        expected_repo: PathBuf,
+        enabled: bool,
+    },
+    SetRefPresets {
+        expected_repo: PathBuf,
+        presets: Vec<RefPreset>,
+    },
 }
 
 impl fmt::Debug for AppRequest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AppRequest::OpenRepo(_) => {
+            AppRequest::OpenRepo(_, _) => {
                 write!(f, "OpenRepo")
             }
             AppRequest::GetCommitGraph { .. } => {
@@ -139,15 +231,33 @@ impl fmt::Debug for AppRequest {
             AppRequest::Refresh { .. } => {
                 write!(f, "Refresh")
             }
-            AppRequest::GetCommit { .. } => {
-                write!(f, "GetCommit")
+            AppRequest::GetCommits { .. } => {
+                write!(f, "GetCommits")
             }
             AppRequest::GetDiffs { .. } => {
                 write!(f, "GetDiff")
             }
+            AppRequest::GetContainingRefs { .. } => {
+                write!(f, "GetContainingRefs")
+            }
+            AppRequest::DescribeCommits { .. } => {
+                write!(f, "DescribeCommits")
+            }
+            AppRequest::GetUpstreamStatuses { .. } => {
+                write!(f, "GetUpstreamStatuses")
+            }
+            AppRequest::GetCommitAuthors { .. } => {
+                write!(f, "GetCommitAuthors")
+            }
+            AppRequest::GetMergeBase { .. } => {
+                write!(f, "GetMergeBase")
+            }
             AppRequest::Search { .. } => {
                 write!(f, "Search")
             }
+            AppRequest::ResolveRevision { .. } => {
+                write!(f, "ResolveRevision")
+            }
             AppRequest::Checkout(_, _) => {
                 write!(f, "Checkout")
             }
@@ -175,28 +285,76 @@ impl fmt::Debug for AppRequest {
             AppRequest::FetchAll(_) => {
                 write!(f, "FetchAll")
             }
+            AppRequest::SetReplaceRefs { .. } => {
+                write!(f, "SetReplaceRefs")
+            }
+            AppRequest::SetRefPresets { .. } => {
+                write!(f, "SetRefPresets")
+            }
         }
     }
 }
 
 pub enum AppEvent {
     OutputLogged(String),
+    RepoOpened {
+        repo_state: RepoState,
+        view_state: ViewState,
+    },
     RepoStateUpdated(RepoState),
     WorkdirUpdated,
     RemoteStateUpdated(RemoteState),
+    /// First chunk of a commit graph, replaces any existing graph
     CommitGraphFetched(ViewState, HistoryGraph),
-    CommitFetched {
+    /// Additional rows for the graph last sent with CommitGraphFetched
+    CommitGraphExtended(ViewState, HistoryGraph),
+    /// New rows on top of the complete graph last sent for the same view state
+    CommitGraphPrepended(ViewState, GraphPrepend),
+    CommitsFetched {
         repo: PathBuf,
-        commit: Commit,
+        commits: Vec<Commit>,
+        /// Requested ids that could not be loaded, along with why
+        failed: Vec<(ObjectId, String)>,
     },
     DiffsFetched {
         repo: PathBuf,
         diffs: Vec<Diff>,
     },
+    ContainingRefsFetched {
+        repo: PathBuf,
+        id: ObjectId,
+        /// Error text if the refs could not be found, so that the GUI stops waiting
+        references: Result<Vec<ReferenceId>, String>,
+    },
+    CommitsDescribed {
+        repo: PathBuf,
+        descriptions: Vec<(ObjectId, Option<Description>)>,
+    },
+    /// Keyed by local branch, only for branches whose upstream exists
+    UpstreamStatusesFetched {
+        repo: PathBuf,
+        upstreams: HashMap<ReferenceId, UpstreamStatus>,
+    },
+    /// Author names are empty for commits that could not be read
+    CommitAuthorsFetched {
+        repo: PathBuf,
+        authors: Vec<(ObjectId, String)>,
+    },
+    /// merge_base is None when a and b have no common history
+    MergeBaseFound {
+        repo: PathBuf,
+        a: ObjectId,
+        b: ObjectId,
+        merge_base: Option<ObjectId>,
+    },
     SearchFinished {
         viewer_id: String,
         matched_id: Option<ObjectId>,
     },
+    RevisionResolved {
+        viewer_id: String,
+        id: ObjectId,
+    },
     Error(String),
 }
 
@@ -206,6 +364,9 @@ impl fmt::Debug for AppEvent {
             AppEvent::OutputLogged(_) => {
                 write!(f, "OutputLogged")
             }
+            AppEvent::RepoOpened { .. } => {
+                write!(f, "RepoOpened")
+            }
             AppEvent::RepoStateUpdated(_) => {
                 write!(f, "RepoStateUpdated")
             }
@@ -218,15 +379,39 @@ impl fmt::Debug for AppEvent {
             AppEvent::CommitGraphFetched(_, _) => {
                 write!(f, "CommitGraphFetched")
             }
-            AppEvent::CommitFetched { .. } => {
-                write!(f, "CommitFetched")
+            AppEvent::CommitGraphExtended(_, _) => {
+                write!(f, "CommitGraphExtended")
+            }
+            AppEvent::CommitGraphPrepended(_, _) => {
+                write!(f, "CommitGraphPrepended")
+            }
+            AppEvent::CommitsFetched { .. } => {
+                write!(f, "CommitsFetched")
             }
             AppEvent::DiffsFetched { .. } => {
                 write!(f, "DiffFetched")
             }
+            AppEvent::ContainingRefsFetched { .. } => {
+                write!(f, "ContainingRefsFetched")
+            }
+            AppEvent::CommitsDescribed { .. } => {
+                write!(f, "CommitsDescribed")
+            }
+            AppEvent::UpstreamStatusesFetched { .. } => {
+                write!(f, "UpstreamStatusesFetched")
+            }
+            AppEvent::CommitAuthorsFetched { .. } => {
+                write!(f, "CommitAuthorsFetched")
+            }
+            AppEvent::MergeBaseFound { .. } => {
+                write!(f, "MergeBaseFound")
+            }
             AppEvent::SearchFinished { .. } => {
                 write!(f, "SearchFinished")
             }
+            AppEvent::RevisionResolved { .. } => {
+                write!(f, "RevisionResolved")
+            }
             AppEvent::Error(_) => {
                 write!(f, "Error")
             }
@@ -234,6 +419,18 @@ impl fmt::Debug for AppEvent {
     }
 }
 
+/// Graph last sent to a viewer, kept around so that new commits can be added to it without
+/// rebuilding it
+struct BuiltGraph {
+    view_state: ViewState,
+    heads: Vec<ObjectId>,
+    /// Shallow boundaries at the time of the build. Deepening changes existing rows
+    shallow: HashSet<ObjectId>,
+    /// Replaced commits at the time of the build, these change existing rows too
+    replaced: HashSet<ObjectId>,
+    graph: GraphSummary,
+}
+
 pub struct App {
     tx: Sender<AppEvent>,
     rx: PriorityQueue,
@@ -241,6 +438,9 @@ pub struct App {
     repo: Option<Repo>,
     // Pin<Box<..>> to allow self reference
     processor: Vec<Pin<Box<DiffProcessorWithData>>>,
+    built_graphs: HashMap<String, BuiltGraph>,
+    /// Last state built for the open repo, so that rebuilding it only loads what changed
+    last_repo_state: Option<RepoState>,
 }
 
 impl App {
@@ -255,12 +455,19 @@ impl App {
             notifier: spawn_watcher(request_tx)?,
             repo: None,
             processor: Vec::new(),
+            built_graphs: HashMap::new(),
+            last_repo_state: None,
         })
     }
 
     pub fn run(&mut self) {
         while let Ok(req) = self.rx.recv() {
             if let Err(e) = self.handle_req(req) {
+                if e.downcast_ref::<Cancelled>().is_some() {
+                    debug!("Request cancelled by a newer request");
+                    continue;
+                }
+
                 let err_s = format!("{:?}", e);
                 error!("{}", err_s);
                 let _ = self.tx.send(AppEvent::Error(err_s));
@@ -343,31 +550,56 @@ impl App {
                 let cmd = cmd.trim();
                 self.execute_command(&repo_state, cmd)?;
             }
-            AppRequest::GetCommit { expected_repo, id } => match &mut self.repo {
+            AppRequest::GetCommits {
+                expected_repo, ids, ..
+            } => match &mut self.repo {
                 Some(repo) => {
                     if repo.repo_root() != expected_repo {
                         debug!(
-                            "Ignoring commit request for {}, {} is no longer open",
-                            id,
+                            "Ignoring request for {} commits, {} is no longer open",
+                            ids.len(),
                             expected_repo.display()
                         );
                         return Ok(());
                     }
 
+                    // One bad commit should not hold back the rest of the batch
+                    let mut commits = Vec::with_capacity(ids.len());
+                    let mut failed = Vec::new();
+                    for id in ids {
+                        match repo.get_commit(&id) {
+                            Ok(commit) => commits.push(commit),
+                            Err(e) => failed.push((id, format!("{:?}", e))),
+                        }
+                    }
+
                     self.tx
-                        .send(AppEvent::CommitFetched {
+                        .send(AppEvent::CommitsFetched {
                             repo: expected_repo,
-                            commit: repo.get_commit(&id)?,
+                            commits,
+                            failed,
                         })
-                        .context("Failed to send commit fetched")?;
+                        .context("Failed to send commits fetched")?;
                 }
                 None => {
-                    bail!("Commit requested without valid repo");
+                    // Reported per id, so that the gui does not consider them in flight forever
+                    let failed = ids
+                        .into_iter()
+                        .map(|id| (id, "Commit requested without valid repo".to_string()))
+                        .collect();
+                    self.tx
+                        .send(AppEvent::CommitsFetched {
+                            repo: expected_repo,
+                            commits: Vec::new(),
+                            failed,
+                        })
+                        .context("Failed to send commits fetched")?;
                 }
             },
             AppRequest::GetDiffs {
                 expected_repo,
                 reqs,
+                ..
             } => {
                 let repo = self
                     .repo
@@ -399,6 +631,7 @@ impl App {
                             options: req.options,
                         },
                         diff: processed_diffs,
+                        unfetched: container.data.unfetched.clone(),
                     });
                 }
 
@@ -407,6 +640,142 @@ impl App {
                     diffs,
                 })?;
             }
+            AppRequest::GetContainingRefs { expected_repo, id } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Containing refs requested without valid repo"))?;
+
+                if expected_repo != repo.repo_root() {
+                    warn!(
+                        "Ignoring containing refs request for closed repo {}",
+                        expected_repo.display()
+                    );
+                    return Ok(());
+                }
+
+                let cancel_token = self.rx.containing_refs_cancellation_token();
+                let references = containing_refs(repo, &id, &cancel_token);
+                let references = match references {
+                    Ok(references) => Ok(references),
+                    // The newer request answers instead
+                    Err(e) if e.downcast_ref::<Cancelled>().is_some() => return Err(e),
+                    Err(e) => {
+                        error!("Failed to find containing refs: {:?}", e);
+                        Err(format!("{:#}", e))
+                    }
+                };
+
+                self.tx.send(AppEvent::ContainingRefsFetched {
+                    repo: expected_repo,
+                    id,
+                    references,
+                })?;
+            }
+            AppRequest::DescribeCommits { expected_repo, ids } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Description requested without valid repo"))?;
+
+                if expected_repo != repo.repo_root() {
+                    warn!(
+                        "Ignoring description request for closed repo {}",
+                        expected_repo.display()
+                    );
+                    return Ok(());
+                }
+
+                let descriptions = repo.describe(&ids).context("Failed to describe commits")?;
+                self.tx.send(AppEvent::CommitsDescribed {
+                    repo: expected_repo,
+                    descriptions: ids.into_iter().zip(descriptions).collect(),
+                })?;
+            }
+            AppRequest::GetUpstreamStatuses { expected_repo } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Upstreams requested without valid repo"))?;
+
+                if expected_repo != repo.repo_root() {
+                    warn!(
+                        "Ignoring upstream request for closed repo {}",
+                        expected_repo.display()
+                    );
+                    return Ok(());
+                }
+
+                let references = repo.references().context("Failed to retrieve references")?;
+                let upstreams = get_upstream_statuses(repo, &references)
+                    .context("Failed to compare branches with their upstreams")?;
+                self.tx.send(AppEvent::UpstreamStatusesFetched {
+                    repo: expected_repo,
+                    upstreams,
+                })?;
+            }
+            AppRequest::GetCommitAuthors { expected_repo, ids } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Authors requested without valid repo"))?;
+
+                if expected_repo != repo.repo_root() {
+                    warn!(
+                        "Ignoring author request for closed repo {}",
+                        expected_repo.display()
+                    );
+                    return Ok(());
+                }
+
+                // Refs can point at commits that are not available locally, they get an empty
+                // author so that they are not requested again
+                let authors = ids
+                    .into_iter()
+                    .map(|id| {
+                        let author = repo.get_commit_author(&id).unwrap_or_else(|e| {
+                            debug!("No author for {}: {:?}", id, e);
+                            String::new()
+                        });
+                        (id, author)
+                    })
+                    .collect();
+                self.tx.send(AppEvent::CommitAuthorsFetched {
+                    repo: expected_repo,
+                    authors,
+                })?;
+            }
+            AppRequest::GetMergeBase {
+                expected_repo,
+                a,
+                b,
+            } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Merge base requested without valid repo"))?;
+
+                if expected_repo != repo.repo_root() {
+                    warn!(
+                        "Ignoring merge base request for closed repo {}",
+                        expected_repo.display()
+                    );
+                    return Ok(());
+                }
+
+                let merge_base = repo
+                    .merge_bases(&a, &b)
+                    .context("Failed to find merge base")?
+                    .into_iter()
+                    .next();
+
+                self.tx.send(AppEvent::MergeBaseFound {
+                    repo: expected_repo,
+                    a,
+                    b,
+                    merge_base,
+                })?;
+            }
             AppRequest::Search {
                 expected_repo,
                 viewer_id,
@@ -445,13 +814,42 @@ impl App {
                     })
                     .context("Failed to send search response")?;
             }
-            AppRequest::OpenRepo(path) => {
+            AppRequest::ResolveRevision {
+                expected_repo,
+                viewer_id,
+                revision,
+            } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Revision requested without valid repo"))?;
+
+                if repo.repo_root() != expected_repo {
+                    bail!(
+                        "Current repo does not match expected repo: {}, {}",
+                        repo.repo_root().display(),
+                        expected_repo.display()
+                    );
+                }
+
+                let id = rev_parse(repo, &revision)?;
+
+                self.tx
+                    .send(AppEvent::RevisionResolved { viewer_id, id })
+                    .context("Failed to send resolved revision")?;
+            }
+            AppRequest::OpenRepo(path, view_spec) => {
                 let mut repo = Repo::new(path, true).context("Failed to load git history")?;
 
-                let repo_state = get_repo_state(&mut repo)?;
+                let repo_state = get_repo_state(&mut repo, None)?;
+                self.last_repo_state = Some(repo_state.clone());
+                let view_state = view_spec.resolve(&repo_state);
 
                 self.tx
-                    .send(AppEvent::RepoStateUpdated(repo_state))
+                    .send(AppEvent::RepoOpened {
+                        repo_state,
+                        view_state,
+                    })
                     .context("Failed to send response branches")?;
 
                 // FIXME: There is a race here where if a new object is created between when we
@@ -465,11 +863,12 @@ impl App {
                 self.notifier
                     .watch(repo.repo_root(), RecursiveMode::Recursive)?;
                 self.repo = Some(repo);
+                self.built_graphs.clear();
             }
             AppRequest::GetCommitGraph {
                 expected_repo,
+                viewer_id,
                 view_state,
-                ..
             } => match &mut self.repo {
                 Some(repo) => {
                     if repo.repo_root() != expected_repo {
@@ -486,11 +885,97 @@ impl App {
                         .map(|id| repo.find_reference_commit_id(id))
                         .collect::<Result<Vec<_>>>()?;
 
-                    let graph = build_git_history_graph(repo, &heads, view_state.sort_type)?;
+                    let excluded_heads = view_state
+                        .excluded_references
+                        .iter()
+                        .map(|id| repo.find_reference_commit_id(id))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    let cancel_token = self.rx.commit_graph_cancellation_token(viewer_id.clone());
+
+                    // Prepending only works for graphs that show everything reachable from the
+                    // heads
+                    let can_prepend = excluded_heads.is_empty() && view_state.path_filter.is_none();
+                    let shallow = repo
+                        .shallow_commits()
+                        .context("Failed to retrieve shallow commits")?
+                        .clone();
+                    let replaced = repo
+                        .replaced_commits()
+                        .context("Failed to retrieve replaced commits")?;
+
+                    let previous_graph = self.built_graphs.remove(&viewer_id).filter(|built| {
+                        can_prepend
+                            && built.view_state == view_state
+                            && built.shallow == shallow
+                            && built.replaced == replaced
+                    });
 
-                    self.tx
-                        .send(AppEvent::CommitGraphFetched(view_state, graph))
-                        .context("Failed to send response commit log")?;
+                    if let Some(mut previous_graph) = previous_graph {
+                        let prepend = prepend_to_history_graph(
+                            repo,
+                            &previous_graph.graph,
+                            &previous_graph.heads,
+                            &heads,
+                            view_state.sort_type,
+                            &cancel_token,
+                        )
+                        .context("Failed to update commit graph")?;
+
+                        if let Some(prepend) = prepend {
+                            previous_graph
+                                .graph
+                                .prepend(prepend.clone())
+                                .context("Failed to update commit graph")?;
+                            previous_graph.heads = heads;
+                            self.built_graphs.insert(viewer_id, previous_graph);
+
+                            self.tx
+                                .send(AppEvent::CommitGraphPrepended(view_state, prepend))
+                                .context("Failed to send response commit log")?;
+                            return Ok(());
+                        }
+                    }
+
+                    let mut first_chunk = true;
+                    let mut summary = GraphSummary::default();
+                    build_git_history_graph(
+                        repo,
+                        &heads,
+                        &excluded_heads,
+                        view_state.sort_type,
+                        view_state.path_filter.as_deref(),
+                        &cancel_token,
+                        |chunk| {
+                            if can_prepend {
+                                summary.extend(&chunk);
+                            }
+
+                            let event = if first_chunk {
+                                AppEvent::CommitGraphFetched(view_state.clone(), chunk)
+                            } else {
+                                AppEvent::CommitGraphExtended(view_state.clone(), chunk)
+                            };
+                            first_chunk = false;
+
+                            self.tx
+                                .send(event)
+                                .context("Failed to send response commit log")
+                        },
+                    )?;
+
+                    if can_prepend {
+                        self.built_graphs.insert(
+                            viewer_id,
+                            BuiltGraph {
+                                view_state,
+                                heads,
+                                shallow,
+                                replaced,
+                                graph: summary,
+                            },
+                        );
+                    }
                 }
                 None => {
                     bail!("Branches selected without valid repo");
@@ -598,6 +1083,54 @@ impl App {
 
                 self.execute_command(&repo_state, git::commandline::fetch_all())?;
             }
+            AppRequest::SetReplaceRefs {
+                expected_repo,
+                enabled,
+            } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Replace refs toggled without valid repo"))?;
+
+                if repo.repo_root() != expected_repo {
+                    bail!(
+                        "Current repo does not match expected repo: {}, {}",
+                        repo.repo_root().display(),
+                        expected_repo.display()
+                    );
+                }
+
+                repo.set_replace_refs_enabled(enabled)
+                    .context("Failed to toggle replace refs")?;
+                let repo_state = self.get_repo_state()?;
+                self.tx
+                    .send(AppEvent::RepoStateUpdated(repo_state))
+                    .context("Failed to send repo state")?;
+            }
+            AppRequest::SetRefPresets {
+                expected_repo,
+                presets,
+            } => {
+                let repo = self
+                    .repo
+                    .as_mut()
+                    .ok_or_else(|| Error::msg("Presets saved without valid repo"))?;
+
+                if repo.repo_root() != expected_repo {
+                    bail!(
+                        "Current repo does not match expected repo: {}, {}",
+                        repo.repo_root().display(),
+                        expected_repo.display()
+                    );
+                }
+
+                repo.set_ref_presets(&presets)
+                    .context("Failed to save ref presets")?;
+                let repo_state = self.get_repo_state()?;
+                self.tx
+                    .send(AppEvent::RepoStateUpdated(repo_state))
+                    .context("Failed to send repo state")?;
+            }
         }
 
         Ok(())
@@ -605,30 +1138,155 @@ impl App {
 
     fn get_repo_state(&mut self) -> Result<RepoState> {
         let repo = self.repo.as_mut().ok_or_else(|| Error::msg("No repo"))?;
-        get_repo_state(repo)
+        let repo_state = get_repo_state(repo, self.last_repo_state.as_ref())?;
+        self.last_repo_state = Some(repo_state.clone());
+        Ok(repo_state)
     }
 }
 
-fn get_repo_state(repo: &mut Repo) -> Result<RepoState> {
-    let mut branches = vec![Ok(Reference {
+/// Current state of the repo. Head commit details that are in previous are reused, so only heads
+/// that moved since then are loaded
+fn get_repo_state(repo: &mut Repo, previous: Option<&RepoState>) -> Result<RepoState> {
+    let mut references = vec![Reference {
         head: repo.find_reference_commit_id(&ReferenceId::head())?,
         id: ReferenceId::head(),
-    })];
-    branches.extend(repo.branches().context("Failed to retrieve branches")?);
-    let mut references = branches.into_iter().collect::<Result<Vec<_>>>()?;
+    }];
+    references.extend(repo.references().context("Failed to retrieve references")?);
+    repo.update_description_tags(&references);
     let head = repo.resolve_reference(&ReferenceId::head())?;
-    let tags = repo.tags().context("Failed to retrieve tags")?;
     let index = repo.index().context("failed to retrieve index")?;
-    references.extend(tags);
+    let shallow = repo
+        .shallow_commits()
+        .context("Failed to retrieve shallow commits")?
+        .clone();
+    let replaced = repo
+        .replaced_commits()
+        .context("Failed to retrieve replaced commits")?;
+
+    let replace_refs = repo.replace_refs_enabled();
+
+    // Replacements change the metadata of the replaced commits
+    let known_reference_commits = previous
+        .filter(|previous| {
+            previous.repo == repo.repo_root()
+                && previous.replace_refs == replace_refs
+                && previous.replaced == replaced
+        })
+        .map(|previous| &previous.reference_commits);
+    let mut reference_commits = HashMap::new();
+    for reference in &references {
+        if reference_commits.contains_key(&reference.head) {
+            continue;
+        }
+
+        let known = known_reference_commits.and_then(|known| known.get(&reference.head));
+        if let Some(reference_commit) = known {
+            reference_commits.insert(reference.head.clone(), reference_commit.clone());
+            continue;
+        }
+
+        // Refs can point at commits that are not available locally, which should not keep the
+        // rest of the state from loading
+        let metadata = match repo.get_commit_metadata(&reference.head) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!("No metadata for {}: {:?}", reference.id, e);
+                continue;
+            }
+        };
+        reference_commits.insert(
+            reference.head.clone(),
+            ReferenceCommit {
+                author_timestamp: metadata.author_timestamp,
+                committer_timestamp: metadata.committer_timestamp,
+            },
+        );
+    }
+
+    // Presets are a convenience, a broken file should not keep the repo from opening
+    let ref_presets = repo.ref_presets().unwrap_or_else(|e| {
+        error!("Failed to read ref presets: {:?}", e);
+        Vec::new()
+    });
 
     Ok(RepoState {
         repo: repo.repo_root().to_path_buf(),
         index,
         head,
         references,
+        shallow,
+        replace_refs,
+        replaced,
+        reference_commits,
+        ref_presets,
     })
 }
 
+/// Refs that have id in their history
+fn containing_refs(
+    repo: &mut Repo,
+    id: &ObjectId,
+    cancel_token: &CancellationToken,
+) -> Result<Vec<ReferenceId>> {
+    let references = repo.references().context("Failed to retrieve references")?;
+    let heads = references
+        .iter()
+        .map(|reference| reference.head.clone())
+        .collect::<Vec<_>>();
+    let containing = repo
+        .heads_containing(id, &heads, cancel_token)
+        .context("Failed to find containing refs")?;
+
+    Ok(references
+        .into_iter()
+        .filter(|reference| containing.contains(&reference.head))
+        .map(|reference| reference.id)
+        .collect())
+}
+
+fn get_upstream_statuses(
+    repo: &mut Repo,
+    references: &[Reference],
+) -> Result<HashMap<ReferenceId, UpstreamStatus>> {
+    let upstream_branches = repo.upstream_branches()?;
+    let heads = references
+        .iter()
+        .map(|reference| (&reference.id, &reference.head))
+        .collect::<HashMap<_, _>>();
+
+    let mut ret = HashMap::new();
+    for reference in references {
+        let ReferenceId::LocalBranch(name) = &reference.id else {
+            continue;
+        };
+        let Some(upstream) = upstream_branches.get(name) else {
+            continue;
+        };
+        // Upstreams that were never fetched or have been deleted have nothing to compare against
+        let Some(upstream_head) = heads.get(upstream) else {
+            continue;
+        };
+
+        let (ahead, behind) = match repo.ahead_behind(&reference.head, upstream_head) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("Failed to compare {} with {}: {:?}", name, upstream, e);
+                continue;
+            }
+        };
+        ret.insert(
+            reference.id.clone(),
+            UpstreamStatus {
+                upstream: upstream.clone(),
+                ahead,
+                behind,
+            },
+        );
+    }
+
+    Ok(ret)
+}
+
 fn path_is_lock_file(path: &Path) -> bool {
     let extension = match path.extension() {
         Some(e) => e,
@@ -801,6 +1459,7 @@ fn ensure_processors_are_for_reqs(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::git::test_util::git;
 
     #[test]
     fn test_lock_file_check() {
@@ -813,6 +1472,124 @@ mod test {
         assert_eq!(path_is_lock_file(&Path::new("test/test.lock")), true);
     }
 
+    #[test]
+    fn test_open_sha256_repo() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let repo_path = tmp_dir.path().to_path_buf();
+        git(&repo_path, &["init", "-q", "--object-format=sha256"])?;
+        std::fs::write(repo_path.join("file"), "content")?;
+        git(&repo_path, &["add", "file"])?;
+        git(&repo_path, &["commit", "-q", "-m", "first"])?;
+        let head: ObjectId = git(&repo_path, &["rev-parse", "HEAD"])?.parse()?;
+        let blob: ObjectId = git(&repo_path, &["rev-parse", "HEAD:file"])?.parse()?;
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let (request_tx, request_rx) = mpsc::channel();
+        let mut app = App::new(event_tx, request_tx, request_rx)?;
+
+        app.handle_req(AppRequest::OpenRepo(repo_path.clone(), ViewSpec::default()))?;
+        let AppEvent::RepoOpened { repo_state, .. } = event_rx.try_recv()? else {
+            panic!("Repo was not opened");
+        };
+        assert_eq!(repo_state.head_object_id(), head);
+        assert_eq!(repo_state.index.files[Path::new("file")], blob);
+        let missing = ObjectId::from([0xab; 20]);
+
+        app.handle_req(AppRequest::GetCommits {
+            expected_repo: repo_path.clone(),
+            ids: vec![missing.clone(), head.clone()],
+            priority: RequestPriority::UserVisible,
+        })?;
+        let AppEvent::CommitsFetched {
+            commits, failed, ..
+        } = event_rx.try_recv()?
+        else {
+            panic!("Commits were not fetched");
+        };
+        // Ids that cannot be loaded do not fail the rest of the batch
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].metadata.id, head);
+        assert_eq!(commits[0].message, "first\n");
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, missing);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_reference_commits() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let repo_path = tmp_dir.path().to_path_buf();
+        git(&repo_path, &["init", "-q", "-b", "main"])?;
+        git(
+            &repo_path,
+            &["commit", "-q", "--allow-empty", "-m", "first"],
+        )?;
+        let first: ObjectId = git(&repo_path, &["rev-parse", "HEAD"])?.parse()?;
+
+        let mut repo = Repo::new(repo_path.clone(), false)?;
+        let mut previous = get_repo_state(&mut repo, None)?;
+        let stale_timestamp = DateTime::<Utc>::default();
+        for reference_commit in previous.reference_commits.values_mut() {
+            reference_commit.author_timestamp = stale_timestamp;
+        }
+
+        git(&repo_path, &["checkout", "-q", "-b", "topic"])?;
+        git(
+            &repo_path,
+            &["commit", "-q", "--allow-empty", "-m", "second"],
+        )?;
+        let second: ObjectId = git(&repo_path, &["rev-parse", "HEAD"])?.parse()?;
+
+        let repo_state = get_repo_state(&mut repo, Some(&previous))?;
+        // Only the head that moved is loaded again
+        assert_eq!(
+            repo_state.reference_commits[&first].author_timestamp,
+            stale_timestamp
+        );
+        assert_ne!(
+            repo_state.reference_commits[&second].author_timestamp,
+            stale_timestamp
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_reftable_repo() -> Result<()> {
+        const GIT_DIR_TARBALL: &[u8] = include_bytes!("../../res/test/reftable_stack.tar");
+        let tmp_dir = tempfile::TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(tmp_dir.path())?;
+        let repo_path = tmp_dir.path().to_path_buf();
+        let head: ObjectId = "748228e0f657e7795788267e116a5e17d19b3436".parse()?;
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let (request_tx, request_rx) = mpsc::channel();
+        let mut app = App::new(event_tx, request_tx, request_rx)?;
+
+        app.handle_req(AppRequest::OpenRepo(repo_path.clone(), ViewSpec::default()))?;
+        let AppEvent::RepoOpened { repo_state, .. } = event_rx.try_recv()? else {
+            panic!("Repo was not opened");
+        };
+        assert_eq!(
+            repo_state.head,
+            ReferenceId::LocalBranch("main".to_string())
+        );
+        assert_eq!(repo_state.head_object_id(), head);
+
+        app.handle_req(AppRequest::GetCommits {
+            expected_repo: repo_path,
+            ids: vec![head.clone()],
+            priority: RequestPriority::UserVisible,
+        })?;
+        let AppEvent::CommitsFetched { commits, .. } = event_rx.try_recv()? else {
+            panic!("Commits were not fetched");
+        };
+        assert_eq!(commits[0].message, "second\n");
+
+        Ok(())
+    }
+
     #[test]
     fn view_state_deleted_branch() -> Result<()> {
         let mut view_state = ViewState {
@@ -821,7 +1598,12 @@ mod test {
                 ReferenceId::RemoteBranch("Test".to_string()),
                 ReferenceId::LocalBranch("Test".to_string()),
             ]),
+            excluded_references: FromIterator::from_iter([
+                ReferenceId::RemoteBranch("Test".to_string()),
+                ReferenceId::LocalBranch("Test".to_string()),
+            ]),
             sort_type: SortType::CommitterTimestamp,
+            ..Default::default()
         };
 
         view_state.update_with_repo_state(&RepoState {
@@ -838,6 +1620,11 @@ mod test {
                     head: "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse()?,
                 },
             ],
+            shallow: Default::default(),
+            replace_refs: true,
+            replaced: Default::default(),
+            reference_commits: Default::default(),
+            ref_presets: Default::default(),
         });
 
         assert_eq!(view_state.selected_references.len(), 2);
@@ -848,6 +1635,10 @@ mod test {
                 ReferenceId::RemoteBranch("Test".to_string())
             ])
         );
+        assert_eq!(
+            view_state.excluded_references,
+            FromIterator::from_iter([ReferenceId::RemoteBranch("Test".to_string())])
+        );
         Ok(())
     }
 
@@ -856,6 +1647,7 @@ mod test {
         let mut view_state = ViewState {
             selected_references: Default::default(),
             sort_type: SortType::CommitterTimestamp,
+            ..Default::default()
         };
 
         view_state.update_with_repo_state(&RepoState {
@@ -866,6 +1658,11 @@ mod test {
                 id: ReferenceId::head(),
                 head: "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse()?,
             }],
+            shallow: Default::default(),
+            replace_refs: true,
+            replaced: Default::default(),
+            reference_commits: Default::default(),
+            ref_presets: Default::default(),
         });
 
         assert_eq!(view_state.selected_references.len(), 0);
@@ -881,6 +1678,7 @@ mod test {
                 "master".into(),
             )]),
             sort_type: SortType::CommitterTimestamp,
+            ..Default::default()
         };
 
         view_state.update_with_repo_state(&RepoState {
@@ -891,6 +1689,11 @@ mod test {
                 id: ReferenceId::head(),
                 head: "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse()?,
             }],
+            shallow: Default::default(),
+            replace_refs: true,
+            replaced: Default::default(),
+            reference_commits: Default::default(),
+            ref_presets: Default::default(),
         });
 
         // Only selected branch remove, swap to HEAD
@@ -1,47 +1,281 @@
-use crate::app::AppRequest;
+use crate::{
+    app::{AppRequest, RequestPriority},
+    git::ObjectId,
+    util::CancellationToken,
+};
 
 use anyhow::Result;
 
-use std::{collections::VecDeque, sync::mpsc::Receiver};
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{mpsc::Receiver, Arc, Mutex, MutexGuard},
+};
 
+/// Identifies requests where only the newest one is useful
+#[derive(PartialEq, Eq)]
+enum LatestWinsKey<'a> {
+    CommitGraph { viewer_id: &'a str },
+    Diffs { viewer_id: &'a str },
+    ContainingRefs,
+    UpstreamStatuses,
+}
+
+fn latest_wins_key(req: &AppRequest) -> Option<LatestWinsKey<'_>> {
+    let key = match req {
+        AppRequest::GetCommitGraph { viewer_id, .. } => LatestWinsKey::CommitGraph { viewer_id },
+        AppRequest::GetDiffs { viewer_id, .. } => LatestWinsKey::Diffs { viewer_id },
+        AppRequest::GetContainingRefs { .. } => LatestWinsKey::ContainingRefs,
+        AppRequest::GetUpstreamStatuses { .. } => LatestWinsKey::UpstreamStatuses,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// Identifies requests for a batch of objects, where an object only has to be requested once
+#[derive(PartialEq, Eq)]
+enum BatchKey<'a> {
+    Commits { expected_repo: &'a Path },
+    Descriptions { expected_repo: &'a Path },
+    Authors { expected_repo: &'a Path },
+}
+
+fn batch_key(req: &mut AppRequest) -> Option<(BatchKey<'_>, &mut Vec<ObjectId>)> {
+    let ret = match req {
+        AppRequest::GetCommits {
+            expected_repo, ids, ..
+        } => (BatchKey::Commits { expected_repo }, ids),
+        AppRequest::DescribeCommits { expected_repo, ids } => {
+            (BatchKey::Descriptions { expected_repo }, ids)
+        }
+        AppRequest::GetCommitAuthors { expected_repo, ids } => {
+            (BatchKey::Authors { expected_repo }, ids)
+        }
+        _ => return None,
+    };
+
+    Some(ret)
+}
+
+/// Remove ids that are already queued with at least the given priority from ids. Ids that are
+/// queued with a lower priority are removed from the queued request instead, so that they are
+/// promoted to the new request
+fn dedupe_batch(
+    output_queues: &mut [VecDeque<QueuedRequest>],
+    key: &BatchKey,
+    ids: &mut Vec<ObjectId>,
+    priority: RequestPriority,
+) {
+    for (queue_priority, queue) in output_queues.iter_mut().enumerate() {
+        for existing_item in queue.iter_mut() {
+            let queued_ids = match batch_key(&mut existing_item.req) {
+                Some((existing_key, queued_ids)) if existing_key == *key => queued_ids,
+                _ => continue,
+            };
+
+            if queue_priority >= priority as usize {
+                ids.retain(|id| !queued_ids.contains(id));
+            } else {
+                queued_ids.retain(|id| !ids.contains(id));
+            }
+        }
+
+        queue.retain_mut(|existing_item| {
+            !matches!(batch_key(&mut existing_item.req), Some((_, ids)) if ids.is_empty())
+        });
+    }
+}
+
+fn priority(req: &AppRequest) -> RequestPriority {
+    match req {
+        AppRequest::GetDiffs { .. } | AppRequest::GetMergeBase { .. } => {
+            RequestPriority::UserVisible
+        }
+        AppRequest::GetCommits { priority, .. } => *priority,
+        // Walks all of history, so anything else the user is waiting on goes first
+        AppRequest::GetContainingRefs { .. }
+        | AppRequest::DescribeCommits { .. }
+        | AppRequest::GetUpstreamStatuses { .. } => RequestPriority::Background,
+        _ => RequestPriority::Normal,
+    }
+}
+
+/// Requests that change the repo, or what the app thread knows about it. Anything requested after
+/// one of these expects to see the change, so nothing may overtake them
+fn is_barrier(req: &AppRequest) -> bool {
+    matches!(
+        req,
+        AppRequest::OpenRepo(..)
+            | AppRequest::Refresh { .. }
+            | AppRequest::Checkout(..)
+            | AppRequest::Delete(..)
+            | AppRequest::CherryPick(..)
+            | AppRequest::Merge(..)
+            | AppRequest::ExecuteGitCommand(..)
+            | AppRequest::UpdateRemotes { .. }
+            | AppRequest::FetchRemoteRef(..)
+            | AppRequest::FetchAll(..)
+            | AppRequest::SetReplaceRefs { .. }
+            | AppRequest::SetRefPresets { .. }
+    )
+}
+
+struct QueuedRequest {
+    req: AppRequest,
+    /// Number of barriers that were queued before this request. It can only be handled once all
+    /// of them have been
+    barriers_before: usize,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// One queue per RequestPriority, lowest priority first
+    output_queues: [VecDeque<QueuedRequest>; RequestPriority::NUM_LEVELS],
+    barriers_pushed: usize,
+    barriers_popped: usize,
+}
+
+impl QueueState {
+    /// Move everything that has been sent so far into the output queues, dropping requests that
+    /// have been made redundant
+    fn receive_pending(&mut self, rx: &Receiver<AppRequest>) {
+        while let Ok(item) = rx.try_recv() {
+            self.push(item);
+        }
+    }
+
+    fn push(&mut self, mut item: AppRequest) {
+        let item_priority = priority(&item);
+
+        if let Some((key, ids)) = batch_key(&mut item) {
+            dedupe_batch(&mut self.output_queues, &key, ids, item_priority);
+            if ids.is_empty() {
+                return;
+            }
+        }
+
+        if let Some(key) = latest_wins_key(&item) {
+            for queue in &mut self.output_queues {
+                queue.retain(|existing_item| {
+                    latest_wins_key(&existing_item.req).as_ref() != Some(&key)
+                });
+            }
+        }
+
+        let barriers_before = self.barriers_pushed;
+        if is_barrier(&item) {
+            self.barriers_pushed += 1;
+        }
+
+        self.output_queues[item_priority as usize].push_back(QueuedRequest {
+            req: item,
+            barriers_before,
+        });
+    }
+
+    fn pop(&mut self) -> Option<AppRequest> {
+        // Requests are queued in order, so anything that is held back by a barrier is at the end
+        // of its queue
+        let barriers_popped = self.barriers_popped;
+        let item = self.output_queues.iter_mut().rev().find_map(|queue| {
+            queue
+                .front()
+                .is_some_and(|item| item.barriers_before <= barriers_popped)
+                .then(|| queue.pop_front())
+                .flatten()
+        })?;
+
+        if is_barrier(&item.req) {
+            self.barriers_popped += 1;
+        }
+
+        Some(item.req)
+    }
+
+    fn has_commit_graph_request(&self, requested_viewer_id: &str) -> bool {
+        self.output_queues.iter().flatten().any(|item| {
+            matches!(&item.req, AppRequest::GetCommitGraph { viewer_id, .. } if viewer_id == requested_viewer_id)
+        })
+    }
+
+    fn has_containing_refs_request(&self) -> bool {
+        self.output_queues
+            .iter()
+            .flatten()
+            .any(|item| matches!(item.req, AppRequest::GetContainingRefs { .. }))
+    }
+}
+
+struct SharedState {
+    queue: Mutex<QueueState>,
+    /// Only locked without the queue lock held by recv, while it waits for a request. Everyone
+    /// else only tries to lock it, there is nothing to receive while recv is waiting
+    rx: Mutex<Receiver<AppRequest>>,
+}
+
+impl SharedState {
+    /// Lock the queue with everything sent so far received into it
+    fn lock_queue(&self) -> MutexGuard<'_, QueueState> {
+        let mut queue = self.queue.lock().expect("Priority queue lock poisoned");
+        if let Ok(rx) = self.rx.try_lock() {
+            queue.receive_pending(&rx);
+        }
+        queue
+    }
+}
+
+/// Request queue for the app thread. Redundant requests are dropped and more urgent requests
+/// overtake less urgent ones, requests of the same priority are handled in order
 pub(crate) struct PriorityQueue {
-    rx: Receiver<AppRequest>,
-    output_queue: VecDeque<AppRequest>,
+    // Shared with cancellation tokens, which check for newer requests while the app thread is
+    // busy
+    state: Arc<SharedState>,
 }
 
 impl PriorityQueue {
     pub(crate) fn new(rx: Receiver<AppRequest>) -> PriorityQueue {
         PriorityQueue {
-            rx,
-            output_queue: Default::default(),
+            state: Arc::new(SharedState {
+                queue: Default::default(),
+                rx: Mutex::new(rx),
+            }),
         }
     }
 
     pub(crate) fn recv(&mut self) -> Result<AppRequest> {
-        while let Ok(item) = self.rx.try_recv() {
-            if let AppRequest::GetCommitGraph { viewer_id, .. } = &item {
-                let output_queue = std::mem::take(&mut self.output_queue);
-                let new_id = &viewer_id;
-                self.output_queue = output_queue
-                    .into_iter()
-                    .filter(|existing_item| {
-                        if let AppRequest::GetCommitGraph { viewer_id, .. } = &existing_item {
-                            &viewer_id != new_id
-                        } else {
-                            true
-                        }
-                    })
-                    .collect()
+        loop {
+            if let Some(item) = self.state.lock_queue().pop() {
+                return Ok(item);
             }
-            self.output_queue.push_back(item);
-        }
 
-        if self.output_queue.is_empty() {
-            Ok(self.rx.recv()?)
-        } else {
-            Ok(self.output_queue.pop_front().unwrap())
+            // Wait without holding the queue lock. The request is queued before the receiver is
+            // released, so that nothing sent after it can be queued first
+            let rx = self
+                .state
+                .rx
+                .lock()
+                .expect("Priority queue receiver poisoned");
+            let item = rx.recv()?;
+            self.state
+                .queue
+                .lock()
+                .expect("Priority queue lock poisoned")
+                .push(item);
         }
     }
+
+    /// Token that is cancelled once a newer commit graph request for the given viewer arrives
+    pub(crate) fn commit_graph_cancellation_token(&self, viewer_id: String) -> CancellationToken {
+        let state = Arc::clone(&self.state);
+        CancellationToken::new(move || state.lock_queue().has_commit_graph_request(&viewer_id))
+    }
+
+    /// Token that is cancelled once a newer containing refs request arrives
+    pub(crate) fn containing_refs_cancellation_token(&self) -> CancellationToken {
+        let state = Arc::clone(&self.state);
+        CancellationToken::new(move || state.lock_queue().has_containing_refs_request())
+    }
 }
 
 #[cfg(test)]
@@ -51,7 +285,7 @@ mod test {
         app::ViewState,
         git::{ReferenceId, SortType},
     };
-    use std::sync::mpsc;
+    use std::{path::PathBuf, sync::mpsc};
 
     macro_rules! is_enum_variant {
         ($v:expr, $p:pat) => {
@@ -68,7 +302,7 @@ mod test {
         let (tx, rx) = mpsc::channel();
         let mut q = PriorityQueue::new(rx);
 
-        tx.send(AppRequest::OpenRepo("1".into()))?;
+        tx.send(AppRequest::OpenRepo("1".into(), Default::default()))?;
         tx.send(AppRequest::Refresh { paths: vec![] })?;
         tx.send(AppRequest::GetCommitGraph {
             expected_repo: "1".into(),
@@ -76,7 +310,7 @@ mod test {
             view_state: Default::default(),
         })?;
 
-        assert!(is_enum_variant!(q.recv()?, AppRequest::OpenRepo(_)));
+        assert!(is_enum_variant!(q.recv()?, AppRequest::OpenRepo(_, _)));
         assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
         assert!(is_enum_variant!(
             q.recv()?,
@@ -97,6 +331,7 @@ mod test {
             view_state: ViewState {
                 selected_references: FromIterator::from_iter([ReferenceId::head()]),
                 sort_type: SortType::CommitterTimestamp,
+                ..Default::default()
             },
         })?;
         tx.send(AppRequest::GetCommitGraph {
@@ -105,6 +340,7 @@ mod test {
             view_state: ViewState {
                 selected_references: Default::default(),
                 sort_type: SortType::CommitterTimestamp,
+                ..Default::default()
             },
         })?;
         tx.send(AppRequest::GetCommitGraph {
@@ -115,6 +351,7 @@ mod test {
                     "master".into(),
                 )]),
                 sort_type: SortType::CommitterTimestamp,
+                ..Default::default()
             },
         })?;
 
@@ -129,4 +366,201 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn cancel_commit_graph() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+
+        let get_commit_graph = |viewer_id: &str| AppRequest::GetCommitGraph {
+            expected_repo: "1".into(),
+            viewer_id: viewer_id.into(),
+            view_state: Default::default(),
+        };
+
+        tx.send(get_commit_graph("Viewer_1"))?;
+        assert!(is_enum_variant!(
+            q.recv()?,
+            AppRequest::GetCommitGraph { .. }
+        ));
+
+        let token = q.commit_graph_cancellation_token("Viewer_1".into());
+        assert!(!token.is_cancelled());
+
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+        tx.send(get_commit_graph("Viewer_2"))?;
+        assert!(!token.is_cancelled());
+
+        tx.send(get_commit_graph("Viewer_1"))?;
+        assert!(token.is_cancelled());
+
+        // Requests seen by the token are still delivered in order
+        assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
+        for expected_viewer_id in ["Viewer_2", "Viewer_1"] {
+            match q.recv()? {
+                AppRequest::GetCommitGraph { viewer_id, .. } => {
+                    assert_eq!(viewer_id, expected_viewer_id)
+                }
+                _ => panic!("Expected commit graph request"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_commits(ids: &[u8], priority: RequestPriority) -> AppRequest {
+        AppRequest::GetCommits {
+            expected_repo: "1".into(),
+            ids: ids.iter().map(|id| ObjectId::from([*id; 20])).collect(),
+            priority,
+        }
+    }
+
+    fn unwrap_commit_ids(req: AppRequest) -> Vec<u8> {
+        match req {
+            AppRequest::GetCommits { ids, .. } => ids.iter().map(|id| id[0]).collect(),
+            _ => panic!("Expected commit request"),
+        }
+    }
+
+    fn get_diffs(viewer_id: &str, expected_repo: &str) -> AppRequest {
+        AppRequest::GetDiffs {
+            expected_repo: expected_repo.into(),
+            viewer_id: viewer_id.into(),
+            reqs: vec![],
+        }
+    }
+
+    #[test]
+    fn latest_diffs_win() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+
+        tx.send(get_diffs("Viewer_1", "1"))?;
+        tx.send(get_diffs("Viewer_2", "2"))?;
+        tx.send(get_diffs("Viewer_1", "3"))?;
+
+        for (expected_viewer_id, expected_repo_name) in [("Viewer_2", "2"), ("Viewer_1", "3")] {
+            match q.recv()? {
+                AppRequest::GetDiffs {
+                    expected_repo,
+                    viewer_id,
+                    ..
+                } => {
+                    assert_eq!(viewer_id, expected_viewer_id);
+                    assert_eq!(expected_repo, PathBuf::from(expected_repo_name));
+                }
+                _ => panic!("Expected diff request"),
+            }
+        }
+
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+        assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_commits() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+
+        tx.send(get_commits(&[1, 2], RequestPriority::Normal))?;
+        tx.send(get_commits(&[2, 3], RequestPriority::Normal))?;
+        tx.send(get_commits(&[1, 3], RequestPriority::Background))?;
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+
+        assert_eq!(unwrap_commit_ids(q.recv()?), [1, 2]);
+        assert_eq!(unwrap_commit_ids(q.recv()?), [3]);
+        assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedupe_only_same_batch_kind() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+
+        let describe_commits = |ids: &[u8]| AppRequest::DescribeCommits {
+            expected_repo: "1".into(),
+            ids: ids.iter().map(|id| ObjectId::from([*id; 20])).collect(),
+        };
+
+        tx.send(get_commits(&[1], RequestPriority::Background))?;
+        tx.send(describe_commits(&[1, 2]))?;
+        tx.send(describe_commits(&[2]))?;
+
+        assert_eq!(unwrap_commit_ids(q.recv()?), [1]);
+        match q.recv()? {
+            AppRequest::DescribeCommits { ids, .. } => assert_eq!(ids.len(), 2),
+            _ => panic!("Expected describe request"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn urgent_requests_first() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+
+        tx.send(get_commits(&[1, 2], RequestPriority::Background))?;
+        tx.send(get_commits(&[3], RequestPriority::Background))?;
+        tx.send(get_diffs("Viewer_1", "1"))?;
+        // Already queued in the background, but now needed urgently
+        tx.send(get_commits(&[2, 3], RequestPriority::UserVisible))?;
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+
+        assert!(is_enum_variant!(q.recv()?, AppRequest::GetDiffs { .. }));
+        assert_eq!(unwrap_commit_ids(q.recv()?), [2, 3]);
+        assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
+        assert_eq!(unwrap_commit_ids(q.recv()?), [1]);
+
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+        assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_overtakes_state_changes() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+
+        tx.send(get_commits(&[1], RequestPriority::Background))?;
+        tx.send(AppRequest::OpenRepo("1".into(), Default::default()))?;
+        tx.send(get_diffs("Viewer_1", "1"))?;
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+        tx.send(get_commits(&[2], RequestPriority::UserVisible))?;
+
+        // Queued before the barriers, so it can still be overtaken by them
+        assert!(is_enum_variant!(q.recv()?, AppRequest::OpenRepo(..)));
+        assert!(is_enum_variant!(q.recv()?, AppRequest::GetDiffs { .. }));
+        assert!(is_enum_variant!(q.recv()?, AppRequest::Refresh { .. }));
+        assert_eq!(unwrap_commit_ids(q.recv()?), [2]);
+        assert_eq!(unwrap_commit_ids(q.recv()?), [1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recv_does_not_block_cancellation() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut q = PriorityQueue::new(rx);
+        let token = q.commit_graph_cancellation_token("Viewer_1".into());
+
+        let receiver = std::thread::spawn(move || q.recv());
+        // Give the receiver a chance to start waiting
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!token.is_cancelled());
+
+        tx.send(AppRequest::Refresh { paths: vec![] })?;
+        assert!(is_enum_variant!(
+            receiver.join().expect("Receiver panicked")?,
+            AppRequest::Refresh { .. }
+        ));
+
+        Ok(())
+    }
 }
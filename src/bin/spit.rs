@@ -1,16 +1,27 @@
 use anyhow::Result;
-use spit::{app::App, gui::Gui};
+use spit::{
+    app::{App, Args, ParsedArgs},
+    gui::Gui,
+};
 
-use std::{env, path::PathBuf, sync::mpsc};
+use std::sync::mpsc;
 
 fn main() -> Result<()> {
     env_logger::init();
+    let args = match Args::parse_env()? {
+        ParsedArgs::Run(args) => args,
+        ParsedArgs::Help(usage) => {
+            print!("{}", usage);
+            return Ok(());
+        }
+    };
+
     let (app_response_tx, app_response_rx) = mpsc::channel();
     let (app_request_tx, app_request_rx) = mpsc::channel();
 
-    if let Some(repo) = env::args().nth(1) {
+    if let Some(repo) = args.repo {
         app_request_tx
-            .send(spit::app::AppRequest::OpenRepo(PathBuf::from(repo)))
+            .send(spit::app::AppRequest::OpenRepo(repo, args.view_spec))
             .expect("Gui TX did not initialize correctly");
     };
 
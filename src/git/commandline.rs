@@ -14,6 +14,22 @@ pub(crate) fn checkout(id: &Identifier) -> String {
     format!("git checkout {}", escaped_string(&id.to_string()))
 }
 
+/// Refs that are managed by other git commands. Removing them with update-ref drops all stash
+/// entries or all notes at once
+fn is_managed_ref(name: &str) -> bool {
+    name == "refs/stash" || name.starts_with("refs/notes/")
+}
+
+/// Whether deleting the ref should be offered. Remote branches are, delete explains how to remove
+/// them from the remote
+pub(crate) fn can_delete(ref_id: &ReferenceId) -> bool {
+    match ref_id {
+        ReferenceId::Other(name) => !is_managed_ref(name),
+        ReferenceId::Unknown => false,
+        _ => true,
+    }
+}
+
 pub(crate) fn delete(ref_id: &ReferenceId) -> Result<String> {
     let ret = match ref_id {
         ReferenceId::Symbolic(name) => {
@@ -31,6 +47,13 @@ pub(crate) fn delete(ref_id: &ReferenceId) -> Result<String> {
         ReferenceId::Tag(name) => {
             format!("git tag -d {}", escaped_string(name))
         }
+        ReferenceId::Other(name) if is_managed_ref(name) => {
+            bail!(
+                "Refusing to delete {}, use git stash or git notes instead",
+                name
+            )
+        }
+        ReferenceId::Other(name) => format!("git update-ref -d {}", escaped_string(name)),
         ReferenceId::Unknown => bail!("Cannot remove unknown ref"),
     };
     Ok(ret)
@@ -63,3 +86,17 @@ pub(crate) fn fetch_remote_ref(remote_ref: &RemoteRef) -> String {
 pub(crate) fn fetch_all() -> &'static str {
     "git fetch --all"
 }
+
+/// Fetch an object that a partial clone left out
+pub(crate) fn fetch_missing_object(remote: &str, id: &ObjectId) -> String {
+    format!(
+        "git fetch --no-tags --filter=blob:none {} {}",
+        escaped_string(remote),
+        id
+    )
+}
+
+/// Fetch more history for a shallow clone
+pub(crate) fn deepen(num_commits: usize) -> String {
+    format!("git fetch --deepen={}", num_commits)
+}
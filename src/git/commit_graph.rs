@@ -0,0 +1,276 @@
+use crate::git::{object_id::ObjectFormat, ObjectId};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use memmap2::Mmap;
+
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+const MAGIC: &[u8; 4] = b"CGPH";
+// magic, version, hash version, number of chunks, number of base graphs
+const HEADER_LEN: usize = 8;
+// chunk id, offset
+const CHUNK_LOOKUP_ENTRY_LEN: usize = 12;
+const CHUNK_OID_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OID_LOOKUP: &[u8; 4] = b"OIDL";
+const CHUNK_COMMIT_DATA: &[u8; 4] = b"CDAT";
+// parent positions, generation and commit time. The tree id comes first
+const COMMIT_DATA_FIXED_LEN: usize = 16;
+
+/// Generation of commits that are not in a commit graph. Compares greater than every real
+/// generation, the way git treats commits outside of the graph
+pub(crate) const GENERATION_INFINITY: u32 = u32::MAX;
+
+/// Topological levels from the commit-graph files of a repo. A commit's level is always greater
+/// than its parents', which lets walks stop without visiting all history
+pub(crate) struct CommitGraph {
+    layers: Vec<Layer>,
+    /// Files the layers were read from along with their modification time, to detect rewrites
+    stamps: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+struct Layer {
+    data: Mmap,
+    fanout_start: usize,
+    oid_lookup_start: usize,
+    commit_data_start: usize,
+    id_len: usize,
+}
+
+impl Layer {
+    fn open(path: &Path, object_format: ObjectFormat) -> Result<Layer> {
+        let file = File::open(path).context("Failed to open commit graph")?;
+        let data = unsafe { Mmap::map(&file).context("Failed to mmap commit graph") }?;
+
+        if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+            bail!("Not a commit graph");
+        }
+        if data[4] != 1 {
+            bail!("Unsupported commit graph version {}", data[4]);
+        }
+        let hash_format = match data[5] {
+            1 => ObjectFormat::Sha1,
+            2 => ObjectFormat::Sha256,
+            v => bail!("Unknown commit graph hash version {}", v),
+        };
+        if hash_format != object_format {
+            bail!("Commit graph is for {} ids", hash_format);
+        }
+
+        let num_chunks = data[6] as usize;
+        let chunk_offset = |id: &[u8; 4]| -> Result<usize> {
+            (0..num_chunks)
+                .map(|i| HEADER_LEN + i * CHUNK_LOOKUP_ENTRY_LEN)
+                .find(|pos| data.get(*pos..*pos + 4) == Some(&id[..]))
+                .map(|pos| read_u64(&data, pos + 4))
+                .ok_or_else(|| anyhow!("Missing chunk {}", String::from_utf8_lossy(id)))?
+        };
+
+        let fanout_start = chunk_offset(CHUNK_OID_FANOUT)?;
+        let oid_lookup_start = chunk_offset(CHUNK_OID_LOOKUP)?;
+        let commit_data_start = chunk_offset(CHUNK_COMMIT_DATA)?;
+        let id_len = object_format.raw_len();
+
+        let layer = Layer {
+            data,
+            fanout_start,
+            oid_lookup_start,
+            commit_data_start,
+            id_len,
+        };
+
+        let num_commits = layer.fanout(255)?;
+        if layer.commit_data_start + num_commits * layer.commit_data_len() > layer.data.len()
+            || layer.oid_lookup_start + num_commits * id_len > layer.data.len()
+        {
+            bail!("Truncated commit graph");
+        }
+
+        // Graphs written before generation numbers existed store zeros, which do not order
+        // anything
+        if (0..num_commits).any(|pos| layer.generation_at(pos) == 0) {
+            bail!("Commit graph has no generation numbers");
+        }
+
+        Ok(layer)
+    }
+
+    fn commit_data_len(&self) -> usize {
+        self.id_len + COMMIT_DATA_FIXED_LEN
+    }
+
+    fn fanout(&self, idx: u8) -> Result<usize> {
+        let pos = self.fanout_start + idx as usize * 4;
+        let bytes = self
+            .data
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow!("Truncated fanout"))?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("Slice not 4 bytes")) as usize)
+    }
+
+    fn position(&self, id: &ObjectId) -> Option<usize> {
+        if id.len() != self.id_len {
+            return None;
+        }
+
+        let start = match id[0] {
+            0 => 0,
+            v => self.fanout(v - 1).ok()?,
+        };
+        let end = self.fanout(id[0]).ok()?;
+        let id_at = |pos: usize| {
+            let start = self.oid_lookup_start + pos * self.id_len;
+            &self.data[start..start + self.id_len]
+        };
+
+        let (mut lower, mut upper) = (start, end);
+        while lower < upper {
+            let mid = (lower + upper) / 2;
+            match id_at(mid).cmp(id) {
+                std::cmp::Ordering::Less => lower = mid + 1,
+                std::cmp::Ordering::Greater => upper = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+
+        None
+    }
+
+    fn generation_at(&self, pos: usize) -> u32 {
+        let start = self.commit_data_start + pos * self.commit_data_len() + self.id_len + 8;
+        let word = u32::from_be_bytes(
+            self.data[start..start + 4]
+                .try_into()
+                .expect("Slice not 4 bytes"),
+        );
+        // The low two bits are the top of the commit time
+        word >> 2
+    }
+}
+
+impl CommitGraph {
+    /// Graphs of all the given object dirs, which may be a single file or a chain of layers.
+    /// Graphs are only an optimization, anything that cannot be read is skipped
+    pub(crate) fn open(object_dirs: &[PathBuf], object_format: ObjectFormat) -> CommitGraph {
+        let mut layers = Vec::new();
+        let paths = graph_paths(object_dirs);
+        let stamps = paths
+            .iter()
+            .map(|path| (path.clone(), modified(path)))
+            .collect();
+
+        for path in &paths {
+            match Layer::open(path, object_format) {
+                Ok(layer) => layers.push(layer),
+                Err(e) => warn!("Ignoring commit graph {}: {:?}", path.display(), e),
+            }
+        }
+
+        CommitGraph { layers, stamps }
+    }
+
+    /// Whether the files on disk were rewritten since they were opened, e.g. by git gc
+    pub(crate) fn is_stale(&self, object_dirs: &[PathBuf]) -> bool {
+        let paths = graph_paths(object_dirs);
+        paths.len() != self.stamps.len()
+            || paths
+                .iter()
+                .zip(&self.stamps)
+                .any(|(path, (old_path, stamp))| path != old_path || modified(path) != *stamp)
+    }
+
+    /// Topological level of the commit, GENERATION_INFINITY if it is not in the graph
+    pub(crate) fn generation(&self, id: &ObjectId) -> u32 {
+        self.layers
+            .iter()
+            .find_map(|layer| layer.position(id).map(|pos| layer.generation_at(pos)))
+            .unwrap_or(GENERATION_INFINITY)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// info/commit-graph, or the layers listed in info/commit-graphs/commit-graph-chain, of each
+/// object dir
+fn graph_paths(object_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    for objects_dir in object_dirs {
+        let info_dir = objects_dir.join("info");
+        let chain_dir = info_dir.join("commit-graphs");
+        match fs::read_to_string(chain_dir.join("commit-graph-chain")) {
+            Ok(chain) => ret.extend(
+                chain
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(|hash| chain_dir.join(format!("graph-{}.graph", hash))),
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let path = info_dir.join("commit-graph");
+                if path.exists() {
+                    ret.push(path);
+                }
+            }
+            Err(e) => warn!("Failed to read commit graph chain: {}", e),
+        }
+    }
+
+    ret
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<usize> {
+    let bytes = data
+        .get(pos..pos + 8)
+        .ok_or_else(|| anyhow!("Truncated chunk lookup"))?;
+    Ok(u64::from_be_bytes(bytes.try_into().expect("Slice not 8 bytes")) as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{commit, git};
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_generations() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let root = commit(repo_path, "root")?;
+        let child = commit(repo_path, "child")?;
+
+        let object_dirs = [repo_path.join(".git/objects")];
+        let graph = CommitGraph::open(&object_dirs, ObjectFormat::Sha1);
+        assert_eq!(graph.generation(&root), GENERATION_INFINITY);
+
+        git(repo_path, &["commit-graph", "write", "--reachable"])?;
+        assert!(graph.is_stale(&object_dirs));
+        let graph = CommitGraph::open(&object_dirs, ObjectFormat::Sha1);
+        assert!(!graph.is_stale(&object_dirs));
+        assert_eq!(graph.generation(&root), 1);
+        assert_eq!(graph.generation(&child), 2);
+
+        // Commits made after the graph was written are not in it
+        let new = commit(repo_path, "new")?;
+        assert_eq!(graph.generation(&new), GENERATION_INFINITY);
+
+        // Split graphs add a layer on top of the existing one
+        git(
+            repo_path,
+            &["commit-graph", "write", "--reachable", "--split=no-merge"],
+        )?;
+        let graph = CommitGraph::open(&object_dirs, ObjectFormat::Sha1);
+        assert_eq!(graph.generation(&child), 2);
+        assert_eq!(graph.generation(&new), 3);
+
+        Ok(())
+    }
+}
@@ -1,33 +1,82 @@
-use crate::git::{CommitMetadataWithoutId, ObjectId};
+use crate::git::{object_id::ObjectFormat, CommitMetadataWithoutId, ObjectId};
 
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
 use flate2::Decompress;
 
+use std::cell::RefCell;
+
+thread_local! {
+    /// Decompressors hold sizeable zlib state, so each thread keeps one around instead of
+    /// allocating one per object. Being per thread means objects can be inflated concurrently
+    static DECOMPRESSOR: RefCell<Decompress> = RefCell::new(Decompress::new(true));
+}
+
+/// Run f with the calling thread's decompressor
+pub(super) fn with_thread_decompressor<T>(f: impl FnOnce(&mut Decompress) -> T) -> T {
+    DECOMPRESSOR.with(|decompressor| f(&mut decompressor.borrow_mut()))
+}
+
+/// Inflate a whole zlib stream from the start of data, which may continue past the end of the
+/// stream. size_hint is the expected output size, if known
+pub(super) fn inflate(
+    data: &[u8],
+    size_hint: usize,
+    decompressor: &mut Decompress,
+) -> Result<Vec<u8>> {
+    decompressor.reset(true);
+    let mut out = Vec::with_capacity(size_hint.max(64));
+    loop {
+        let total_in = decompressor.total_in();
+        let total_out = decompressor.total_out();
+        let status = decompressor
+            .decompress_vec(
+                &data[total_in as usize..],
+                &mut out,
+                flate2::FlushDecompress::None,
+            )
+            .context("Failed to inflate object")?;
+
+        if status == flate2::Status::StreamEnd {
+            return Ok(out);
+        }
+
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        } else if decompressor.total_in() == total_in && decompressor.total_out() == total_out {
+            bail!("Truncated zlib stream");
+        }
+    }
+}
+
 pub(super) fn decompress_commit_metadata(
     commit: &[u8],
     decompressor: &mut Decompress,
     from_pack: bool,
+    object_format: ObjectFormat,
 ) -> Result<CommitMetadataWithoutId> {
     // FIXME: Long function that should be split up
 
-    // Long hashes are 20 bytes * 2 for string encoded
-    const OBJ_HASH_LEN: usize = 40;
+    // Hex encoded hash of the largest supported object format
+    const MAX_OBJ_HASH_LEN: usize = 64;
+    let obj_hash_len = object_format.hex_len();
     // tree hash\n
-    const TREE_LINE_LEN: usize = 6 + OBJ_HASH_LEN;
+    let tree_line_len = 6 + obj_hash_len;
     // parent hash\n
-    const PARENT_LINE_LEN: usize = 8 + OBJ_HASH_LEN;
+    let parent_line_len = 8 + obj_hash_len;
 
     decompressor.reset(true);
     if from_pack {
-        let mut tree_buf = [0; TREE_LINE_LEN];
+        let mut tree_buf = [0; 6 + MAX_OBJ_HASH_LEN];
+        let tree_buf = &mut tree_buf[..tree_line_len];
         decompressor
-            .decompress(commit, &mut tree_buf, flate2::FlushDecompress::None)
+            .decompress(commit, tree_buf, flate2::FlushDecompress::None)
             .context("Failed to decompress tree line")?;
     } else {
-        let mut tree_buf = [0; TREE_LINE_LEN];
+        let mut tree_buf = [0; 6 + MAX_OBJ_HASH_LEN];
+        let tree_buf = &mut tree_buf[..tree_line_len];
         decompressor
-            .decompress(commit, &mut tree_buf, flate2::FlushDecompress::None)
+            .decompress(commit, tree_buf, flate2::FlushDecompress::None)
             .context("Failed to decompress start of line")?;
         let null_byte_pos = match tree_buf.iter().position(|x| *x == 0) {
             Some(v) => v,
@@ -44,7 +93,8 @@ pub(super) fn decompress_commit_metadata(
             .context("Failed to decompress end of tree line")?;
     }
 
-    let mut parent_buf = [0; PARENT_LINE_LEN];
+    let mut parent_buf = [0; 8 + MAX_OBJ_HASH_LEN];
+    let parent_buf = &mut parent_buf[..parent_line_len];
     let mut parents: Vec<ObjectId> = Vec::new();
     loop {
         let total_in = decompressor.total_in() as usize;
@@ -52,7 +102,7 @@ pub(super) fn decompress_commit_metadata(
         decompressor
             .decompress(
                 &commit[total_in..],
-                &mut parent_buf,
+                parent_buf,
                 flate2::FlushDecompress::None,
             )
             .context("Failed to decompress pack obj data")?;
@@ -61,11 +111,11 @@ pub(super) fn decompress_commit_metadata(
             break;
         }
 
-        parents.push([0; 20].into());
-
-        // 7 bytes for parent
-        // 2*20 character kex string
-        faster_hex::hex_decode(&parent_buf[7..47], parents.last_mut().unwrap()).unwrap();
+        // 7 bytes for parent, then the hex string
+        parents.push(
+            ObjectId::from_hex(&parent_buf[7..7 + obj_hash_len])
+                .context("Failed to parse parent id")?,
+        );
     }
 
     // To get the date is a little trickier
@@ -89,7 +139,7 @@ pub(super) fn decompress_commit_metadata(
     // Note that we could also extract the author nearly for free here as well with 0 allocations
     // by just finding the ranges of the mapped data, but that seems difficult and unnecessary for
     // the time being
-    let author_buf = &mut parent_buf;
+    let author_buf = parent_buf;
     assert!(author_buf.starts_with(b"author"));
     continue_extraction_until_newline(author_buf, 0, commit, decompressor)
         .context("Failed to author newline")?;
@@ -1,15 +1,23 @@
-use crate::git::{CommitMetadata, ObjectId, Repo, SortType};
+use crate::{
+    git::{CommitMetadata, ObjectId, Repo, SortType},
+    util::CancellationToken,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::debug;
 
-#[derive(Debug)]
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct GraphPoint {
     pub x: i32,
     pub y: i32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Edge {
     pub a: GraphPoint,
     pub b: GraphPoint,
@@ -24,15 +32,29 @@ impl Edge {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CommitNode {
     pub position: GraphPoint,
     pub id: ObjectId,
 }
 
+/// A history graph, or a chunk of rows of one while it is still being built
+#[derive(Clone, Default)]
 pub struct HistoryGraph {
     pub nodes: Vec<CommitNode>,
     pub edges: Vec<Edge>,
+    /// Provisional edges for lines that continue past the last node. Empty once the graph is
+    /// complete
+    pub open_edges: Vec<Edge>,
+}
+
+impl HistoryGraph {
+    /// Append the next chunk emitted by build_git_history_graph
+    pub(crate) fn extend(&mut self, chunk: HistoryGraph) {
+        self.nodes.extend(chunk.nodes);
+        self.edges.extend(chunk.edges);
+        self.open_edges = chunk.open_edges;
+    }
 }
 
 #[derive(Debug)]
@@ -46,15 +68,59 @@ struct GraphBuilder {
     nodes: Vec<CommitNode>,
     edges: Vec<Edge>,
     tails: Vec<TailData>,
+    /// Number of rows already handed out by take_chunk
+    num_emitted_rows: usize,
 }
 
 impl GraphBuilder {
+    fn num_rows(&self) -> usize {
+        self.num_emitted_rows + self.nodes.len()
+    }
+
+    /// If processing the same commits from here on results in the same layout as other, with
+    /// rows offset by shift, returns how the start rows of lines in other map to ours
+    fn converged_with(
+        &self,
+        other: &GraphBuilder,
+        shift: i32,
+    ) -> Result<Option<HashMap<i32, i32>>> {
+        if self.tails.len() != other.tails.len() {
+            return Ok(None);
+        }
+
+        let first_row: i32 = other.num_rows().try_into()?;
+        let mut start_map = HashMap::new();
+        for (tail, other_tail) in self.tails.iter().zip(&other.tails) {
+            if tail.oid != other_tail.oid {
+                return Ok(None);
+            }
+
+            // Lines are drawn differently if they start on the row of the commit that ends them,
+            // so starts that can still be reached have to line up exactly
+            let reachable = tail.edge_start_y >= first_row + shift;
+            let other_reachable = other_tail.edge_start_y >= first_row;
+            if (reachable || other_reachable)
+                && tail.edge_start_y != other_tail.edge_start_y + shift
+            {
+                return Ok(None);
+            }
+
+            // Lines are only identified by their start, so it has to be unambiguous
+            let existing = start_map.insert(other_tail.edge_start_y, tail.edge_start_y);
+            if existing.is_some_and(|existing| existing != tail.edge_start_y) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(start_map))
+    }
+
     fn process_commit(&mut self, commit: &CommitMetadata) -> Result<()> {
-        let commit_y_pos = self.nodes.len().try_into().context("Too many commits")?;
+        let commit_y_pos = self.num_rows().try_into().context("Too many commits")?;
         let commit_tail_idx = ensure_commit_in_vec(commit, &mut self.tails, commit_y_pos);
         let parent_ids = &commit.parents;
 
-        add_commit_to_node_list(commit_tail_idx, commit, &mut self.nodes)?;
+        add_commit_to_node_list(commit_tail_idx, commit_y_pos, commit, &mut self.nodes)?;
         debug!("Tails before removal: {:?}", self.tails);
         let removed_data =
             replace_tail_with_parents(parent_ids, commit_tail_idx, commit_y_pos, &mut self.tails)?;
@@ -105,26 +171,39 @@ impl GraphBuilder {
         Ok(())
     }
 
+    /// Hand out all rows processed since the last chunk. Edges that are not yet finished are
+    /// drawn to the end of the chunk in open_edges
+    fn take_chunk(&mut self) -> Result<HistoryGraph> {
+        let end_y = self.num_rows().try_into()?;
+        let mut open_edges = Vec::new();
+        finish_edges(&self.tails, end_y, &mut open_edges)?;
+
+        self.num_emitted_rows = self.num_rows();
+        Ok(HistoryGraph {
+            nodes: std::mem::take(&mut self.nodes),
+            edges: std::mem::take(&mut self.edges),
+            open_edges,
+        })
+    }
+
     fn build(mut self) -> Result<HistoryGraph> {
-        let end_y = self.nodes.len().try_into()?;
+        let end_y = self.num_rows().try_into()?;
         finish_edges(&self.tails, end_y, &mut self.edges)?;
         Ok(HistoryGraph {
             nodes: self.nodes,
             edges: self.edges,
+            open_edges: Vec::new(),
         })
     }
 }
 
 fn add_commit_to_node_list(
     x_idx: usize,
+    y: i32,
     commit: &CommitMetadata,
     node_list: &mut Vec<CommitNode>,
 ) -> Result<()> {
     let x = x_idx.try_into().context("Commit index too large")?;
-    let y = node_list
-        .last()
-        .map(|node| node.position.y + 1)
-        .unwrap_or(0);
     let position = GraphPoint { x, y };
     let id = commit.id.clone();
     node_list.push(CommitNode { position, id });
@@ -247,33 +326,453 @@ fn finish_edges(tails: &[TailData], end_y: i32, edges: &mut Vec<Edge>) -> Result
     Ok(())
 }
 
-pub(crate) fn build_git_history_graph(
+/// Rewrite a child first list of commits so that it only contains commits that modify the given
+/// path. Parents of the remaining commits are replaced by their nearest remaining ancestors. Like
+/// git log's default history simplification, a commit with the same content at path as one of its
+/// parents is replaced by that parent
+fn simplify_history(
+    repo: &mut Repo,
+    commits: Vec<CommitMetadata>,
+    path: &Path,
+    cancel_token: &CancellationToken,
+) -> Result<Vec<CommitMetadata>> {
+    let mut entry_cache: HashMap<ObjectId, Option<ObjectId>> = HashMap::new();
+    let mut path_entry = |id: &ObjectId| -> Result<Option<ObjectId>> {
+        if let Some(entry) = entry_cache.get(id) {
+            return Ok(entry.clone());
+        }
+
+        let entry = repo.path_entry_id(id, path)?;
+        entry_cache.insert(id.clone(), entry.clone());
+        Ok(entry)
+    };
+
+    // The remaining commits that stand in for a given commit. Commits that are kept stand in for
+    // themselves
+    let mut replacements: HashMap<ObjectId, Vec<ObjectId>> = HashMap::new();
+    let mut ret = Vec::new();
+
+    // Parents are always seen after their children. Walk backwards so that replacements for all
+    // parents are known by the time we see a commit
+    for (i, mut commit) in commits.into_iter().rev().enumerate() {
+        if i % CancellationToken::CHECK_INTERVAL == 0 {
+            cancel_token.check()?;
+        }
+
+        let entry = path_entry(&commit.id)?;
+
+        let mut treesame_parent = None;
+        for parent in &commit.parents {
+            if path_entry(parent)? == entry {
+                treesame_parent = Some(parent);
+                break;
+            }
+        }
+
+        if let Some(parent) = treesame_parent {
+            let parent_replacements = replacements.get(parent).cloned().unwrap_or_default();
+            replacements.insert(commit.id.clone(), parent_replacements);
+            continue;
+        }
+
+        if commit.parents.is_empty() && entry.is_none() {
+            continue;
+        }
+
+        let mut rewritten_parents: Vec<ObjectId> = Vec::new();
+        for parent in &commit.parents {
+            for replacement in replacements.get(parent).into_iter().flatten() {
+                if !rewritten_parents.contains(replacement) {
+                    rewritten_parents.push(replacement.clone());
+                }
+            }
+        }
+
+        commit.parents = rewritten_parents;
+        replacements.insert(commit.id.clone(), vec![commit.id.clone()]);
+        ret.push(commit);
+    }
+
+    ret.reverse();
+    Ok(ret)
+}
+
+/// Emits rows to a callback in chunks so that the start of a large history can be shown before
+/// the whole graph is built. Chunks start small and grow to keep the number of chunks down
+struct ChunkedGraphBuilder<'a, F> {
+    builder: GraphBuilder,
+    chunk_size: usize,
+    on_chunk: F,
+    cancel_token: &'a CancellationToken,
+}
+
+impl<'a, F> ChunkedGraphBuilder<'a, F>
+where
+    F: FnMut(HistoryGraph) -> Result<()>,
+{
+    const INITIAL_CHUNK_SIZE: usize = 256;
+    const MAX_CHUNK_SIZE: usize = 65536;
+
+    fn new(on_chunk: F, cancel_token: &'a CancellationToken) -> Self {
+        ChunkedGraphBuilder {
+            builder: GraphBuilder::default(),
+            chunk_size: Self::INITIAL_CHUNK_SIZE,
+            on_chunk,
+            cancel_token,
+        }
+    }
+
+    fn process_commit(&mut self, commit: &CommitMetadata) -> Result<()> {
+        self.builder
+            .process_commit(commit)
+            .context("Failed to add commit to graph")?;
+
+        if self.builder.num_rows() % CancellationToken::CHECK_INTERVAL == 0 {
+            self.cancel_token.check()?;
+        }
+
+        if self.builder.nodes.len() >= self.chunk_size {
+            (self.on_chunk)(self.builder.take_chunk()?)?;
+            self.chunk_size = usize::min(self.chunk_size * 2, Self::MAX_CHUNK_SIZE);
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        (self.on_chunk)(self.builder.build()?)
+    }
+}
+
+/// Build the history graph for the given heads. Rows are handed to on_chunk in order as they are
+/// built, the final chunk contains the remaining rows and closes off all edges. Stops with a
+/// Cancelled error if cancel_token is cancelled part way through
+pub(crate) fn build_git_history_graph<F>(
     repo: &mut Repo,
     heads: &[ObjectId],
+    excluded_heads: &[ObjectId],
     sort_type: SortType,
-) -> Result<HistoryGraph> {
-    let mut graph_builder = GraphBuilder::default();
+    path_filter: Option<&Path>,
+    cancel_token: &CancellationToken,
+    on_chunk: F,
+) -> Result<()>
+where
+    F: FnMut(HistoryGraph) -> Result<()>,
+{
+    let mut graph_builder = ChunkedGraphBuilder::new(on_chunk, cancel_token);
 
-    let revwalk = repo.metadata_iter(heads, sort_type)?;
-    for metadata in revwalk {
-        graph_builder
-            .process_commit(metadata)
-            .context("Failed to add commit to graph")?;
+    if excluded_heads.is_empty() && path_filter.is_none() {
+        let revwalk = repo.metadata_iter(heads, sort_type, cancel_token)?;
+        for metadata in revwalk {
+            graph_builder.process_commit(&metadata?)?;
+        }
+
+        return graph_builder.finish();
+    }
+
+    let excluded = repo
+        .reachable_commits(excluded_heads, cancel_token)
+        .context("Failed to find excluded commits")?;
+
+    let mut commits = repo
+        .metadata_iter(heads, sort_type, cancel_token)?
+        .filter(|metadata| {
+            metadata
+                .as_ref()
+                .map_or(true, |metadata| !excluded.contains(&metadata.id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(path) = path_filter {
+        commits = simplify_history(repo, commits, path, cancel_token)
+            .context("Failed to filter by path")?;
+    }
+
+    for mut metadata in commits {
+        metadata.parents.retain(|parent| !excluded.contains(parent));
+        graph_builder.process_commit(&metadata)?;
+    }
+
+    graph_builder.finish()
+}
+
+/// Old rows are re-laid out after the new rows until the lanes look the same as they did in the
+/// old graph. Past that point the old rows can be reused. Usually this happens within a couple of
+/// rows
+const MAX_REPLAYED_ROWS: usize = 1024;
+
+/// Rows that prepend_to_history_graph puts on top of an existing graph. Rows further down only
+/// move, so this stays small however large the graph is
+#[derive(Clone)]
+pub struct GraphPrepend {
+    /// New rows followed by the old rows that were laid out again
+    nodes: Vec<CommitNode>,
+    edges: Vec<Edge>,
+    /// Number of old rows that are replaced by the end of nodes
+    num_replayed_rows: usize,
+    /// Number of new rows, which is how far all old rows move down
+    shift: i32,
+    /// New start rows of old lines that started in a replayed row
+    start_map: HashMap<i32, i32>,
+}
+
+impl GraphPrepend {
+    /// Where an old edge ends up. None if it was drawn again while replaying rows, an error if the
+    /// edge does not belong to the graph the rows were prepended to
+    fn move_edge(&self, edge: &Edge) -> Result<Option<Edge>> {
+        let first_reused_row: i32 = self.num_replayed_rows.try_into()?;
+        let vertical = edge.a.x == edge.b.x;
+        // Edges that were drawn while processing the replayed rows have been drawn again
+        let replayed = if vertical {
+            edge.b.y < first_reused_row
+        } else {
+            edge.a.y < first_reused_row
+        };
+        if replayed {
+            return Ok(None);
+        }
+
+        // Lines that started above the first reused row start somewhere else now
+        let a_y = if edge.a.y < first_reused_row {
+            *self
+                .start_map
+                .get(&edge.a.y)
+                .ok_or_else(|| anyhow!("No new start for line from row {}", edge.a.y))?
+        } else {
+            edge.a.y + self.shift
+        };
+
+        Ok(Some(Edge::new(
+            edge.a.x,
+            a_y,
+            edge.b.x,
+            edge.b.y + self.shift,
+        )))
+    }
+}
+
+impl HistoryGraph {
+    /// Apply rows from prepend_to_history_graph to the complete graph they were built for
+    pub(crate) fn prepend(&mut self, prepend: GraphPrepend) -> Result<()> {
+        if prepend.num_replayed_rows > self.nodes.len() || !self.open_edges.is_empty() {
+            bail!("Rows were prepended to a different graph");
+        }
+
+        let mut edges = self
+            .edges
+            .iter()
+            .filter_map(|edge| prepend.move_edge(edge).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        edges.extend(prepend.edges);
+        self.edges = edges;
+
+        let old_nodes = self.nodes.drain(prepend.num_replayed_rows..);
+        let mut nodes = prepend.nodes;
+        nodes.extend(old_nodes.map(|node| CommitNode {
+            position: GraphPoint {
+                x: node.position.x,
+                y: node.position.y + prepend.shift,
+            },
+            id: node.id,
+        }));
+        self.nodes = nodes;
+
+        Ok(())
     }
+}
 
-    graph_builder.build()
+/// What prepend_to_history_graph needs to know about a complete graph, so that the graph itself
+/// does not have to be kept around after it was handed out
+#[derive(Default)]
+pub(crate) struct GraphSummary {
+    /// Ids of all rows
+    ids: HashSet<ObjectId>,
+    /// The rows that may be laid out again and the edges starting in them
+    top: HistoryGraph,
+}
+
+impl GraphSummary {
+    const NUM_TOP_ROWS: usize = MAX_REPLAYED_ROWS;
+
+    /// Add the next chunk emitted by build_git_history_graph
+    pub(crate) fn extend(&mut self, chunk: &HistoryGraph) {
+        let num_top_rows = i32::try_from(Self::NUM_TOP_ROWS).expect("Top rows fit in i32");
+        let num_missing = Self::NUM_TOP_ROWS.saturating_sub(self.top.nodes.len());
+
+        self.ids
+            .extend(chunk.nodes.iter().map(|node| node.id.clone()));
+        self.top
+            .nodes
+            .extend(chunk.nodes.iter().take(num_missing).cloned());
+        self.top.edges.extend(
+            chunk
+                .edges
+                .iter()
+                .filter(|edge| edge.a.y < num_top_rows)
+                .cloned(),
+        );
+    }
+
+    /// Update the summary with rows that were sent on top of the graph
+    pub(crate) fn prepend(&mut self, prepend: GraphPrepend) -> Result<()> {
+        let num_top_rows = i32::try_from(Self::NUM_TOP_ROWS).expect("Top rows fit in i32");
+
+        self.ids
+            .extend(prepend.nodes.iter().map(|node| node.id.clone()));
+        self.top.prepend(prepend)?;
+        self.top.nodes.truncate(Self::NUM_TOP_ROWS);
+        self.top.edges.retain(|edge| edge.a.y < num_top_rows);
+
+        Ok(())
+    }
+}
+
+/// Find rows for commits that were added on top of the graph summarized by old_graph, e.g. after
+/// a commit or a fast forward fetch, without walking the existing history again. old_graph must
+/// be for a graph built by build_git_history_graph for old_heads without excluded heads or a path
+/// filter. New commits are placed above all existing rows. Returns None if the graph cannot be
+/// updated in place, e.g. because a head moved backwards or a new commit is older than the
+/// existing rows
+pub(crate) fn prepend_to_history_graph(
+    repo: &mut Repo,
+    old_graph: &GraphSummary,
+    old_heads: &[ObjectId],
+    heads: &[ObjectId],
+    sort_type: SortType,
+    cancel_token: &CancellationToken,
+) -> Result<Option<GraphPrepend>> {
+    let new_commits = repo
+        .metadata_iter_until(heads, &old_graph.ids, sort_type, cancel_token)?
+        .collect::<Result<Vec<_>>>()?;
+
+    // Every old head has to still be reachable, otherwise old rows would have to be removed
+    let old_head_reachable = |old_head: &ObjectId| {
+        heads.contains(old_head)
+            || new_commits
+                .iter()
+                .any(|commit| commit.parents.contains(old_head))
+    };
+    if !old_heads.iter().all(old_head_reachable) {
+        return Ok(None);
+    }
+
+    // A rebuild would sort commits older than the top row in between the old rows, e.g. with
+    // clock skew or when an old branch is fetched
+    if let Some(top_node) = old_graph.top.nodes.first() {
+        let timestamp = |metadata: &CommitMetadata| match sort_type {
+            SortType::AuthorTimestamp => metadata.author_timestamp,
+            SortType::CommitterTimestamp => metadata.committer_timestamp,
+        };
+        let top_timestamp = timestamp(&repo.get_commit_metadata(&top_node.id)?);
+        if new_commits
+            .iter()
+            .any(|commit| timestamp(commit) < top_timestamp)
+        {
+            debug!("New commits are older than the graph, rebuilding graph");
+            return Ok(None);
+        }
+    }
+
+    let mut builder = GraphBuilder::default();
+    for commit in &new_commits {
+        builder.process_commit(commit)?;
+    }
+    let num_new_rows = builder.num_rows();
+    let shift: i32 = num_new_rows.try_into().context("Too many commits")?;
+
+    let mut old_builder = GraphBuilder::default();
+    let mut num_replayed_rows = 0;
+    let start_map = loop {
+        if let Some(start_map) = builder.converged_with(&old_builder, shift)? {
+            break start_map;
+        }
+
+        if num_replayed_rows >= usize::min(MAX_REPLAYED_ROWS, old_graph.top.nodes.len()) {
+            debug!("Lanes did not converge, rebuilding graph");
+            return Ok(None);
+        }
+
+        let old_node = &old_graph.top.nodes[num_replayed_rows];
+        let metadata = repo.get_commit_metadata(&old_node.id)?;
+        builder.process_commit(&metadata)?;
+        old_builder.process_commit(&metadata)?;
+
+        // The old graph was not laid out the way we expected, it cannot be reused
+        if old_builder.nodes.last().map(|node| &node.position) != Some(&old_node.position) {
+            return Ok(None);
+        }
+
+        num_replayed_rows += 1;
+    };
+
+    let prepend = GraphPrepend {
+        nodes: builder.nodes,
+        edges: builder.edges,
+        num_replayed_rows,
+        shift,
+        start_map,
+    };
+
+    // Every line that started in a replayed row has to have started again
+    for edge in &old_graph.top.edges {
+        if prepend.move_edge(edge).is_err() {
+            return Ok(None);
+        }
+    }
+
+    debug!(
+        "Prepended {} rows, replayed {} rows",
+        num_new_rows, num_replayed_rows
+    );
+
+    Ok(Some(prepend))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::git::{
+        test_util::{commit_at, git},
+        ReferenceId,
+    };
     use anyhow::Result;
     use tempfile::TempDir;
 
-    fn get_all_heads(repo: &Repo) -> Result<Vec<ObjectId>> {
-        repo.branches()?
-            .map(|b| Ok(b?.head))
-            .collect::<Result<Vec<ObjectId>>>()
+    fn build_graph(
+        repo: &mut Repo,
+        heads: &[ObjectId],
+        excluded_heads: &[ObjectId],
+        sort_type: SortType,
+        path_filter: Option<&Path>,
+    ) -> Result<HistoryGraph> {
+        let mut graph: Option<HistoryGraph> = None;
+        build_git_history_graph(
+            repo,
+            heads,
+            excluded_heads,
+            sort_type,
+            path_filter,
+            &CancellationToken::default(),
+            |chunk| {
+                match &mut graph {
+                    Some(graph) => graph.extend(chunk),
+                    None => graph = Some(chunk),
+                }
+                Ok(())
+            },
+        )?;
+
+        let graph = graph.expect("No chunks emitted");
+        assert!(graph.open_edges.is_empty());
+        Ok(graph)
+    }
+
+    fn get_all_heads(repo: &mut Repo) -> Result<Vec<ObjectId>> {
+        Ok(repo
+            .references()?
+            .into_iter()
+            .map(|reference| reference.head)
+            .collect())
     }
 
     fn find_edge(x1: i32, y1: i32, x2: i32, y2: i32, edges: &[Edge]) -> bool {
@@ -295,8 +794,14 @@ mod tests {
             .unwrap();
 
         let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
-        let all_heads = get_all_heads(&repo)?;
-        let graph = build_git_history_graph(&mut repo, &all_heads, SortType::CommitterTimestamp)?;
+        let all_heads = get_all_heads(&mut repo)?;
+        let graph = build_graph(
+            &mut repo,
+            &all_heads,
+            &[],
+            SortType::CommitterTimestamp,
+            None,
+        )?;
         assert_eq!(graph.nodes.len(), 3);
         assert_eq!(graph.nodes[0].position.x, 0);
         assert_eq!(graph.nodes[1].position.x, 0);
@@ -323,8 +828,14 @@ mod tests {
             .unwrap();
 
         let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
-        let all_heads = get_all_heads(&repo)?;
-        let graph = build_git_history_graph(&mut repo, &all_heads, SortType::CommitterTimestamp)?;
+        let all_heads = get_all_heads(&mut repo)?;
+        let graph = build_graph(
+            &mut repo,
+            &all_heads,
+            &[],
+            SortType::CommitterTimestamp,
+            None,
+        )?;
         assert_eq!(graph.nodes.len(), 4);
         assert_eq!(graph.nodes[0].position.x, 0);
         assert_eq!(graph.nodes[1].position.x, 1);
@@ -352,8 +863,14 @@ mod tests {
             .unwrap();
 
         let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
-        let all_heads = get_all_heads(&repo)?;
-        let graph = build_git_history_graph(&mut repo, &all_heads, SortType::CommitterTimestamp)?;
+        let all_heads = get_all_heads(&mut repo)?;
+        let graph = build_graph(
+            &mut repo,
+            &all_heads,
+            &[],
+            SortType::CommitterTimestamp,
+            None,
+        )?;
         assert_eq!(graph.nodes.len(), 4);
         assert_eq!(graph.nodes[0].position.x, 0);
         assert_eq!(graph.nodes[1].position.x, 0);
@@ -382,8 +899,14 @@ mod tests {
             .unwrap();
 
         let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
-        let all_heads = get_all_heads(&repo)?;
-        let graph = build_git_history_graph(&mut repo, &all_heads, SortType::CommitterTimestamp)?;
+        let all_heads = get_all_heads(&mut repo)?;
+        let graph = build_graph(
+            &mut repo,
+            &all_heads,
+            &[],
+            SortType::CommitterTimestamp,
+            None,
+        )?;
         assert_eq!(graph.nodes.len(), 6);
         assert_eq!(graph.nodes[0].position.x, 0);
         assert_eq!(graph.nodes[1].position.x, 1);
@@ -412,4 +935,229 @@ mod tests {
         assert!(find_edge(1, 4, 0, 5, &graph.edges));
         Ok(())
     }
+
+    #[test]
+    fn excluded_heads() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        tar::Archive::new(SINGLE_FORK)
+            .unpack(tmp_dir.path())
+            .unwrap();
+
+        let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
+        let master = repo.find_reference_commit_id(&ReferenceId::LocalBranch("master".into()))?;
+        let b = repo.find_reference_commit_id(&ReferenceId::LocalBranch("b".into()))?;
+
+        let graph = build_graph(
+            &mut repo,
+            std::slice::from_ref(&master),
+            &[b],
+            SortType::CommitterTimestamp,
+            None,
+        )?;
+        assert_eq!(graph.nodes.len(), 1);
+        assert_eq!(graph.nodes[0].id, master);
+        assert!(graph.edges.is_empty());
+        Ok(())
+    }
+
+    fn commit_file(dir: &Path, name: &str, content: &str) -> Result<ObjectId> {
+        std::fs::write(dir.join(name), content)?;
+        git(dir, &["add", name])?;
+        git(dir, &["commit", "-m", name])?;
+        git(dir, &["rev-parse", "HEAD"])?.parse()
+    }
+
+    #[test]
+    fn path_filter() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let dir = tmp_dir.path();
+        git(dir, &["init"])?;
+
+        let a1 = commit_file(dir, "a.txt", "1")?;
+        commit_file(dir, "b.txt", "1")?;
+        let a2 = commit_file(dir, "a.txt", "2")?;
+        let head = commit_file(dir, "b.txt", "2")?;
+
+        let mut repo = Repo::new(dir.to_path_buf(), false)?;
+        let graph = build_graph(
+            &mut repo,
+            &[head],
+            &[],
+            SortType::CommitterTimestamp,
+            Some(Path::new("a.txt")),
+        )?;
+
+        assert_eq!(graph.nodes.len(), 2);
+        assert_eq!(graph.nodes[0].id, a2);
+        assert_eq!(graph.nodes[1].id, a1);
+        assert_eq!(graph.edges.len(), 1);
+        assert!(find_edge(0, 0, 0, 1, &graph.edges));
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_graph_matches_full_graph() -> Result<()> {
+        const OCTOPUS_MERGE: &[u8] =
+            include_bytes!("../../res/test/multi_obj_multi_pack_octopus_merge.tar");
+        let tmp_dir = TempDir::new()?;
+        tar::Archive::new(OCTOPUS_MERGE).unpack(tmp_dir.path())?;
+
+        let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
+        let all_heads = get_all_heads(&mut repo)?;
+        let commits = repo
+            .metadata_iter(
+                &all_heads,
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        assert!(commits.len() > 4);
+
+        let mut full_builder = GraphBuilder::default();
+        for commit in &commits {
+            full_builder.process_commit(commit)?;
+        }
+        let full_graph = full_builder.build()?;
+
+        let mut chunked_builder = GraphBuilder::default();
+        let mut chunked_graph: Option<HistoryGraph> = None;
+        for (i, commit) in commits.iter().enumerate() {
+            chunked_builder.process_commit(commit)?;
+            if i % 2 == 1 {
+                let chunk = chunked_builder.take_chunk()?;
+                assert_eq!(chunk.nodes.len(), 2);
+                assert!(chunk.open_edges.iter().all(|edge| edge.b.y == i as i32 + 1));
+
+                match &mut chunked_graph {
+                    Some(graph) => graph.extend(chunk),
+                    None => chunked_graph = Some(chunk),
+                }
+            }
+        }
+
+        let mut chunked_graph = chunked_graph.expect("No chunks taken");
+        chunked_graph.extend(chunked_builder.build()?);
+
+        assert_eq!(chunked_graph.nodes, full_graph.nodes);
+        assert_eq!(chunked_graph.edges, full_graph.edges);
+        assert!(chunked_graph.open_edges.is_empty());
+
+        Ok(())
+    }
+
+    fn sorted_edges(graph: &HistoryGraph) -> Vec<(i32, i32, i32, i32)> {
+        let mut edges = graph
+            .edges
+            .iter()
+            .map(|edge| (edge.a.y, edge.a.x, edge.b.y, edge.b.x))
+            .collect::<Vec<_>>();
+        edges.sort();
+        edges
+    }
+
+    /// Prepend to old_graph and check that the result matches a full rebuild. Returns false if
+    /// the graph could not be updated in place
+    fn check_prepend(
+        dir: &Path,
+        old_graph: &HistoryGraph,
+        old_heads: &[ObjectId],
+        heads: &[ObjectId],
+    ) -> Result<bool> {
+        let mut repo = Repo::new(dir.to_path_buf(), false)?;
+        let mut summary = GraphSummary::default();
+        summary.extend(old_graph);
+        let prepend = prepend_to_history_graph(
+            &mut repo,
+            &summary,
+            old_heads,
+            heads,
+            SortType::CommitterTimestamp,
+            &CancellationToken::default(),
+        )?;
+
+        let Some(prepend) = prepend else {
+            return Ok(false);
+        };
+
+        let mut prepended = old_graph.clone();
+        prepended.prepend(prepend.clone())?;
+        summary.prepend(prepend)?;
+
+        let rebuilt = build_graph(&mut repo, heads, &[], SortType::CommitterTimestamp, None)?;
+        assert_eq!(prepended.nodes, rebuilt.nodes);
+        assert_eq!(sorted_edges(&prepended), sorted_edges(&rebuilt));
+        assert!(prepended.open_edges.is_empty());
+        assert_eq!(summary.top.nodes, rebuilt.nodes);
+        assert_eq!(summary.ids.len(), rebuilt.nodes.len());
+
+        Ok(true)
+    }
+
+    #[test]
+    fn prepend_new_commits() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let dir = tmp_dir.path();
+        git(dir, &["init", "-b", "main"])?;
+
+        let mut main = Vec::new();
+        for i in 0..5 {
+            main.push(commit_at(dir, &format!("main {}", i), 1000 + i)?);
+        }
+
+        let graph_for = |heads: &[ObjectId]| -> Result<HistoryGraph> {
+            let mut repo = Repo::new(dir.to_path_buf(), false)?;
+            build_graph(&mut repo, heads, &[], SortType::CommitterTimestamp, None)
+        };
+
+        // New commit on top of the only head
+        let old_heads = [main[4].clone()];
+        let old_graph = graph_for(&old_heads)?;
+        let new_main = commit_at(dir, "main 5", 1005)?;
+        assert!(check_prepend(
+            dir,
+            &old_graph,
+            &old_heads,
+            std::slice::from_ref(&new_main)
+        )?);
+
+        // New branch from deep in the history, which needs a new lane next to existing rows
+        let old_heads = [new_main.clone()];
+        let old_graph = graph_for(&old_heads)?;
+        git(dir, &["checkout", "-b", "topic", &main[1].to_string()])?;
+        commit_at(dir, "topic 0", 1006)?;
+        let topic = commit_at(dir, "topic 1", 1007)?;
+        let heads = [new_main.clone(), topic.clone()];
+        assert!(check_prepend(dir, &old_graph, &old_heads, &heads)?);
+
+        // Merge on top of two heads
+        let old_heads = heads;
+        let old_graph = graph_for(&old_heads)?;
+        git(dir, &["checkout", "main"])?;
+        git(dir, &["merge", "--no-ff", "--no-commit", "topic"])?;
+        let merge = commit_at(dir, "merge", 1008)?;
+        let heads = [merge.clone(), topic.clone()];
+        assert!(check_prepend(dir, &old_graph, &old_heads, &heads)?);
+
+        // Moving a head backwards removes rows, which needs a rebuild
+        let old_heads = heads;
+        let old_graph = graph_for(&old_heads)?;
+        assert!(!check_prepend(
+            dir,
+            &old_graph,
+            &old_heads,
+            &[new_main, topic.clone()]
+        )?);
+
+        // A commit with a skewed clock sorts below the topic head in a rebuild, so it cannot
+        // simply go on top
+        let skewed = commit_at(dir, "skewed", 1003)?;
+        assert!(!check_prepend(
+            dir,
+            &old_graph,
+            &old_heads,
+            &[skewed, topic]
+        )?);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,199 @@
+use crate::git::{object_id::ObjectFormat, ObjectId};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::{fs, io::ErrorKind, path::Path};
+
+/// Size of the stat data, mode and file size that start each entry
+const ENTRY_STAT_SIZE: usize = 40;
+const FLAG_EXTENDED: u16 = 0x4000;
+const NAME_MASK: u16 = 0xfff;
+
+pub(crate) struct IndexEntry {
+    pub(crate) path: Vec<u8>,
+    pub(crate) id: ObjectId,
+    /// 0 for regular entries, 1-3 for the sides of a conflict
+    pub(crate) stage: u8,
+}
+
+/// Entries of the index file at path, empty if there is no index yet
+pub(crate) fn read_index(path: &Path, object_format: ObjectFormat) -> Result<Vec<IndexEntry>> {
+    let data = match fs::read(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read index"),
+    };
+
+    parse_index(&data, object_format)
+}
+
+/// Parse an index in any of the formats git writes (versions 2 to 4)
+fn parse_index(data: &[u8], object_format: ObjectFormat) -> Result<Vec<IndexEntry>> {
+    let read_u32 = |pos: usize| -> Result<u32> {
+        let bytes = data
+            .get(pos..pos + 4)
+            .ok_or_else(|| anyhow!("Truncated index"))?;
+        Ok(u32::from_be_bytes(
+            bytes.try_into().expect("Slice not 4 bytes"),
+        ))
+    };
+    let read_u16 = |pos: usize| -> Result<u16> {
+        let bytes = data
+            .get(pos..pos + 2)
+            .ok_or_else(|| anyhow!("Truncated index"))?;
+        Ok(u16::from_be_bytes(
+            bytes.try_into().expect("Slice not 2 bytes"),
+        ))
+    };
+
+    if !data.starts_with(b"DIRC") {
+        bail!("Index has an invalid signature");
+    }
+
+    let version = read_u32(4)?;
+    if !(2..=4).contains(&version) {
+        bail!("Unsupported index version {}", version);
+    }
+
+    let num_entries = read_u32(8)? as usize;
+    let id_len = object_format.raw_len();
+    let mut entries: Vec<IndexEntry> = Vec::with_capacity(num_entries);
+    let mut pos = 12;
+
+    for _ in 0..num_entries {
+        let entry_start = pos;
+        let id_start = pos + ENTRY_STAT_SIZE;
+        let id = data
+            .get(id_start..id_start + id_len)
+            .ok_or_else(|| anyhow!("Truncated index"))?
+            .try_into()?;
+        let flags = read_u16(id_start + id_len)?;
+        pos = id_start + id_len + 2;
+        if version >= 3 && flags & FLAG_EXTENDED != 0 {
+            pos += 2;
+        }
+
+        let path = if version == 4 {
+            // Paths are stored as the number of bytes to drop from the end of the previous path,
+            // followed by the suffix to append
+            let (strip_len, read_bytes) = read_offset_varint(&data[pos..])?;
+            pos += read_bytes;
+            let prev = entries.last().map_or(&[][..], |entry| &entry.path[..]);
+            let keep = prev
+                .len()
+                .checked_sub(strip_len)
+                .ok_or_else(|| anyhow!("Invalid path compression in index"))?;
+            let suffix_len = find_nul(&data[pos..])?;
+            let mut path = prev[..keep].to_vec();
+            path.extend(&data[pos..pos + suffix_len]);
+            pos += suffix_len + 1;
+            path
+        } else {
+            let name_len = match flags & NAME_MASK {
+                // Names that do not fit in the flags are only terminated by the nul
+                NAME_MASK => find_nul(&data[pos..])?,
+                len => len as usize,
+            };
+            let path = data
+                .get(pos..pos + name_len)
+                .ok_or_else(|| anyhow!("Truncated index"))?
+                .to_vec();
+            // Entries are padded with 1-8 nul bytes to a multiple of 8
+            pos = entry_start + (pos - entry_start + name_len + 8) / 8 * 8;
+            path
+        };
+
+        entries.push(IndexEntry {
+            path,
+            id,
+            stage: ((flags >> 12) & 0x3) as u8,
+        });
+    }
+
+    // A split index keeps most entries in a shared index file, which is rare enough to not
+    // bother with
+    let extensions_end = data.len().saturating_sub(id_len);
+    while pos + 8 <= extensions_end {
+        let signature = &data[pos..pos + 4];
+        if signature == b"link" {
+            bail!("Split indexes are not supported");
+        }
+        pos += 8 + read_u32(pos + 4)? as usize;
+    }
+
+    Ok(entries)
+}
+
+fn find_nul(data: &[u8]) -> Result<usize> {
+    data.iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| anyhow!("Unterminated path in index"))
+}
+
+/// The variable length integer encoding git uses for offsets, see varint.c
+fn read_offset_varint(data: &[u8]) -> Result<(usize, usize)> {
+    let mut i = 0;
+    let mut b = *data.first().ok_or_else(|| anyhow!("Truncated index"))?;
+    let mut val = (b & 0x7f) as usize;
+    while b & 0x80 != 0 {
+        i += 1;
+        b = *data.get(i).ok_or_else(|| anyhow!("Truncated index"))?;
+        val = ((val + 1) << 7) | (b & 0x7f) as usize;
+    }
+
+    Ok((val, i + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::git;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_read_index_versions() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+
+        git(repo_path, &["init", "-q"])?;
+        fs::create_dir_all(repo_path.join("dir/sub"))?;
+        for path in ["a", "dir/b", "dir/sub/c", "dir/sub/cd"] {
+            fs::write(repo_path.join(path), path)?;
+        }
+        git(repo_path, &["add", "."])?;
+        // Too long for the name length in the entry flags, and for the file system
+        let blob = git(repo_path, &["rev-parse", ":a"])?;
+        let long_name = format!("dir/{}", "d".repeat(5000));
+        git(
+            repo_path,
+            &[
+                "update-index",
+                "--add",
+                "--cacheinfo",
+                &format!("100644,{},{}", blob, long_name),
+            ],
+        )?;
+
+        let expected = format!("{}\n", git(repo_path, &["ls-files", "-s"])?);
+        for version in ["2", "3", "4"] {
+            git(repo_path, &["update-index", "--index-version", version])?;
+            let entries = read_index(&repo_path.join(".git/index"), ObjectFormat::Sha1)?;
+            let actual = entries
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "100644 {} {}\t{}\n",
+                        entry.id,
+                        entry.stage,
+                        String::from_utf8_lossy(&entry.path)
+                    )
+                })
+                .collect::<String>();
+            assert_eq!(actual, expected, "index version {}", version);
+        }
+
+        assert!(read_index(&repo_path.join("missing"), ObjectFormat::Sha1)?.is_empty());
+        Ok(())
+    }
+}
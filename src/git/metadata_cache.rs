@@ -0,0 +1,533 @@
+use crate::git::{object_id::ObjectFormat, CommitMetadata, ObjectId};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::Crc;
+use log::{debug, error, warn};
+use memmap2::Mmap;
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+};
+
+const MAGIC: &[u8; 8] = b"SPITMETA";
+const VERSION: u32 = 2;
+// magic, version, id length
+const HEADER_LEN: usize = 16;
+// crc, number of parents, author timestamp, committer timestamp. The id follows the number of
+// parents
+const RECORD_FIXED_LEN: usize = 4 + 4 + 8 + 8;
+// Concurrent sessions can append the same commits. Once duplicates take up more than 1 / this of
+// the file it is rewritten without them
+const COMPACT_RATIO: usize = 4;
+
+/// Parsed commit metadata persisted across sessions, so that reopening a repo does not have to
+/// inflate every commit again. Commits never change, so records never go stale. The file is
+/// append only, each record is checksummed and a corrupt tail (e.g. from a crash mid write) is
+/// discarded on open. Other sessions may have the file mapped, so it is never shrunk in place.
+/// Discarding data or compacting writes a new file that is renamed over the old one, which is why
+/// the file is reopened for every append
+pub(crate) struct MetadataCache {
+    data: Option<Mmap>,
+    /// Offset of each record in data
+    offsets: HashMap<ObjectId, usize>,
+    /// Newly parsed commits that have not been handed to the writer yet
+    pending: Vec<CommitMetadata>,
+    writer: Option<Writer>,
+    /// Length of the ids of the repo's object format
+    id_len: usize,
+}
+
+struct Writer {
+    tx: Sender<Vec<CommitMetadata>>,
+    handle: JoinHandle<()>,
+}
+
+impl MetadataCache {
+    /// Open the cache file at path, creating it if needed. The cache is only an optimization, so
+    /// failures are logged and result in a cache that does not persist anything
+    pub(crate) fn open(path: &Path, object_format: ObjectFormat) -> MetadataCache {
+        let id_len = object_format.raw_len();
+        match Self::open_file(path, id_len) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Commit metadata cache {} disabled: {:?}", path.display(), e);
+                MetadataCache {
+                    data: None,
+                    offsets: HashMap::new(),
+                    pending: Vec::new(),
+                    writer: None,
+                    id_len,
+                }
+            }
+        }
+    }
+
+    fn open_file(path: &Path, id_len: usize) -> Result<MetadataCache> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create cache dir")?;
+        }
+
+        let mut data = map_file(path)?;
+        let mut offsets = HashMap::new();
+        let mut rewrite = false;
+        if data.len() < HEADER_LEN || data[..HEADER_LEN] != header(id_len) {
+            if !data.is_empty() {
+                warn!("Resetting commit metadata cache with unknown format");
+            }
+            rewrite = true;
+        } else {
+            let (valid_offsets, valid_len) = index_records(&data, id_len);
+            if valid_len < data.len() {
+                warn!(
+                    "Discarding {} corrupt bytes from commit metadata cache",
+                    data.len() - valid_len
+                );
+                rewrite = true;
+            }
+
+            let used_len = HEADER_LEN
+                + valid_offsets
+                    .values()
+                    .map(|offset| record_len(&data[*offset..], id_len))
+                    .sum::<usize>();
+            if (valid_len - used_len) * COMPACT_RATIO > valid_len {
+                debug!(
+                    "Compacting {} duplicate bytes from commit metadata cache",
+                    valid_len - used_len
+                );
+                rewrite = true;
+            }
+            offsets = valid_offsets;
+        }
+
+        if rewrite {
+            write_compacted(path, id_len, &data, &offsets)?;
+            data = map_file(path)?;
+            offsets = index_records(&data, id_len).0;
+        }
+        debug!("Loaded {} cached commits", offsets.len());
+
+        Ok(MetadataCache {
+            data: Some(data),
+            offsets,
+            pending: Vec::new(),
+            writer: Some(Writer::spawn(path.to_path_buf(), id_len)),
+            id_len,
+        })
+    }
+
+    pub(crate) fn get(&self, id: &ObjectId) -> Option<CommitMetadata> {
+        let offset = *self.offsets.get(id)?;
+        let data = self.data.as_ref()?;
+        let metadata = parse_record(&data[offset..], self.id_len);
+
+        if metadata.as_ref().map(|metadata| &metadata.id) != Some(id) {
+            warn!("Ignoring invalid cache entry for {}", id);
+            return None;
+        }
+
+        metadata
+    }
+
+    /// Queue metadata that was not found in the cache to be written on the next flush
+    pub(crate) fn push(&mut self, metadata: &CommitMetadata) {
+        if self.writer.is_some() && metadata.id.len() == self.id_len {
+            self.pending.push(metadata.clone());
+        }
+    }
+
+    /// Hand queued metadata to the background writer
+    pub(crate) fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(writer) = &self.writer {
+            if writer.tx.send(pending).is_err() {
+                error!("Commit metadata cache writer stopped");
+                self.writer = None;
+            }
+        }
+    }
+}
+
+impl Drop for MetadataCache {
+    fn drop(&mut self) {
+        self.flush();
+
+        if let Some(writer) = self.writer.take() {
+            drop(writer.tx);
+            if writer.handle.join().is_err() {
+                error!("Commit metadata cache writer panicked");
+            }
+        }
+    }
+}
+
+impl Writer {
+    fn spawn(path: PathBuf, id_len: usize) -> Writer {
+        let (tx, rx) = mpsc::channel::<Vec<CommitMetadata>>();
+        let handle = thread::spawn(move || {
+            for batch in rx {
+                let mut buf = Vec::new();
+                for metadata in &batch {
+                    encode_record(metadata, &mut buf);
+                }
+
+                if let Err(e) = append(&path, id_len, &buf) {
+                    error!("Failed to write commit metadata cache: {:?}", e);
+                    return;
+                }
+            }
+        });
+
+        Writer { tx, handle }
+    }
+}
+
+/// Append records to the file currently at path. Another session may have replaced it since it
+/// was opened, possibly with one for a different object format
+fn append(path: &Path, id_len: usize, records: &[u8]) -> Result<()> {
+    let mut file = match OpenOptions::new().read(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("Commit metadata cache was removed, dropping new records");
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("Failed to open cache file for appending"),
+    };
+
+    let mut file_header = [0; HEADER_LEN];
+    if file.read_exact(&mut file_header).is_err() || file_header != header(id_len) {
+        debug!("Commit metadata cache was reset by another session, dropping new records");
+        return Ok(());
+    }
+
+    // One write per batch so that concurrent sessions are unlikely to interleave records. If
+    // they do, the checksums catch it on the next open
+    file.write_all(records)
+        .context("Failed to append to cache file")
+}
+
+/// Map the cache file at path, creating an empty one if it does not exist
+fn map_file(path: &Path) -> Result<Mmap> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .context("Failed to open cache file")?;
+
+    unsafe { Mmap::map(&file).context("Failed to mmap cache file") }
+}
+
+/// Replace the file at path with one that has the header and the records at the given offsets
+/// of data, in their original order
+fn write_compacted(
+    path: &Path,
+    id_len: usize,
+    data: &[u8],
+    offsets: &HashMap<ObjectId, usize>,
+) -> Result<()> {
+    let mut offsets = offsets.values().copied().collect::<Vec<_>>();
+    offsets.sort_unstable();
+
+    let mut contents = header(id_len).to_vec();
+    for offset in offsets {
+        contents.extend_from_slice(&data[offset..offset + record_len(&data[offset..], id_len)]);
+    }
+
+    // Unique per process, so that sessions rewriting at the same time do not mix their writes
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = fs::write(&tmp_path, &contents)
+        .context("Failed to write compacted cache file")
+        .and_then(|_| fs::rename(&tmp_path, path).context("Failed to replace cache file"));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+fn header(id_len: usize) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..].copy_from_slice(&(id_len as u32).to_le_bytes());
+    header
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().expect("Invalid u32 slice"))
+}
+
+fn read_i64(buf: &[u8]) -> i64 {
+    i64::from_le_bytes(buf[..8].try_into().expect("Invalid i64 slice"))
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(buf);
+    crc.sum()
+}
+
+/// Length of the record at the start of buf, None if it is truncated or corrupt
+fn validate_record(buf: &[u8], id_len: usize) -> Option<usize> {
+    let record_header_len = RECORD_FIXED_LEN + id_len;
+    if buf.len() < record_header_len {
+        return None;
+    }
+
+    let num_parents = read_u32(&buf[4..]) as usize;
+    let record_len = num_parents
+        .checked_mul(id_len)?
+        .checked_add(record_header_len)?;
+    if buf.len() < record_len {
+        return None;
+    }
+
+    if read_u32(buf) != checksum(&buf[4..record_len]) {
+        return None;
+    }
+
+    Some(record_len)
+}
+
+/// Length of a record that was already validated
+fn record_len(buf: &[u8], id_len: usize) -> usize {
+    RECORD_FIXED_LEN + id_len + read_u32(&buf[4..]) as usize * id_len
+}
+
+/// Find the offsets of all valid records, and the length of the valid part of data
+fn index_records(data: &[u8], id_len: usize) -> (HashMap<ObjectId, usize>, usize) {
+    let mut offsets = HashMap::new();
+    let mut pos = HEADER_LEN;
+
+    while let Some(record_len) = validate_record(&data[pos..], id_len) {
+        let id = ObjectId::try_from(&data[pos + 8..pos + 8 + id_len]).expect("Invalid id slice");
+        offsets.entry(id).or_insert(pos);
+        pos += record_len;
+    }
+
+    (offsets, pos)
+}
+
+fn parse_timestamp(buf: &[u8]) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(read_i64(buf), 0).single()
+}
+
+fn parse_record(buf: &[u8], id_len: usize) -> Option<CommitMetadata> {
+    let record_len = validate_record(buf, id_len)?;
+    let num_parents = read_u32(&buf[4..]) as usize;
+
+    let timestamps_start = 8 + id_len;
+    let id = ObjectId::try_from(&buf[8..timestamps_start]).ok()?;
+    let author_timestamp = parse_timestamp(&buf[timestamps_start..])?;
+    let committer_timestamp = parse_timestamp(&buf[timestamps_start + 8..])?;
+    let parents = buf[RECORD_FIXED_LEN + id_len..record_len]
+        .chunks_exact(id_len)
+        .map(ObjectId::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    debug_assert_eq!(parents.len(), num_parents);
+
+    Some(CommitMetadata {
+        id,
+        parents,
+        author_timestamp,
+        committer_timestamp,
+    })
+}
+
+fn encode_record(metadata: &CommitMetadata, buf: &mut Vec<u8>) {
+    let record_start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&(metadata.parents.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&metadata.id);
+    buf.extend_from_slice(&metadata.author_timestamp.timestamp().to_le_bytes());
+    buf.extend_from_slice(&metadata.committer_timestamp.timestamp().to_le_bytes());
+    for parent in &metadata.parents {
+        buf.extend_from_slice(parent);
+    }
+
+    let crc = checksum(&buf[record_start + 4..]);
+    buf[record_start..record_start + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    fn metadata(id: &str, parents: &[&str], timestamp: i64) -> Result<CommitMetadata> {
+        Ok(CommitMetadata {
+            id: ObjectId::from_str(id)?,
+            parents: parents
+                .iter()
+                .map(|parent| ObjectId::from_str(parent))
+                .collect::<Result<_>>()?,
+            author_timestamp: Utc.timestamp_opt(timestamp, 0).unwrap(),
+            committer_timestamp: Utc.timestamp_opt(timestamp + 1, 0).unwrap(),
+        })
+    }
+
+    fn assert_metadata_eq(a: &CommitMetadata, b: &CommitMetadata) {
+        assert_eq!(a.id, b.id);
+        assert_eq!(a.parents, b.parents);
+        assert_eq!(a.author_timestamp, b.author_timestamp);
+        assert_eq!(a.committer_timestamp, b.committer_timestamp);
+    }
+
+    #[test]
+    fn test_persist_and_discard_corruption() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("spit/commit-metadata");
+
+        let root = metadata("1111111111111111111111111111111111111111", &[], 1000)?;
+        let merge = metadata(
+            "2222222222222222222222222222222222222222",
+            &[
+                "1111111111111111111111111111111111111111",
+                "3333333333333333333333333333333333333333",
+            ],
+            2000,
+        )?;
+
+        {
+            let mut cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+            assert!(cache.get(&root.id).is_none());
+            cache.push(&root);
+            cache.push(&merge);
+        }
+
+        let cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+        assert_metadata_eq(&cache.get(&root.id).expect("Root not cached"), &root);
+        assert_metadata_eq(&cache.get(&merge.id).expect("Merge not cached"), &merge);
+        drop(cache);
+
+        // Simulate a torn write followed by another session appending more commits
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[1, 2, 3])?;
+        drop(file);
+
+        let valid_len = {
+            let mut cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+            assert_metadata_eq(&cache.get(&merge.id).expect("Merge not cached"), &merge);
+            let new_commit = metadata("4444444444444444444444444444444444444444", &[], 3000)?;
+            cache.push(&new_commit);
+            fs::metadata(&path)?.len()
+        };
+
+        let cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+        assert!(fs::metadata(&path)?.len() > valid_len);
+        assert_eq!(cache.offsets.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_keeps_mapped_data() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("spit/commit-metadata");
+        let root = metadata("1111111111111111111111111111111111111111", &[], 1000)?;
+
+        {
+            let mut cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+            cache.push(&root);
+        }
+        let cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+
+        // A session with a different format resets the file while the first one still reads it
+        drop(MetadataCache::open(&path, ObjectFormat::Sha256));
+        assert_eq!(fs::metadata(&path)?.len(), HEADER_LEN as u64);
+        assert_metadata_eq(&cache.get(&root.id).expect("Root not cached"), &root);
+
+        // Records for the old format are not appended to the new file
+        let mut cache = cache;
+        cache.push(&metadata(
+            "2222222222222222222222222222222222222222",
+            &[],
+            2000,
+        )?);
+        drop(cache);
+        assert_eq!(fs::metadata(&path)?.len(), HEADER_LEN as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_after_rewrite() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("spit/commit-metadata");
+        let root = metadata("1111111111111111111111111111111111111111", &[], 1000)?;
+        let child = metadata(
+            "2222222222222222222222222222222222222222",
+            &["1111111111111111111111111111111111111111"],
+            2000,
+        )?;
+
+        {
+            let mut cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+            cache.push(&root);
+        }
+        let mut cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+
+        // Another session discards a torn write by renaming a new file over the old one
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[1, 2, 3])?;
+        drop(file);
+        drop(MetadataCache::open(&path, ObjectFormat::Sha1));
+
+        cache.push(&child);
+        drop(cache);
+
+        let cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+        assert_metadata_eq(&cache.get(&root.id).expect("Root not cached"), &root);
+        assert_metadata_eq(&cache.get(&child.id).expect("Child not cached"), &child);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_duplicates() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("spit/commit-metadata");
+        let commits = (1..=4)
+            .map(|i| metadata(&i.to_string().repeat(40), &[], i * 1000))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Two sessions that both parse the same commits
+        {
+            let mut a = MetadataCache::open(&path, ObjectFormat::Sha1);
+            let mut b = MetadataCache::open(&path, ObjectFormat::Sha1);
+            for commit in &commits {
+                a.push(commit);
+                b.push(commit);
+            }
+        }
+        let records_len = fs::metadata(&path)?.len() as usize - HEADER_LEN;
+
+        let cache = MetadataCache::open(&path, ObjectFormat::Sha1);
+        assert_eq!(
+            fs::metadata(&path)?.len() as usize,
+            HEADER_LEN + records_len / 2
+        );
+        for commit in &commits {
+            assert_metadata_eq(&cache.get(&commit.id).expect("Commit not cached"), commit);
+        }
+
+        Ok(())
+    }
+}
@@ -1,13 +1,28 @@
 pub(crate) mod commandline;
+mod commit_graph;
 mod decompress;
 pub(crate) mod graph;
+mod index;
+mod metadata_cache;
+mod object;
 mod object_id;
 mod pack;
+mod prefetch;
+mod ref_presets;
+mod ref_store;
+mod reftable;
 mod repo;
+mod rev_parse;
+#[cfg(test)]
+pub(crate) mod test_util;
 
-pub(crate) use graph::{build_git_history_graph, HistoryGraph};
+pub(crate) use graph::{
+    build_git_history_graph, prepend_to_history_graph, GraphPrepend, GraphSummary, HistoryGraph,
+};
 pub(crate) use object_id::ObjectId;
+pub(crate) use ref_presets::{RefFilterMode, RefPreset};
 pub(crate) use repo::{Repo, SortType};
+pub(crate) use rev_parse::rev_parse;
 
 use anyhow::{Error, Result};
 use chrono::{DateTime, Utc};
@@ -47,6 +62,9 @@ pub enum ReferenceId {
     LocalBranch(String),
     RemoteBranch(String),
     Tag(String),
+    /// Any other ref, e.g. refs/notes/commits, refs/pull/1/head or refs/stash. Holds the full ref
+    /// name
+    Other(String),
     #[default]
     Unknown,
 }
@@ -62,6 +80,7 @@ impl ReferenceId {
             ReferenceId::LocalBranch(name) => format!("refs/heads/{}", name),
             ReferenceId::RemoteBranch(name) => format!("refs/remotes/{}", name),
             ReferenceId::Tag(name) => format!("refs/tags/{}", name),
+            ReferenceId::Other(name) => name.clone(),
             ReferenceId::Unknown => {
                 return Err(Error::msg("Cannot find object id of unknown reference"));
             }
@@ -78,6 +97,8 @@ impl fmt::Display for ReferenceId {
             | ReferenceId::RemoteBranch(name)
             | ReferenceId::LocalBranch(name)
             | ReferenceId::Tag(name) => f.write_str(name)?,
+            // Short enough to read, but still resolvable by git since refs/ is implied
+            ReferenceId::Other(name) => f.write_str(name.strip_prefix("refs/").unwrap_or(name))?,
             ReferenceId::Unknown => f.write_str("Unknown")?,
         }
 
@@ -85,46 +106,6 @@ impl fmt::Display for ReferenceId {
     }
 }
 
-impl<'a> TryFrom<git2::Reference<'a>> for ReferenceId {
-    type Error = Error;
-    fn try_from(r: git2::Reference<'a>) -> Result<Self> {
-        TryFrom::try_from(&r)
-    }
-}
-
-impl<'a> TryFrom<&git2::Reference<'a>> for ReferenceId {
-    type Error = Error;
-    fn try_from(r: &git2::Reference<'a>) -> Result<Self> {
-        let name = r
-            .name()
-            .ok_or_else(|| Error::msg("Branch name is invalid"))?;
-        let id = if r.is_branch() {
-            if r.is_remote() {
-                const REMOTES_START: &str = "refs/remotes/";
-                if !name.starts_with(REMOTES_START) {
-                    return Err(Error::msg(format!(
-                        "{} does not start with {}",
-                        name, REMOTES_START
-                    )));
-                }
-                ReferenceId::RemoteBranch(name[REMOTES_START.len()..].to_string())
-            } else {
-                const LOCAL_START: &str = "refs/heads/";
-                if !name.starts_with(LOCAL_START) {
-                    return Err(Error::msg(format!(
-                        "{} does not start with {}",
-                        name, LOCAL_START
-                    )));
-                }
-                ReferenceId::LocalBranch(name[LOCAL_START.len()..].to_string())
-            }
-        } else {
-            ReferenceId::Unknown
-        };
-        Ok(id)
-    }
-}
-
 #[derive(Debug, Eq, PartialEq, Clone, PartialOrd, Ord)]
 pub enum Identifier {
     Reference(ReferenceId),
@@ -154,9 +135,28 @@ pub struct RemoteRef {
     pub(crate) ref_name: String,
 }
 
+/// Nearest tag in the history of a commit, like git describe --tags
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    pub(crate) tag: String,
+    /// Number of commits in the history of the described commit that are not in the tag's
+    pub(crate) distance: usize,
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.distance {
+            0 => f.write_str(&self.tag),
+            distance => write!(f, "{}-{}", self.tag, distance),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Commit {
     pub(crate) metadata: CommitMetadata,
+    /// Shortest unambiguous abbreviation of the commit id
+    pub(crate) short_id: String,
     pub(crate) message: String,
     pub(crate) author: String,
 }
@@ -225,6 +225,17 @@ pub struct Diff {
     #[allow(unused)]
     pub(crate) metadata: DiffMetadata,
     pub(crate) diff: ProcessedDiffCollection,
+    pub(crate) unfetched: Vec<UnfetchedFile>,
+}
+
+/// Changed file whose content a partial clone has not fetched, so it is left out of the diff.
+/// Fetching is left to the user since it needs network access
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnfetchedFile {
+    pub(crate) path: String,
+    /// None if the partial clone does not say which remote has the content
+    pub(crate) remote: Option<String>,
+    pub(crate) fetch_command: Option<String>,
 }
 
 pub struct ModifiedFiles {
@@ -233,4 +244,5 @@ pub struct ModifiedFiles {
     pub(crate) files_a: Vec<Option<Vec<u8>>>,
     pub(crate) files_b: Vec<Option<Vec<u8>>>,
     pub(crate) labels: Vec<String>,
+    pub(crate) unfetched: Vec<UnfetchedFile>,
 }
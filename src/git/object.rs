@@ -0,0 +1,286 @@
+use crate::git::{decompress, object_id::ObjectFormat, CommitMetadataWithoutId, ObjectId};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ObjectType {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl FromStr for ObjectType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ObjectType> {
+        match s {
+            "commit" => Ok(ObjectType::Commit),
+            "tree" => Ok(ObjectType::Tree),
+            "blob" => Ok(ObjectType::Blob),
+            "tag" => Ok(ObjectType::Tag),
+            _ => bail!("Unknown object type {}", s),
+        }
+    }
+}
+
+impl fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectType::Commit => f.write_str("commit"),
+            ObjectType::Tree => f.write_str("tree"),
+            ObjectType::Blob => f.write_str("blob"),
+            ObjectType::Tag => f.write_str("tag"),
+        }
+    }
+}
+
+/// Fully inflated object, with deltas applied
+pub(crate) struct Object {
+    pub(crate) typ: ObjectType,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Parts of a commit that are not needed to walk history
+pub(crate) struct CommitDetails {
+    pub(crate) tree: ObjectId,
+    /// Author as "name <email>"
+    pub(crate) author: String,
+    pub(crate) author_name: String,
+    pub(crate) message: String,
+}
+
+/// Loose objects are a zlib stream of "<type> <size>\0" followed by the content
+pub(super) fn parse_loose_object(compressed: &[u8]) -> Result<Object> {
+    let mut data = decompress::with_thread_decompressor(|decompressor| {
+        decompress::inflate(compressed, 0, decompressor)
+    })
+    .context("Failed to inflate loose object")?;
+
+    let header_end = data
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| anyhow!("Loose object has no header"))?;
+    let header = std::str::from_utf8(&data[..header_end]).context("Invalid object header")?;
+    let (typ, size) = header
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("Invalid object header {}", header))?;
+    let typ = typ.parse()?;
+    let size: usize = size.parse().context("Invalid object size")?;
+    if data.len() - header_end - 1 != size {
+        bail!(
+            "Object is {} bytes, header says {}",
+            data.len() - header_end - 1,
+            size
+        );
+    }
+
+    data.drain(..header_end + 1);
+    Ok(Object { typ, data })
+}
+
+/// Header lines of a commit or tag, up to the blank line before the message
+fn header_lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|b| *b == b'\n')
+        .take_while(|line| !line.is_empty())
+}
+
+pub(super) fn parse_commit_metadata(
+    data: &[u8],
+    object_format: ObjectFormat,
+) -> Result<CommitMetadataWithoutId> {
+    let mut parents = Vec::new();
+    let mut author_timestamp = None;
+    let mut committer_timestamp = None;
+
+    for line in header_lines(data) {
+        if let Some(parent) = line.strip_prefix(b"parent ") {
+            if parent.len() != object_format.hex_len() {
+                bail!("Invalid parent line");
+            }
+            parents.push(ObjectId::from_hex(parent).context("Failed to parse parent id")?);
+        } else if line.starts_with(b"author ") {
+            author_timestamp = Some(decompress::extract_timestamp_from_buf(line)?);
+        } else if line.starts_with(b"committer ") {
+            committer_timestamp = Some(decompress::extract_timestamp_from_buf(line)?);
+        }
+    }
+
+    Ok(CommitMetadataWithoutId {
+        parents,
+        author_timestamp: author_timestamp.ok_or_else(|| anyhow!("Commit has no author"))?,
+        committer_timestamp: committer_timestamp
+            .ok_or_else(|| anyhow!("Commit has no committer"))?,
+    })
+}
+
+pub(crate) fn parse_commit(data: &[u8]) -> Result<CommitDetails> {
+    let mut tree = None;
+    let mut author = None;
+
+    for line in header_lines(data) {
+        if let Some(id) = line.strip_prefix(b"tree ") {
+            tree = Some(ObjectId::from_hex(id).context("Failed to parse tree id")?);
+        } else if let Some(signature) = line.strip_prefix(b"author ") {
+            // Everything up to the closing bracket of the email, the timestamp follows
+            let end = signature
+                .iter()
+                .rposition(|b| *b == b'>')
+                .map_or(signature.len(), |pos| pos + 1);
+            author = Some(&signature[..end]);
+        }
+    }
+
+    let tree = tree.ok_or_else(|| anyhow!("Commit has no tree"))?;
+    let author = author.ok_or_else(|| anyhow!("Commit has no author"))?;
+    let name_end = author
+        .windows(2)
+        .position(|w| w == b" <")
+        .unwrap_or(author.len());
+
+    let message = data
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map_or(&[][..], |pos| &data[pos + 2..]);
+
+    Ok(CommitDetails {
+        tree,
+        author: String::from_utf8_lossy(author).into_owned(),
+        author_name: String::from_utf8_lossy(&author[..name_end]).into_owned(),
+        message: String::from_utf8_lossy(message).into_owned(),
+    })
+}
+
+/// Object an annotated tag points at, along with its type
+pub(crate) fn parse_tag_target(data: &[u8]) -> Result<(ObjectId, ObjectType)> {
+    let mut target = None;
+    let mut typ = None;
+
+    for line in header_lines(data) {
+        if let Some(id) = line.strip_prefix(b"object ") {
+            target = Some(ObjectId::from_hex(id).context("Failed to parse tag target")?);
+        } else if let Some(t) = line.strip_prefix(b"type ") {
+            typ = Some(std::str::from_utf8(t)?.parse()?);
+        }
+    }
+
+    match (target, typ) {
+        (Some(target), Some(typ)) => Ok((target, typ)),
+        _ => bail!("Tag is missing its target"),
+    }
+}
+
+pub(crate) struct TreeEntry<'a> {
+    pub(crate) mode: u32,
+    pub(crate) name: &'a [u8],
+    pub(crate) id: ObjectId,
+}
+
+impl TreeEntry<'_> {
+    pub(crate) fn is_tree(&self) -> bool {
+        self.mode == 0o40000
+    }
+}
+
+/// Entries of a tree object, each is "<octal mode> <name>\0<raw id>"
+pub(crate) fn tree_entries(
+    data: &[u8],
+    object_format: ObjectFormat,
+) -> impl Iterator<Item = Result<TreeEntry<'_>>> {
+    let mut remaining = data;
+    std::iter::from_fn(move || {
+        if remaining.is_empty() {
+            return None;
+        }
+
+        let mut parse = || -> Result<TreeEntry> {
+            let space = remaining
+                .iter()
+                .position(|b| *b == b' ')
+                .ok_or_else(|| anyhow!("Tree entry has no mode"))?;
+            let mode = u32::from_str_radix(std::str::from_utf8(&remaining[..space])?, 8)
+                .context("Invalid tree entry mode")?;
+            let name_end = remaining[space..]
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow!("Tree entry has no name"))?
+                + space;
+            let id_end = name_end + 1 + object_format.raw_len();
+            if id_end > remaining.len() {
+                bail!("Truncated tree entry");
+            }
+
+            let entry = TreeEntry {
+                mode,
+                name: &remaining[space + 1..name_end],
+                id: remaining[name_end + 1..id_end].try_into()?,
+            };
+            remaining = &remaining[id_end..];
+            Ok(entry)
+        };
+
+        let ret = parse();
+        if ret.is_err() {
+            // Nothing after a broken entry can be trusted
+            remaining = &[];
+        }
+        Some(ret)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_commit() -> Result<()> {
+        let data = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            parent 1111111111111111111111111111111111111111\n\
+            author A U Thor <author@example.com> 1700000000 +0100\n\
+            committer C O Mitter <committer@example.com> 1700000100 +0000\n\
+            \n\
+            Subject\n\nBody\n";
+
+        let details = parse_commit(data)?;
+        assert_eq!(details.author, "A U Thor <author@example.com>");
+        assert_eq!(details.author_name, "A U Thor");
+        assert_eq!(details.message, "Subject\n\nBody\n");
+        assert_eq!(
+            details.tree,
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904".parse()?
+        );
+
+        let metadata = parse_commit_metadata(data, ObjectFormat::Sha1)?;
+        assert_eq!(
+            metadata.parents,
+            ["1111111111111111111111111111111111111111".parse()?]
+        );
+        assert_eq!(metadata.author_timestamp.timestamp(), 1700000000);
+        assert_eq!(metadata.committer_timestamp.timestamp(), 1700000100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_entries() -> Result<()> {
+        let mut data = b"100644 file\0".to_vec();
+        data.extend([0x11; 20]);
+        data.extend(b"40000 dir\0");
+        data.extend([0x22; 20]);
+
+        let entries = tree_entries(&data, ObjectFormat::Sha1).collect::<Result<Vec<_>>>()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, b"file");
+        assert!(!entries[0].is_tree());
+        assert_eq!(entries[1].name, b"dir");
+        assert!(entries[1].is_tree());
+        assert_eq!(entries[1].id, ObjectId::from([0x22; 20]));
+
+        assert!(
+            tree_entries(&data[..data.len() - 1], ObjectFormat::Sha1).any(|entry| entry.is_err())
+        );
+        Ok(())
+    }
+}
@@ -1,21 +1,95 @@
 use anyhow::{bail, Context, Result};
-use std::{fmt, result::Result as StdResult, str::FromStr};
+use std::{fmt, str::FromStr};
 
+/// Length of the longest supported hash in bytes
+const MAX_RAW_LEN: usize = 32;
+
+/// Hash algorithm used to name objects, from extensions.objectFormat
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum ObjectFormat {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    /// Length of an id in bytes
+    pub(crate) fn raw_len(&self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 20,
+            ObjectFormat::Sha256 => 32,
+        }
+    }
+
+    /// Length of an id in hex characters
+    pub(crate) fn hex_len(&self) -> usize {
+        self.raw_len() * 2
+    }
+}
+
+impl fmt::Display for ObjectFormat {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectFormat::Sha1 => fmt.write_str("sha1"),
+            ObjectFormat::Sha256 => fmt.write_str("sha256"),
+        }
+    }
+}
+
+impl FromStr for ObjectFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha1" => Ok(ObjectFormat::Sha1),
+            "sha256" => Ok(ObjectFormat::Sha256),
+            _ => bail!("Unknown object format {}", s),
+        }
+    }
+}
+
+/// Id of a SHA-1 or SHA-256 object. Unused trailing bytes are always zero so that the derived
+/// comparisons only look at the id itself
 #[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct ObjectId {
-    id: [u8; 20],
+    id: [u8; MAX_RAW_LEN],
+    len: u8,
+}
+
+impl ObjectId {
+    /// Parse an id from its hex representation, the length decides the object format
+    pub(crate) fn from_hex(hex: &[u8]) -> Result<ObjectId> {
+        let format = match hex.len() {
+            40 => ObjectFormat::Sha1,
+            64 => ObjectFormat::Sha256,
+            _ => bail!("Object ID strings should be 40 or 64 chars"),
+        };
+
+        let mut id = [0; MAX_RAW_LEN];
+        faster_hex::hex_decode(hex, &mut id[..format.raw_len()])
+            .context("Failed to decode ObjectId string")?;
+
+        Ok(ObjectId {
+            id,
+            len: format.raw_len() as u8,
+        })
+    }
 }
 
 impl From<[u8; 20]> for ObjectId {
     fn from(id: [u8; 20]) -> Self {
-        ObjectId { id }
+        ObjectId::try_from(&id[..]).expect("20 bytes is a valid id length")
+    }
+}
+
+impl From<[u8; 32]> for ObjectId {
+    fn from(id: [u8; 32]) -> Self {
+        ObjectId { id, len: 32 }
     }
 }
 
 impl From<&git2::Oid> for ObjectId {
     fn from(id: &git2::Oid) -> Self {
-        let id: [u8; 20] = id.as_bytes().try_into().expect("Invalid OID");
-        ObjectId { id }
+        ObjectId::try_from(id.as_bytes()).expect("Invalid OID")
     }
 }
 
@@ -33,24 +107,34 @@ impl From<ObjectId> for git2::Oid {
 
 impl From<&ObjectId> for git2::Oid {
     fn from(id: &ObjectId) -> Self {
-        git2::Oid::from_bytes(&id.id).expect("Invalid id")
+        git2::Oid::from_bytes(id).expect("Invalid id")
     }
 }
 
 impl TryFrom<&[u8]> for ObjectId {
-    type Error = std::array::TryFromSliceError;
+    type Error = anyhow::Error;
 
-    fn try_from(id: &[u8]) -> StdResult<Self, Self::Error> {
-        Ok(ObjectId { id: id.try_into()? })
+    fn try_from(id: &[u8]) -> Result<Self> {
+        if id.len() != ObjectFormat::Sha1.raw_len() && id.len() != ObjectFormat::Sha256.raw_len() {
+            bail!("Invalid object id length {}", id.len());
+        }
+
+        let mut padded = [0; MAX_RAW_LEN];
+        padded[..id.len()].copy_from_slice(id);
+        Ok(ObjectId {
+            id: padded,
+            len: id.len() as u8,
+        })
     }
 }
 
 impl fmt::Display for ObjectId {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut buf = [0; 40];
-        faster_hex::hex_encode(&self.id, &mut buf).map_err(|_| fmt::Error)?;
+        let mut buf = [0; MAX_RAW_LEN * 2];
+        let buf = faster_hex::hex_encode(self, &mut buf[..self.len as usize * 2])
+            .map_err(|_| fmt::Error)?;
 
-        fmt.write_str(unsafe { std::str::from_utf8_unchecked(&buf) })?;
+        fmt.write_str(buf)?;
 
         Ok(())
     }
@@ -59,15 +143,7 @@ impl fmt::Display for ObjectId {
 impl FromStr for ObjectId {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self> {
-        let mut id = [0; 20];
-        if s.len() != 40 {
-            bail!("Object ID strings should be 40 chars");
-        }
-
-        faster_hex::hex_decode(s.as_bytes(), &mut id)
-            .context("Failed to decode ObjectId string")?;
-
-        Ok(ObjectId { id })
+        ObjectId::from_hex(s.as_bytes())
     }
 }
 
@@ -75,12 +151,120 @@ impl std::ops::Deref for ObjectId {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.id
+        &self.id[..self.len as usize]
     }
 }
 
-impl std::ops::DerefMut for ObjectId {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.id
+/// Abbreviated object id, e.g. as typed by a user
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ObjectIdPrefix {
+    /// Prefix padded with zeros, which is also the smallest id that can match
+    id: [u8; MAX_RAW_LEN],
+    /// Length of the prefix in hex characters
+    len: usize,
+}
+
+impl ObjectIdPrefix {
+    pub(crate) const MIN_LEN: usize = 4;
+
+    pub(crate) fn matches(&self, id: &[u8]) -> bool {
+        if self.len > id.len() * 2 {
+            return false;
+        }
+
+        let full_bytes = self.len / 2;
+        if id[..full_bytes] != self.id[..full_bytes] {
+            return false;
+        }
+
+        if self.len % 2 == 1 {
+            return id[full_bytes] >> 4 == self.id[full_bytes] >> 4;
+        }
+
+        true
+    }
+
+    /// Smallest id of the given length in bytes that can match
+    pub(crate) fn lower_bound(&self, raw_len: usize) -> &[u8] {
+        &self.id[..raw_len]
+    }
+}
+
+impl fmt::Display for ObjectIdPrefix {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = ObjectId::from(self.id).to_string();
+        fmt.write_str(&id[..self.len])
+    }
+}
+
+impl FromStr for ObjectIdPrefix {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        const MAX_LEN: usize = MAX_RAW_LEN * 2;
+        if s.len() < Self::MIN_LEN || s.len() > MAX_LEN {
+            bail!(
+                "Object ID prefixes should be between {} and {} chars",
+                Self::MIN_LEN,
+                MAX_LEN
+            );
+        }
+
+        let mut padded = [b'0'; MAX_LEN];
+        padded[..s.len()].copy_from_slice(s.as_bytes());
+
+        let mut id = [0; MAX_RAW_LEN];
+        faster_hex::hex_decode(&padded, &mut id)
+            .context("Failed to decode ObjectIdPrefix string")?;
+
+        Ok(ObjectIdPrefix { id, len: s.len() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefix_matches() -> Result<()> {
+        let id = ObjectId::from_str("848f40d1e2a7c2b8a1cd6a0c1d1a7c0b39d1b5b4")?;
+
+        for prefix in ["848f", "848f4", "848f40d1e", &id.to_string()] {
+            let prefix = ObjectIdPrefix::from_str(prefix)?;
+            assert!(prefix.matches(&id), "{}", prefix);
+        }
+
+        for prefix in ["848e", "848f5", "848f40d1f"] {
+            let prefix = ObjectIdPrefix::from_str(prefix)?;
+            assert!(!prefix.matches(&id), "{}", prefix);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_invalid() {
+        assert!(ObjectIdPrefix::from_str("848").is_err());
+        assert!(ObjectIdPrefix::from_str("848g").is_err());
+        assert!(ObjectIdPrefix::from_str(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_sha256_ids() -> Result<()> {
+        let s = "3562d3466cc241a707291a0bd351436f9491d36b198c9cb3311a04407d176b0f";
+        let id = ObjectId::from_str(s)?;
+        assert_eq!(id.len(), ObjectFormat::Sha256.raw_len());
+        assert_eq!(id.to_string(), s);
+        assert_eq!(ObjectId::try_from(&*id)?, id);
+
+        let prefix = ObjectIdPrefix::from_str(&s[..50])?;
+        assert!(prefix.matches(&id));
+        assert_eq!(prefix.to_string(), &s[..50]);
+
+        let sha1 = ObjectId::from_str("848f40d1e2a7c2b8a1cd6a0c1d1a7c0b39d1b5b4")?;
+        assert_eq!(sha1.len(), ObjectFormat::Sha1.raw_len());
+        assert!(!prefix.matches(&sha1));
+        assert!(ObjectId::from_str(&s[..50]).is_err());
+
+        Ok(())
     }
 }
@@ -1,7 +1,11 @@
-use crate::git::{decompress, CommitMetadata, CommitMetadataWithoutId, ObjectId};
+use crate::git::{
+    decompress,
+    object::{self, Object, ObjectType},
+    object_id::{ObjectFormat, ObjectIdPrefix},
+    CommitMetadata, CommitMetadataWithoutId, ObjectId,
+};
 
 use anyhow::{bail, Context, Result};
-use flate2::Decompress;
 use memmap2::Mmap;
 
 use std::{fs::File, path::Path};
@@ -9,20 +13,24 @@ use std::{fs::File, path::Path};
 mod index_impl {
     use super::PackIndex;
 
-    use crate::git::ObjectId;
+    use crate::git::{
+        object_id::{ObjectFormat, ObjectIdPrefix},
+        ObjectId,
+    };
 
     use anyhow::{bail, Result};
     use memmap2::Mmap;
 
     use std::{fs::File, path::Path};
 
-    const OBJECT_SIZE: usize = 20;
     const FANOUT_ENTRY_SIZE: usize = 4;
     const CRC_SIZE: usize = 4;
     const OFFSET_ENTRY_SIZE: usize = 4;
 
     pub(super) struct PackIndexV2 {
         index_data: Mmap,
+        /// Size of each object name in bytes
+        object_size: usize,
     }
 
     impl PackIndexV2 {
@@ -31,28 +39,70 @@ mod index_impl {
     }
 
     impl PackIndex for PackIndexV2 {
+        fn num_objects(&self) -> usize {
+            read_fanout(&self.index_data, Self::FANOUT_START, 255) as usize
+        }
+
         fn object_offset(&self, obj: &ObjectId) -> Result<Option<usize>> {
             let num_elems = read_fanout(&self.index_data, Self::FANOUT_START, 255) as usize;
             let object_index = match binary_search_object_index(
                 &self.index_data,
                 Self::FANOUT_START,
                 Self::OBJECT_START,
+                self.object_size,
                 obj,
             ) {
                 Some(index) => index,
                 None => return Ok(None),
             };
             let offset_table_offset =
-                Self::OBJECT_START + num_elems * OBJECT_SIZE + num_elems * CRC_SIZE;
+                Self::OBJECT_START + num_elems * self.object_size + num_elems * CRC_SIZE;
             Ok(Some(offset_from_index(
                 &self.index_data,
                 offset_table_offset,
                 object_index,
             )?))
         }
+
+        fn objects_with_prefix(&self, prefix: &ObjectIdPrefix) -> Vec<ObjectId> {
+            let lower_bound = prefix.lower_bound(self.object_size);
+            let bucket_start = if lower_bound[0] == 0 {
+                0usize
+            } else {
+                read_fanout(&self.index_data, Self::FANOUT_START, lower_bound[0] - 1) as usize
+            };
+            let bucket_end =
+                read_fanout(&self.index_data, Self::FANOUT_START, lower_bound[0]) as usize;
+
+            let object_at = |index: usize| {
+                let item_start = Self::OBJECT_START + self.object_size * index;
+                &self.index_data[item_start..item_start + self.object_size]
+            };
+
+            // Find the first object that is not less than the prefix, every match follows it
+            let mut start = bucket_start;
+            let mut end = bucket_end;
+            while start < end {
+                let mid = (start + end) / 2;
+                if object_at(mid) < lower_bound {
+                    start = mid + 1;
+                } else {
+                    end = mid;
+                }
+            }
+
+            (start..bucket_end)
+                .map(object_at)
+                .take_while(|obj| prefix.matches(obj))
+                .map(|obj| obj.try_into().expect("Invalid object size"))
+                .collect()
+        }
     }
 
-    pub(super) fn construct_index(path: &Path) -> Result<Box<dyn PackIndex + Send + Sync>> {
+    pub(super) fn construct_index(
+        path: &Path,
+        object_format: ObjectFormat,
+    ) -> Result<Box<dyn PackIndex + Send + Sync>> {
         let f = File::open(path).unwrap();
         let index_data = unsafe { Mmap::map(&f).unwrap() };
 
@@ -62,7 +112,10 @@ mod index_impl {
 
         let version = u32::from_be_bytes(index_data[4..8].try_into().unwrap());
         if version == 2 {
-            return Ok(Box::new(PackIndexV2 { index_data }));
+            return Ok(Box::new(PackIndexV2 {
+                index_data,
+                object_size: object_format.raw_len(),
+            }));
         }
 
         bail!("Unsupported index version");
@@ -82,9 +135,13 @@ mod index_impl {
         data: &[u8],
         fanout_start: usize,
         object_start: usize,
+        object_size: usize,
         desired_obj: &[u8],
     ) -> Option<usize> {
-        assert_eq!(desired_obj.len(), 20);
+        if desired_obj.len() != object_size {
+            // Ids from a different object format can never be in this pack
+            return None;
+        }
         let mut lower_bound = if desired_obj[0] == 0 {
             0usize
         } else {
@@ -96,9 +153,9 @@ mod index_impl {
 
         let mut index = (lower_bound + upper_bound) / 2;
         loop {
-            let item_start = object_start + OBJECT_SIZE * index;
+            let item_start = object_start + object_size * index;
 
-            let current_obj = &data[item_start..item_start + OBJECT_SIZE];
+            let current_obj = &data[item_start..item_start + object_size];
             match current_obj.cmp(desired_obj) {
                 std::cmp::Ordering::Less => {
                     lower_bound = index;
@@ -142,6 +199,8 @@ mod index_impl {
 }
 
 mod pack_impl {
+    use crate::git::object;
+
     use anyhow::{bail, Result};
 
     #[derive(Debug, PartialEq, Eq)]
@@ -170,6 +229,19 @@ mod pack_impl {
         }
     }
 
+    impl ObjectType {
+        /// Type of the object itself, None for deltas
+        pub(super) fn base_type(&self) -> Option<object::ObjectType> {
+            match self {
+                ObjectType::Commit => Some(object::ObjectType::Commit),
+                ObjectType::Tree => Some(object::ObjectType::Tree),
+                ObjectType::Blob => Some(object::ObjectType::Blob),
+                ObjectType::Tag => Some(object::ObjectType::Tag),
+                ObjectType::OffsetDelta | ObjectType::RefDelta => None,
+            }
+        }
+    }
+
     impl std::fmt::Display for ObjectType {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             match self {
@@ -289,161 +361,148 @@ mod pack_impl {
 }
 
 trait PackIndex {
+    fn num_objects(&self) -> usize;
     fn object_offset(&self, obj: &ObjectId) -> Result<Option<usize>>;
+    fn objects_with_prefix(&self, prefix: &ObjectIdPrefix) -> Vec<ObjectId>;
 }
 
-use std::cell::RefCell;
-
 struct PackData {
     data: Mmap,
-    decompressor: RefCell<Decompress>,
+    object_format: ObjectFormat,
 }
 
 impl PackData {
-    fn new(path: &Path) -> Result<PackData> {
+    fn new(path: &Path, object_format: ObjectFormat) -> Result<PackData> {
         let file = File::open(path).context("Failed to open pack file")?;
         let data = unsafe { Mmap::map(&file).context("Failed to mmap file") }?;
-        let decompressor = RefCell::new(Decompress::new(true));
 
-        Ok(PackData { data, decompressor })
+        Ok(PackData {
+            data,
+            object_format,
+        })
     }
 
-    fn get_commit_metadata(&self, pack_obj_location: usize) -> Result<CommitMetadataWithoutId> {
-        use pack_impl::ObjectType;
-
-        let mut decompressor = self.decompressor.borrow_mut();
-
+    /// Metadata of the commit at the given location. Whole commits are only partially inflated,
+    /// deltified commits have to be rebuilt in full
+    fn get_commit_metadata(
+        &self,
+        index: &dyn PackIndex,
+        pack_obj_location: usize,
+    ) -> Result<CommitMetadataWithoutId> {
         let (header, pack_obj_data_offset) =
             pack_impl::read_pack_obj_header(&self.data[pack_obj_location..])?;
-        match header.typ {
-            ObjectType::Commit => {
-                let pack_obj_data_start = pack_obj_location + pack_obj_data_offset;
-                // As far as I can tell, the size found in the header is not guaranteed to be
-                // correct unless we are using it for delta patching. Reading through packfile.c in
-                // git's repo it does not look like the size value is used for regular object
-                // types.
-                //
-                // On commit 7b7abfe3dd81d659a0889f88965168f7eef8c5c6 in the linux kernel I see a
-                // header size of 214, but that ends up truncating the commit and I cannot extract
-                // the committer date.
-                //
-                // Just provide the full file and let the decompressor go wild I guess
-                //
-                // I may just be patching over a bug, but even if I copy paste the logic from
-                // packfile.c into read_pack_obj_header I end up with the same results
-                let pack_obj_data = &self.data[pack_obj_data_start..];
-                decompress::decompress_commit_metadata(pack_obj_data, &mut decompressor, true)
+        if header.typ != pack_impl::ObjectType::Commit {
+            let object = self.read_object(index, pack_obj_location)?;
+            if object.typ != ObjectType::Commit {
+                bail!("Expected a commit, found a {}", object.typ);
             }
-            ObjectType::OffsetDelta => {
-                let base_ref_offset_start = pack_obj_location + pack_obj_data_offset;
-                let (base_ref_offset, read_bytes) = pack_impl::parse_offset_delta_base_obj_offset(
-                    &self.data[base_ref_offset_start..],
-                );
-                let base_ref_location = pack_obj_location - base_ref_offset;
-
-                let mut curr_data_loc = base_ref_location;
-                let mut patch_stack = vec![(base_ref_offset_start + read_bytes, header.size)];
-                // May have to fix this later
-                let mut patch_buf = Vec::new();
-                loop {
-                    let (base_header, header_read_bytes) =
-                        pack_impl::read_pack_obj_header(&self.data[curr_data_loc..])?;
-                    if base_header.typ != ObjectType::OffsetDelta {
-                        assert!(base_header.typ == ObjectType::Commit);
-                        // FIXME: We can probably merge code with parse_pack_commit somehow
-                        let base_data_loc = curr_data_loc + header_read_bytes;
-                        decompressor.reset(true);
-                        // Annoyingly, there's no guarantee that the patch for a header is
-                        // going to come from a header. This means that we _have_ to decompress
-                        // the whole commit to be able to parse the whole header of the delta
-                        // >:(
-                        patch_buf.reserve(base_header.size);
-                        decompressor
-                            .decompress_vec(
-                                &self.data[base_data_loc..],
-                                &mut patch_buf,
-                                flate2::FlushDecompress::None,
-                            )
-                            .context("Failed to decompress base of pack patch")?;
-                        break;
-                    } else {
-                        let base_ref_offset_start = curr_data_loc + header_read_bytes;
-                        let (base_ref_offset, read_bytes) =
-                            pack_impl::parse_offset_delta_base_obj_offset(
-                                &self.data[base_ref_offset_start..],
-                            );
-                        let base_ref_location = curr_data_loc - base_ref_offset;
-
-                        curr_data_loc = base_ref_location;
-                        patch_stack.push((base_ref_offset_start + read_bytes, base_header.size));
-                    }
-                }
-
-                while let Some((patch_loc, patch_size)) = patch_stack.pop() {
-                    decompressor.reset(true);
-                    let mut patch_data = Vec::with_capacity(patch_size);
-                    decompressor
-                        .decompress_vec(
-                            &self.data[patch_loc..],
-                            &mut patch_data,
-                            flate2::FlushDecompress::None,
-                        )
-                        .unwrap();
-                    // FIXME: We could only decompress the parts of the patch that are relevant
-                    // FIXME: We could cache patches
-                    patch_buf = pack_impl::pack_apply_delta(&patch_buf, &patch_data);
-                }
-
-                assert!(patch_buf.starts_with(b"tree"));
+            return object::parse_commit_metadata(&object.data, self.object_format);
+        }
 
-                let mut parents: Vec<ObjectId> = Vec::new();
-                let mut timestamp = None;
-                let mut committer_timestamp = None;
+        let pack_obj_data_start = pack_obj_location + pack_obj_data_offset;
+        // As far as I can tell, the size found in the header is not guaranteed to be
+        // correct unless we are using it for delta patching. Reading through packfile.c in
+        // git's repo it does not look like the size value is used for regular object
+        // types.
+        //
+        // On commit 7b7abfe3dd81d659a0889f88965168f7eef8c5c6 in the linux kernel I see a
+        // header size of 214, but that ends up truncating the commit and I cannot extract
+        // the committer date.
+        //
+        // Just provide the full file and let the decompressor go wild I guess
+        //
+        // I may just be patching over a bug, but even if I copy paste the logic from
+        // packfile.c into read_pack_obj_header I end up with the same results
+        let pack_obj_data = &self.data[pack_obj_data_start..];
+        decompress::with_thread_decompressor(|decompressor| {
+            decompress::decompress_commit_metadata(
+                pack_obj_data,
+                decompressor,
+                true,
+                self.object_format,
+            )
+        })
+    }
 
-                for line in patch_buf.split(|&x| x == b'\n') {
-                    if line.is_empty() {
-                        break;
-                    }
-                    if line.starts_with(b"parent") && line.len() >= 47 {
-                        parents.push([0; 20].into());
-                        // FIXME: Shouldn't just blindly  look for the strign parent
-                        faster_hex::hex_decode(&line[7..47], parents.last_mut().unwrap()).unwrap()
-                    } else if line.starts_with(b"author") {
-                        timestamp = Some(decompress::extract_timestamp_from_buf(line)?);
-                    } else if line.starts_with(b"committer") {
-                        committer_timestamp = Some(decompress::extract_timestamp_from_buf(line)?);
-                    }
+    /// Inflate the object at the given location, resolving both kinds of deltas. Packs on disk are
+    /// never thin, so the bases of ref deltas are always in the same pack
+    fn read_object(&self, index: &dyn PackIndex, pack_obj_location: usize) -> Result<Object> {
+        use pack_impl::ObjectType as PackObjectType;
+
+        let mut curr_data_loc = pack_obj_location;
+        // Location and size of each delta, outermost first
+        let mut patch_stack = Vec::new();
+        let (typ, mut data) = loop {
+            let (header, header_read_bytes) =
+                pack_impl::read_pack_obj_header(&self.data[curr_data_loc..])?;
+            let data_loc = curr_data_loc + header_read_bytes;
+            match header.typ {
+                PackObjectType::OffsetDelta => {
+                    let (base_ref_offset, read_bytes) =
+                        pack_impl::parse_offset_delta_base_obj_offset(&self.data[data_loc..]);
+                    patch_stack.push((data_loc + read_bytes, header.size));
+                    curr_data_loc = curr_data_loc
+                        .checked_sub(base_ref_offset)
+                        .context("Offset delta base is outside of the pack")?;
+                }
+                PackObjectType::RefDelta => {
+                    let id_end = data_loc + self.object_format.raw_len();
+                    let base_id = ObjectId::try_from(&self.data[data_loc..id_end])?;
+                    patch_stack.push((id_end, header.size));
+                    curr_data_loc = index.object_offset(&base_id)?.with_context(|| {
+                        format!("Ref delta base {} is not in the pack", base_id)
+                    })?;
+                }
+                typ => {
+                    let data = decompress::with_thread_decompressor(|decompressor| {
+                        decompress::inflate(&self.data[data_loc..], header.size, decompressor)
+                    })
+                    .context("Failed to inflate base object")?;
+                    break (typ.base_type().expect("Deltas handled above"), data);
                 }
+            }
 
-                let timestamp = timestamp.unwrap();
-                let committer_timestamp = committer_timestamp.unwrap();
-                Ok(CommitMetadataWithoutId {
-                    parents,
-                    author_timestamp: timestamp,
-                    committer_timestamp,
-                })
+            if patch_stack.len() > MAX_DELTA_DEPTH {
+                bail!("Delta chain is too long, pack may be corrupt");
             }
-            _ => bail!(format!("Unimplemented parser for {}", header.typ)),
+        };
+
+        while let Some((patch_loc, patch_size)) = patch_stack.pop() {
+            let patch_data = decompress::with_thread_decompressor(|decompressor| {
+                decompress::inflate(&self.data[patch_loc..], patch_size, decompressor)
+            })
+            .context("Failed to inflate delta")?;
+            // FIXME: We could cache bases that are shared between objects
+            data = pack_impl::pack_apply_delta(&data, &patch_data);
         }
+
+        Ok(Object { typ, data })
     }
 }
 
+/// Well above git's own limit of 4095, only here to stop cycles in corrupt packs
+const MAX_DELTA_DEPTH: usize = 10000;
+
 pub(crate) struct Pack {
     index: Box<dyn PackIndex + Send + Sync>,
     pack: PackData,
 }
 
 impl Pack {
-    pub(crate) fn new(pack_path: &Path) -> Result<Pack> {
+    pub(crate) fn new(pack_path: &Path, object_format: ObjectFormat) -> Result<Pack> {
         let index_path = pack_path.with_extension("idx");
-        let index =
-            index_impl::construct_index(&index_path).context("Failed to construct index")?;
+        let index = index_impl::construct_index(&index_path, object_format)
+            .context("Failed to construct index")?;
 
-        let pack = PackData::new(pack_path).context("Failed to construct pack")?;
+        let pack = PackData::new(pack_path, object_format).context("Failed to construct pack")?;
 
         Ok(Pack { index, pack })
     }
 
+    pub(crate) fn num_objects(&self) -> usize {
+        self.index.num_objects()
+    }
+
     pub(crate) fn get_commit_metadata(&self, obj: ObjectId) -> Result<Option<CommitMetadata>> {
         let offset = self
             .index
@@ -457,9 +516,30 @@ impl Pack {
 
         let ret = self
             .pack
-            .get_commit_metadata(offset)
+            .get_commit_metadata(self.index.as_ref(), offset)
             .with_context(|| format!("Failed to read metadata for found commit: {}", obj))?;
 
         Ok(Some(ret.into_full_metadata(obj)))
     }
+
+    pub(crate) fn read_object(&self, obj: &ObjectId) -> Result<Option<Object>> {
+        let offset = self
+            .index
+            .object_offset(obj)
+            .with_context(|| format!("Failed to lookup object {}", obj))?;
+
+        let Some(offset) = offset else {
+            return Ok(None);
+        };
+
+        let object = self
+            .pack
+            .read_object(self.index.as_ref(), offset)
+            .with_context(|| format!("Failed to read object {}", obj))?;
+        Ok(Some(object))
+    }
+
+    pub(crate) fn objects_with_prefix(&self, prefix: &ObjectIdPrefix) -> Vec<ObjectId> {
+        self.index.objects_with_prefix(prefix)
+    }
 }
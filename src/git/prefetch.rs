@@ -0,0 +1,233 @@
+use crate::git::{object_id::ObjectFormat, pack::Pack, repo, CommitMetadata, ObjectId};
+
+use log::{debug, error};
+
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// Commits one job parses before it hands the rest of its history back to the walk
+const MAX_JOB_COMMITS: usize = 1024;
+
+/// Parses commit metadata ahead of history walks on a pool of worker threads. A job starts at a
+/// commit the walk is about to need and keeps following parents, so that even linear history is
+/// parsed ahead of the walk while it does its own bookkeeping. Wide frontiers hand out one job
+/// per commit and spread over all workers
+pub(crate) struct Prefetcher {
+    object_dirs: Arc<[PathBuf]>,
+    object_format: ObjectFormat,
+    /// Spawned on the first prefetch, most repos are opened for more than a single walk
+    workers: Option<Workers>,
+    /// Ids that a job took on. Jobs stop where they meet history another job already covers
+    claimed: Arc<Mutex<HashSet<ObjectId>>>,
+    /// Claimed ids that a result came back for. Anything else claimed is still in flight
+    received: HashSet<ObjectId>,
+}
+
+struct Workers {
+    job_tx: Sender<Job>,
+    result_rx: Receiver<(ObjectId, Option<CommitMetadata>)>,
+    /// Set on drop, so that workers do not finish jobs nobody is waiting for
+    stop: Arc<AtomicBool>,
+}
+
+struct Job {
+    packs: Arc<Vec<Pack>>,
+    id: ObjectId,
+}
+
+impl Prefetcher {
+    pub(crate) fn new(object_dirs: &[PathBuf], object_format: ObjectFormat) -> Prefetcher {
+        Prefetcher {
+            object_dirs: object_dirs.into(),
+            object_format,
+            workers: None,
+            claimed: Default::default(),
+            received: HashSet::new(),
+        }
+    }
+
+    /// Start parsing the given commits and their history in the background
+    pub(crate) fn prefetch(&mut self, packs: &Arc<Vec<Pack>>, ids: &[ObjectId]) {
+        let workers = self.workers.get_or_insert_with(|| {
+            Workers::spawn(
+                Arc::clone(&self.object_dirs),
+                self.object_format,
+                Arc::clone(&self.claimed),
+            )
+        });
+
+        let mut claimed = self.claimed.lock().expect("Prefetch claims poisoned");
+        for id in ids {
+            // Claimed up front so that the walk waits for the job instead of racing it
+            if !claimed.insert(id.clone()) {
+                continue;
+            }
+
+            let job = Job {
+                packs: Arc::clone(packs),
+                id: id.clone(),
+            };
+            if workers.job_tx.send(job).is_err() {
+                error!("Commit prefetch workers stopped");
+                claimed.remove(id);
+                return;
+            }
+        }
+    }
+
+    /// Whether a result for the given id is still to come
+    pub(crate) fn is_pending(&self, id: &ObjectId) -> bool {
+        !self.received.contains(id)
+            && self
+                .claimed
+                .lock()
+                .expect("Prefetch claims poisoned")
+                .contains(id)
+    }
+
+    /// Everything parsed since the last call. If wait_for is pending, blocks until it arrives.
+    /// Commits that could not be parsed are left out, they are up to the regular lookup
+    pub(crate) fn receive(&mut self, wait_for: Option<&ObjectId>) -> Vec<CommitMetadata> {
+        let Some(workers) = &self.workers else {
+            return Vec::new();
+        };
+
+        let mut ret = Vec::new();
+        let mut waiting = wait_for.is_some_and(|id| self.is_pending(id));
+        loop {
+            let result = if waiting {
+                workers.result_rx.recv().ok()
+            } else {
+                workers.result_rx.try_recv().ok()
+            };
+            let Some((id, metadata)) = result else {
+                break;
+            };
+
+            if wait_for == Some(&id) {
+                waiting = false;
+            }
+            self.received.insert(id);
+            ret.extend(metadata);
+        }
+
+        ret
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Workers exit once their current job notices, there is nothing left to flush
+        if let Some(workers) = &self.workers {
+            workers.stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Workers {
+    fn spawn(
+        object_dirs: Arc<[PathBuf]>,
+        object_format: ObjectFormat,
+        claimed: Arc<Mutex<HashSet<ObjectId>>>,
+    ) -> Workers {
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let (result_tx, result_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let num_workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        debug!("Starting {} commit prefetch workers", num_workers);
+
+        for _ in 0..num_workers {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            let object_dirs = Arc::clone(&object_dirs);
+            let claimed = Arc::clone(&claimed);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || loop {
+                let job = match job_rx.lock().expect("Prefetch jobs poisoned").recv() {
+                    Ok(v) => v,
+                    Err(_) => return,
+                };
+
+                let ok = run_job(
+                    &job,
+                    &object_dirs,
+                    object_format,
+                    &claimed,
+                    &stop,
+                    &result_tx,
+                );
+                if !ok {
+                    return;
+                }
+            });
+        }
+
+        Workers {
+            job_tx,
+            result_rx,
+            stop,
+        }
+    }
+}
+
+/// Parse the job's commit and follow its parents, first parents first. Every claimed id gets a
+/// result, even when parsing fails or the job stops. Returns false if nobody is listening anymore
+fn run_job(
+    job: &Job,
+    object_dirs: &[PathBuf],
+    object_format: ObjectFormat,
+    claimed: &Mutex<HashSet<ObjectId>>,
+    stop: &AtomicBool,
+    result_tx: &Sender<(ObjectId, Option<CommitMetadata>)>,
+) -> bool {
+    // The job's own commit was claimed when it was queued
+    let mut stack = vec![job.id.clone()];
+    let mut num_parsed = 0;
+
+    while let Some(id) = stack.pop() {
+        if id != job.id {
+            if num_parsed == MAX_JOB_COMMITS || stop.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            if !claimed
+                .lock()
+                .expect("Prefetch claims poisoned")
+                .insert(id.clone())
+            {
+                continue;
+            }
+        }
+
+        let metadata = if stop.load(Ordering::Relaxed) {
+            None
+        } else {
+            repo::read_commit_metadata(object_dirs, object_format, &job.packs, &id)
+                .ok()
+                .flatten()
+        };
+        num_parsed += 1;
+
+        if let Some(metadata) = &metadata {
+            stack.extend(metadata.parents.iter().rev().cloned());
+        }
+
+        if result_tx.send((id, metadata)).is_err() {
+            return false;
+        }
+    }
+
+    true
+}
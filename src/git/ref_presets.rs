@@ -0,0 +1,151 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+/// How the text of a ref filter is matched against ref names
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum RefFilterMode {
+    #[default]
+    Substring,
+    Glob,
+    Regex,
+}
+
+impl FromStr for RefFilterMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<RefFilterMode> {
+        match s {
+            "substring" => Ok(RefFilterMode::Substring),
+            "glob" => Ok(RefFilterMode::Glob),
+            "regex" => Ok(RefFilterMode::Regex),
+            _ => bail!("Unknown filter mode {}", s),
+        }
+    }
+}
+
+impl fmt::Display for RefFilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefFilterMode::Substring => f.write_str("substring"),
+            RefFilterMode::Glob => f.write_str("glob"),
+            RefFilterMode::Regex => f.write_str("regex"),
+        }
+    }
+}
+
+/// A named ref filter. Applying a preset selects the refs that match it at that time, so refs
+/// created later are picked up as well
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefPreset {
+    pub(crate) name: String,
+    pub(crate) mode: RefFilterMode,
+    pub(crate) pattern: String,
+}
+
+/// Reads presets saved with write_ref_presets. A missing file means there are no presets
+pub(super) fn read_ref_presets(path: &Path) -> Result<Vec<RefPreset>> {
+    let content = match fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+
+    parse_ref_presets(&content).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+pub(super) fn write_ref_presets(path: &Path, presets: &[RefPreset]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+
+    // Written to the side first so that a concurrent read never sees half a file
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, format_ref_presets(presets))
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move presets to {}", path.display()))?;
+    Ok(())
+}
+
+/// Presets are stored as a "[name]" line followed by a "<mode> <pattern>" line
+fn parse_ref_presets(content: &str) -> Result<Vec<RefPreset>> {
+    let mut presets = Vec::new();
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty());
+    while let Some((line_num, line)) = lines.next() {
+        let name = line
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .ok_or_else(|| anyhow!("Line {} is not a preset name", line_num + 1))?;
+
+        let (line_num, filter) = lines
+            .next()
+            .ok_or_else(|| anyhow!("Preset {} has no filter", name))?;
+        // Patterns may contain spaces, the mode never does
+        let (mode, pattern) = filter.split_once(' ').unwrap_or((filter, ""));
+        let mode = mode
+            .parse()
+            .with_context(|| format!("Invalid filter on line {}", line_num + 1))?;
+
+        presets.push(RefPreset {
+            name: name.to_string(),
+            mode,
+            pattern: pattern.to_string(),
+        });
+    }
+
+    Ok(presets)
+}
+
+fn format_ref_presets(presets: &[RefPreset]) -> String {
+    presets
+        .iter()
+        .map(|preset| format!("[{}]\n{} {}\n", preset.name, preset.mode, preset.pattern))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ref_presets_round_trip() -> Result<()> {
+        let presets = [
+            RefPreset {
+                name: "my branches + main".into(),
+                mode: RefFilterMode::Regex,
+                pattern: "^(main|me/.*)$".into(),
+            },
+            RefPreset {
+                name: "everything".into(),
+                mode: RefFilterMode::Substring,
+                pattern: "".into(),
+            },
+            RefPreset {
+                name: "[release] tags".into(),
+                mode: RefFilterMode::Glob,
+                pattern: "release/* 2024".into(),
+            },
+        ];
+
+        let content = format_ref_presets(&presets);
+        assert_eq!(parse_ref_presets(&content)?, presets);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("spit/ref-presets");
+        assert_eq!(read_ref_presets(&path)?, []);
+        write_ref_presets(&path, &presets)?;
+        assert_eq!(read_ref_presets(&path)?, presets);
+
+        assert!(parse_ref_presets("glob release/*\n").is_err());
+        assert!(parse_ref_presets("[name]\n").is_err());
+        assert!(parse_ref_presets("[name]\nfuzzy release\n").is_err());
+        Ok(())
+    }
+}
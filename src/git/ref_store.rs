@@ -0,0 +1,597 @@
+use crate::git::{reftable, ObjectId};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+/// Refs that live in each worktree's git dir instead of the common dir
+const PER_WORKTREE_PREFIXES: &[&str] = &["refs/bisect/", "refs/worktree/", "refs/rewritten/"];
+/// Same limit as git, mostly to stop symbolic ref cycles
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// Ref backend of a repo, from extensions.refStorage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum RefStorage {
+    /// Loose refs under refs/ and packed-refs
+    #[default]
+    Files,
+    /// Stack of tables under reftable/
+    Reftable,
+}
+
+impl FromStr for RefStorage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "files" => Ok(RefStorage::Files),
+            "reftable" => Ok(RefStorage::Reftable),
+            _ => bail!("Unknown ref storage {}", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RefTarget {
+    Object(ObjectId),
+    Symbolic(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RefValue {
+    pub(crate) target: RefTarget,
+    /// Object an annotated tag points at, when packed-refs recorded it
+    pub(crate) peeled: Option<ObjectId>,
+}
+
+/// Names of refs that were added, changed or removed since the previous refresh
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct RefChanges {
+    pub(crate) updated: Vec<String>,
+    pub(crate) removed: Vec<String>,
+}
+
+/// Identifies a version of a file without reading it. Git replaces ref files instead of
+/// rewriting them in place, so any update results in a new file. The inode catches updates that
+/// keep the modification time and length, e.g. two updates within the timestamp granularity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FileStamp {
+    pub(crate) fn new(metadata: &fs::Metadata) -> Option<FileStamp> {
+        Some(FileStamp {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(metadata),
+        })
+    }
+}
+
+/// Native ref reader for both the files and the reftable backends. Keeps a snapshot of all refs
+/// so that refreshing only reads files that changed since the previous refresh
+pub(crate) struct RefStore {
+    git_dir: PathBuf,
+    common_dir: PathBuf,
+    storage: RefStorage,
+    /// Stamps of the tables.list files the snapshot was read from, None before the first read.
+    /// Tables are immutable, so these change with every reftable update
+    reftable_stamps: Option<Vec<Option<FileStamp>>>,
+    /// Loose refs with the stamp of the file they were read from
+    loose: HashMap<String, (FileStamp, RefTarget)>,
+    packed_stamp: Option<FileStamp>,
+    packed: BTreeMap<String, RefValue>,
+    /// Loose and packed refs merged, loose refs take precedence
+    refs: BTreeMap<String, RefValue>,
+}
+
+impl RefStore {
+    pub(crate) fn new(git_dir: PathBuf, common_dir: PathBuf, storage: RefStorage) -> RefStore {
+        RefStore {
+            git_dir,
+            common_dir,
+            storage,
+            reftable_stamps: None,
+            loose: HashMap::new(),
+            packed_stamp: None,
+            packed: BTreeMap::new(),
+            refs: BTreeMap::new(),
+        }
+    }
+
+    /// Snapshot from the last refresh, keyed by full ref name
+    pub(crate) fn refs(&self) -> &BTreeMap<String, RefValue> {
+        &self.refs
+    }
+
+    /// Update the snapshot with the refs currently on disk
+    pub(crate) fn refresh(&mut self) -> Result<RefChanges> {
+        match self.storage {
+            RefStorage::Files => self.refresh_files(),
+            RefStorage::Reftable => {
+                let stamps = self.current_reftable_stamps()?;
+                if self.reftable_stamps.as_ref() == Some(&stamps) {
+                    return Ok(RefChanges::default());
+                }
+                let refs = self.read_reftable_refs()?;
+                self.reftable_stamps = Some(stamps);
+                Ok(self.replace_refs(refs))
+            }
+        }
+    }
+
+    fn replace_refs(&mut self, refs: BTreeMap<String, RefValue>) -> RefChanges {
+        let mut changes = RefChanges::default();
+        for (name, value) in &refs {
+            if self.refs.get(name) != Some(value) {
+                changes.updated.push(name.clone());
+            }
+        }
+        for name in self.refs.keys() {
+            if !refs.contains_key(name) {
+                changes.removed.push(name.clone());
+            }
+        }
+        self.refs = refs;
+
+        changes
+    }
+
+    /// Only refs whose loose file changed, or all packed refs if packed-refs changed, are looked
+    /// at again
+    fn refresh_files(&mut self) -> Result<RefChanges> {
+        // Refs that may have a new value
+        let mut candidates = BTreeSet::new();
+
+        let packed_path = self.common_dir.join("packed-refs");
+        let packed_stamp = file_stamp(&packed_path)?;
+        if packed_stamp != self.packed_stamp {
+            let old_packed = std::mem::replace(&mut self.packed, read_packed_refs(&packed_path)?);
+            candidates.extend(old_packed.into_keys());
+            candidates.extend(self.packed.keys().cloned());
+            self.packed_stamp = packed_stamp;
+        }
+
+        let mut loose = HashMap::new();
+        let is_linked_worktree = self.git_dir != self.common_dir;
+        scan_loose_refs(
+            &self.common_dir.join("refs"),
+            "refs/",
+            is_linked_worktree,
+            &self.loose,
+            &mut loose,
+        )?;
+        if is_linked_worktree {
+            for prefix in PER_WORKTREE_PREFIXES {
+                scan_loose_refs(
+                    &self.git_dir.join(prefix),
+                    prefix,
+                    false,
+                    &self.loose,
+                    &mut loose,
+                )?;
+            }
+        }
+        read_loose_into("HEAD", &self.git_dir.join("HEAD"), &self.loose, &mut loose)?;
+
+        for (name, entry) in &loose {
+            if self.loose.get(name) != Some(entry) {
+                candidates.insert(name.clone());
+            }
+        }
+        for name in self.loose.keys() {
+            if !loose.contains_key(name) {
+                candidates.insert(name.clone());
+            }
+        }
+        self.loose = loose;
+
+        let mut changes = RefChanges::default();
+        for name in candidates {
+            let value = match self.loose.get(&name) {
+                Some((_, target)) => Some(RefValue {
+                    target: target.clone(),
+                    peeled: None,
+                }),
+                None => self.packed.get(&name).cloned(),
+            };
+
+            if self.refs.get(&name) == value.as_ref() {
+                continue;
+            }
+
+            match value {
+                Some(value) => {
+                    self.refs.insert(name.clone(), value);
+                    changes.updated.push(name);
+                }
+                None => {
+                    self.refs.remove(&name);
+                    changes.removed.push(name);
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    fn reftable_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![self.common_dir.join("reftable")];
+        if self.git_dir != self.common_dir {
+            dirs.push(self.git_dir.join("reftable"));
+        }
+        dirs
+    }
+
+    fn current_reftable_stamps(&self) -> Result<Vec<Option<FileStamp>>> {
+        self.reftable_dirs()
+            .iter()
+            .map(|dir| file_stamp(&dir.join("tables.list")))
+            .collect()
+    }
+
+    /// Refs of the shared stack, combined with the worktree's own stack for linked worktrees
+    fn read_reftable_refs(&self) -> Result<BTreeMap<String, RefValue>> {
+        let mut refs = reftable::read_stack(&self.common_dir.join("reftable"))
+            .context("Failed to read reftable stack")?;
+        if self.git_dir != self.common_dir {
+            refs.retain(|name, _| !is_per_worktree_ref(name));
+            refs.extend(
+                reftable::read_stack(&self.git_dir.join("reftable"))
+                    .context("Failed to read worktree reftable stack")?,
+            );
+        }
+        Ok(refs)
+    }
+
+    /// Read a single ref from disk, ignoring the snapshot. None if the ref does not exist
+    pub(crate) fn find(&self, name: &str) -> Result<Option<RefValue>> {
+        if !is_valid_ref_name(name) {
+            return Ok(None);
+        }
+
+        if self.storage == RefStorage::Reftable {
+            if self.reftable_stamps.as_ref() == Some(&self.current_reftable_stamps()?) {
+                return Ok(self.refs.get(name).cloned());
+            }
+            return Ok(self.read_reftable_refs()?.remove(name));
+        }
+
+        let path = self.ref_path(name);
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let target = parse_loose_ref(&content)
+                    .with_context(|| format!("Failed to parse ref {}", name))?;
+                return Ok(Some(RefValue {
+                    target,
+                    peeled: None,
+                }));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound || path.is_dir() => (),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read ref {}", name)),
+        }
+
+        let packed_path = self.common_dir.join("packed-refs");
+        if file_stamp(&packed_path)? == self.packed_stamp {
+            return Ok(self.packed.get(name).cloned());
+        }
+
+        Ok(read_packed_refs(&packed_path)?.remove(name))
+    }
+
+    /// Follow symbolic refs on disk until a ref holding an object id. Returns the name of that ref
+    /// along with its value
+    pub(crate) fn resolve(&self, name: &str) -> Result<(String, RefValue)> {
+        resolve_with(name, |name| self.find(name))
+    }
+
+    /// Like resolve, but using the snapshot from the last refresh
+    pub(crate) fn resolve_in_snapshot(&self, name: &str) -> Result<(String, RefValue)> {
+        resolve_with(name, |name| Ok(self.refs.get(name).cloned()))
+    }
+
+    /// Values the ref was updated to according to its reflog, oldest first
+    pub(crate) fn reflog(&self, name: &str) -> Result<Vec<ObjectId>> {
+        if self.storage == RefStorage::Reftable {
+            bail!("Reading reflogs from reftable is not supported");
+        }
+
+        let path = self.ref_dir(name).join("logs").join(name);
+        let content = match fs::read_to_string(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read log for {}", name)),
+        };
+
+        content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(parse_reflog_line)
+            .collect()
+    }
+
+    fn ref_path(&self, name: &str) -> PathBuf {
+        self.ref_dir(name).join(name)
+    }
+
+    /// Directory that the ref and its log are stored relative to
+    fn ref_dir(&self, name: &str) -> &Path {
+        if is_per_worktree_ref(name) {
+            &self.git_dir
+        } else {
+            &self.common_dir
+        }
+    }
+}
+
+/// Whether each worktree has its own version of the ref, e.g. HEAD
+fn is_per_worktree_ref(name: &str) -> bool {
+    !name.starts_with("refs/")
+        || PER_WORKTREE_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+fn resolve_with(
+    name: &str,
+    lookup: impl Fn(&str) -> Result<Option<RefValue>>,
+) -> Result<(String, RefValue)> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        let value = lookup(&name)?.ok_or_else(|| anyhow!("Reference {} not found", name))?;
+        match &value.target {
+            RefTarget::Object(_) => return Ok((name, value)),
+            RefTarget::Symbolic(target) => name = target.clone(),
+        }
+    }
+
+    Err(anyhow!("Too many levels of symbolic refs at {}", name))
+}
+
+/// Reject names that could escape the git dir. Stricter checks are left to git when the ref is
+/// created
+fn is_valid_ref_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.ends_with(".lock")
+        && name
+            .split('/')
+            .all(|component| !component.is_empty() && !component.starts_with('.'))
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+}
+
+fn file_stamp(path: &Path) -> Result<Option<FileStamp>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(FileStamp::new(&metadata)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to stat {}", path.display())),
+    }
+}
+
+/// Contents of a loose ref file, either an object id or "ref: " followed by another ref name
+fn parse_loose_ref(content: &str) -> Result<RefTarget> {
+    if let Some(target) = content.strip_prefix("ref:") {
+        return Ok(RefTarget::Symbolic(target.trim().to_string()));
+    }
+
+    // Files like FETCH_HEAD have more after the id
+    let id = content
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("Empty ref file"))?;
+    Ok(RefTarget::Object(id.parse()?))
+}
+
+/// Reflog lines are "<old id> <new id> <committer> <timestamp> <tz>\t<message>", only the new id
+/// is needed
+fn parse_reflog_line(line: &str) -> Result<ObjectId> {
+    let new_id = line
+        .split(' ')
+        .nth(1)
+        .ok_or_else(|| anyhow!("Reflog line is missing the new id"))?;
+    new_id.parse()
+}
+
+fn read_packed_refs(path: &Path) -> Result<BTreeMap<String, RefValue>> {
+    match fs::read_to_string(path) {
+        Ok(content) => parse_packed_refs(&content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).context("Failed to read packed-refs"),
+    }
+}
+
+/// packed-refs has one "<id> <name>" line per ref. Annotated tags may be followed by a
+/// "^<id>" line with the object the tag peels to
+fn parse_packed_refs(content: &str) -> Result<BTreeMap<String, RefValue>> {
+    let mut ret: BTreeMap<String, RefValue> = BTreeMap::new();
+    let mut last_name: Option<&str> = None;
+
+    for line in content.lines() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        if let Some(peeled) = line.strip_prefix('^') {
+            let value = last_name
+                .and_then(|name| ret.get_mut(name))
+                .ok_or_else(|| anyhow!("Peeled value without a ref in packed-refs"))?;
+            value.peeled = Some(peeled.trim().parse()?);
+            continue;
+        }
+
+        let (id, name) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Invalid packed-refs line: {}", line))?;
+        ret.insert(
+            name.to_string(),
+            RefValue {
+                target: RefTarget::Object(id.parse()?),
+                peeled: None,
+            },
+        );
+        last_name = Some(name);
+    }
+
+    Ok(ret)
+}
+
+/// Collect loose refs under dir, reusing values from previous for files that did not change
+fn scan_loose_refs(
+    dir: &Path,
+    name_prefix: &str,
+    skip_per_worktree: bool,
+    previous: &HashMap<String, (FileStamp, RefTarget)>,
+    out: &mut HashMap<String, (FileStamp, RefTarget)>,
+) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+
+    for entry in entries {
+        let entry = entry.context("Failed to read ref dir entry")?;
+        let Some(file_name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            warn!("Ignoring non utf8 ref in {}", dir.display());
+            continue;
+        };
+        let name = format!("{}{}", name_prefix, file_name);
+
+        let file_type = entry.file_type().context("Failed to get ref file type")?;
+        if file_type.is_dir() {
+            let dir_prefix = format!("{}/", name);
+            if skip_per_worktree && PER_WORKTREE_PREFIXES.contains(&dir_prefix.as_str()) {
+                continue;
+            }
+            scan_loose_refs(&entry.path(), &dir_prefix, false, previous, out)?;
+        } else if !name.ends_with(".lock") {
+            read_loose_into(&name, &entry.path(), previous, out)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_loose_into(
+    name: &str,
+    path: &Path,
+    previous: &HashMap<String, (FileStamp, RefTarget)>,
+    out: &mut HashMap<String, (FileStamp, RefTarget)>,
+) -> Result<()> {
+    let Some(stamp) = file_stamp(path)? else {
+        // Deleted since the directory was listed
+        return Ok(());
+    };
+
+    if let Some(entry) = previous.get(name).filter(|(prev, _)| *prev == stamp) {
+        out.insert(name.to_string(), entry.clone());
+        return Ok(());
+    }
+
+    let content = match fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read ref {}", name)),
+    };
+
+    match parse_loose_ref(&content) {
+        Ok(target) => {
+            out.insert(name.to_string(), (stamp, target));
+        }
+        Err(e) => warn!("Ignoring invalid ref {}: {:?}", name, e),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{commit, git};
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_packed_refs() -> Result<()> {
+        let refs = parse_packed_refs(
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             1111111111111111111111111111111111111111 refs/heads/main\n\
+             2222222222222222222222222222222222222222 refs/tags/v1\n\
+             ^1111111111111111111111111111111111111111\n",
+        )?;
+
+        assert_eq!(
+            refs["refs/heads/main"],
+            RefValue {
+                target: RefTarget::Object("1111111111111111111111111111111111111111".parse()?),
+                peeled: None,
+            }
+        );
+        assert_eq!(
+            refs["refs/tags/v1"].peeled,
+            Some("1111111111111111111111111111111111111111".parse()?)
+        );
+
+        assert!(parse_packed_refs("^1111111111111111111111111111111111111111\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_changes() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let first = commit(repo_path, "first")?;
+        git(repo_path, &["tag", "-a", "-m", "tag", "v1"])?;
+        git(repo_path, &["pack-refs", "--all"])?;
+
+        let git_dir = repo_path.join(".git");
+        let mut store = RefStore::new(git_dir.clone(), git_dir, RefStorage::Files);
+        let changes = store.refresh()?;
+        assert_eq!(changes.updated, ["HEAD", "refs/heads/main", "refs/tags/v1"]);
+        assert!(changes.removed.is_empty());
+        assert_eq!(store.refs()["refs/tags/v1"].peeled, Some(first.clone()));
+        assert_eq!(
+            store.resolve("HEAD")?,
+            (
+                "refs/heads/main".to_string(),
+                RefValue {
+                    target: RefTarget::Object(first.clone()),
+                    peeled: None,
+                }
+            )
+        );
+
+        assert_eq!(store.refresh()?, RefChanges::default());
+
+        // A loose ref shadows its packed value
+        commit(repo_path, "second")?;
+        git(repo_path, &["branch", "other"])?;
+        git(repo_path, &["tag", "-d", "v1"])?;
+        let changes = store.refresh()?;
+        assert_eq!(changes.updated, ["refs/heads/main", "refs/heads/other"]);
+        assert_eq!(changes.removed, ["refs/tags/v1"]);
+        assert_eq!(store.find("refs/tags/v1")?, None);
+
+        assert_eq!(store.find("../config")?, None);
+        assert_eq!(store.find("refs/heads/missing")?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+use crate::git::{
+    ref_store::{RefTarget, RefValue},
+    ObjectId,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::Crc;
+
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::Path};
+
+const MAGIC: &[u8; 4] = b"REFT";
+// magic, version, block size, min and max update index
+const V1_HEADER_LEN: usize = 24;
+// V1 header followed by the hash id
+const V2_HEADER_LEN: usize = 28;
+// ref index, obj and obj index, log and log index positions, crc
+const FOOTER_FIELDS_LEN: usize = 5 * 8 + 4;
+// block type, block length
+const BLOCK_HEADER_LEN: usize = 4;
+/// Tables can be removed by a concurrent compaction between reading tables.list and opening them
+const MAX_STACK_READ_ATTEMPTS: usize = 3;
+
+const VALUE_DELETION: u64 = 0;
+const VALUE_ID: u64 = 1;
+const VALUE_PEELED: u64 = 2;
+const VALUE_SYMREF: u64 = 3;
+
+/// Read all refs in the reftable stack in dir. Tables are applied oldest to newest, so newer
+/// records override and delete refs from older tables
+pub(super) fn read_stack(dir: &Path) -> Result<BTreeMap<String, RefValue>> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match read_stack_once(dir) {
+            Err(e) if attempt < MAX_STACK_READ_ATTEMPTS && is_not_found(&e) => continue,
+            res => return res,
+        }
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::NotFound)
+}
+
+fn read_stack_once(dir: &Path) -> Result<BTreeMap<String, RefValue>> {
+    let tables = match fs::read_to_string(dir.join("tables.list")) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).context("Failed to read tables.list"),
+    };
+
+    let mut refs = BTreeMap::new();
+    for table in tables.lines().filter(|line| !line.is_empty()) {
+        let data = fs::read(dir.join(table))?;
+        let records =
+            read_table(&data).with_context(|| format!("Failed to parse reftable {}", table))?;
+        for (name, value) in records {
+            match value {
+                Some(value) => refs.insert(name, value),
+                None => refs.remove(&name),
+            };
+        }
+    }
+
+    Ok(refs)
+}
+
+struct Header {
+    block_size: usize,
+    header_len: usize,
+    hash_len: usize,
+}
+
+fn read_u24(buf: &[u8]) -> usize {
+    (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize
+}
+
+fn read_u64(buf: &[u8]) -> usize {
+    u64::from_be_bytes(buf[..8].try_into().expect("Invalid u64 slice")) as usize
+}
+
+fn parse_header(data: &[u8]) -> Result<Header> {
+    if data.len() < V1_HEADER_LEN || &data[..4] != MAGIC {
+        bail!("Not a reftable");
+    }
+
+    let block_size = read_u24(&data[5..]);
+    let (header_len, hash_len) = match data[4] {
+        1 => (V1_HEADER_LEN, 20),
+        2 => {
+            let hash_id = data
+                .get(V1_HEADER_LEN..V2_HEADER_LEN)
+                .ok_or_else(|| anyhow!("Truncated header"))?;
+            match hash_id {
+                b"sha1" => (V2_HEADER_LEN, 20),
+                b"s256" => (V2_HEADER_LEN, 32),
+                _ => bail!("Unknown hash id {:?}", hash_id),
+            }
+        }
+        version => bail!("Unsupported reftable version {}", version),
+    };
+
+    Ok(Header {
+        block_size,
+        header_len,
+        hash_len,
+    })
+}
+
+/// Records of a single table, in the order they are stored. Deletions have no value
+fn read_table(data: &[u8]) -> Result<Vec<(String, Option<RefValue>)>> {
+    let header = parse_header(data)?;
+
+    let footer_len = header.header_len + FOOTER_FIELDS_LEN;
+    let footer_start = data
+        .len()
+        .checked_sub(footer_len)
+        .filter(|start| *start >= header.header_len)
+        .ok_or_else(|| anyhow!("Truncated reftable"))?;
+    let footer = &data[footer_start..];
+    if footer[..header.header_len] != data[..header.header_len] {
+        bail!("Footer does not match header");
+    }
+
+    let mut crc = Crc::new();
+    crc.update(&footer[..footer_len - 4]);
+    if crc.sum().to_be_bytes() != footer[footer_len - 4..] {
+        bail!("Footer checksum mismatch");
+    }
+
+    // Ref blocks come first, followed by the first of the optional sections
+    let fields = &footer[header.header_len..];
+    let ref_index_position = read_u64(fields);
+    let obj_position = read_u64(&fields[8..]) >> 5;
+    let log_position = read_u64(&fields[24..]);
+    let refs_end = [ref_index_position, obj_position, log_position]
+        .into_iter()
+        .filter(|position| *position != 0)
+        .min()
+        .unwrap_or(footer_start)
+        .min(footer_start);
+
+    let mut records = Vec::new();
+    let mut block_start = 0;
+    loop {
+        // The first block shares its space with the file header
+        let block_header_start = block_start.max(header.header_len);
+        if block_header_start >= refs_end || data[block_header_start] != b'r' {
+            break;
+        }
+
+        let block_len = read_u24(
+            data.get(block_header_start + 1..block_header_start + BLOCK_HEADER_LEN)
+                .ok_or_else(|| anyhow!("Truncated block header"))?,
+        );
+        let block_end = block_start + block_len;
+        let block = data
+            .get(..block_end)
+            .filter(|_| block_end >= block_header_start + BLOCK_HEADER_LEN)
+            .ok_or_else(|| anyhow!("Invalid block length {}", block_len))?;
+        read_ref_block(
+            block,
+            block_header_start + BLOCK_HEADER_LEN,
+            header.hash_len,
+            &mut records,
+        )?;
+
+        // Aligned blocks are padded with zeros up to the block size
+        let is_padded = header.block_size != 0 && data.get(block_end) == Some(&0);
+        block_start = if is_padded {
+            block_start + header.block_size
+        } else {
+            block_end
+        };
+    }
+
+    Ok(records)
+}
+
+/// Parse the records of a ref block. block holds everything up to the end of the block, records
+/// start at pos
+fn read_ref_block(
+    block: &[u8],
+    mut pos: usize,
+    hash_len: usize,
+    records: &mut Vec<(String, Option<RefValue>)>,
+) -> Result<()> {
+    let restart_count_start = block
+        .len()
+        .checked_sub(2)
+        .ok_or_else(|| anyhow!("Truncated block"))?;
+    let restart_count =
+        u16::from_be_bytes([block[restart_count_start], block[restart_count_start + 1]]) as usize;
+    let records_end = restart_count_start
+        .checked_sub(3 * restart_count)
+        .filter(|end| *end >= pos)
+        .ok_or_else(|| anyhow!("Invalid restart count {}", restart_count))?;
+
+    let mut reader = Reader {
+        data: &block[..records_end],
+        pos: &mut pos,
+    };
+    let mut name = Vec::new();
+    while !reader.is_empty() {
+        let prefix_len = reader.varint()? as usize;
+        let suffix_and_type = reader.varint()?;
+        let suffix = reader.bytes((suffix_and_type >> 3) as usize)?;
+        if prefix_len > name.len() {
+            bail!("Invalid prefix length {}", prefix_len);
+        }
+        name.truncate(prefix_len);
+        name.extend_from_slice(suffix);
+
+        // Update index delta
+        reader.varint()?;
+
+        let value = match suffix_and_type & 0x7 {
+            VALUE_DELETION => None,
+            VALUE_ID => Some(RefValue {
+                target: RefTarget::Object(ObjectId::try_from(reader.bytes(hash_len)?)?),
+                peeled: None,
+            }),
+            VALUE_PEELED => Some(RefValue {
+                target: RefTarget::Object(ObjectId::try_from(reader.bytes(hash_len)?)?),
+                peeled: Some(ObjectId::try_from(reader.bytes(hash_len)?)?),
+            }),
+            VALUE_SYMREF => {
+                let target_len = reader.varint()? as usize;
+                let target = std::str::from_utf8(reader.bytes(target_len)?)
+                    .context("Symbolic ref target is not utf8")?;
+                Some(RefValue {
+                    target: RefTarget::Symbolic(target.to_string()),
+                    peeled: None,
+                })
+            }
+            value_type => bail!("Unknown ref value type {}", value_type),
+        };
+
+        let name = String::from_utf8(name.clone()).context("Ref name is not utf8")?;
+        records.push((name, value));
+    }
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: &'a mut usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        *self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let ret = self
+            .data
+            .get(*self.pos..*self.pos + len)
+            .ok_or_else(|| anyhow!("Truncated record"))?;
+        *self.pos += len;
+        Ok(ret)
+    }
+
+    /// Same encoding as offsets in pack files, each continuation byte adds one before shifting
+    fn varint(&mut self) -> Result<u64> {
+        let mut byte = self.bytes(1)?[0];
+        let mut val = (byte & 0x7f) as u64;
+        while byte & 0x80 != 0 {
+            byte = self.bytes(1)?[0];
+            val = val
+                .checked_add(1)
+                .and_then(|val| val.checked_mul(1 << 7))
+                .ok_or_else(|| anyhow!("Varint overflow"))?
+                | (byte & 0x7f) as u64;
+        }
+        Ok(val)
+    }
+}
+
+/// Minimal reftable writer for building fixtures, git in the test environment may predate
+/// reftable support. Each inner slice becomes one ref block
+#[cfg(test)]
+pub(super) fn encode_table(blocks: &[&[(&str, Option<RefValue>)]], block_size: usize) -> Vec<u8> {
+    fn put_varint(mut val: u64, out: &mut Vec<u8>) {
+        let mut buf = [0; 10];
+        let mut i = buf.len() - 1;
+        buf[i] = (val & 0x7f) as u8;
+        val >>= 7;
+        while val != 0 {
+            val -= 1;
+            i -= 1;
+            buf[i] = 0x80 | (val & 0x7f) as u8;
+            val >>= 7;
+        }
+        out.extend_from_slice(&buf[i..]);
+    }
+
+    let mut header = MAGIC.to_vec();
+    header.push(1);
+    header.extend_from_slice(&(block_size as u32).to_be_bytes()[1..]);
+    header.extend_from_slice(&1u64.to_be_bytes());
+    header.extend_from_slice(&1u64.to_be_bytes());
+
+    let mut data = header.clone();
+    for (i, records) in blocks.iter().enumerate() {
+        let block_start = if i == 0 { 0 } else { data.len() };
+        data.extend_from_slice(&[b'r', 0, 0, 0]);
+
+        let mut last_name: &[u8] = &[];
+        let mut restarts = Vec::new();
+        for (j, (name, value)) in records.iter().enumerate() {
+            // Restart every other record to exercise both compressed and restart records
+            let prefix_len = if j % 2 == 0 {
+                restarts.push(data.len() - block_start);
+                0
+            } else {
+                last_name
+                    .iter()
+                    .zip(name.as_bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            };
+            let suffix = &name.as_bytes()[prefix_len..];
+            let value_type = match value.as_ref().map(|v| (&v.target, &v.peeled)) {
+                None => VALUE_DELETION,
+                Some((RefTarget::Object(_), None)) => VALUE_ID,
+                Some((RefTarget::Object(_), Some(_))) => VALUE_PEELED,
+                Some((RefTarget::Symbolic(_), _)) => VALUE_SYMREF,
+            };
+
+            put_varint(prefix_len as u64, &mut data);
+            put_varint((suffix.len() as u64) << 3 | value_type, &mut data);
+            data.extend_from_slice(suffix);
+            put_varint(0, &mut data);
+            match value.as_ref().map(|v| (&v.target, &v.peeled)) {
+                None => (),
+                Some((RefTarget::Object(id), peeled)) => {
+                    data.extend_from_slice(id);
+                    if let Some(peeled) = peeled {
+                        data.extend_from_slice(peeled);
+                    }
+                }
+                Some((RefTarget::Symbolic(target), _)) => {
+                    put_varint(target.len() as u64, &mut data);
+                    data.extend_from_slice(target.as_bytes());
+                }
+            }
+            last_name = name.as_bytes();
+        }
+
+        for restart in &restarts {
+            data.extend_from_slice(&(*restart as u32).to_be_bytes()[1..]);
+        }
+        data.extend_from_slice(&(restarts.len() as u16).to_be_bytes());
+
+        let block_len = data.len() - block_start;
+        let block_header_start = block_start.max(header.len());
+        data[block_header_start + 1..block_header_start + 4]
+            .copy_from_slice(&(block_len as u32).to_be_bytes()[1..]);
+
+        if block_size != 0 && i + 1 != blocks.len() {
+            data.resize(block_start + block_size, 0);
+        }
+    }
+
+    let footer_start = data.len();
+    data.extend_from_slice(&header);
+    data.extend_from_slice(&[0; FOOTER_FIELDS_LEN - 4]);
+    let mut crc = Crc::new();
+    crc.update(&data[footer_start..]);
+    data.extend_from_slice(&crc.sum().to_be_bytes());
+
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn id_value(id: &str, peeled: Option<&str>) -> Option<RefValue> {
+        Some(RefValue {
+            target: RefTarget::Object(id.parse().unwrap()),
+            peeled: peeled.map(|peeled| peeled.parse().unwrap()),
+        })
+    }
+
+    fn symref_value(target: &str) -> Option<RefValue> {
+        Some(RefValue {
+            target: RefTarget::Symbolic(target.to_string()),
+            peeled: None,
+        })
+    }
+
+    #[test]
+    fn test_read_stack() -> Result<()> {
+        const A: &str = "1111111111111111111111111111111111111111";
+        const B: &str = "2222222222222222222222222222222222222222";
+        const C: &str = "3333333333333333333333333333333333333333";
+
+        let tmp_dir = TempDir::new()?;
+        let base: &[(&str, Option<RefValue>)] = &[
+            ("HEAD", symref_value("refs/heads/main")),
+            ("refs/heads/feature", id_value(A, None)),
+            ("refs/heads/main", id_value(A, None)),
+        ];
+        let tags: &[(&str, Option<RefValue>)] = &[
+            ("refs/tags/v1", id_value(B, Some(A))),
+            ("refs/tags/v2", id_value(C, None)),
+        ];
+        fs::write(
+            tmp_dir.path().join("base.ref"),
+            encode_table(&[base, tags], 256),
+        )?;
+
+        let update: &[(&str, Option<RefValue>)] = &[
+            ("refs/heads/feature", None),
+            ("refs/heads/main", id_value(C, None)),
+        ];
+        fs::write(
+            tmp_dir.path().join("update.ref"),
+            encode_table(&[update], 0),
+        )?;
+
+        assert!(read_stack(tmp_dir.path())?.is_empty());
+
+        fs::write(tmp_dir.path().join("tables.list"), "base.ref\nupdate.ref\n")?;
+        let refs = read_stack(tmp_dir.path())?;
+        assert_eq!(
+            refs.into_iter().collect::<Vec<_>>(),
+            [
+                ("HEAD".to_string(), symref_value("refs/heads/main").unwrap()),
+                ("refs/heads/main".to_string(), id_value(C, None).unwrap()),
+                ("refs/tags/v1".to_string(), id_value(B, Some(A)).unwrap()),
+                ("refs/tags/v2".to_string(), id_value(C, None).unwrap()),
+            ]
+        );
+
+        let mut corrupt = encode_table(&[update], 0);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(read_table(&corrupt).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use crate::git::{
+    object_id::{ObjectFormat, ObjectIdPrefix},
+    pack::Pack,
+    ObjectId, Repo,
+};
+
+use anyhow::Result;
+
+use std::{collections::BTreeSet, fs, path::PathBuf};
+
+impl Repo {
+    /// All objects, of any type, whose id starts with the given prefix
+    pub(crate) fn find_objects_with_prefix(
+        &mut self,
+        prefix: &ObjectIdPrefix,
+    ) -> Result<BTreeSet<ObjectId>> {
+        let prefix_s = prefix.to_string();
+        let (dir_name, file_prefix) = prefix_s.split_at(2);
+
+        let mut ret = BTreeSet::new();
+
+        for objects_dir in self.object_dirs.clone() {
+            let loose_dir = objects_dir.join(dir_name);
+            for file_name in self.loose_object_names(&loose_dir)? {
+                if !file_name.starts_with(file_prefix) {
+                    continue;
+                }
+
+                if let Ok(id) = format!("{}{}", dir_name, file_name).parse() {
+                    ret.insert(id);
+                }
+            }
+        }
+
+        let search_packs = |packs: &[Pack], ret: &mut BTreeSet<ObjectId>| {
+            for pack in packs {
+                ret.extend(pack.objects_with_prefix(prefix));
+            }
+        };
+
+        search_packs(&self.packs, &mut ret);
+        // Double check if any new packs have been added
+        if ret.is_empty() && self.reload_packs()? {
+            search_packs(&self.packs, &mut ret);
+        }
+
+        Ok(ret)
+    }
+
+    /// Shortest abbreviation of the given id that is at least core.abbrev characters long and does
+    /// not match any other object in the repo
+    pub(crate) fn shortest_unique_prefix(&mut self, id: &ObjectId) -> Result<String> {
+        let id_s = id.to_string();
+        let prefix = id_s[..self.min_abbrev_len].parse()?;
+
+        let mut len = self.min_abbrev_len;
+        for other in self.find_objects_with_prefix(&prefix)? {
+            if other == *id {
+                continue;
+            }
+
+            let common_len = id_s
+                .bytes()
+                .zip(other.to_string().bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            len = len.max(common_len + 1);
+        }
+
+        Ok(id_s[..len.min(id_s.len())].to_string())
+    }
+}
+
+pub(super) fn read_min_abbrev_len(
+    config: &git2::Config,
+    object_format: ObjectFormat,
+    object_dirs: &[PathBuf],
+    packs: &[Pack],
+) -> usize {
+    let abbrev = config.get_string("core.abbrev");
+
+    match abbrev.as_deref() {
+        // Explicitly disabled abbreviation, show full ids
+        Ok("no") => object_format.hex_len(),
+        Ok(v) => match v.parse::<usize>() {
+            Ok(len) => len.clamp(ObjectIdPrefix::MIN_LEN, object_format.hex_len()),
+            // "auto", or something we do not understand
+            Err(_) => auto_abbrev_len(approximate_object_count(object_dirs, packs)),
+        },
+        Err(_) => auto_abbrev_len(approximate_object_count(object_dirs, packs)),
+    }
+}
+
+/// Abbreviation length git picks for core.abbrev=auto. With around 2^n objects a collision is
+/// expected at n / 2 bits, and every hex digit is 4 bits, so n / 2 digits are used
+fn auto_abbrev_len(num_objects: usize) -> usize {
+    const MIN_AUTO_ABBREV_LEN: usize = 7;
+
+    // One more than the highest set bit makes it an upper bound on the count
+    let bits = num_objects.checked_ilog2().unwrap_or(0) as usize + 1;
+    bits.div_ceil(2).max(MIN_AUTO_ABBREV_LEN)
+}
+
+/// Number of objects in the packs, plus an estimate of the loose objects from a single fan out
+/// directory like gc --auto
+fn approximate_object_count(object_dirs: &[PathBuf], packs: &[Pack]) -> usize {
+    let packed = packs.iter().map(Pack::num_objects).sum::<usize>();
+    let loose = object_dirs
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir.join("17")).ok())
+        .map(|entries| entries.count() * 256)
+        .sum::<usize>();
+
+    packed + loose
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{git, git_with_input};
+    use tempfile::TempDir;
+
+    const GIT_DIR_TARBALL: &[u8] =
+        include_bytes!("../../../res/test/multi_obj_multi_pack_octopus_merge.tar");
+
+    #[test]
+    fn test_find_objects_with_prefix() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+
+        // Loose and packed objects respectively
+        for id in [
+            "83fc68fe02d76e37231b8f880bca5f151cb62e39",
+            "760e2389d32e245213eaf71d88e314fa63709c79",
+        ] {
+            let prefix = id[..7].parse()?;
+            let objects = repo.find_objects_with_prefix(&prefix)?;
+            assert_eq!(objects.into_iter().collect::<Vec<_>>(), [id.parse()?]);
+        }
+
+        let prefix = "ffffffff".parse()?;
+        assert!(repo.find_objects_with_prefix(&prefix)?.is_empty());
+
+        // Objects written after a lookup are found, the cached listing of their dir is refreshed
+        let new_id: ObjectId =
+            git_with_input(git_dir.path(), &["hash-object", "--stdin"], b"new blob")?.parse()?;
+        let prefix = new_id.to_string()[..7].parse()?;
+        assert!(repo.find_objects_with_prefix(&prefix)?.is_empty());
+        git_with_input(
+            git_dir.path(),
+            &["hash-object", "-w", "--stdin"],
+            b"new blob",
+        )?;
+        let objects = repo.find_objects_with_prefix(&prefix)?;
+        assert_eq!(objects.into_iter().collect::<Vec<_>>(), [new_id]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_abbrev_len() {
+        assert_eq!(auto_abbrev_len(0), 7);
+        assert_eq!(auto_abbrev_len(1000), 7);
+        assert_eq!(auto_abbrev_len((1 << 14) - 1), 7);
+        assert_eq!(auto_abbrev_len(1 << 14), 8);
+        // Around the size of linux.git, where git picks 12 characters
+        assert_eq!(auto_abbrev_len(10_000_000), 12);
+    }
+
+    #[test]
+    fn test_shortest_unique_prefix() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        let blob_dir = TempDir::new()?;
+
+        let write_blobs = |range: std::ops::Range<usize>| -> Result<Vec<String>> {
+            let mut paths = Vec::new();
+            for i in range {
+                let path = blob_dir.path().join(i.to_string());
+                fs::write(&path, i.to_string())?;
+                paths.push(path.display().to_string());
+            }
+
+            let mut args = vec!["hash-object", "-w"];
+            args.extend(paths.iter().map(|s| s.as_str()));
+            Ok(git(git_dir.path(), &args)?
+                .lines()
+                .map(|s| s.to_string())
+                .collect())
+        };
+
+        git(git_dir.path(), &["init"])?;
+
+        // Enough objects that 4 character prefixes are very likely to collide. Half of them are
+        // packed to ensure that both loose objects and packs are checked
+        let mut ids = write_blobs(0..500)?;
+        git(git_dir.path(), &["repack", "-a", "-d", "-q"])?;
+        ids.extend(write_blobs(500..1000)?);
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        assert_eq!(repo.min_abbrev_len, 7);
+        repo.min_abbrev_len = 4;
+
+        // The blobs are the only objects in the repo, so the expected prefix can be brute forced
+        let expected_len = |id: &str| {
+            (4..40)
+                .find(|len| {
+                    ids.iter()
+                        .filter(|other| other.starts_with(&id[..*len]))
+                        .count()
+                        == 1
+                })
+                .unwrap_or(40)
+        };
+
+        let mut num_extended = 0;
+        for id in &ids {
+            let short = repo.shortest_unique_prefix(&id.parse()?)?;
+            assert_eq!(short, id[..expected_len(id)]);
+            if short.len() > 4 {
+                num_extended += 1;
+            }
+        }
+        assert!(num_extended > 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use crate::git::{commit_graph::GENERATION_INFINITY, ObjectId, Repo};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+impl Repo {
+    /// Commits reachable only from a, and only from b, like git rev-list --left-right --count a...b
+    pub(crate) fn ahead_behind(&mut self, a: &ObjectId, b: &ObjectId) -> Result<(usize, usize)> {
+        // Branches and their upstreams rarely move, so this is mostly asked the same questions
+        const MAX_CACHED_COUNTS: usize = 1024;
+
+        self.refresh_shallow()
+            .context("Failed to read shallow commits")?;
+        self.refresh_replacements()
+            .context("Failed to read replace refs")?;
+        self.refresh_commit_graph();
+
+        let key = (a.clone(), b.clone());
+        if let Some(counts) = self.ahead_behind_counts.get(&key) {
+            return Ok(*counts);
+        }
+
+        let counts = self.ahead_behind_uncached(a, b)?;
+        if self.ahead_behind_counts.len() >= MAX_CACHED_COUNTS {
+            self.ahead_behind_counts.clear();
+        }
+        self.ahead_behind_counts.insert(key, counts);
+        Ok(counts)
+    }
+
+    fn ahead_behind_uncached(&mut self, a: &ObjectId, b: &ObjectId) -> Result<(usize, usize)> {
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+        const BOTH: u8 = LEFT | RIGHT;
+
+        let mut flags: HashMap<usize, u8> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut queued = HashSet::new();
+        // Queued commits that are not yet known to be reachable from both sides
+        let mut interesting = 0;
+        // Oldest commit that was reached from only one side. Commits with the same timestamp can
+        // come out of the queue in any order, so common history is walked down to here before
+        // giving up on it still reaching one of them
+        let mut oldest_unique = None;
+
+        for (id, flag) in [(a, LEFT), (b, RIGHT)] {
+            let idx = self.get_commit_metadata_idx(id)?;
+            *flags.entry(idx).or_default() |= flag;
+            if queued.insert(idx) {
+                queue.push(self.ahead_behind_queue_entry(idx));
+            }
+        }
+        for idx in &queued {
+            if flags[idx] != BOTH {
+                interesting += 1;
+                oldest_unique = Some(self.metadata_storage[*idx].committer_timestamp);
+            }
+        }
+
+        // Walking highest generation and then newest first means children are almost always
+        // seen before their parents. With clock skew and no generation numbers a commit can be
+        // painted again after it has been visited, in which case it is simply visited again
+        while let Some((generation, timestamp, idx)) = queue.pop() {
+            queued.remove(&idx);
+            let flag = flags[&idx];
+            if flag != BOTH {
+                interesting -= 1;
+            } else if interesting == 0
+                // Everything left is an ancestor of commits that were visited, with generation
+                // numbers none of them can be painted again
+                && (generation != GENERATION_INFINITY
+                    || !matches!(oldest_unique, Some(oldest) if timestamp >= oldest))
+            {
+                break;
+            }
+
+            for parent in self.metadata_storage[idx].parents.clone() {
+                let parent_idx = self.get_commit_metadata_idx(&parent)?;
+                let parent_flag = flags.entry(parent_idx).or_default();
+                let new_flag = *parent_flag | flag;
+                if new_flag == *parent_flag {
+                    continue;
+                }
+                *parent_flag = new_flag;
+
+                if queued.contains(&parent_idx) {
+                    if new_flag == BOTH {
+                        interesting -= 1;
+                    }
+                } else {
+                    queued.insert(parent_idx);
+                    let entry = self.ahead_behind_queue_entry(parent_idx);
+                    let timestamp = entry.1;
+                    queue.push(entry);
+                    if new_flag != BOTH {
+                        interesting += 1;
+                        oldest_unique =
+                            Some(oldest_unique.map_or(timestamp, |oldest| oldest.min(timestamp)));
+                    }
+                }
+            }
+        }
+
+        let count = |side| flags.values().filter(|flag| **flag == side).count();
+        Ok((count(LEFT), count(RIGHT)))
+    }
+
+    fn ahead_behind_queue_entry(&self, idx: usize) -> (u32, DateTime<Utc>, usize) {
+        let metadata = &self.metadata_storage[idx];
+        (
+            self.generation(&metadata.id),
+            metadata.committer_timestamp,
+            idx,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::{test_util::git, ReferenceId};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ahead_behind() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let upstream_path = tmp_dir.path().join("upstream");
+        let repo_path = tmp_dir.path().join("repo");
+
+        fs::create_dir(&upstream_path)?;
+        git(&upstream_path, &["init", "-q", "-b", "main"])?;
+        git(
+            &upstream_path,
+            &["commit", "-q", "--allow-empty", "-m", "base"],
+        )?;
+        git(
+            tmp_dir.path(),
+            &["clone", "-q", upstream_path.to_str().unwrap(), "repo"],
+        )?;
+
+        for i in 0..5 {
+            let message = format!("upstream {i}");
+            git(
+                &upstream_path,
+                &["commit", "-q", "--allow-empty", "-m", &message],
+            )?;
+        }
+        git(&repo_path, &["fetch", "-q"])?;
+
+        git(&repo_path, &["checkout", "-q", "-b", "topic"])?;
+        git(&repo_path, &["branch", "-q", "--set-upstream-to", "main"])?;
+        for i in 0..3 {
+            let message = format!("local {i}");
+            git(
+                &repo_path,
+                &["commit", "-q", "--allow-empty", "-m", &message],
+            )?;
+        }
+        git(&repo_path, &["merge", "-q", "--no-edit", "origin/main"])?;
+        git(
+            &repo_path,
+            &["commit", "-q", "--allow-empty", "-m", "after merge"],
+        )?;
+
+        let mut repo = Repo::new(repo_path.clone(), false)?;
+        assert_eq!(
+            repo.upstream_branches()?,
+            HashMap::from([
+                (
+                    "main".to_string(),
+                    ReferenceId::RemoteBranch("origin/main".into())
+                ),
+                ("topic".to_string(), ReferenceId::LocalBranch("main".into())),
+            ])
+        );
+
+        let rev =
+            |name: &str| -> Result<ObjectId> { git(&repo_path, &["rev-parse", name])?.parse() };
+        let expected = [
+            // Local main was never updated, so topic is also ahead by everything it merged in
+            ("topic", "main", (10, 0)),
+            ("topic", "origin/main", (5, 0)),
+            ("main", "origin/main", (0, 5)),
+            ("topic~2", "origin/main", (3, 5)),
+            ("main", "main", (0, 0)),
+        ];
+        for (a, b, counts) in expected {
+            assert_eq!(repo.ahead_behind(&rev(a)?, &rev(b)?)?, counts, "{a} {b}");
+        }
+
+        // Generation numbers end the walks early, the counts stay the same
+        git(&repo_path, &["commit-graph", "write", "--reachable"])?;
+        let mut repo = Repo::new(repo_path.clone(), false)?;
+        for (a, b, counts) in expected {
+            assert_eq!(repo.ahead_behind(&rev(a)?, &rev(b)?)?, counts, "{a} {b}");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    git::{commit_graph::GENERATION_INFINITY, ObjectId, Repo},
+    util::CancellationToken,
+};
+
+use anyhow::{Context, Result};
+
+use std::collections::{HashMap, HashSet};
+
+/// Child links of all commits reachable from a set of heads
+pub(super) struct ReverseDag {
+    heads: HashSet<ObjectId>,
+    /// Indices of all reachable commits
+    walked: HashSet<usize>,
+    /// Child indices of each metadata_storage index
+    children: Vec<Vec<usize>>,
+}
+
+impl Repo {
+    /// Subset of heads that have the given commit in their history, like git branch --contains
+    pub(crate) fn heads_containing(
+        &mut self,
+        id: &ObjectId,
+        heads: &[ObjectId],
+        cancel_token: &CancellationToken,
+    ) -> Result<HashSet<ObjectId>> {
+        self.refresh_shallow()
+            .context("Failed to read shallow commits")?;
+        self.refresh_replacements()
+            .context("Failed to read replace refs")?;
+        self.refresh_commit_graph();
+
+        let head_set = heads.iter().cloned().collect::<HashSet<_>>();
+        let have_dag = matches!(&self.containing_dag, Some(dag) if dag.heads == head_set);
+        let target_generation = self.generation(id);
+        if !have_dag && target_generation != GENERATION_INFINITY {
+            return self.heads_containing_by_generation(id, target_generation, heads, cancel_token);
+        }
+
+        if !have_dag {
+            let (walked, children) =
+                self.build_reverse_dag(heads, &HashSet::new(), cancel_token)?;
+            self.containing_dag = Some(ReverseDag {
+                heads: head_set,
+                walked,
+                children,
+            });
+        }
+        let dag = self
+            .containing_dag
+            .as_ref()
+            .expect("Reverse dag was just built");
+
+        let Some(&target_idx) = self
+            .metadata_lookup
+            .get(id)
+            .filter(|idx| dag.walked.contains(idx))
+        else {
+            return Ok(HashSet::new());
+        };
+
+        // Everything reachable through child links is a descendant of id, and so contains it
+        let mut descendants = HashSet::from([target_idx]);
+        let mut to_visit = vec![target_idx];
+        while let Some(idx) = to_visit.pop() {
+            for &child_idx in dag.children.get(idx).into_iter().flatten() {
+                if descendants.insert(child_idx) {
+                    to_visit.push(child_idx);
+                }
+            }
+        }
+
+        Ok(heads
+            .iter()
+            .filter(|head| {
+                self.metadata_lookup
+                    .get(*head)
+                    .is_some_and(|idx| descendants.contains(idx))
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// heads_containing for a commit in the commit graph. Commits with a generation at or below
+    /// the target's cannot have it in their history, so the walk stops there instead of at the
+    /// roots
+    fn heads_containing_by_generation(
+        &mut self,
+        id: &ObjectId,
+        target_generation: u32,
+        heads: &[ObjectId],
+        cancel_token: &CancellationToken,
+    ) -> Result<HashSet<ObjectId>> {
+        let target_idx = self.get_commit_metadata_idx(id)?;
+        // Whether id is in the history of a commit, for every commit the walk has finished
+        let mut contains = HashMap::from([(target_idx, true)]);
+        let mut num_expanded: usize = 0;
+
+        let mut ret = HashSet::new();
+        for head in heads {
+            let head_idx = self.get_commit_metadata_idx(head)?;
+            // Depth first, a commit is resolved once all of its parents are. Parent indices are
+            // kept with the commit until then
+            let mut stack: Vec<(usize, Option<Vec<usize>>)> = vec![(head_idx, None)];
+            while let Some((idx, parent_indices)) = stack.pop() {
+                if let Some(parent_indices) = parent_indices {
+                    let result = parent_indices
+                        .iter()
+                        .any(|parent_idx| contains.get(parent_idx) == Some(&true));
+                    contains.insert(idx, result);
+                    continue;
+                }
+
+                if contains.contains_key(&idx) {
+                    continue;
+                }
+
+                let metadata = &self.metadata_storage[idx];
+                if self.generation(&metadata.id) <= target_generation {
+                    contains.insert(idx, false);
+                    continue;
+                }
+
+                num_expanded += 1;
+                if num_expanded % CancellationToken::CHECK_INTERVAL == 0 {
+                    self.metadata_cache.flush();
+                    cancel_token.check()?;
+                }
+
+                let parents = metadata.parents.clone();
+                let parent_indices = self.get_commit_metadata_indices(&parents)?;
+                let unresolved = parent_indices
+                    .iter()
+                    .filter(|parent_idx| !contains.contains_key(parent_idx))
+                    .map(|parent_idx| (*parent_idx, None))
+                    .collect::<Vec<_>>();
+                stack.push((idx, Some(parent_indices)));
+                stack.extend(unresolved);
+            }
+
+            if contains[&head_idx] {
+                ret.insert(head.clone());
+            }
+        }
+
+        self.metadata_cache.flush();
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{commit, git};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_heads_containing() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let base = commit(repo_path, "base")?;
+        let fix = commit(repo_path, "fix")?;
+        git(
+            repo_path,
+            &["checkout", "-q", "-b", "release", &base.to_string()],
+        )?;
+        let release = commit(repo_path, "release")?;
+        git(repo_path, &["checkout", "-q", "-b", "backport"])?;
+        git(repo_path, &["merge", "-q", "--no-edit", "main"])?;
+        let backport: ObjectId = git(repo_path, &["rev-parse", "HEAD"])?.parse()?;
+
+        // Not reachable from any of the heads
+        git(
+            repo_path,
+            &["checkout", "-q", "--detach", &base.to_string()],
+        )?;
+        let dangling = commit(repo_path, "dangling")?;
+
+        let heads = [fix.clone(), release.clone(), backport.clone()];
+        let expected = [
+            (&base, vec![&fix, &release, &backport]),
+            (&fix, vec![&fix, &backport]),
+            (&release, vec![&release, &backport]),
+            (&backport, vec![&backport]),
+            (&dangling, vec![]),
+        ];
+        let check = |repo: &mut Repo| -> Result<()> {
+            for (id, containing) in &expected {
+                assert_eq!(
+                    repo.heads_containing(id, &heads, &CancellationToken::default())?,
+                    containing.iter().map(|head| (*head).clone()).collect(),
+                    "{id}"
+                );
+            }
+            Ok(())
+        };
+
+        check(&mut Repo::new(repo_path.to_path_buf(), false)?)?;
+
+        // Commits in the graph are answered by a walk that stops at their generation
+        git(repo_path, &["commit-graph", "write", "--reachable"])?;
+        check(&mut Repo::new(repo_path.to_path_buf(), false)?)?;
+
+        Ok(())
+    }
+}
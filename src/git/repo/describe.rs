@@ -0,0 +1,335 @@
+use crate::{
+    git::{Description, ObjectId, Reference, ReferenceId, Repo},
+    util::Cache,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Descriptions kept between batches. Enough for the rows that are on screen many times over
+const MAX_CACHED_DESCRIPTIONS: usize = 4096;
+
+/// What describe remembers between calls. Only valid for the tags it was computed with
+pub(super) struct DescribeCache {
+    /// Tagged commits that descriptions were computed with, tagged commit -> tag name
+    tags: HashMap<ObjectId, String>,
+    descriptions: Cache<ObjectId, Option<Description>>,
+    /// Commits with no tag in their history, by metadata index. Every ancestor of one of these is
+    /// also untagged, so describe walks can stop once nothing else is left to walk
+    untagged: HashSet<usize>,
+}
+
+impl DescribeCache {
+    pub(super) fn new() -> DescribeCache {
+        DescribeCache {
+            tags: HashMap::new(),
+            descriptions: Cache::new(MAX_CACHED_DESCRIPTIONS),
+            untagged: HashSet::new(),
+        }
+    }
+
+    /// Forget everything that was computed from the current history
+    pub(super) fn clear(&mut self) {
+        self.descriptions = Cache::new(MAX_CACHED_DESCRIPTIONS);
+        self.untagged.clear();
+    }
+}
+
+impl Repo {
+    /// Sets the tags that describe works with. Called with the references of each new repo
+    /// state, so that describing commits does not have to re-read refs
+    pub(crate) fn update_description_tags(&mut self, references: &[Reference]) {
+        let mut tags = HashMap::new();
+        // References are sorted, so the first tag by name wins for commits with several
+        for reference in references {
+            if let ReferenceId::Tag(name) = &reference.id {
+                tags.entry(reference.head.clone())
+                    .or_insert_with(|| name.clone());
+            }
+        }
+
+        if tags != self.describe_cache.tags {
+            self.describe_cache.tags = tags;
+            self.describe_cache.clear();
+        }
+    }
+
+    /// Nearest tag in the history of each id, or None if there is no tag in its history
+    pub(crate) fn describe(&mut self, ids: &[ObjectId]) -> Result<Vec<Option<Description>>> {
+        ids.iter()
+            .map(|id| {
+                if let Some(description) = self.describe_cache.descriptions.get(id) {
+                    return Ok(description.clone());
+                }
+
+                let description = self.describe_uncached(id)?;
+                self.describe_cache
+                    .descriptions
+                    .push(id.clone(), description.clone());
+                Ok(description)
+            })
+            .collect()
+    }
+
+    /// The walk from git describe. Walking newest first, every tagged commit becomes a candidate
+    /// and each candidate counts the walked commits that are not in its history. The walk ends
+    /// once enough candidates are found and their counts can no longer change
+    fn describe_uncached(&mut self, id: &ObjectId) -> Result<Option<Description>> {
+        const MAX_CANDIDATES: usize = 10;
+
+        if self.describe_cache.tags.is_empty() {
+            return Ok(None);
+        }
+
+        let start_idx = self.get_commit_metadata_idx(id)?;
+        if self.describe_cache.untagged.contains(&start_idx) {
+            return Ok(None);
+        }
+
+        let mut queue = DescribeQueue::default();
+        queue.push(
+            start_idx,
+            self.metadata_storage[start_idx].committer_timestamp,
+            0,
+            &self.describe_cache.untagged,
+        );
+        let mut candidates: Vec<DescribeCandidate> = Vec::new();
+        let mut num_seen = 0;
+
+        while let Some(idx) = queue.pop(&self.describe_cache.untagged) {
+            // Nothing left to find a tag in, an earlier walk already went through the rest
+            if candidates.is_empty()
+                && self.describe_cache.untagged.contains(&idx)
+                && queue.num_unknown == 0
+            {
+                break;
+            }
+
+            num_seen += 1;
+
+            let tag = self.describe_cache.tags.get(&self.metadata_storage[idx].id);
+            if let Some(tag) = tag {
+                if candidates.len() == MAX_CANDIDATES {
+                    // Anything found from here on is further away than the candidates we have
+                    let flag = queue.flags[&idx];
+                    let timestamp = self.metadata_storage[idx].committer_timestamp;
+                    queue.push(idx, timestamp, flag, &self.describe_cache.untagged);
+                    break;
+                }
+
+                let flag = 1 << candidates.len();
+                candidates.push(DescribeCandidate {
+                    tag: tag.clone(),
+                    flag,
+                    depth: num_seen - 1,
+                });
+                queue.add_candidate(idx, flag);
+            }
+
+            self.describe_step(idx, &mut queue, &mut candidates)?;
+        }
+
+        // Finish counting commits outside of each candidate's history, until only commits that
+        // are in every candidate's history are left
+        while queue.num_incomplete > 0 {
+            let Some(idx) = queue.pop(&self.describe_cache.untagged) else {
+                break;
+            };
+            self.describe_step(idx, &mut queue, &mut candidates)?;
+        }
+
+        if candidates.is_empty() {
+            self.describe_cache.untagged.extend(queue.flags.into_keys());
+            return Ok(None);
+        }
+
+        // min_by_key returns the first minimum, so ties go to the candidate that was found first
+        let description = candidates
+            .into_iter()
+            .min_by_key(|candidate| candidate.depth)
+            .map(|candidate| Description {
+                tag: candidate.tag,
+                distance: candidate.depth,
+            });
+        Ok(description)
+    }
+
+    /// Counts a walked commit against the candidates that do not have it in their history and
+    /// passes its flags on to its parents
+    fn describe_step(
+        &mut self,
+        idx: usize,
+        queue: &mut DescribeQueue,
+        candidates: &mut [DescribeCandidate],
+    ) -> Result<()> {
+        let flag = queue.flags[&idx];
+        for candidate in candidates {
+            if flag & candidate.flag == 0 {
+                candidate.depth += 1;
+            }
+        }
+
+        for parent in self.metadata_storage[idx].parents.clone() {
+            let parent_idx = self.get_commit_metadata_idx(&parent)?;
+            if queue.flags.contains_key(&parent_idx) {
+                queue.add_flag(parent_idx, flag);
+            } else {
+                let timestamp = self.metadata_storage[parent_idx].committer_timestamp;
+                queue.push(parent_idx, timestamp, flag, &self.describe_cache.untagged);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Commits left to walk by describe, newest first. Keeps count of the queued commits that decide
+/// whether the walk can stop, so that it does not have to look through the queue for them
+#[derive(Default)]
+struct DescribeQueue {
+    heap: BinaryHeap<(DateTime<Utc>, usize)>,
+    queued: HashSet<usize>,
+    /// Bit per candidate that has the commit in its history, for every commit seen
+    flags: HashMap<usize, u16>,
+    /// Flags of commits in the history of every candidate
+    all_flags: u16,
+    /// Queued commits that no earlier walk found to be untagged
+    num_unknown: usize,
+    /// Queued commits that are not in the history of every candidate
+    num_incomplete: usize,
+}
+
+impl DescribeQueue {
+    fn push(&mut self, idx: usize, timestamp: DateTime<Utc>, flag: u16, untagged: &HashSet<usize>) {
+        self.flags.insert(idx, flag);
+        self.heap.push((timestamp, idx));
+        self.queued.insert(idx);
+        if !untagged.contains(&idx) {
+            self.num_unknown += 1;
+        }
+        if flag != self.all_flags {
+            self.num_incomplete += 1;
+        }
+    }
+
+    fn pop(&mut self, untagged: &HashSet<usize>) -> Option<usize> {
+        let (_, idx) = self.heap.pop()?;
+        self.queued.remove(&idx);
+        if !untagged.contains(&idx) {
+            self.num_unknown -= 1;
+        }
+        if self.flags[&idx] != self.all_flags {
+            self.num_incomplete -= 1;
+        }
+        Some(idx)
+    }
+
+    /// Mark an already seen commit as being in the history of more candidates
+    fn add_flag(&mut self, idx: usize, flag: u16) {
+        let flags = self.flags.get_mut(&idx).expect("seen commits are flagged");
+        let was_complete = *flags == self.all_flags;
+        *flags |= flag;
+        if self.queued.contains(&idx) && !was_complete && *flags == self.all_flags {
+            self.num_incomplete -= 1;
+        }
+    }
+
+    /// Add a candidate for the popped commit idx
+    fn add_candidate(&mut self, idx: usize, flag: u16) {
+        self.all_flags |= flag;
+        *self.flags.get_mut(&idx).expect("seen commits are flagged") |= flag;
+        // Only the new candidate's history has its flag, and that has not been walked yet
+        self.num_incomplete = self.heap.len();
+    }
+}
+
+/// Tag that a commit could be described relative to
+struct DescribeCandidate {
+    tag: String,
+    /// Bit that marks commits in the tag's history
+    flag: u16,
+    /// Walked commits that are not in the tag's history
+    depth: usize,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{commit_at, git};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_describe() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+        // Distinct commit dates so that the walk order matches git's
+        let timestamp = std::cell::Cell::new(1700000000);
+        let commit = |message: &str| -> Result<ObjectId> {
+            timestamp.set(timestamp.get() + 60);
+            commit_at(repo_path, message, timestamp.get())
+        };
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let untagged = commit("untagged")?;
+        commit("v1.0")?;
+        git(repo_path, &["tag", "-a", "-m", "v1.0", "v1.0"])?;
+        commit("main 1")?;
+        let main = commit("main 2")?;
+        git(repo_path, &["checkout", "-q", "-b", "topic", "HEAD~2"])?;
+        commit("topic 1")?;
+        git(repo_path, &["tag", "v1.1-rc"])?;
+        commit("topic 2")?;
+        git(repo_path, &["checkout", "-q", "main"])?;
+        git(repo_path, &["merge", "-q", "--no-edit", "topic"])?;
+        let merge: ObjectId = git(repo_path, &["rev-parse", "HEAD"])?.parse()?;
+
+        let mut repo = Repo::new(repo_path.to_path_buf(), false)?;
+        let references = repo.references()?;
+        repo.update_description_tags(&references);
+        let describe = |repo: &mut Repo, id: &ObjectId| -> Result<Option<String>> {
+            Ok(repo.describe(std::slice::from_ref(id))?[0]
+                .as_ref()
+                .map(|d| d.to_string()))
+        };
+
+        for id in [&main, &merge] {
+            let expected = git(
+                repo_path,
+                &["describe", "--tags", "--abbrev=0", &id.to_string()],
+            )?;
+            let distance = git(
+                repo_path,
+                &["rev-list", "--count", &format!("{expected}..{id}")],
+            )?;
+            let expected = match distance.as_str() {
+                "0" => expected,
+                _ => format!("{expected}-{distance}"),
+            };
+            assert_eq!(describe(&mut repo, id)?, Some(expected));
+        }
+        assert_eq!(describe(&mut repo, &main)?.as_deref(), Some("v1.0-2"));
+        assert_eq!(describe(&mut repo, &merge)?.as_deref(), Some("v1.1-rc-4"));
+        assert_eq!(describe(&mut repo, &untagged)?, None);
+
+        // Untagged history is remembered, commits on top of it stop at the remembered part
+        git(
+            repo_path,
+            &["checkout", "-q", "-b", "side", &untagged.to_string()],
+        )?;
+        let side = commit("side")?;
+        assert_eq!(describe(&mut repo, &side)?, None);
+        assert!(repo.describe_cache.untagged.len() >= 2);
+
+        // New tags invalidate cached descriptions
+        git(repo_path, &["tag", "v2.0", &main.to_string()])?;
+        git(repo_path, &["tag", "v0.1", &untagged.to_string()])?;
+        let references = repo.references()?;
+        repo.update_description_tags(&references);
+        assert_eq!(describe(&mut repo, &main)?.as_deref(), Some("v2.0"));
+        assert_eq!(describe(&mut repo, &side)?.as_deref(), Some("v0.1-1"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use crate::git::{ObjectId, Repo};
+
+use anyhow::Result;
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+impl Repo {
+    /// Best common ancestors of a and b, newest first, like git merge-base --all. Empty when the
+    /// histories are unrelated
+    pub(crate) fn merge_bases(&mut self, a: &ObjectId, b: &ObjectId) -> Result<Vec<ObjectId>> {
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+        const BOTH: u8 = LEFT | RIGHT;
+        // Reachable from a common ancestor, so it can't be a best one
+        const STALE: u8 = 4;
+
+        let mut flags: HashMap<usize, u8> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut queued = HashSet::new();
+        let mut results = Vec::new();
+        // Queued commits that could still lead to a new common ancestor
+        let mut non_stale = 0;
+
+        for (id, flag) in [(a, LEFT), (b, RIGHT)] {
+            let idx = self.get_commit_metadata_idx(id)?;
+            *flags.entry(idx).or_default() |= flag;
+            if queued.insert(idx) {
+                queue.push((self.metadata_storage[idx].committer_timestamp, idx));
+                non_stale += 1;
+            }
+        }
+
+        while non_stale > 0 {
+            let Some((_, idx)) = queue.pop() else {
+                break;
+            };
+            queued.remove(&idx);
+
+            let mut flag = flags[&idx];
+            if flag & STALE == 0 {
+                non_stale -= 1;
+            }
+            if flag == BOTH {
+                if !results.contains(&idx) {
+                    results.push(idx);
+                }
+                flag |= STALE;
+            }
+
+            for parent in self.metadata_storage[idx].parents.clone() {
+                let parent_idx = self.get_commit_metadata_idx(&parent)?;
+                let parent_flag = flags.entry(parent_idx).or_default();
+                let old_flag = *parent_flag;
+                let new_flag = old_flag | flag;
+                if new_flag == old_flag {
+                    continue;
+                }
+                *parent_flag = new_flag;
+
+                if queued.contains(&parent_idx) {
+                    if old_flag & STALE == 0 && new_flag & STALE != 0 {
+                        non_stale -= 1;
+                    }
+                } else {
+                    queued.insert(parent_idx);
+                    let timestamp = self.metadata_storage[parent_idx].committer_timestamp;
+                    queue.push((timestamp, parent_idx));
+                    if new_flag & STALE == 0 {
+                        non_stale += 1;
+                    }
+                }
+            }
+        }
+
+        // Results that were reached from a newer result are in its history
+        let mut candidates: Vec<ObjectId> = results
+            .into_iter()
+            .filter(|idx| flags[idx] & STALE == 0)
+            .map(|idx| self.metadata_storage[idx].id.clone())
+            .collect();
+
+        // With clock skew the walk can still leave a candidate that is an ancestor of another
+        if candidates.len() > 1 {
+            let mut redundant = HashSet::new();
+            for candidate in &candidates {
+                for other in &candidates {
+                    if candidate != other && !redundant.contains(other) {
+                        let (ahead, _) = self.ahead_behind(candidate, other)?;
+                        if ahead == 0 {
+                            redundant.insert(candidate.clone());
+                            break;
+                        }
+                    }
+                }
+            }
+            candidates.retain(|candidate| !redundant.contains(candidate));
+        }
+
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{commit, git};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_merge_bases() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+        let merge = |branch: &str| -> Result<ObjectId> {
+            git(repo_path, &["merge", "-q", "--no-edit", "--no-ff", branch])?;
+            git(repo_path, &["rev-parse", "HEAD"])?.parse()
+        };
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let base = commit(repo_path, "base")?;
+        let main_1 = commit(repo_path, "main 1")?;
+        git(
+            repo_path,
+            &["checkout", "-q", "-b", "topic", &base.to_string()],
+        )?;
+        let topic_1 = commit(repo_path, "topic 1")?;
+
+        // Criss-cross merge, both sides have two best common ancestors
+        let topic_merge = merge("main")?;
+        git(repo_path, &["checkout", "-q", "main"])?;
+        git(
+            repo_path,
+            &["merge", "-q", "--no-edit", "--no-ff", &topic_1.to_string()],
+        )?;
+        let main_merge = commit(repo_path, "main 2")?;
+        git(repo_path, &["checkout", "-q", "topic"])?;
+        let topic_2 = commit(repo_path, "topic 2")?;
+
+        git(repo_path, &["checkout", "-q", "--orphan", "unrelated"])?;
+        let unrelated = commit(repo_path, "unrelated")?;
+
+        let mut repo = Repo::new(repo_path.to_path_buf(), false)?;
+        let mut check = |a: &ObjectId, b: &ObjectId| -> Result<Vec<ObjectId>> {
+            let expected = git(
+                repo_path,
+                &["merge-base", "--all", &a.to_string(), &b.to_string()],
+            )?
+            .lines()
+            .map(|line| line.parse())
+            .collect::<Result<HashSet<ObjectId>>>()?;
+            let merge_bases = repo.merge_bases(a, b)?;
+            assert_eq!(
+                merge_bases.iter().cloned().collect::<HashSet<_>>(),
+                expected
+            );
+            Ok(merge_bases)
+        };
+
+        assert_eq!(check(&main_1, &topic_1)?, vec![base.clone()]);
+        assert_eq!(check(&base, &topic_2)?, vec![base.clone()]);
+        assert_eq!(check(&topic_merge, &main_1)?, vec![main_1.clone()]);
+        assert_eq!(check(&main_merge, &topic_2)?.len(), 2);
+        assert_eq!(repo.merge_bases(&main_merge, &unrelated)?, []);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,3074 @@
+mod abbrev;
+mod ahead_behind;
+mod containing;
+mod describe;
+mod merge_base;
+mod replace;
+mod shallow;
+
+use abbrev::read_min_abbrev_len;
+use containing::ReverseDag;
+use describe::DescribeCache;
+use replace::read_grafts;
+use shallow::read_shallow;
+
+use crate::{
+    app::IndexState,
+    git::{
+        commandline,
+        commit_graph::{CommitGraph, GENERATION_INFINITY},
+        decompress, index,
+        metadata_cache::MetadataCache,
+        object::{self, CommitDetails, Object, ObjectType},
+        object_id::ObjectFormat,
+        pack::Pack,
+        prefetch::Prefetcher,
+        ref_presets::{read_ref_presets, write_ref_presets, RefPreset},
+        ref_store::{FileStamp, RefStorage, RefStore, RefTarget, RefValue},
+        Commit, CommitMetadata, DiffTarget, ModifiedFiles, ObjectId, Reference, ReferenceId,
+        RemoteRef, UnfetchedFile,
+    },
+    util::{CancellationToken, Timer},
+};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use git2::{RepositoryOpenFlags, TreeEntry, TreeWalkMode, TreeWalkResult};
+use log::{debug, error, warn};
+
+use std::{
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SortType {
+    AuthorTimestamp,
+    #[default]
+    CommitterTimestamp,
+}
+
+/// Where a partial clone lazily gets the objects it left out
+enum Promisor {
+    Remote(String),
+    /// Promisor packs without a configured remote, e.g. from a clone whose config was edited
+    Unknown,
+}
+
+pub(crate) struct Repo {
+    allow_libgit2_fallback: bool,
+    /// None when libgit2 cannot open the repo, e.g. because it uses SHA-256 object ids. Only the
+    /// native commit metadata readers work in that case
+    git2_repo: Option<git2::Repository>,
+    git_dir: PathBuf,
+    /// Hash algorithm of the repo, from extensions.objectFormat
+    object_format: ObjectFormat,
+    /// The repo's own object dir followed by its alternates
+    object_dirs: Vec<PathBuf>,
+    common_dir: PathBuf,
+    /// Commits whose parents were cut off by a shallow clone. These are treated as root commits
+    shallow: HashSet<ObjectId>,
+    /// Set for partial clones, which are missing objects on purpose
+    promisor: Option<Promisor>,
+    /// Whether refs/replace/* are applied, like git without --no-replace-objects
+    use_replace_refs: bool,
+    /// Applied replace refs, replaced commit -> replacement commit
+    replacements: HashMap<ObjectId, ObjectId>,
+    /// Parent overrides from info/grafts
+    grafts: HashMap<ObjectId, Vec<ObjectId>>,
+    ref_store: RefStore,
+    /// Branches and tags as of the last ref store refresh, keyed by full ref name
+    references: BTreeMap<String, Reference>,
+    /// Commit each ref target peels to, None for targets that are not commit-ish. Objects never
+    /// change so this is never invalidated
+    peeled: HashMap<ObjectId, Option<ObjectId>>,
+    repo_root: PathBuf,
+    /// Shared with prefetch jobs, reloading swaps in a new list instead of changing this one
+    packs: Arc<Vec<Pack>>,
+    /// Stamps of the pack dirs when packs were last found. Adding or removing a pack changes them
+    pack_dir_stamps: Vec<Option<FileStamp>>,
+    /// Loose object file names by fanout dir, with the stamp of the dir when it was listed
+    loose_listings: HashMap<PathBuf, (FileStamp, Vec<String>)>,
+    // NOTE: We do not store the commit metadata within the hashmap directly because it makes it
+    // difficult to hand out references to the metadata without copying it out. Instead we hand out
+    // metadata IDs that look up the CommitMetadata on demand.
+    metadata_lookup: HashMap<ObjectId, usize>,
+    metadata_storage: Vec<CommitMetadata>,
+    /// Metadata parsed in previous sessions
+    metadata_cache: MetadataCache,
+    /// Parses commits ahead of history walks, results are moved into metadata_storage as they
+    /// are needed
+    prefetcher: Prefetcher,
+    commit_graph: CommitGraph,
+    /// Minimum length of abbreviated object ids, from core.abbrev
+    min_abbrev_len: usize,
+    describe_cache: DescribeCache,
+    /// Results of ahead_behind by (a, b). They only change when history is rewritten
+    ahead_behind_counts: HashMap<(ObjectId, ObjectId), (usize, usize)>,
+    /// Reverse dag that heads_containing last built. Selecting commits one after another asks
+    /// about the same heads each time
+    containing_dag: Option<ReverseDag>,
+    /// Author names from get_commit_author, which is asked about the same ref heads repeatedly
+    authors: HashMap<ObjectId, String>,
+}
+
+impl Repo {
+    pub(crate) fn new(mut repo_root: PathBuf, allow_libgit2_fallback: bool) -> Result<Repo> {
+        if repo_root.is_relative() {
+            repo_root = std::env::current_dir()
+                .context("failed to get cwd for relative repo root")?
+                .join(repo_root);
+        }
+
+        let ceiling_dirs: &[&Path] = &[];
+        let (git2_repo, git_dir, config) = match git2::Repository::open_ext(
+            repo_root.clone(),
+            RepositoryOpenFlags::empty(),
+            ceiling_dirs,
+        ) {
+            Ok(git2_repo) => {
+                let git_dir = git2_repo.path().to_path_buf();
+                let config = git2_repo.config().context("Failed to read repo config")?;
+                (Some(git2_repo), git_dir, config)
+            }
+            Err(e) => {
+                // libgit2 rejects object formats and ref backends it was not built with. Our own
+                // readers can still handle those, so find the repo without it
+                let Ok(git_dir) = find_git_dir(&repo_root) else {
+                    return Err(e).context("Failed to open git2 repo");
+                };
+                let config = git2::Config::open(&git_dir.join("config"))
+                    .context("Failed to read repo config")?;
+                if read_object_format(&config)? == ObjectFormat::Sha1
+                    && read_ref_storage(&config)? == RefStorage::Files
+                {
+                    return Err(e).context("Failed to open git2 repo");
+                }
+                (None, git_dir, config)
+            }
+        };
+
+        let object_format = read_object_format(&config)?;
+        let ref_storage = read_ref_storage(&config)?;
+        if git2_repo.is_none() {
+            warn!(
+                "libgit2 does not support {} repositories with {:?} ref storage, only commit \
+                 history and refs will be available",
+                object_format, ref_storage
+            );
+        }
+
+        let common_dir = find_common_dir(&git_dir);
+        let object_dirs = find_object_dirs(&common_dir.join("objects"));
+        let shallow = read_shallow(&common_dir)?;
+        // Stamped before listing, a pack added in between is found on the next reload
+        let pack_dir_stamps = read_pack_dir_stamps(&object_dirs);
+        let packs = find_packs(&object_dirs, object_format)?;
+        let promisor = find_promisor(&config, &object_dirs);
+        let use_replace_refs = std::env::var_os("GIT_NO_REPLACE_OBJECTS").is_none()
+            && config.get_bool("core.useReplaceRefs").unwrap_or(true);
+        let grafts = read_grafts(&common_dir)?;
+        let ref_store = RefStore::new(git_dir.clone(), common_dir.clone(), ref_storage);
+        let min_abbrev_len = read_min_abbrev_len(&config, object_format, &object_dirs, &packs);
+        let metadata_cache =
+            MetadataCache::open(&common_dir.join("spit/commit-metadata"), object_format);
+        let commit_graph = CommitGraph::open(&object_dirs, object_format);
+        let prefetcher = Prefetcher::new(&object_dirs, object_format);
+
+        let mut repo = Repo {
+            allow_libgit2_fallback,
+            git2_repo,
+            repo_root,
+            git_dir,
+            object_format,
+            object_dirs,
+            common_dir,
+            shallow,
+            promisor,
+            use_replace_refs,
+            replacements: HashMap::new(),
+            grafts,
+            ref_store,
+            references: BTreeMap::new(),
+            peeled: HashMap::new(),
+            packs: Arc::new(packs),
+            pack_dir_stamps,
+            loose_listings: HashMap::new(),
+            metadata_lookup: HashMap::new(),
+            metadata_storage: Vec::new(),
+            metadata_cache,
+            prefetcher,
+            commit_graph,
+            min_abbrev_len,
+            describe_cache: DescribeCache::new(),
+            ahead_behind_counts: HashMap::new(),
+            containing_dag: None,
+            authors: HashMap::new(),
+        };
+        // Replace refs are read through the ref store, which only exists from here on
+        repo.refresh_replacements()
+            .context("Failed to read replace refs")?;
+
+        Ok(repo)
+    }
+
+    fn git2_repo(&self) -> Result<&git2::Repository> {
+        self.git2_repo.as_ref().ok_or_else(|| {
+            anyhow!(
+                "libgit2 does not support {} repositories",
+                self.object_format
+            )
+        })
+    }
+
+    pub(crate) fn object_format(&self) -> ObjectFormat {
+        self.object_format
+    }
+
+    pub(crate) fn get_commit_metadata(&mut self, id: &ObjectId) -> Result<CommitMetadata> {
+        let idx = self.get_commit_metadata_idx(id)?;
+        Ok(self.metadata_storage[idx].clone())
+    }
+
+    fn get_commit_metadata_libgit2(&self, id: &ObjectId) -> Result<CommitMetadata> {
+        let rev = id.into();
+        let commit = self
+            .git2_repo()?
+            .find_commit(rev)
+            .context("Failed to find commit for rev")?;
+        let oid = ObjectId::from(&rev);
+        let parents = commit
+            .parents()
+            .map(|p| ObjectId::from(p.id()))
+            .collect::<Vec<_>>();
+        let to_datetime = |t: git2::Time| -> Result<_> {
+            #[allow(deprecated)]
+            let date_time = NaiveDateTime::from_timestamp_opt(t.seconds(), 0)
+                .ok_or_else(|| anyhow!("Invalid timestamp"))?;
+            let offset = FixedOffset::east_opt(t.offset_minutes())
+                .ok_or_else(|| anyhow!("Invalid timezone"))?;
+            #[allow(deprecated)]
+            Ok(DateTime::<FixedOffset>::from_local(date_time, offset))
+        };
+        let author_timestamp = to_datetime(commit.author().when())
+            .context("Failed to get author timestamp")?
+            .into();
+
+        let committer_timestamp = to_datetime(commit.time())
+            .context("Failed to get committer timestamp")?
+            .into();
+
+        Ok(CommitMetadata {
+            id: oid,
+            parents,
+            author_timestamp,
+            committer_timestamp,
+        })
+    }
+
+    pub(crate) fn get_commit(&mut self, id: &ObjectId) -> Result<Commit> {
+        let details = self.read_commit_details(id)?;
+
+        let metadata = self
+            .get_commit_metadata(id)
+            .context("Failed to lookup commit metadata")?;
+
+        let short_id = self
+            .shortest_unique_prefix(id)
+            .context("Failed to abbreviate commit id")?;
+
+        Ok(Commit {
+            metadata,
+            short_id,
+            message: details.message,
+            author: details.author,
+        })
+    }
+
+    /// Upstream of every local branch that has one configured, keyed by branch name
+    pub(crate) fn upstream_branches(&self) -> Result<HashMap<String, ReferenceId>> {
+        let config = git2::Config::open(&self.common_dir.join("config"))
+            .context("Failed to read repo config")?;
+
+        let mut ret = HashMap::new();
+        let mut entries = config
+            .entries(Some(r"branch\..*\.merge"))
+            .context("Failed to read branch config")?;
+        while let Some(entry) = entries.next() {
+            let entry = entry.context("Failed to read branch config entry")?;
+            let Some(branch) = entry
+                .name()
+                .and_then(|key| key.strip_prefix("branch."))
+                .and_then(|s| s.strip_suffix(".merge"))
+            else {
+                continue;
+            };
+
+            let upstream_name = configured_upstream(&config, branch)?;
+            if let Some(upstream) = upstream_name.as_deref().and_then(reference_id_from_name) {
+                ret.insert(branch.to_string(), upstream);
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Named reference selections saved for this repository
+    pub(crate) fn ref_presets(&self) -> Result<Vec<RefPreset>> {
+        read_ref_presets(&self.ref_presets_path())
+    }
+
+    pub(crate) fn set_ref_presets(&self, presets: &[RefPreset]) -> Result<()> {
+        write_ref_presets(&self.ref_presets_path(), presets)
+    }
+
+    fn ref_presets_path(&self) -> PathBuf {
+        self.git_dir.join("spit/ref-presets")
+    }
+
+    /// Name of the author of the given commit
+    pub(crate) fn get_commit_author(&mut self, id: &ObjectId) -> Result<String> {
+        if let Some(author) = self.authors.get(id) {
+            return Ok(author.clone());
+        }
+
+        let author = self.read_commit_details(id)?.author_name;
+        self.authors.insert(id.clone(), author.clone());
+        Ok(author)
+    }
+
+    /// Message, author and tree of a commit, with replace refs applied
+    fn read_commit_details(&mut self, id: &ObjectId) -> Result<CommitDetails> {
+        let replacement = self.replacement(id).clone();
+        let object = self
+            .read_object(&replacement)
+            .context("Failed to find commit id")?;
+        if object.typ != ObjectType::Commit {
+            bail!("{} is a {}, not a commit", id, object.typ);
+        }
+
+        object::parse_commit(&object.data).context("Failed to parse commit")
+    }
+
+    /// Read any object from loose objects or packs
+    fn read_object(&mut self, id: &ObjectId) -> Result<Object> {
+        if let Some(object) = read_object(&self.object_dirs, self.object_format, &self.packs, id)? {
+            return Ok(object);
+        }
+
+        // Double check if any new packs have been added
+        if self.reload_packs()? {
+            if let Some(object) =
+                read_object(&self.object_dirs, self.object_format, &self.packs, id)?
+            {
+                return Ok(object);
+            }
+        }
+
+        bail!("Object {} not found", id)
+    }
+
+    /// Find packs again if a pack dir changed since they were last found. Returns whether the
+    /// packs were reloaded
+    fn reload_packs(&mut self) -> Result<bool> {
+        let stamps = read_pack_dir_stamps(&self.object_dirs);
+        if stamps == self.pack_dir_stamps {
+            return Ok(false);
+        }
+
+        self.packs = Arc::new(
+            find_packs(&self.object_dirs, self.object_format).context("Failed to reload packs")?,
+        );
+        self.pack_dir_stamps = stamps;
+        Ok(true)
+    }
+
+    /// Names of the loose object files in the given fanout dir. Listings are reused until the dir
+    /// changes
+    fn loose_object_names(&mut self, loose_dir: &Path) -> Result<&[String]> {
+        let Some(stamp) = fs::metadata(loose_dir)
+            .ok()
+            .and_then(|metadata| FileStamp::new(&metadata))
+        else {
+            self.loose_listings.remove(loose_dir);
+            return Ok(&[]);
+        };
+
+        let up_to_date = matches!(
+            self.loose_listings.get(loose_dir),
+            Some((listed_stamp, _)) if *listed_stamp == stamp
+        );
+        if !up_to_date {
+            let mut names = Vec::new();
+            for entry in fs::read_dir(loose_dir).context("Failed to read loose object dir")? {
+                let file_name = entry
+                    .context("Failed to read loose object entry")?
+                    .file_name();
+                if let Some(file_name) = file_name.to_str() {
+                    names.push(file_name.to_string());
+                }
+            }
+            self.loose_listings
+                .insert(loose_dir.to_path_buf(), (stamp, names));
+        }
+
+        Ok(&self.loose_listings[loose_dir].1)
+    }
+
+    /// Private implementation of get_commit_metadata that returns the vector index instead of a
+    /// reference to dodge ownership rules associated with handing out CommitMetadata references
+    /// when walking our history
+    fn get_commit_metadata_idx(&mut self, id: &ObjectId) -> Result<usize> {
+        if let Some(idx) = self.metadata_lookup.get(id) {
+            return Ok(*idx);
+        }
+
+        if self.prefetcher.is_pending(id) {
+            self.store_prefetched(Some(id));
+            if let Some(idx) = self.metadata_lookup.get(id) {
+                return Ok(*idx);
+            }
+        }
+
+        // A replaced commit takes everything but its id from its replacement
+        let mut metadata = match self.replacements.get(id).cloned() {
+            Some(replacement) => self.load_commit_metadata(&replacement)?,
+            None => self.load_commit_metadata(id)?,
+        };
+        metadata.id = id.clone();
+
+        Ok(self.store_metadata(metadata))
+    }
+
+    /// Metadata for the commit object with the given id, without replace refs or grafts applied
+    fn load_commit_metadata(&mut self, id: &ObjectId) -> Result<CommitMetadata> {
+        // FIXME: This function does not read nicely at all...
+
+        if let Some(metadata) = self.metadata_cache.get(id) {
+            return Ok(metadata);
+        }
+
+        let mut search_result =
+            read_commit_metadata(&self.object_dirs, self.object_format, &self.packs, id);
+        if let Ok(None) = search_result {
+            // Double check if any new packs have been added
+            self.reload_packs()?;
+            search_result =
+                read_commit_metadata(&self.object_dirs, self.object_format, &self.packs, id);
+        }
+
+        match search_result {
+            Ok(Some(metadata)) => {
+                self.metadata_cache.push(&metadata);
+                return Ok(metadata);
+            }
+            Ok(None) => {
+                warn!("Failed to find rev {}", id);
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to parse rev {}: {:?}. Falling back on libgit2",
+                    id, e
+                );
+            }
+        };
+
+        if !self.allow_libgit2_fallback {
+            return Err(anyhow!("Failed to find requested commit id: {}", id));
+        }
+
+        // If at this point we still haven't found the commit, we fall back on libgit2 to do it for
+        // us
+
+        let metadata = self
+            .get_commit_metadata_libgit2(id)
+            .with_context(|| format!("Failed to use libgit2 to find id {}", id))?;
+
+        self.metadata_cache.push(&metadata);
+        Ok(metadata)
+    }
+
+    /// get_commit_metadata_idx for a batch of ids. Commits that have not been seen before are
+    /// handed to the prefetcher, which keeps parsing their history while the caller walks it
+    fn get_commit_metadata_indices(&mut self, ids: &[ObjectId]) -> Result<Vec<usize>> {
+        self.store_prefetched(None);
+
+        let mut missing = Vec::new();
+        let mut seen = HashSet::new();
+        for id in ids {
+            // Replaced commits are rare, leave them to the regular lookup
+            if self.metadata_lookup.contains_key(id)
+                || self.replacements.contains_key(id)
+                || self.prefetcher.is_pending(id)
+                || !seen.insert(id)
+            {
+                continue;
+            }
+
+            match self.metadata_cache.get(id) {
+                Some(metadata) => {
+                    self.store_metadata(metadata);
+                }
+                None => missing.push(id.clone()),
+            }
+        }
+
+        self.prefetcher.prefetch(&self.packs, &missing);
+
+        // Anything the prefetcher could not handle goes through the regular lookup, which knows
+        // how to reload packs and fall back on libgit2
+        ids.iter()
+            .map(|id| self.get_commit_metadata_idx(id))
+            .collect()
+    }
+
+    /// Move finished prefetch results into metadata storage, waiting for wait_for if it is still
+    /// being parsed
+    fn store_prefetched(&mut self, wait_for: Option<&ObjectId>) {
+        for metadata in self.prefetcher.receive(wait_for) {
+            // The walk may have gotten there first, and replaced commits take their metadata from
+            // elsewhere
+            if self.metadata_lookup.contains_key(&metadata.id)
+                || self.replacements.contains_key(&metadata.id)
+            {
+                continue;
+            }
+
+            self.metadata_cache.push(&metadata);
+            self.store_metadata(metadata);
+        }
+    }
+
+    fn store_metadata(&mut self, mut metadata: CommitMetadata) -> usize {
+        if let Some(parents) = self.grafts.get(&metadata.id) {
+            metadata.parents = parents.clone();
+        }
+
+        if self.shallow.contains(&metadata.id) {
+            metadata.parents.clear();
+        }
+
+        let storage_idx = self.metadata_storage.len();
+        self.metadata_lookup
+            .insert(metadata.id.clone(), storage_idx);
+        self.metadata_storage.push(metadata);
+        storage_idx
+    }
+
+    /// Forget results that depend on the parents of commits
+    fn clear_history_caches(&mut self) {
+        self.ahead_behind_counts.clear();
+        self.containing_dag = None;
+        self.describe_cache.clear();
+    }
+
+    /// Build an iterator that iterates over metadatas. Items are sorted such that children are always
+    /// seen before parents. When there are multiple choices available the most recent commit is
+    /// preferred. This list should be effectively time sorted unless a child has an author time
+    /// before a parent. In this case the parent will appear after
+    ///
+    /// Rows are produced while history is being walked if the repo has a commit-graph. Without
+    /// one any commit not walked yet could still be a child, so the whole history is walked
+    /// before the first row, the same as git log --topo-order
+    pub(crate) fn metadata_iter<'a>(
+        &'a mut self,
+        heads: &[ObjectId],
+        sort_type: SortType,
+        cancel_token: &CancellationToken,
+    ) -> Result<MetadataIter<'a>> {
+        MetadataIter::new(self, heads, None, sort_type, cancel_token)
+    }
+
+    /// Like metadata_iter, but the walk stops at commits in known. Known commits and their
+    /// ancestors are not part of the output
+    pub(crate) fn metadata_iter_until<'a>(
+        &'a mut self,
+        heads: &[ObjectId],
+        known: &'a HashSet<ObjectId>,
+        sort_type: SortType,
+        cancel_token: &CancellationToken,
+    ) -> Result<MetadataIter<'a>> {
+        MetadataIter::new(self, heads, Some(known), sort_type, cancel_token)
+    }
+
+    /// Pick up commit-graph files written since the last walk, e.g. by git gc or fetch
+    fn refresh_commit_graph(&mut self) {
+        if self.commit_graph.is_stale(&self.object_dirs) {
+            self.commit_graph = CommitGraph::open(&self.object_dirs, self.object_format);
+        }
+    }
+
+    /// Topological level of the commit from the commit-graph, GENERATION_INFINITY if it is
+    /// unknown
+    fn generation(&self, id: &ObjectId) -> u32 {
+        // Like git, ignore the commit-graph once parents are rewritten. The levels in it were
+        // computed from the original parents
+        if !self.replacements.is_empty() || !self.grafts.is_empty() || !self.shallow.is_empty() {
+            return GENERATION_INFINITY;
+        }
+
+        self.commit_graph.generation(id)
+    }
+
+    /// Build the reversed dag for the given heads. The output is a Vec of Vecs that represents the
+    /// child indices for each metadata_storage index
+    fn build_reverse_dag(
+        &mut self,
+        heads: &[ObjectId],
+        known: &HashSet<ObjectId>,
+        cancel_token: &CancellationToken,
+    ) -> Result<(HashSet<usize>, Vec<Vec<usize>>)> {
+        let timer = Timer::new();
+
+        self.refresh_shallow()
+            .context("Failed to read shallow commits")?;
+        self.refresh_replacements()
+            .context("Failed to read replace refs")?;
+
+        let heads = heads
+            .iter()
+            .filter(|head| !known.contains(head))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Walk one generation at a time so that all parents of the frontier can be parsed
+        // together, in parallel when the history is wide enough
+        let mut frontier = self.get_commit_metadata_indices(&heads)?;
+
+        // Multiple children will have the same parent. Keep track of which indices we've walked to
+        // avoid processing the same index twice
+        let mut walked = HashSet::new();
+        let mut child_indices: Vec<Vec<usize>> = Vec::new();
+
+        while !frontier.is_empty() {
+            // (child index, parent id)
+            let mut parent_links = Vec::new();
+            for idx in frontier {
+                if !walked.insert(idx) {
+                    continue;
+                }
+
+                if walked.len() % CancellationToken::CHECK_INTERVAL == 0 {
+                    self.metadata_cache.flush();
+                    cancel_token.check()?;
+                }
+
+                for parent in &self.metadata_storage[idx].parents {
+                    if !known.contains(parent) {
+                        parent_links.push((idx, parent.clone()));
+                    }
+                }
+            }
+
+            let parents = parent_links
+                .iter()
+                .map(|(_, parent)| parent.clone())
+                .collect::<Vec<_>>();
+            let parent_indices = self.get_commit_metadata_indices(&parents)?;
+
+            frontier = Vec::new();
+            for ((idx, _), parent_idx) in parent_links.into_iter().zip(parent_indices) {
+                if child_indices.len() <= parent_idx {
+                    child_indices.resize(parent_idx + 1, Vec::new());
+                }
+                child_indices[parent_idx].push(idx);
+
+                if !walked.contains(&parent_idx) {
+                    frontier.push(parent_idx);
+                }
+            }
+        }
+
+        self.metadata_cache.flush();
+
+        debug!(
+            "Building reverse dag took: {}",
+            timer.elapsed().as_secs_f32()
+        );
+
+        Ok((walked, child_indices))
+    }
+
+    /// Find the ids of all commits reachable from the given heads
+    pub(crate) fn reachable_commits(
+        &mut self,
+        heads: &[ObjectId],
+        cancel_token: &CancellationToken,
+    ) -> Result<HashSet<ObjectId>> {
+        let (walked_indices, _) = self.build_reverse_dag(heads, &HashSet::new(), cancel_token)?;
+        Ok(walked_indices
+            .into_iter()
+            .map(|idx| self.metadata_storage[idx].id.clone())
+            .collect())
+    }
+
+    /// Id of the tree entry at the repo relative path in the given commit, or None if the path
+    /// does not exist in that commit
+    pub(crate) fn path_entry_id(&mut self, id: &ObjectId, path: &Path) -> Result<Option<ObjectId>> {
+        let mut entry_id = self.read_commit_details(id)?.tree;
+        let mut is_tree = true;
+
+        for component in path.components() {
+            if !is_tree {
+                return Ok(None);
+            }
+
+            let tree = self.read_object(&entry_id)?;
+            if tree.typ != ObjectType::Tree {
+                bail!("{} is a {}, not a tree", entry_id, tree.typ);
+            }
+
+            let name = component.as_os_str().as_encoded_bytes();
+            let mut found = None;
+            for entry in object::tree_entries(&tree.data, self.object_format) {
+                let entry = entry.with_context(|| format!("Failed to parse tree {}", entry_id))?;
+                if entry.name == name {
+                    found = Some((entry.is_tree(), entry.id));
+                    break;
+                }
+            }
+
+            match found {
+                Some((entry_is_tree, id)) => {
+                    entry_id = id;
+                    is_tree = entry_is_tree;
+                }
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(entry_id))
+    }
+
+    pub(crate) fn index(&self) -> Result<IndexState> {
+        let entries = index::read_index(&self.git_dir.join("index"), self.object_format)
+            .context("failed to read index")?;
+        let mut files = HashMap::new();
+        // Conflicted paths have an entry for each side, show ours
+        for entry in entries
+            .into_iter()
+            .filter(|entry| entry.stage == 0 || entry.stage == 2)
+        {
+            let path_s =
+                String::from_utf8(entry.path).context("index entry does not have utf8 path")?;
+            files.insert(path_s.into(), entry.id);
+        }
+
+        Ok(IndexState { files })
+    }
+
+    /// All refs that point at commits, sorted by category and name. Only refs that changed since
+    /// the last call are resolved again
+    pub(crate) fn references(&mut self) -> Result<Vec<Reference>> {
+        self.refresh_references()?;
+
+        let mut references = self.references.values().cloned().collect::<Vec<_>>();
+        references.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(references)
+    }
+
+    /// Re-read refs that changed since the previous refresh
+    fn refresh_references(&mut self) -> Result<()> {
+        let changes = self.ref_store.refresh().context("Failed to read refs")?;
+        for name in &changes.removed {
+            self.references.remove(name);
+        }
+
+        // Symbolic refs, e.g. origin/HEAD, change whenever their target does
+        let symbolic = self
+            .ref_store
+            .refs()
+            .iter()
+            .filter(|(_, value)| matches!(value.target, RefTarget::Symbolic(_)))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        for name in changes.updated.into_iter().chain(symbolic) {
+            let Some(id) = reference_id_from_name(&name) else {
+                continue;
+            };
+
+            let head = match self.ref_store.resolve_in_snapshot(&name) {
+                Ok((_, value)) => self.peel_ref_value(&value)?,
+                Err(e) => {
+                    warn!("Ignoring unresolvable ref {}: {:?}", name, e);
+                    None
+                }
+            };
+
+            // It's possible for a tag to point directly to a tree (e.g. in the linux kernel). We
+            // do not flag it as an error, but we do not show it because it doesn't fit our type
+            // well
+            match head {
+                Some(head) => self.references.insert(name, Reference { id, head }),
+                None => self.references.remove(&name),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Commit a ref value points at, following annotated tags
+    fn peel_ref_value(&mut self, value: &RefValue) -> Result<Option<ObjectId>> {
+        let RefTarget::Object(target) = &value.target else {
+            bail!("Cannot peel unresolved symbolic ref");
+        };
+
+        if let Some(peeled) = self.peeled.get(target) {
+            return Ok(peeled.clone());
+        }
+
+        // Prefer the peeled value from packed-refs so that tag objects do not have to be read
+        let candidate = value.peeled.as_ref().unwrap_or(target).clone();
+        let peeled = match self.peel_to_commit(&candidate) {
+            Ok(v) => v,
+            Err(e) => {
+                // Objects may still show up, e.g. in the middle of a fetch, so do not cache this
+                warn!("Failed to peel ref target {}: {:?}", candidate, e);
+                return Ok(None);
+            }
+        };
+
+        self.peeled.insert(target.clone(), peeled.clone());
+        Ok(peeled)
+    }
+
+    pub(crate) fn remote_refs(&self) -> Result<Vec<RemoteRef>> {
+        let mut ret = Vec::new();
+        for remote_name in &self.git2_repo()?.remotes()? {
+            let remote_name = match remote_name {
+                Some(v) => v,
+                None => {
+                    error!("Unexpected null remote name");
+                    continue;
+                }
+            };
+
+            let remote_refs = match get_refs_for_remote(&self.git_dir, remote_name) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed to get refs for remote {}: {}", remote_name, e);
+                    continue;
+                }
+            };
+            ret.extend(remote_refs.into_iter());
+        }
+        Ok(ret)
+    }
+
+    pub(crate) fn find_reference_commit_id(&mut self, id: &ReferenceId) -> Result<ObjectId> {
+        let ref_name = id.reference_string()?;
+        self.find_reference_name_commit_id(&ref_name)
+    }
+
+    pub(crate) fn find_reference_name_commit_id(&mut self, ref_name: &str) -> Result<ObjectId> {
+        let (_, value) = self.ref_store.resolve(ref_name)?;
+        self.peel_ref_value(&value)?
+            .ok_or_else(|| anyhow!("{} does not point at a commit", ref_name))
+    }
+
+    /// Expand a short reference name (e.g. "main" or "origin/main") to the full reference name
+    /// using the same precedence as git
+    pub(crate) fn expand_reference_name(&self, name: &str) -> Option<String> {
+        let candidates = [
+            name.to_string(),
+            format!("refs/{}", name),
+            format!("refs/tags/{}", name),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+            format!("refs/remotes/{}/HEAD", name),
+        ];
+
+        candidates
+            .into_iter()
+            .find(|candidate| matches!(self.ref_store.find(candidate), Ok(Some(_))))
+    }
+
+    /// Full name of the branch HEAD points at, or HEAD itself if detached
+    pub(crate) fn current_reference_name(&self) -> Result<String> {
+        let (name, _) = self
+            .ref_store
+            .resolve("HEAD")
+            .context("Failed to resolve HEAD")?;
+        if !name.starts_with("refs/heads/") {
+            return Ok("HEAD".to_string());
+        }
+
+        Ok(name)
+    }
+
+    pub(crate) fn upstream_reference_name(&self, ref_name: &str) -> Result<String> {
+        let config = git2::Config::open(&self.common_dir.join("config"))
+            .context("Failed to read repo config")?;
+
+        ref_name
+            .strip_prefix("refs/heads/")
+            .map(|branch| configured_upstream(&config, branch))
+            .transpose()?
+            .flatten()
+            .ok_or_else(|| anyhow!("No upstream configured for {}", ref_name))
+    }
+
+    /// Value of the reference n updates ago, where 0 is the current value
+    pub(crate) fn reflog_entry(&self, ref_name: &str, n: usize) -> Result<ObjectId> {
+        let reflog = self
+            .ref_store
+            .reflog(ref_name)
+            .with_context(|| format!("Failed to read reflog for {}", ref_name))?;
+
+        reflog
+            .iter()
+            .rev()
+            .nth(n)
+            .cloned()
+            .ok_or_else(|| anyhow!("Log for {} only has {} entries", ref_name, reflog.len()))
+    }
+
+    /// Commit the given object resolves to, following annotated tags. None if the object is not
+    /// commit-ish
+    pub(crate) fn peel_to_commit(&mut self, id: &ObjectId) -> Result<Option<ObjectId>> {
+        let mut candidate = id.clone();
+        // Tags may point at other tags. Ids are hashes of the content, so this cannot loop
+        loop {
+            let object = self
+                .read_object(&candidate)
+                .with_context(|| format!("Failed to find object {}", candidate))?;
+
+            match object.typ {
+                ObjectType::Commit => return Ok(Some(candidate)),
+                ObjectType::Tag => match object::parse_tag_target(&object.data)
+                    .with_context(|| format!("Failed to parse tag {}", candidate))?
+                {
+                    (next, ObjectType::Commit | ObjectType::Tag) => candidate = next,
+                    _ => return Ok(None),
+                },
+                ObjectType::Tree | ObjectType::Blob => return Ok(None),
+            }
+        }
+    }
+
+    pub(crate) fn repo_root(&self) -> &Path {
+        &self.repo_root
+    }
+
+    pub(crate) fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    pub(crate) fn resolve_reference(&self, id: &ReferenceId) -> Result<ReferenceId> {
+        let ref_name = id.reference_string()?;
+        let (name, _) = self.ref_store.resolve(&ref_name)?;
+        Ok(reference_id_from_name(&name).unwrap_or_default())
+    }
+
+    pub(crate) fn modified_files(&self, id1: &ObjectId, id2: &ObjectId) -> Result<ModifiedFiles> {
+        let t1_files = object_id_to_file_list(self.git2_repo()?, self.replacement(id1))
+            .context("failed to get files for id1")?;
+        let t2_files = object_id_to_file_list(self.git2_repo()?, self.replacement(id2))
+            .context("failed to get files for id2")?;
+
+        modified_files_between_trees(
+            self.git2_repo()?,
+            self.promisor.as_ref(),
+            DiffTarget::Object(id1.clone()),
+            DiffTarget::Object(id2.clone()),
+            &t1_files,
+            &t2_files,
+        )
+    }
+
+    pub(crate) fn modified_files_with_index(&self, id: &ObjectId) -> Result<ModifiedFiles> {
+        let index_files =
+            index_file_list(self.git2_repo()?).context("failed to get files for index")?;
+        let object_files = object_id_to_file_list(self.git2_repo()?, self.replacement(id))
+            .context("failed to get files for object")?;
+
+        modified_files_between_trees(
+            self.git2_repo()?,
+            self.promisor.as_ref(),
+            DiffTarget::Object(id.clone()),
+            DiffTarget::Index,
+            &object_files,
+            &index_files,
+        )
+    }
+
+    pub(crate) fn modified_files_index_to_workdir(&self) -> Result<ModifiedFiles> {
+        let modified_files = modified_files_in_dir(&self.repo_root, self.git2_repo()?)
+            .context("failed to find modified files")?;
+        let index_files =
+            index_file_list(self.git2_repo()?).context("failed to get files for index")?;
+
+        let mut workdir_files = index_files.clone();
+        for file in modified_files {
+            let file_bytes = file.clone().into_os_string().into_encoded_bytes();
+            if workdir_files.contains_key(&file_bytes) {
+                workdir_files.insert(file_bytes, FileListItem::Path(file));
+            }
+        }
+
+        modified_files_between_trees(
+            self.git2_repo()?,
+            self.promisor.as_ref(),
+            DiffTarget::Index,
+            DiffTarget::WorkingDirModified,
+            &index_files,
+            &workdir_files,
+        )
+    }
+
+    pub(crate) fn untracked_files(&self) -> Result<ModifiedFiles> {
+        let modified_files = modified_files_in_dir(&self.repo_root, self.git2_repo()?)
+            .context("failed to find modified files")?;
+        let index_files =
+            index_file_list(self.git2_repo()?).context("failed to get files for index")?;
+
+        let mut workdir_files = HashMap::new();
+        for file in modified_files {
+            let file_bytes = file.clone().into_os_string().into_encoded_bytes();
+            if !index_files.contains_key(&file_bytes) {
+                workdir_files.insert(file_bytes, FileListItem::Path(file));
+            }
+        }
+
+        modified_files_between_trees(
+            self.git2_repo()?,
+            self.promisor.as_ref(),
+            DiffTarget::Index,
+            DiffTarget::WorkingDirUntracked,
+            &HashMap::new(),
+            &workdir_files,
+        )
+    }
+
+    pub(crate) fn is_ignored(&self, path: &Path) -> Result<bool> {
+        let Ok(repo_relative_entry_path) = path.strip_prefix(&self.repo_root) else {
+            return Ok(false);
+        };
+
+        self.git2_repo()?
+            .is_path_ignored(repo_relative_entry_path)
+            .context("failed to check ignore for file")
+    }
+}
+
+/// Full name of the ref that branch.<name>.remote and branch.<name>.merge point at, None if the
+/// branch has no upstream or the remote does not fetch it
+fn configured_upstream(config: &git2::Config, branch: &str) -> Result<Option<String>> {
+    let (Ok(remote), Ok(merge)) = (
+        config.get_string(&format!("branch.{branch}.remote")),
+        config.get_string(&format!("branch.{branch}.merge")),
+    ) else {
+        return Ok(None);
+    };
+
+    // A remote of . tracks another local branch
+    if remote == "." {
+        return Ok(Some(merge));
+    }
+
+    let mut refspecs = Vec::new();
+    config
+        .multivar(&format!("remote.{remote}.fetch"), None)
+        .context("Failed to read remote fetch refspecs")?
+        .for_each(|entry| refspecs.extend(entry.value().map(str::to_string)))
+        .context("Failed to read remote fetch refspec")?;
+    Ok(map_through_refspecs(&refspecs, &merge))
+}
+
+/// Where a fetch with the given refspecs stores the remote ref with the given name
+fn map_through_refspecs(refspecs: &[String], name: &str) -> Option<String> {
+    for refspec in refspecs {
+        // Negative refspecs only exclude refs, they never map them
+        if refspec.starts_with('^') {
+            continue;
+        }
+
+        let refspec = refspec.strip_prefix('+').unwrap_or(refspec);
+        let Some((src, dst)) = refspec.split_once(':') else {
+            continue;
+        };
+
+        match (src.split_once('*'), dst.split_once('*')) {
+            (Some((src_prefix, src_suffix)), Some((dst_prefix, dst_suffix))) => {
+                let matched = name
+                    .strip_prefix(src_prefix)
+                    .and_then(|s| s.strip_suffix(src_suffix));
+                if let Some(matched) = matched {
+                    return Some(format!("{dst_prefix}{matched}{dst_suffix}"));
+                }
+            }
+            (None, None) if src == name && !dst.is_empty() => return Some(dst.to_string()),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// ReferenceId for a full ref name. None for refs that are not shown, i.e. pseudo refs like HEAD
+/// and replace refs, which are applied to history instead
+pub(super) fn reference_id_from_name(name: &str) -> Option<ReferenceId> {
+    if let Some(name) = name.strip_prefix("refs/heads/") {
+        Some(ReferenceId::LocalBranch(name.to_string()))
+    } else if let Some(name) = name.strip_prefix("refs/remotes/") {
+        Some(ReferenceId::RemoteBranch(name.to_string()))
+    } else if let Some(name) = name.strip_prefix("refs/tags/") {
+        Some(ReferenceId::Tag(name.to_string()))
+    } else if name.starts_with("refs/") && !name.starts_with("refs/replace/") {
+        Some(ReferenceId::Other(name.to_string()))
+    } else {
+        None
+    }
+}
+
+fn get_refs_for_remote(git_path: &Path, remote_name: &str) -> Result<Vec<RemoteRef>> {
+    // NOTE: Github does not have as wide libgit2 support as the git CLI client due to libssh2 not
+    // supporting SHA-2. This means that ssh keys that work with the git client will not work with
+    // libgit2, which is an annoying and unexpected change.
+    //
+    // Use the git command line and parse the output
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(git_path)
+        .args(["ls-remote", "-q", remote_name])
+        .output()?;
+
+    if !output.status.success() {
+        let err = std::str::from_utf8(&output.stderr).unwrap_or("Failed to parse stderr");
+        return Err(Error::msg(format!(
+            "ls-remote failed for {}: {}",
+            remote_name, err
+        )));
+    }
+
+    let output_str = std::str::from_utf8(&output.stdout)?;
+    output_str
+        .lines()
+        .map(|l| {
+            let mut line_iter = l.split('\t');
+
+            let ref_name = line_iter
+                .nth(1)
+                .context("Could not get reference id for remote ref")?
+                .to_string();
+
+            Ok(RemoteRef {
+                remote: remote_name.to_string(),
+                ref_name,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+}
+
+struct WalkNode {
+    generation: u32,
+    /// Children that were walked but not emitted yet
+    children_remaining: usize,
+    /// Whether the parents of this commit were walked
+    expanded: bool,
+    emitted: bool,
+}
+
+/// Iterator returned by Repo::metadata_iter. Effectively Kahn's algorithm where we choose the
+/// order based off timestamp, run while history is still being walked. A commit is only emitted
+/// once no commit left to walk can be its child. Without generation numbers from a commit-graph
+/// that means the whole history is walked before the first item
+pub(crate) struct MetadataIter<'a> {
+    repo: &'a mut Repo,
+    known: Option<&'a HashSet<ObjectId>>,
+    sort_type: SortType,
+    cancel_token: CancellationToken,
+    nodes: HashMap<usize, WalkNode>,
+    /// Commits whose parents still have to be walked, highest generation first
+    walk_queue: BinaryHeap<(u32, DateTime<Utc>, usize)>,
+    /// Commits with no remaining children, newest first. The sequence number makes ties pick the
+    /// most recently freed commit. Entries are checked when popped rather than removed when a
+    /// commit stops being a candidate
+    ready: BinaryHeap<(DateTime<Utc>, usize, usize)>,
+    num_pushed: usize,
+    num_expanded: usize,
+    num_emitted: usize,
+    /// Time since the walk started, reset when the first commit is emitted
+    timer: Timer,
+    finished: bool,
+}
+
+impl<'a> MetadataIter<'a> {
+    fn new(
+        repo: &'a mut Repo,
+        heads: &[ObjectId],
+        known: Option<&'a HashSet<ObjectId>>,
+        sort_type: SortType,
+        cancel_token: &CancellationToken,
+    ) -> Result<MetadataIter<'a>> {
+        repo.refresh_shallow()
+            .context("Failed to read shallow commits")?;
+        repo.refresh_replacements()
+            .context("Failed to read replace refs")?;
+        repo.refresh_commit_graph();
+
+        let heads = heads
+            .iter()
+            .filter(|head| !known.is_some_and(|known| known.contains(head)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let head_indices = repo.get_commit_metadata_indices(&heads)?;
+
+        let mut iter = MetadataIter {
+            repo,
+            known,
+            sort_type,
+            cancel_token: cancel_token.clone(),
+            nodes: HashMap::new(),
+            walk_queue: BinaryHeap::new(),
+            ready: BinaryHeap::new(),
+            num_pushed: 0,
+            num_expanded: 0,
+            num_emitted: 0,
+            timer: Timer::new(),
+            finished: false,
+        };
+
+        for idx in head_indices {
+            if iter.discover(idx) {
+                iter.push_ready(idx);
+            }
+        }
+
+        Ok(iter)
+    }
+
+    /// Start tracking the commit at idx, returns false if it was already tracked
+    fn discover(&mut self, idx: usize) -> bool {
+        if self.nodes.contains_key(&idx) {
+            return false;
+        }
+
+        let metadata = &self.repo.metadata_storage[idx];
+        let generation = self.repo.generation(&metadata.id);
+        self.walk_queue
+            .push((generation, metadata.committer_timestamp, idx));
+        self.nodes.insert(
+            idx,
+            WalkNode {
+                generation,
+                children_remaining: 0,
+                expanded: false,
+                emitted: false,
+            },
+        );
+
+        true
+    }
+
+    fn push_ready(&mut self, idx: usize) {
+        let metadata = &self.repo.metadata_storage[idx];
+        let timestamp = match self.sort_type {
+            SortType::AuthorTimestamp => metadata.author_timestamp,
+            SortType::CommitterTimestamp => metadata.committer_timestamp,
+        };
+        self.num_pushed += 1;
+        self.ready.push((timestamp, self.num_pushed, idx));
+    }
+
+    /// Walk to the parents of the given commits. They are loaded together so that they can be
+    /// parsed in parallel
+    fn expand(&mut self, indices: &[usize]) -> Result<()> {
+        let mut parents = Vec::new();
+        for idx in indices {
+            let node = self
+                .nodes
+                .get_mut(idx)
+                .expect("Expanded commit not tracked");
+            node.expanded = true;
+
+            self.num_expanded += 1;
+            if self.num_expanded % CancellationToken::CHECK_INTERVAL == 0 {
+                self.repo.metadata_cache.flush();
+                self.cancel_token.check()?;
+            }
+
+            let known = self.known;
+            parents.extend(
+                self.repo.metadata_storage[*idx]
+                    .parents
+                    .iter()
+                    .filter(|parent| !known.is_some_and(|known| known.contains(parent)))
+                    .cloned(),
+            );
+        }
+
+        for parent_idx in self.repo.get_commit_metadata_indices(&parents)? {
+            self.discover(parent_idx);
+            let node = self.nodes.get_mut(&parent_idx).expect("Parent not tracked");
+            node.children_remaining += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Expand every queued commit of the highest queued generation
+    fn expand_generation(&mut self, generation: u32) -> Result<()> {
+        let mut indices = Vec::new();
+        while let Some(&(queued_generation, _, idx)) = self.walk_queue.peek() {
+            if queued_generation != generation {
+                break;
+            }
+            self.walk_queue.pop();
+            if !self.nodes[&idx].expanded {
+                indices.push(idx);
+            }
+        }
+
+        self.expand(&indices)
+    }
+
+    fn next_idx(&mut self) -> Result<Option<usize>> {
+        loop {
+            while let Some(&(_, _, idx)) = self.ready.peek() {
+                let node = &self.nodes[&idx];
+                if node.emitted || node.children_remaining > 0 {
+                    self.ready.pop();
+                } else {
+                    break;
+                }
+            }
+
+            while let Some(&(_, _, idx)) = self.walk_queue.peek() {
+                if self.nodes[&idx].expanded {
+                    self.walk_queue.pop();
+                } else {
+                    break;
+                }
+            }
+
+            let max_queued_generation =
+                self.walk_queue.peek().map(|(generation, _, _)| *generation);
+            let Some(&(_, _, idx)) = self.ready.peek() else {
+                match max_queued_generation {
+                    Some(generation) => {
+                        self.expand_generation(generation)?;
+                        continue;
+                    }
+                    None => return Ok(None),
+                }
+            };
+
+            let node = &self.nodes[&idx];
+            if !node.expanded {
+                self.expand(&[idx])?;
+                continue;
+            }
+
+            // Unwalked commits are either queued or ancestors of queued commits, so they all have
+            // a generation of at most the highest queued one. Children always have a higher
+            // generation than their parents
+            if let Some(generation) = max_queued_generation {
+                let safe = generation < node.generation
+                    || (generation == node.generation && generation != GENERATION_INFINITY);
+                if !safe {
+                    self.expand_generation(generation)?;
+                    continue;
+                }
+            }
+
+            self.ready.pop();
+            self.nodes
+                .get_mut(&idx)
+                .expect("Emitted commit not tracked")
+                .emitted = true;
+
+            let known = self.known;
+            let parent_indices = self.repo.metadata_storage[idx]
+                .parents
+                .iter()
+                .filter(|parent| !known.is_some_and(|known| known.contains(parent)))
+                .map(|parent| self.repo.metadata_lookup[parent])
+                .collect::<Vec<_>>();
+            for parent_idx in parent_indices {
+                let parent_node = self.nodes.get_mut(&parent_idx).expect("Parent not tracked");
+                parent_node.children_remaining -= 1;
+                if parent_node.children_remaining == 0 {
+                    self.push_ready(parent_idx);
+                }
+            }
+
+            return Ok(Some(idx));
+        }
+    }
+}
+
+impl Iterator for MetadataIter<'_> {
+    type Item = Result<CommitMetadata>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_idx() {
+            Ok(Some(idx)) => {
+                if self.num_emitted == 0 {
+                    debug!(
+                        "Walked {} commits before the first one could be emitted, took: {}",
+                        self.nodes.len(),
+                        self.timer.elapsed().as_secs_f32()
+                    );
+                    self.timer.reset();
+                }
+                self.num_emitted += 1;
+                Some(Ok(self.repo.metadata_storage[idx].clone()))
+            }
+            Ok(None) => {
+                debug!(
+                    "Emitting {} commits after the first took: {}",
+                    self.num_emitted,
+                    self.timer.elapsed().as_secs_f32()
+                );
+                self.finished = true;
+                self.repo.metadata_cache.flush();
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for MetadataIter<'_> {
+    fn drop(&mut self) {
+        self.repo.metadata_cache.flush();
+    }
+}
+
+/// Parse commit metadata from loose objects or the given packs. Only shared access is needed, so
+/// this can run on several threads at once
+pub(crate) fn read_commit_metadata(
+    object_dirs: &[PathBuf],
+    object_format: ObjectFormat,
+    packs: &[Pack],
+    id: &ObjectId,
+) -> Result<Option<CommitMetadata>> {
+    // Check unpacked objects first since they are cheap
+    if let Some(unpacked_path) = find_loose_object(object_dirs, id) {
+        let commit = fs::read(unpacked_path).context("Failed to read object file")?;
+        let metadata = decompress::with_thread_decompressor(|decompressor| {
+            decompress::decompress_commit_metadata(&commit, decompressor, false, object_format)
+        })?;
+        return Ok(Some(metadata.into_full_metadata(id.clone())));
+    }
+
+    for pack in packs {
+        if let Some(metadata) = pack.get_commit_metadata(id.clone())? {
+            return Ok(Some(metadata));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read an object of any type from loose objects or the given packs
+fn read_object(
+    object_dirs: &[PathBuf],
+    object_format: ObjectFormat,
+    packs: &[Pack],
+    id: &ObjectId,
+) -> Result<Option<Object>> {
+    if id.len() != object_format.raw_len() {
+        bail!("{} is not a {} object id", id, object_format);
+    }
+
+    if let Some(path) = find_loose_object(object_dirs, id) {
+        let compressed = fs::read(path).context("Failed to read object file")?;
+        let object = object::parse_loose_object(&compressed)
+            .with_context(|| format!("Failed to parse loose object {}", id))?;
+        return Ok(Some(object));
+    }
+
+    for pack in packs {
+        if let Some(object) = pack.read_object(id)? {
+            return Ok(Some(object));
+        }
+    }
+
+    Ok(None)
+}
+
+fn find_loose_object(object_dirs: &[PathBuf], id: &ObjectId) -> Option<PathBuf> {
+    let id_s = id.to_string();
+    object_dirs
+        .iter()
+        .map(|objects_dir| objects_dir.join(&id_s[..2]).join(&id_s[2..]))
+        .find(|path| path.exists())
+}
+
+fn read_object_format(config: &git2::Config) -> Result<ObjectFormat> {
+    match config.get_string("extensions.objectFormat") {
+        Ok(v) => v.parse(),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(ObjectFormat::Sha1),
+        Err(e) => Err(e).context("Failed to read extensions.objectFormat"),
+    }
+}
+
+/// Look for a .git dir, or a .git file pointing at one, in repo_root or its parents
+fn find_dot_git(repo_root: &Path) -> Option<PathBuf> {
+    repo_root.ancestors().find_map(|dir| {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return Some(dot_git);
+        }
+
+        let content = fs::read_to_string(&dot_git).ok()?;
+        let git_dir = content.strip_prefix("gitdir:")?.trim();
+        Some(dir.join(git_dir))
+    })
+}
+
+fn read_ref_storage(config: &git2::Config) -> Result<RefStorage> {
+    match config.get_string("extensions.refStorage") {
+        Ok(v) => v.parse(),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(RefStorage::Files),
+        Err(e) => Err(e).context("Failed to read extensions.refStorage"),
+    }
+}
+
+/// Find the git dir for a repo that libgit2 cannot open
+fn find_git_dir(repo_root: &Path) -> Result<PathBuf> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_root)
+        .args(["rev-parse", "--absolute-git-dir"])
+        .output()?;
+
+    if !output.status.success() {
+        // The installed git may be older than the repo, e.g. one without reftable support
+        if let Some(git_dir) = find_dot_git(repo_root) {
+            return Ok(git_dir);
+        }
+
+        let err = std::str::from_utf8(&output.stderr).unwrap_or("Failed to parse stderr");
+        return Err(Error::msg(format!("rev-parse failed: {}", err)));
+    }
+
+    let git_dir = std::str::from_utf8(&output.stdout).context("Git dir is not utf8")?;
+    Ok(PathBuf::from(git_dir.trim_end()))
+}
+
+/// Remote that objects missing from a partial clone come from, None for complete clones
+fn find_promisor(config: &git2::Config, object_dirs: &[PathBuf]) -> Option<Promisor> {
+    if let Ok(remote) = config.get_string("extensions.partialClone") {
+        return Some(Promisor::Remote(remote));
+    }
+
+    if let Ok(mut entries) = config.entries(Some(r"remote\..*\.promisor")) {
+        while let Some(Ok(entry)) = entries.next() {
+            let is_promisor = entry.value().and_then(|v| git2::Config::parse_bool(v).ok());
+            let remote = entry
+                .name()
+                .and_then(|name| name.strip_prefix("remote."))
+                .and_then(|name| name.strip_suffix(".promisor"));
+            if let (Some(true), Some(remote)) = (is_promisor, remote) {
+                return Some(Promisor::Remote(remote.to_string()));
+            }
+        }
+    }
+
+    // Still a partial clone, but guessing the remote could suggest fetching from the wrong one
+    let has_promisor_packs = object_dirs.iter().any(|objects_dir| {
+        find_pack_paths(objects_dir).is_ok_and(|packs| {
+            packs
+                .iter()
+                .any(|pack| pack.with_extension("promisor").exists())
+        })
+    });
+    has_promisor_packs.then_some(Promisor::Unknown)
+}
+
+/// Directory shared by all worktrees of a repo, which is where objects and packed refs live
+fn find_common_dir(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim_end()),
+        Err(_) => git_dir.to_path_buf(),
+    }
+}
+
+/// The given object dir followed by every alternate object dir it borrows from, recursively, in
+/// the order git searches them
+fn find_object_dirs(objects_dir: &Path) -> Vec<PathBuf> {
+    let mut ret: Vec<PathBuf> = Vec::new();
+    let mut to_visit = vec![objects_dir.to_path_buf()];
+
+    while let Some(dir) = to_visit.pop() {
+        // Canonicalize so that cycles between alternates are detected
+        let dir = fs::canonicalize(&dir).unwrap_or(dir);
+        if ret.contains(&dir) {
+            continue;
+        }
+
+        let alternates = match fs::read_to_string(dir.join("info/alternates")) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                warn!("Failed to read alternates for {}: {}", dir.display(), e);
+                String::new()
+            }
+        };
+
+        // Relative alternates are relative to the object dir that names them. Push in reverse
+        // so that the first alternate is searched first
+        to_visit.extend(
+            alternates
+                .lines()
+                .map(str::trim_end)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| dir.join(line))
+                .rev(),
+        );
+        ret.push(dir);
+    }
+
+    ret
+}
+
+fn find_pack_paths(objects_dir: &Path) -> Result<Vec<PathBuf>> {
+    let pack_dir = objects_dir.join("pack");
+
+    if !pack_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    fs::read_dir(&pack_dir)
+        .context("Failed to read pack dir")?
+        .filter_map(|e| match e {
+            Ok(v) => {
+                if v.path().extension() == Some(std::ffi::OsStr::new("pack")) {
+                    Some(Ok(v.path()))
+                } else {
+                    None
+                }
+            }
+            Err(e) => Some(Err(anyhow::Error::from(e))),
+        })
+        .collect()
+}
+
+fn read_pack_dir_stamps(object_dirs: &[PathBuf]) -> Vec<Option<FileStamp>> {
+    object_dirs
+        .iter()
+        .map(|objects_dir| {
+            let metadata = fs::metadata(objects_dir.join("pack")).ok()?;
+            FileStamp::new(&metadata)
+        })
+        .collect()
+}
+
+fn find_packs(object_dirs: &[PathBuf], object_format: ObjectFormat) -> Result<Vec<Pack>> {
+    let mut packs = Vec::new();
+    for objects_dir in object_dirs {
+        for pack_path in find_pack_paths(objects_dir)? {
+            packs.push(Pack::new(&pack_path, object_format)?);
+        }
+    }
+
+    Ok(packs)
+}
+
+#[derive(Clone, Hash, Eq, PartialEq)]
+enum FileListItem {
+    Object(git2::Oid),
+    Commit(git2::Oid),
+    Path(PathBuf),
+}
+
+fn index_file_list(git2_repo: &git2::Repository) -> Result<HashMap<Vec<u8>, FileListItem>> {
+    let mut ret = HashMap::new();
+    let mut index = git2_repo.index().context("failed to get index")?;
+    index.read(false).unwrap();
+    for entry in index.iter() {
+        let id = entry.id;
+        let path = entry.path;
+        // FIXME: Check object type (i.e. tree, commit, submodule, etc.) like in
+        // object_id_to_file_list
+        ret.insert(path, FileListItem::Object(id));
+    }
+
+    Ok(ret)
+}
+
+fn object_id_to_file_list(
+    git2_repo: &git2::Repository,
+    id: &ObjectId,
+) -> Result<HashMap<Vec<u8>, FileListItem>> {
+    let oid1 = id.into();
+    let tree = git2_repo.find_commit(oid1)?.tree()?;
+    let mut ret = HashMap::new();
+
+    let walk_insert_filename = |root: &str, entry: &TreeEntry, files: &mut HashMap<_, _>| {
+        let mut full_path = root.as_bytes().to_vec();
+        full_path.extend(entry.name_bytes());
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                return TreeWalkResult::Ok;
+            }
+            Some(git2::ObjectType::Commit) => {
+                files.insert(full_path, FileListItem::Commit(entry.id()));
+            }
+            _ => {
+                files.insert(full_path, FileListItem::Object(entry.id()));
+            }
+        }
+
+        TreeWalkResult::Ok
+    };
+
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        walk_insert_filename(root, entry, &mut ret)
+    })
+    .context("Failed to walk tree 1")?;
+
+    Ok(ret)
+}
+
+fn modified_files_between_trees(
+    git2_repo: &git2::Repository,
+    promisor: Option<&Promisor>,
+    id1: DiffTarget,
+    id2: DiffTarget,
+    t1_files: &HashMap<Vec<u8>, FileListItem>,
+    t2_files: &HashMap<Vec<u8>, FileListItem>,
+) -> Result<ModifiedFiles> {
+    let mut changed_paths = t1_files
+        .iter()
+        .filter_map(|(path, id)| {
+            if t2_files.get(path) == Some(id) {
+                None
+            } else {
+                Some(path.clone())
+            }
+        })
+        .collect::<BTreeSet<Vec<u8>>>();
+
+    for (path, id) in t2_files.iter() {
+        if t1_files.get(path) != Some(id) {
+            changed_paths.insert(path.clone());
+        }
+    }
+
+    // Blobs that have not been fetched into a partial clone, by path
+    let mut unfetched = BTreeMap::<Vec<u8>, Vec<ObjectId>>::new();
+    let mut paths_to_contents = |oid_lookup: &HashMap<Vec<u8>, FileListItem>| {
+        changed_paths
+            .iter()
+            .map(|filename| -> Result<Option<_>> {
+                let id = match oid_lookup.get(filename) {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+
+                let id = match id {
+                    FileListItem::Commit(id) => {
+                        let stringized = format!("Subproject commit {}", id);
+                        return Ok(Some(stringized.into_bytes()));
+                    }
+                    FileListItem::Object(id) => id,
+                    FileListItem::Path(path) => {
+                        return Ok(Some(fs::read(path).context("failed to read workdir data")?))
+                    }
+                };
+
+                let object = match (git2_repo.find_object(*id, None), promisor) {
+                    (Ok(v), _) => v,
+                    (Err(e), Some(_)) if e.code() == git2::ErrorCode::NotFound => {
+                        unfetched
+                            .entry(filename.clone())
+                            .or_default()
+                            .push(id.into());
+                        return Ok(None);
+                    }
+                    (Err(e), _) => return Err(e).context("Failed to retrieve object"),
+                };
+
+                if let Some(blob) = object.as_blob() {
+                    Ok(Some(blob.content().to_vec()))
+                } else {
+                    let description = object
+                        .describe(&git2::DescribeOptions::default())
+                        .context("Failed to generate description for object")?;
+                    let stringized = description
+                        .format(None)
+                        .context("Failed to stringize description")?;
+                    Ok(Some(stringized.into_bytes()))
+                }
+            })
+            .collect::<Result<Vec<Option<Vec<u8>>>>>()
+    };
+
+    let content_1 =
+        paths_to_contents(t1_files).context("Failed to retrieve file content for tree 1")?;
+    let content_2 =
+        paths_to_contents(t2_files).context("Failed to retrieve file content for tree 2")?;
+
+    // Half of a diff against missing content would make up changes, so those paths are left out
+    let mut files_a = Vec::new();
+    let mut files_b = Vec::new();
+    let mut labels = Vec::new();
+    for ((path, content_1), content_2) in changed_paths.iter().zip(content_1).zip(content_2) {
+        if unfetched.contains_key(path) {
+            continue;
+        }
+        files_a.push(content_1);
+        files_b.push(content_2);
+        labels.push(String::from_utf8_lossy(path).to_string());
+    }
+
+    let remote = match promisor {
+        Some(Promisor::Remote(remote)) => Some(remote.as_str()),
+        _ => None,
+    };
+    let unfetched = unfetched
+        .into_iter()
+        .map(|(path, ids)| UnfetchedFile {
+            path: String::from_utf8_lossy(&path).to_string(),
+            remote: remote.map(str::to_string),
+            fetch_command: remote.map(|remote| {
+                ids.iter()
+                    .map(|id| commandline::fetch_missing_object(remote, id))
+                    .collect::<Vec<_>>()
+                    .join(" && ")
+            }),
+        })
+        .collect();
+
+    Ok(ModifiedFiles {
+        id_a: id1,
+        id_b: id2,
+        files_a,
+        files_b,
+        labels,
+        unfetched,
+    })
+}
+
+fn modified_files_in_dir_impl(
+    root: &Path,
+    path: &Path,
+    git2_repo: &git2::Repository,
+    output: &mut Vec<PathBuf>,
+    index: &git2::Index,
+) -> Result<()> {
+    let dir_iter = fs::read_dir(path).context("failed to get directory iterator")?;
+
+    for entry in dir_iter {
+        let entry = entry.context("failed to iterate directory")?;
+        let entry_path = entry.path();
+        debug!("Checking modified for {:?}", entry_path);
+
+        let repo_relative_entry_path = entry_path
+            .strip_prefix(root)
+            .expect("failed to strip root from entry path")
+            .to_path_buf();
+        debug!("repo relative path: {:?}", repo_relative_entry_path);
+
+        if git2_repo
+            .is_path_ignored(&repo_relative_entry_path)
+            .context("failed to check ignore for file")?
+        {
+            debug!("{:?} ignored", entry_path);
+            continue;
+        }
+
+        if entry
+            .file_type()
+            .context("failed to get file type of entry")?
+            .is_dir()
+        {
+            modified_files_in_dir_impl(root, &entry_path, git2_repo, output, index)?;
+            continue;
+        }
+
+        // git2/index.h git_index_stage_t
+        // https://libgit2.org/libgit2/ex/HEAD/ls-files.html#git_index_get_bypath-4
+        // NOTE: Wanted to use stage_any, but index entry is always none in that case
+        // FIXME: Need to think about merge conflicts later
+        const STAGE_NORMAL: i32 = 0;
+        let index_entry = match index.get_path(&repo_relative_entry_path, STAGE_NORMAL) {
+            Some(v) => v,
+            None => {
+                debug!("File not found in index");
+                output.push(repo_relative_entry_path);
+                continue;
+            }
+        };
+
+        let index_entry_blob = git2_repo
+            .find_blob(index_entry.id)
+            .context("failed to find blob")?;
+
+        let entry_content = fs::read(&entry_path).context("failed to read content of entry")?;
+        let index_content = index_entry_blob.content();
+
+        if entry_content != index_content {
+            debug!("Content did not match: {entry_content:?}, {index_content:?}");
+            output.push(repo_relative_entry_path);
+        }
+    }
+    Ok(())
+}
+
+fn modified_files_in_dir(path: &Path, git2_repo: &git2::Repository) -> Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    let mut index = git2_repo.index().context("failed to retrieve index")?;
+    index.read(false).context("failed to update index")?;
+    modified_files_in_dir_impl(path, path, git2_repo, &mut ret, &index)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::{commit, git, git_with_input};
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    const GIT_DIR_TARBALL: &[u8] =
+        include_bytes!("../../../res/test/multi_obj_multi_pack_octopus_merge.tar");
+    #[test]
+    fn test_unpacked() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL)
+            .unpack(git_dir.path())
+            .unwrap();
+
+        let mut history = Repo::new(git_dir.path().to_path_buf(), false)?;
+
+        let oid = "83fc68fe02d76e37231b8f880bca5f151cb62e39".parse()?;
+        let expected_parent: ObjectId = "ce4f6371c0a653f6206e4020704674d63fc8e3d4".parse()?;
+        let metadata = history.get_commit_metadata(&oid)?;
+
+        assert_eq!(metadata.parents.len(), 1);
+        assert_ne!(
+            metadata.parents.iter().find(|id| **id == expected_parent),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_1() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL)
+            .unpack(git_dir.path())
+            .unwrap();
+
+        let mut history = Repo::new(git_dir.path().to_path_buf(), false)?;
+
+        let oid = "760e2389d32e245213eaf71d88e314fa63709c79".parse()?;
+        let expected_parent: ObjectId = "54c637bcfcaab19064ac59db025bc05d941a3bf3".parse()?;
+        let metadata = history.get_commit_metadata(&oid)?;
+
+        assert_eq!(metadata.parents.len(), 1);
+        assert_ne!(
+            metadata.parents.iter().find(|id| **id == expected_parent),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_2() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL)
+            .unpack(git_dir.path())
+            .unwrap();
+
+        let pack = Pack::new(
+            &git_dir
+                .path()
+                .join(".git/objects/pack/pack-d263ed5546c1c402dad86f0970272add736ccb1f.pack"),
+            ObjectFormat::Sha1,
+        )?;
+
+        let oid = "bf57fac4272accfb0a0af73d1648bb406a8e84a2".parse()?;
+        let expected_parent_1: ObjectId = "93fc7325bad6205598b6cc601bbdb75d0eab5c48".parse()?;
+        let expected_parent_2: ObjectId = "cee9d1a5528b2a8731d79bbb30de24c4a05a8937".parse()?;
+        let expected_parent_3: ObjectId = "43ffc82ef7b65acaa19f589a62eba882c8f0ad69".parse()?;
+
+        let metadata = pack
+            .get_commit_metadata(oid)?
+            .expect("Expected to find commit");
+
+        assert_eq!(metadata.parents.len(), 3);
+        assert_ne!(
+            metadata.parents.iter().find(|id| **id == expected_parent_1),
+            None
+        );
+        assert_ne!(
+            metadata.parents.iter().find(|id| **id == expected_parent_2),
+            None
+        );
+        assert_ne!(
+            metadata.parents.iter().find(|id| **id == expected_parent_3),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_pack_paths() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL)
+            .unpack(git_dir.path())
+            .unwrap();
+
+        let packs = find_pack_paths(&git_dir.path().join(".git/objects"))?;
+
+        let expected_pack_1 = git_dir
+            .path()
+            .join(".git/objects/pack/pack-66c4253986146290e8d86a6057cb8b076f43c325.pack");
+        let expected_pack_2 = git_dir
+            .path()
+            .join(".git/objects/pack/pack-d263ed5546c1c402dad86f0970272add736ccb1f.pack");
+
+        assert_eq!(packs.len(), 2);
+        assert_ne!(packs.iter().find(|p| **p == expected_pack_1), None);
+        assert_ne!(packs.iter().find(|p| **p == expected_pack_2), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_branches() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let git_dir2 = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir2.path())?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir2.path())
+            .args(&[
+                "branch",
+                "test_branch",
+                "760e2389d32e245213eaf71d88e314fa63709c79",
+            ])
+            .output()?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&["remote", "add", "origin"])
+            .arg(git_dir2.path())
+            .output()?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&["fetch", "origin"])
+            .output()?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&[
+                "branch",
+                "test_branch",
+                "ce4f6371c0a653f6206e4020704674d63fc8e3d4",
+            ])
+            .output()?;
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        let mut branches = repo.references()?;
+        branches.sort();
+
+        assert_eq!(
+            branches,
+            &[
+                Reference {
+                    id: ReferenceId::LocalBranch("master".to_string()),
+                    head: "83fc68fe02d76e37231b8f880bca5f151cb62e39".parse()?
+                },
+                Reference {
+                    id: ReferenceId::LocalBranch("test_branch".to_string()),
+                    head: "ce4f6371c0a653f6206e4020704674d63fc8e3d4".parse()?
+                },
+                Reference {
+                    id: ReferenceId::RemoteBranch("origin/master".to_string()),
+                    head: "83fc68fe02d76e37231b8f880bca5f151cb62e39".parse()?
+                },
+                Reference {
+                    id: ReferenceId::RemoteBranch("origin/test_branch".to_string()),
+                    head: "760e2389d32e245213eaf71d88e314fa63709c79".parse()?
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_through_refspecs() {
+        let refspecs = [
+            "^refs/heads/secret".to_string(),
+            "+refs/heads/*:refs/remotes/origin/*".to_string(),
+            "refs/tags/*-rc:refs/tags/rc/*".to_string(),
+        ];
+        assert_eq!(
+            map_through_refspecs(&refspecs, "refs/heads/feature/a").as_deref(),
+            Some("refs/remotes/origin/feature/a")
+        );
+        assert_eq!(
+            map_through_refspecs(&refspecs, "refs/tags/v1-rc").as_deref(),
+            Some("refs/tags/rc/v1")
+        );
+        assert_eq!(map_through_refspecs(&refspecs, "refs/notes/commits"), None);
+        assert_eq!(
+            map_through_refspecs(&["refs/heads/main:".to_string()], "refs/heads/main"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_references() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let first = commit(repo_path, "first")?;
+        git(repo_path, &["tag", "-a", "-m", "packed", "packed"])?;
+        git(repo_path, &["tag", "tree", "HEAD^{tree}"])?;
+        git(repo_path, &["pack-refs", "--all"])?;
+        git(repo_path, &["tag", "-a", "-m", "loose", "loose"])?;
+        // Tag of a tag, which has to be peeled twice
+        git(repo_path, &["tag", "-a", "-m", "nested", "nested", "loose"])?;
+
+        let reference = |id, head: &ObjectId| Reference {
+            id,
+            head: head.clone(),
+        };
+
+        let mut repo = Repo::new(repo_path.to_path_buf(), false)?;
+        assert_eq!(
+            repo.references()?,
+            [
+                reference(ReferenceId::LocalBranch("main".into()), &first),
+                reference(ReferenceId::Tag("loose".into()), &first),
+                reference(ReferenceId::Tag("nested".into()), &first),
+                reference(ReferenceId::Tag("packed".into()), &first),
+            ]
+        );
+
+        git(
+            repo_path,
+            &["commit", "-q", "--allow-empty", "-m", "second"],
+        )?;
+        let second: ObjectId = git(repo_path, &["rev-parse", "HEAD"])?.parse()?;
+        git(repo_path, &["tag", "-d", "loose"])?;
+        git(repo_path, &["update-ref", "refs/pull/1/head", "HEAD~"])?;
+        git(repo_path, &["replace", "--graft", "HEAD"])?;
+        git(repo_path, &["notes", "add", "-m", "note"])?;
+        let notes: ObjectId = git(repo_path, &["rev-parse", "refs/notes/commits"])?.parse()?;
+        assert_eq!(
+            repo.references()?,
+            [
+                reference(ReferenceId::LocalBranch("main".into()), &second),
+                reference(ReferenceId::Tag("nested".into()), &first),
+                reference(ReferenceId::Tag("packed".into()), &first),
+                reference(ReferenceId::Other("refs/notes/commits".into()), &notes),
+                reference(ReferenceId::Other("refs/pull/1/head".into()), &first),
+            ]
+        );
+        assert_eq!(
+            repo.find_reference_commit_id(&ReferenceId::Other("refs/pull/1/head".into()))?,
+            first
+        );
+        assert_eq!(
+            repo.resolve_reference(&ReferenceId::head())?,
+            ReferenceId::LocalBranch("main".into())
+        );
+        assert_eq!(repo.find_reference_commit_id(&ReferenceId::head())?, second);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reftable_repo() -> Result<()> {
+        use crate::git::reftable::encode_table;
+
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path();
+
+        git(repo_path, &["init", "-q", "-b", "main"])?;
+        let first = commit(repo_path, "first")?;
+        git(
+            repo_path,
+            &["commit", "-q", "--allow-empty", "-m", "second"],
+        )?;
+        let second: ObjectId = git(repo_path, &["rev-parse", "HEAD"])?.parse()?;
+
+        // Convert to the layout git init --ref-format=reftable creates. The installed git may
+        // not support reftable, so the tables are written by hand
+        git(repo_path, &["config", "core.repositoryformatversion", "1"])?;
+        git(repo_path, &["config", "extensions.refStorage", "reftable"])?;
+        let git_dir = repo_path.join(".git");
+        fs::remove_dir_all(git_dir.join("refs"))?;
+        fs::create_dir(git_dir.join("refs"))?;
+        fs::write(
+            git_dir.join("refs/heads"),
+            "this repository uses the reftable format\n",
+        )?;
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/.invalid\n")?;
+        fs::create_dir(git_dir.join("reftable"))?;
+
+        let id_value = |id: &ObjectId| {
+            Some(RefValue {
+                target: RefTarget::Object(id.clone()),
+                peeled: None,
+            })
+        };
+        let base = [
+            (
+                "HEAD",
+                Some(RefValue {
+                    target: RefTarget::Symbolic("refs/heads/main".into()),
+                    peeled: None,
+                }),
+            ),
+            ("refs/heads/main", id_value(&second)),
+            ("refs/tags/v1", id_value(&first)),
+        ];
+        fs::write(
+            git_dir.join("reftable/base.ref"),
+            encode_table(&[&base], 4096),
+        )?;
+        fs::write(git_dir.join("reftable/tables.list"), "base.ref\n")?;
+
+        let mut repo = Repo::new(repo_path.to_path_buf(), false)?;
+        assert_eq!(
+            repo.references()?,
+            [
+                Reference {
+                    id: ReferenceId::LocalBranch("main".into()),
+                    head: second.clone(),
+                },
+                Reference {
+                    id: ReferenceId::Tag("v1".into()),
+                    head: first.clone(),
+                },
+            ]
+        );
+        assert_eq!(repo.current_reference_name()?, "refs/heads/main");
+        let head = repo.find_reference_commit_id(&ReferenceId::head())?;
+        assert_eq!(crate::git::rev_parse(&mut repo, "v1")?, first);
+
+        let history = repo
+            .metadata_iter(
+                &[head],
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| Ok(metadata?.id))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(history, [second.clone(), first.clone()]);
+
+        // New tables override older ones
+        let update = [
+            ("refs/heads/feature", id_value(&first)),
+            ("refs/tags/v1", None),
+        ];
+        fs::write(
+            git_dir.join("reftable/update.ref"),
+            encode_table(&[&update], 0),
+        )?;
+        fs::write(
+            git_dir.join("reftable/tables.list"),
+            "base.ref\nupdate.ref\n",
+        )?;
+        assert_eq!(
+            repo.references()?,
+            [
+                Reference {
+                    id: ReferenceId::LocalBranch("feature".into()),
+                    head: first.clone(),
+                },
+                Reference {
+                    id: ReferenceId::LocalBranch("main".into()),
+                    head: second.clone(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reftable_stack_fixture() -> Result<()> {
+        // A stack of three tables as git's reftable writer lays them out: 4096 byte ref blocks
+        // with a restart every 16 records, deflated log blocks after the refs, update index
+        // deltas and a deletion in the newest table
+        const GIT_DIR_TARBALL: &[u8] = include_bytes!("../../../res/test/reftable_stack.tar");
+        let first: ObjectId = "d1ce156c5f62f351ea957f373afc97aef67800f4".parse()?;
+        let second: ObjectId = "748228e0f657e7795788267e116a5e17d19b3436".parse()?;
+
+        let tmp_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(tmp_dir.path())?;
+        let mut repo = Repo::new(tmp_dir.path().to_path_buf(), false)?;
+
+        let mut expected = vec![Reference {
+            id: ReferenceId::LocalBranch("main".into()),
+            head: second.clone(),
+        }];
+        expected.extend((0..20).map(|i| Reference {
+            id: ReferenceId::Tag(format!("bulk-{:02}", i)),
+            head: first.clone(),
+        }));
+        expected.push(Reference {
+            id: ReferenceId::Tag("v1".into()),
+            head: first.clone(),
+        });
+        assert_eq!(repo.references()?, expected);
+        assert_eq!(repo.current_reference_name()?, "refs/heads/main");
+
+        let head = repo.find_reference_commit_id(&ReferenceId::head())?;
+        let history = repo
+            .metadata_iter(
+                &[head],
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| Ok(metadata?.id))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(history, [second, first]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_branch_head() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let git_dir2 = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir2.path())?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&["remote", "add", "origin"])
+            .arg(git_dir2.path())
+            .output()?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&["fetch", "origin"])
+            .output()?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&[
+                "branch",
+                "test_branch",
+                "ce4f6371c0a653f6206e4020704674d63fc8e3d4",
+            ])
+            .output()?;
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+
+        let head =
+            repo.find_reference_commit_id(&ReferenceId::LocalBranch("test_branch".into()))?;
+        assert_eq!(head, "ce4f6371c0a653f6206e4020704674d63fc8e3d4".parse()?);
+
+        let head = repo.find_reference_commit_id(&ReferenceId::head())?;
+        assert_eq!(head, "83fc68fe02d76e37231b8f880bca5f151cb62e39".parse()?);
+
+        let head =
+            repo.find_reference_commit_id(&ReferenceId::RemoteBranch("origin/master".into()))?;
+        assert_eq!(head, "83fc68fe02d76e37231b8f880bca5f151cb62e39".parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_commit() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        let original_head = repo
+            .metadata_iter(
+                &["83fc68fe02d76e37231b8f880bca5f151cb62e39".parse()?],
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .next()
+            .unwrap()?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(git_dir.path())
+            .args(&["commit", "-m", "testing", "--allow-empty"])
+            .output()?;
+
+        let object_id = repo.find_reference_commit_id(&ReferenceId::head())?;
+        let new_head = repo
+            .metadata_iter(
+                &[object_id],
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .next()
+            .unwrap()?;
+
+        assert_ne!(original_head.id, new_head.id);
+        assert_eq!(new_head.parents.len(), 1);
+        assert_eq!(new_head.parents, &[original_head.id]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_cache_reopen() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let collect_history = |repo: &mut Repo, head: &ObjectId| -> Result<Vec<_>> {
+            repo.metadata_iter(
+                std::slice::from_ref(head),
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| {
+                let metadata = metadata?;
+                Ok((metadata.id, metadata.parents, metadata.committer_timestamp))
+            })
+            .collect()
+        };
+
+        let (head, expected) = {
+            let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+            let head = repo.find_reference_commit_id(&ReferenceId::head())?;
+            let history = collect_history(&mut repo, &head)?;
+            (head, history)
+        };
+        assert!(expected.len() > 1);
+
+        // With all objects gone, the history can only come from the cache
+        let objects_dir = git_dir.path().join(".git/objects");
+        for entry in fs::read_dir(&objects_dir)? {
+            let path = entry?.path();
+            if path.file_name() != Some(std::ffi::OsStr::new("info")) {
+                fs::remove_dir_all(path)?;
+            }
+        }
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        assert_eq!(collect_history(&mut repo, &head)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_entry_id() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        let dir = git_dir.path();
+        git(dir, &["init", "-q"])?;
+        fs::create_dir_all(dir.join("dir/sub"))?;
+        fs::write(dir.join("dir/sub/file"), "content")?;
+        fs::write(dir.join("top"), "top")?;
+        git(dir, &["add", "."])?;
+        let head = commit(dir, "files")?;
+
+        let mut repo = Repo::new(dir.to_path_buf(), false)?;
+        for path in ["dir", "dir/sub", "dir/sub/file", "top"] {
+            let expected: ObjectId =
+                git(dir, &["rev-parse", &format!("HEAD:{}", path)])?.parse()?;
+            assert_eq!(
+                repo.path_entry_id(&head, Path::new(path))?,
+                Some(expected),
+                "{}",
+                path
+            );
+        }
+
+        for path in ["missing", "dir/missing", "top/child", "dir/sub/file/child"] {
+            assert_eq!(
+                repo.path_entry_id(&head, Path::new(path))?,
+                None,
+                "{}",
+                path
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_walk() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        git(git_dir.path(), &["init", "-q"])?;
+
+        // Many independent branches give a frontier that is prefetched by many jobs at once
+        let mut stream = String::new();
+        for branch in 0..256 {
+            for generation in 0..3 {
+                stream += &format!(
+                    "commit refs/heads/b{branch}\ncommitter Test <test@example.com> {} +0000\ndata 0\n\n",
+                    1000 + branch * 3 + generation
+                );
+            }
+        }
+        git_with_input(
+            git_dir.path(),
+            &["fast-import", "--quiet"],
+            stream.as_bytes(),
+        )?;
+
+        let mut expected = git(git_dir.path(), &["rev-list", "--all", "--parents"])?
+            .lines()
+            .map(|line| {
+                let mut ids = line.split(' ').map(|id| id.parse::<ObjectId>());
+                let id = ids.next().expect("Empty rev-list line")?;
+                Ok((id, ids.collect::<Result<Vec<_>>>()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        expected.sort();
+        assert_eq!(expected.len(), 256 * 3);
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        let heads = (0..256)
+            .map(|branch| {
+                repo.find_reference_commit_id(&ReferenceId::LocalBranch(format!("b{branch}")))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut walked = repo
+            .metadata_iter(
+                &heads,
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| metadata.map(|metadata| (metadata.id, metadata.parents)))
+            .collect::<Result<Vec<_>>>()?;
+        walked.sort();
+        assert_eq!(walked, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_linear_walk() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        git(git_dir.path(), &["init", "-q", "-b", "main"])?;
+
+        // Long enough that the walk has to start several prefetch jobs one after another
+        let mut stream = String::new();
+        for n in 0..3000 {
+            stream += &format!(
+                "commit refs/heads/main\ncommitter Test <test@example.com> {} +0000\ndata 0\n\n",
+                1000 + n
+            );
+        }
+        git_with_input(
+            git_dir.path(),
+            &["fast-import", "--quiet"],
+            stream.as_bytes(),
+        )?;
+
+        let expected = git(git_dir.path(), &["rev-list", "main"])?
+            .lines()
+            .map(|id| id.parse())
+            .collect::<Result<Vec<ObjectId>>>()?;
+        assert_eq!(expected.len(), 3000);
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        let walked = repo
+            .metadata_iter(
+                &expected[..1],
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| metadata.map(|metadata| metadata.id))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(walked, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_graph_walk() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        git(git_dir.path(), &["init", "-q", "-b", "main"])?;
+
+        // A side branch merged back every 10 commits, with some commits older than their parents
+        let mut stream = String::new();
+        for n in 1..=200 {
+            if n % 10 == 0 {
+                stream += &format!(
+                    "commit refs/heads/side\nmark :{}\ncommitter Test <test@example.com> {} +0000\ndata 0\nfrom :{}\n",
+                    1000 + n,
+                    1000 + n * 10 - 3,
+                    n - 5
+                );
+            }
+            let timestamp = match n % 7 {
+                0 => 1000 + n * 10 - 25,
+                _ => 1000 + n * 10,
+            };
+            stream += &format!(
+                "commit refs/heads/main\nmark :{n}\ncommitter Test <test@example.com> {timestamp} +0000\ndata 0\n"
+            );
+            if n > 1 {
+                stream += &format!("from :{}\n", n - 1);
+            }
+            if n % 10 == 0 {
+                stream += &format!("merge :{}\n", 1000 + n);
+            }
+        }
+        git_with_input(
+            git_dir.path(),
+            &["fast-import", "--quiet"],
+            stream.as_bytes(),
+        )?;
+
+        let expected = git(
+            git_dir.path(),
+            &["rev-list", "--date-order", "main", "side"],
+        )?
+        .lines()
+        .map(|id| id.parse())
+        .collect::<Result<Vec<ObjectId>>>()?;
+        assert_eq!(expected.len(), 220);
+
+        let walk = |repo: &mut Repo| -> Result<Vec<ObjectId>> {
+            let heads = [
+                repo.find_reference_commit_id(&ReferenceId::LocalBranch("main".to_string()))?,
+                repo.find_reference_commit_id(&ReferenceId::LocalBranch("side".to_string()))?,
+            ];
+            repo.metadata_iter(
+                &heads,
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| Ok(metadata?.id))
+            .collect()
+        };
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        assert_eq!(walk(&mut repo)?, expected);
+
+        // Without generation numbers nothing can be emitted before the walk reaches the root
+        let cancel_token = CancellationToken::default();
+        let mut iter = repo.metadata_iter(
+            &[expected[0].clone()],
+            SortType::CommitterTimestamp,
+            &cancel_token,
+        )?;
+        assert_eq!(
+            iter.next().transpose()?.map(|metadata| metadata.id),
+            Some(expected[0].clone())
+        );
+        assert_eq!(iter.nodes.len(), expected.len());
+
+        git(git_dir.path(), &["commit-graph", "write", "--reachable"])?;
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        assert_eq!(walk(&mut repo)?, expected);
+
+        // With generation numbers the first rows do not need the rest of the history
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        let cancel_token = CancellationToken::default();
+        let mut iter = repo.metadata_iter(
+            &[expected[0].clone()],
+            SortType::CommitterTimestamp,
+            &cancel_token,
+        )?;
+        assert_eq!(
+            iter.next().transpose()?.map(|metadata| metadata.id),
+            Some(expected[0].clone())
+        );
+        assert!(iter.nodes.len() < 10, "walked {} commits", iter.nodes.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha256_repo() -> Result<()> {
+        let git_dir = TempDir::new()?;
+        git(git_dir.path(), &["init", "-q", "--object-format=sha256"])?;
+        git(
+            git_dir.path(),
+            &["commit", "-q", "--allow-empty", "-m", "root"],
+        )?;
+        git(git_dir.path(), &["checkout", "-q", "-b", "side"])?;
+        git(
+            git_dir.path(),
+            &["commit", "-q", "--allow-empty", "-m", "side"],
+        )?;
+        git(git_dir.path(), &["checkout", "-q", "-"])?;
+        git(
+            git_dir.path(),
+            &["commit", "-q", "--allow-empty", "-m", "main"],
+        )?;
+        git(
+            git_dir.path(),
+            &["merge", "-q", "--no-ff", "-m", "merge", "side"],
+        )?;
+
+        let mut expected = git(git_dir.path(), &["rev-list", "--parents", "HEAD"])?
+            .lines()
+            .map(|line| {
+                let mut ids = line.split(' ').map(|id| id.parse::<ObjectId>());
+                let id = ids.next().expect("Empty rev-list line")?;
+                Ok((id, ids.collect::<Result<Vec<_>>>()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        expected.sort();
+        assert_eq!(expected.len(), 4);
+        let head = git(git_dir.path(), &["rev-parse", "HEAD"])?
+            .trim()
+            .parse::<ObjectId>()?;
+
+        let walk = || -> Result<Vec<(ObjectId, Vec<ObjectId>)>> {
+            let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+            assert_eq!(repo.object_format(), ObjectFormat::Sha256);
+            let mut walked = repo
+                .metadata_iter(
+                    std::slice::from_ref(&head),
+                    SortType::CommitterTimestamp,
+                    &CancellationToken::default(),
+                )?
+                .map(|metadata| metadata.map(|metadata| (metadata.id, metadata.parents)))
+                .collect::<Result<Vec<_>>>()?;
+            walked.sort();
+            Ok(walked)
+        };
+
+        // Loose objects
+        assert_eq!(walk()?, expected);
+
+        // Packed objects, without the metadata cache from the previous walk
+        fs::remove_dir_all(git_dir.path().join(".git/spit"))?;
+        git(git_dir.path(), &["gc", "-q"])?;
+        assert_eq!(walk()?, expected);
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+        let short_id = repo.shortest_unique_prefix(&head)?;
+        assert!(head.to_string().starts_with(&short_id));
+        assert_eq!(
+            repo.find_objects_with_prefix(&short_id.parse()?)?,
+            BTreeSet::from([head])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_alternates() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(tmp_dir.path().join("source"))?;
+
+        // A chain of shared clones, each with a loose commit of its own, and a worktree of the
+        // last one
+        git(
+            tmp_dir.path(),
+            &["clone", "-q", "--shared", "source", "middle"],
+        )?;
+        git(
+            &tmp_dir.path().join("middle"),
+            &["commit", "-q", "--allow-empty", "-m", "middle"],
+        )?;
+        git(
+            tmp_dir.path(),
+            &["clone", "-q", "--shared", "middle", "leaf"],
+        )?;
+        git(
+            &tmp_dir.path().join("leaf"),
+            &["commit", "-q", "--allow-empty", "-m", "leaf"],
+        )?;
+        git(
+            &tmp_dir.path().join("leaf"),
+            &["worktree", "add", "-q", "--detach", "../worktree"],
+        )?;
+
+        let expected_len = git(
+            &tmp_dir.path().join("leaf"),
+            &["rev-list", "--count", "HEAD"],
+        )?
+        .trim()
+        .parse::<usize>()?;
+
+        for dir in ["leaf", "worktree"] {
+            let mut repo = Repo::new(tmp_dir.path().join(dir), false)?;
+            let head = repo.find_reference_commit_id(&ReferenceId::head())?;
+            let walked = repo
+                .metadata_iter(
+                    &[head],
+                    SortType::CommitterTimestamp,
+                    &CancellationToken::default(),
+                )?
+                .count();
+            assert_eq!(walked, expected_len, "{}", dir);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_clone() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+
+        let source_path = tmp_dir.path().join("source");
+        git(tmp_dir.path(), &["init", "-q", "source"])?;
+        git(
+            tmp_dir.path(),
+            &["-C", "source", "config", "uploadpack.allowfilter", "true"],
+        )?;
+        fs::write(source_path.join("file.txt"), "old\n")?;
+        git(tmp_dir.path(), &["-C", "source", "add", "file.txt"])?;
+        git(
+            tmp_dir.path(),
+            &["-C", "source", "commit", "-q", "-m", "first"],
+        )?;
+        fs::write(source_path.join("file.txt"), "new\n")?;
+        git(
+            tmp_dir.path(),
+            &["-C", "source", "commit", "-q", "-a", "-m", "second"],
+        )?;
+
+        let source_url = format!("file://{}", source_path.display());
+        git(
+            tmp_dir.path(),
+            &[
+                "clone",
+                "-q",
+                "--no-checkout",
+                "--filter=blob:none",
+                &source_url,
+                "partial",
+            ],
+        )?;
+
+        let repo = Repo::new(tmp_dir.path().join("partial"), false)?;
+        assert!(matches!(&repo.promisor, Some(Promisor::Remote(remote)) if remote == "origin"));
+
+        let old_blob: ObjectId = git(
+            tmp_dir.path(),
+            &["-C", "source", "rev-parse", "HEAD~:file.txt"],
+        )?
+        .trim()
+        .parse()?;
+        let new_blob: ObjectId = git(
+            tmp_dir.path(),
+            &["-C", "source", "rev-parse", "HEAD:file.txt"],
+        )?
+        .trim()
+        .parse()?;
+        let first: ObjectId = git(tmp_dir.path(), &["-C", "source", "rev-parse", "HEAD~"])?
+            .trim()
+            .parse()?;
+        let second: ObjectId = git(tmp_dir.path(), &["-C", "source", "rev-parse", "HEAD"])?
+            .trim()
+            .parse()?;
+        let modified_files = repo.modified_files(&first, &second)?;
+
+        // Nothing is diffed against missing content, the file is listed as unfetched instead
+        assert!(modified_files.labels.is_empty());
+        assert!(modified_files.files_a.is_empty());
+        assert!(modified_files.files_b.is_empty());
+        assert_eq!(
+            modified_files.unfetched,
+            [UnfetchedFile {
+                path: "file.txt".to_string(),
+                remote: Some("origin".to_string()),
+                fetch_command: Some(format!(
+                    "{} && {}",
+                    commandline::fetch_missing_object("origin", &old_blob),
+                    commandline::fetch_missing_object("origin", &new_blob)
+                )),
+            }]
+        );
+
+        // Without a configured remote there is no fetch command to suggest
+        let partial_path = tmp_dir.path().join("partial");
+        git(
+            &partial_path,
+            &["config", "--unset", "remote.origin.promisor"],
+        )?;
+        // Only set by some versions of git, unsetting fails if it is not
+        let _ = git(
+            &partial_path,
+            &["config", "--unset", "extensions.partialClone"],
+        );
+        let repo = Repo::new(partial_path, false)?;
+        assert!(matches!(repo.promisor, Some(Promisor::Unknown)));
+        assert_eq!(
+            repo.modified_files(&first, &second)?.unfetched,
+            [UnfetchedFile {
+                path: "file.txt".to_string(),
+                remote: None,
+                fetch_command: None,
+            }]
+        );
+
+        // Missing objects in complete clones are still errors
+        let old_blob_s = old_blob.to_string();
+        fs::remove_file(
+            source_path
+                .join(".git/objects")
+                .join(&old_blob_s[..2])
+                .join(&old_blob_s[2..]),
+        )?;
+        let repo = Repo::new(source_path, false)?;
+        assert!(repo.promisor.is_none());
+        assert!(repo.modified_files(&first, &second).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refdelta_pack() -> Result<()> {
+        const GIT_DIR_TARBALL: &[u8] =
+            include_bytes!("../../../res/test/two_commits_ref_delta_pack.tar");
+
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let mut repo = Repo::new(git_dir.path().to_path_buf(), false)?;
+
+        let it = repo.metadata_iter(
+            &["a0dc968acca0ab483897a600b50e7b372960a509".parse()?],
+            SortType::CommitterTimestamp,
+            &CancellationToken::default(),
+        )?;
+
+        let commits = it.collect::<Result<Vec<_>>>()?;
+        assert_eq!(commits.len(), 2);
+        assert_eq!(
+            commits[0].id,
+            "a0dc968acca0ab483897a600b50e7b372960a509".parse()?
+        );
+        assert_eq!(
+            commits[1].id,
+            "7686bd4e339afa6ef86c5638049c75e19e5a8943".parse()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_modified_files() -> Result<()> {
+        const GIT_DIR_TARBALL: &[u8] = include_bytes!("../../../res/test/modified_file_test.tar");
+
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let repo = Repo::new(git_dir.path().to_path_buf().join("repo"), false)?;
+
+        let modified_files = repo.modified_files(
+            &"491819c1d0e44904c905d9daac719a2eb990a5f1".parse()?,
+            &"25fa40a48f04500736c199e1b0448ca3bf2c7e52".parse()?,
+        )?;
+
+        assert_eq!(modified_files.labels.len(), 4);
+        assert!(modified_files.labels.contains(&"test.txt".to_string()));
+        assert!(modified_files.labels.contains(&"test2.txt".to_string()));
+        assert!(modified_files
+            .labels
+            .contains(&"test_binary_file".to_string()));
+        assert!(modified_files.labels.contains(&"submodule".to_string()));
+
+        let compare_content_a = |file_name, content| {
+            let position = modified_files
+                .labels
+                .iter()
+                .position(|x| x == file_name)
+                .unwrap();
+            assert_eq!(modified_files.files_a[position], content);
+        };
+
+        compare_content_a("test.txt", Some("Test text file\n".as_bytes().to_vec()));
+        compare_content_a("test2.txt", None);
+        compare_content_a(
+            "submodule",
+            Some(
+                "Subproject commit 73f89df6a3c049523eafd798092b1aaf60944ac2"
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        );
+
+        let compare_content_b = |file_name, content| {
+            let position = modified_files
+                .labels
+                .iter()
+                .position(|x| x == file_name)
+                .unwrap();
+            assert_eq!(modified_files.files_b[position], content);
+        };
+
+        compare_content_b("test.txt", None);
+        compare_content_b(
+            "test2.txt",
+            Some("Modified test text file\n".as_bytes().to_vec()),
+        );
+        compare_content_b(
+            "submodule",
+            Some(
+                "Subproject commit f4df290482ada1325c22e1907cb2bf1e7819afa7"
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        );
+
+        // No point in testing random binary data
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_reload() -> Result<()> {
+        // If we fetch more commits than fetch.unpackLimit, we may end up in a situation where
+        // metadata is not in our metadata cache, but goes directly to a new packfile that we have
+        // not parsed yet. Previously this resulted in unfindable metadata because we only looked
+        // for packfiles when we opened the git dir
+
+        const GIT_DIR_TARBALL: &[u8] = include_bytes!("../../../res/test/test_pack_reload.tar");
+
+        let git_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(git_dir.path())?;
+
+        let git_dir = git_dir.path().to_path_buf().join("child_repo");
+
+        let mut repo = Repo::new(git_dir.clone(), false)?;
+
+        Command::new("git")
+            .current_dir(&git_dir)
+            .arg("fetch")
+            .arg("--all")
+            .output()?;
+
+        let origin_master =
+            repo.find_reference_commit_id(&ReferenceId::RemoteBranch("origin/master".to_string()))?;
+
+        let commits = repo
+            .metadata_iter(
+                &[origin_master],
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(commits.len(), 7);
+        let first_commit = commits[0].clone();
+        repo.get_commit(&first_commit.id)?;
+
+        Ok(())
+    }
+}
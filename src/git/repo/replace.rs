@@ -0,0 +1,216 @@
+use crate::git::{
+    ref_store::{RefStore, RefTarget},
+    ObjectId, Repo,
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    ops::Bound,
+    path::Path,
+};
+
+impl Repo {
+    /// Commits whose history is rewritten by an applied replace ref or a graft
+    pub(crate) fn replaced_commits(&mut self) -> Result<HashSet<ObjectId>> {
+        self.refresh_replacements()?;
+        Ok(self
+            .replacements
+            .keys()
+            .chain(self.grafts.keys())
+            .cloned()
+            .collect())
+    }
+
+    pub(crate) fn replace_refs_enabled(&self) -> bool {
+        self.use_replace_refs
+    }
+
+    /// Toggle replace refs, equivalent to git's --no-replace-objects when disabled
+    pub(crate) fn set_replace_refs_enabled(&mut self, enabled: bool) -> Result<()> {
+        self.use_replace_refs = enabled;
+        self.refresh_replacements()
+    }
+
+    /// Pick up changes to replace refs and grafts
+    pub(super) fn refresh_replacements(&mut self) -> Result<()> {
+        let replacements = if self.use_replace_refs {
+            self.refresh_references()?;
+            read_replace_refs(&self.ref_store)
+        } else {
+            HashMap::new()
+        };
+        let grafts = read_grafts(&self.common_dir)?;
+
+        // Like with shallow boundaries, affected commits are parsed again on the next lookup
+        let changed = changed_keys(&self.replacements, &replacements)
+            .chain(changed_keys(&self.grafts, &grafts))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            self.clear_history_caches();
+        }
+        for id in changed {
+            self.metadata_lookup.remove(&id);
+            self.authors.remove(&id);
+        }
+        self.replacements = replacements;
+        self.grafts = grafts;
+
+        Ok(())
+    }
+
+    /// Commit that provides the content of the given commit
+    pub(super) fn replacement<'a>(&'a self, id: &'a ObjectId) -> &'a ObjectId {
+        self.replacements.get(id).unwrap_or(id)
+    }
+}
+
+/// Replacements from refs/replace/*, replaced object -> replacement
+fn read_replace_refs(ref_store: &RefStore) -> HashMap<ObjectId, ObjectId> {
+    let mut ret = HashMap::new();
+    // Refs are sorted by name, so the replace refs are next to each other
+    for (name, value) in ref_store
+        .refs()
+        .range::<str, _>((Bound::Included("refs/replace/"), Bound::Unbounded))
+    {
+        let Some(replaced) = name.strip_prefix("refs/replace/") else {
+            break;
+        };
+        let RefTarget::Object(target) = &value.target else {
+            warn!("Ignoring symbolic replace ref {}", name);
+            continue;
+        };
+
+        match replaced.parse() {
+            Ok(replaced) => {
+                ret.insert(replaced, target.clone());
+            }
+            Err(_) => warn!("Ignoring replace ref with invalid name {}", name),
+        }
+    }
+
+    ret
+}
+
+/// Parent overrides from the deprecated info/grafts file. Each line is a commit followed by its
+/// new parents
+pub(super) fn read_grafts(common_dir: &Path) -> Result<HashMap<ObjectId, Vec<ObjectId>>> {
+    let content = match fs::read_to_string(common_dir.join("info/grafts")) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e).context("Failed to read grafts file"),
+    };
+
+    content
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut ids = line
+                .split_whitespace()
+                .map(|id| id.parse().context("Invalid id in grafts file"));
+            let id = ids.next().context("Empty graft")??;
+            Ok((id, ids.collect::<Result<_>>()?))
+        })
+        .collect()
+}
+
+/// Keys with a different value, or missing, in one of the maps
+fn changed_keys<'a, V: PartialEq>(
+    a: &'a HashMap<ObjectId, V>,
+    b: &'a HashMap<ObjectId, V>,
+) -> impl Iterator<Item = &'a ObjectId> {
+    a.iter()
+        .filter(|(id, v)| b.get(id) != Some(v))
+        .chain(b.iter().filter(|(id, _)| !a.contains_key(id)))
+        .map(|(id, _)| id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        git::{
+            test_util::{commit, git},
+            SortType,
+        },
+        util::CancellationToken,
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_replace_refs_and_grafts() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let repo_path = tmp_dir.path().join("repo");
+
+        // Old history imported separately from the new history, then stitched together
+        fs::create_dir(&repo_path)?;
+        git(&repo_path, &["init", "-q"])?;
+        let old_root = commit(&repo_path, "old root")?;
+        let old_tip = commit(&repo_path, "old tip")?;
+        git(&repo_path, &["checkout", "-q", "--orphan", "new"])?;
+        let new_root = commit(&repo_path, "new root")?;
+        let new_tip = commit(&repo_path, "new tip")?;
+        git(
+            &repo_path,
+            &[
+                "replace",
+                "--graft",
+                &new_root.to_string(),
+                &old_tip.to_string(),
+            ],
+        )?;
+
+        let mut repo = Repo::new(repo_path.clone(), false)?;
+        let walk = |repo: &mut Repo| -> Result<Vec<ObjectId>> {
+            repo.metadata_iter(
+                std::slice::from_ref(&new_tip),
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .map(|metadata| Ok(metadata?.id))
+            .collect()
+        };
+
+        assert!(repo.replace_refs_enabled());
+        assert_eq!(
+            walk(&mut repo)?,
+            [
+                new_tip.clone(),
+                new_root.clone(),
+                old_tip.clone(),
+                old_root.clone()
+            ]
+        );
+        assert_eq!(repo.replaced_commits()?, HashSet::from([new_root.clone()]));
+        assert_eq!(repo.get_commit(&new_root)?.message.trim(), "new root");
+
+        repo.set_replace_refs_enabled(false)?;
+        assert_eq!(walk(&mut repo)?, [new_tip.clone(), new_root.clone()]);
+        assert!(repo.replaced_commits()?.is_empty());
+
+        // Grafts apply regardless of replace refs, and changes are picked up by an open repo
+        fs::write(
+            repo_path.join(".git/info/grafts"),
+            format!("# comment\n{} {}\n", new_tip, old_root),
+        )?;
+        assert_eq!(walk(&mut repo)?, [new_tip.clone(), old_root.clone()]);
+        assert_eq!(repo.replaced_commits()?, HashSet::from([new_tip.clone()]));
+
+        // Packed replace refs are read from packed-refs like any other ref
+        fs::remove_file(repo_path.join(".git/info/grafts"))?;
+        git(&repo_path, &["pack-refs", "--all"])?;
+        assert!(!repo_path
+            .join(".git/refs/replace")
+            .join(new_root.to_string())
+            .exists());
+        repo.set_replace_refs_enabled(true)?;
+        assert_eq!(repo.replaced_commits()?, HashSet::from([new_root.clone()]));
+        assert_eq!(walk(&mut repo)?.len(), 4);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::git::{ObjectId, Repo};
+
+use anyhow::{Context, Result};
+
+use std::{collections::HashSet, fs, path::Path};
+
+impl Repo {
+    /// Commits at which a shallow clone's history ends
+    pub(crate) fn shallow_commits(&mut self) -> Result<&HashSet<ObjectId>> {
+        self.refresh_shallow()?;
+        Ok(&self.shallow)
+    }
+
+    /// Pick up changes to the shallow file, e.g. after a fetch with --deepen
+    pub(super) fn refresh_shallow(&mut self) -> Result<()> {
+        let shallow = read_shallow(&self.common_dir)?;
+        if shallow == self.shallow {
+            return Ok(());
+        }
+
+        // Commits that became or stopped being boundaries have to be parsed again to get the right
+        // parents. Their old storage entries are left behind unreferenced, this only happens
+        // when history is deepened so it is not worth compacting the storage for
+        for id in shallow.symmetric_difference(&self.shallow) {
+            self.metadata_lookup.remove(id);
+        }
+        self.shallow = shallow;
+        self.clear_history_caches();
+
+        Ok(())
+    }
+}
+
+pub(super) fn read_shallow(common_dir: &Path) -> Result<HashSet<ObjectId>> {
+    let content = match fs::read_to_string(common_dir.join("shallow")) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).context("Failed to read shallow file"),
+    };
+
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse().context("Invalid id in shallow file"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        git::{test_util::git, CommitMetadata, ReferenceId, SortType},
+        util::CancellationToken,
+    };
+    use tempfile::TempDir;
+
+    const GIT_DIR_TARBALL: &[u8] =
+        include_bytes!("../../../res/test/multi_obj_multi_pack_octopus_merge.tar");
+
+    #[test]
+    fn test_shallow() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        tar::Archive::new(GIT_DIR_TARBALL).unpack(tmp_dir.path().join("source"))?;
+
+        let source_url = format!("file://{}", tmp_dir.path().join("source").display());
+        git(
+            tmp_dir.path(),
+            &["clone", "-q", "--depth=2", &source_url, "shallow"],
+        )?;
+
+        let shallow_path = tmp_dir.path().join("shallow");
+        let mut repo = Repo::new(shallow_path.clone(), false)?;
+        let head = repo.find_reference_commit_id(&ReferenceId::head())?;
+
+        let walk = |repo: &mut Repo| -> Result<Vec<CommitMetadata>> {
+            repo.metadata_iter(
+                std::slice::from_ref(&head),
+                SortType::CommitterTimestamp,
+                &CancellationToken::default(),
+            )?
+            .collect()
+        };
+
+        let boundary = fs::read_to_string(shallow_path.join(".git/shallow"))?
+            .lines()
+            .map(|line| line.parse())
+            .collect::<Result<HashSet<ObjectId>>>()?;
+        assert_eq!(repo.shallow_commits()?, &boundary);
+
+        let history = walk(&mut repo)?;
+        let expected_len = git(
+            tmp_dir.path(),
+            &["-C", "shallow", "rev-list", "--count", "HEAD"],
+        )?;
+        assert_eq!(history.len(), expected_len.trim().parse::<usize>()?);
+        for metadata in &history {
+            assert_eq!(
+                boundary.contains(&metadata.id),
+                metadata.parents.is_empty(),
+                "{}",
+                metadata.id
+            );
+        }
+
+        // Deepening is picked up by an already open repo
+        git(
+            tmp_dir.path(),
+            &["-C", "shallow", "fetch", "-q", "--unshallow"],
+        )?;
+        assert!(repo.shallow_commits()?.is_empty());
+        let history = walk(&mut repo)?;
+        let expected_len = git(
+            tmp_dir.path(),
+            &["-C", "source", "rev-list", "--count", "HEAD"],
+        )?;
+        assert_eq!(history.len(), expected_len.trim().parse::<usize>()?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,372 @@
+use crate::git::{object_id::ObjectIdPrefix, ObjectId, Repo};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ReflogSelector {
+    /// `@{upstream}` or `@{u}`
+    Upstream,
+    /// `@{n}`
+    Entry(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Navigation {
+    /// `~n`, follow the first parent n times
+    Ancestor(usize),
+    /// `^n`, select the nth parent. `^0` is the commit itself
+    Parent(usize),
+}
+
+/// Parsed form of a rev-parse style expression, e.g. `main@{u}~2^2`
+#[derive(Debug, PartialEq, Eq)]
+struct Revision {
+    /// Reference name or (abbreviated) object id. Empty when only a reflog selector is given,
+    /// which refers to the current branch
+    name: String,
+    reflog: Option<ReflogSelector>,
+    navigation: Vec<Navigation>,
+}
+
+impl FromStr for Revision {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name_end = [s.find('~'), s.find('^'), s.find("@{")]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(s.len());
+
+        let (name, mut rest) = s.split_at(name_end);
+        let name = if name == "@" { "HEAD" } else { name };
+
+        let mut reflog = None;
+        if let Some(selector_start) = rest.strip_prefix("@{") {
+            let selector_end = selector_start
+                .find('}')
+                .ok_or_else(|| Error::msg("Unterminated @{"))?;
+            let selector = &selector_start[..selector_end];
+            reflog = Some(match selector {
+                "u" | "upstream" => ReflogSelector::Upstream,
+                _ => ReflogSelector::Entry(
+                    selector
+                        .parse()
+                        .with_context(|| format!("Unsupported selector @{{{}}}", selector))?,
+                ),
+            });
+            rest = &selector_start[selector_end + 1..];
+        }
+
+        if name.is_empty() && reflog.is_none() {
+            bail!("Missing revision name");
+        }
+
+        let mut navigation = Vec::new();
+        while !rest.is_empty() {
+            let op = rest.as_bytes()[0];
+            rest = &rest[1..];
+
+            if op == b'^' && rest.starts_with('{') {
+                // Peel operators. Everything is peeled to a commit anyways, so only accept the
+                // ones that are a no-op for us
+                let peel_end = rest
+                    .find('}')
+                    .ok_or_else(|| Error::msg("Unterminated ^{"))?;
+                let peel = &rest[1..peel_end];
+                if !peel.is_empty() && peel != "commit" {
+                    bail!("Unsupported peel ^{{{}}}", peel);
+                }
+                rest = &rest[peel_end + 1..];
+                continue;
+            }
+
+            let num_end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let n = match &rest[..num_end] {
+                "" => 1,
+                n => n.parse().context("Invalid revision count")?,
+            };
+            rest = &rest[num_end..];
+
+            navigation.push(match op {
+                b'~' => Navigation::Ancestor(n),
+                b'^' => Navigation::Parent(n),
+                _ => bail!("Unexpected character {} in revision", op as char),
+            });
+        }
+
+        Ok(Revision {
+            name: name.to_string(),
+            reflog,
+            navigation,
+        })
+    }
+}
+
+/// Resolve a rev-parse style expression to a commit. Supports full and abbreviated object ids,
+/// reference names, `@{upstream}`, `@{n}`, `~n` and `^n`
+pub(crate) fn rev_parse(repo: &mut Repo, s: &str) -> Result<ObjectId> {
+    let revision: Revision = s
+        .trim()
+        .parse()
+        .with_context(|| format!("Failed to parse revision {}", s))?;
+
+    let mut id = match &revision.reflog {
+        Some(selector) => resolve_reflog_selector(repo, &revision.name, selector)?,
+        None => resolve_name(repo, &revision.name)?,
+    };
+
+    for navigation in &revision.navigation {
+        id = match navigation {
+            Navigation::Ancestor(n) => {
+                for _ in 0..*n {
+                    let metadata = repo.get_commit_metadata(&id)?;
+                    id = match metadata.parents.first() {
+                        Some(parent) => parent.clone(),
+                        None => bail!("{} has no parent", id),
+                    };
+                }
+                id
+            }
+            Navigation::Parent(0) => id,
+            Navigation::Parent(n) => {
+                let metadata = repo.get_commit_metadata(&id)?;
+                match metadata.parents.get(n - 1) {
+                    Some(parent) => parent.clone(),
+                    None => bail!("{} does not have {} parents", id, n),
+                }
+            }
+        };
+    }
+
+    Ok(id)
+}
+
+fn resolve_reflog_selector(
+    repo: &mut Repo,
+    name: &str,
+    selector: &ReflogSelector,
+) -> Result<ObjectId> {
+    // HEAD has no upstream of its own, HEAD@{u} is the upstream of the checked out branch. Its
+    // reflog is separate from the branch's though, so HEAD@{n} stays as is
+    let upstream_of_head = name == "HEAD" && *selector == ReflogSelector::Upstream;
+    let ref_name = if name.is_empty() || upstream_of_head {
+        repo.current_reference_name()?
+    } else {
+        repo.expand_reference_name(name)
+            .ok_or_else(|| anyhow!("Unknown reference {}", name))?
+    };
+
+    match selector {
+        ReflogSelector::Upstream => {
+            let upstream = repo.upstream_reference_name(&ref_name)?;
+            repo.find_reference_name_commit_id(&upstream)
+        }
+        ReflogSelector::Entry(n) => repo.reflog_entry(&ref_name, *n),
+    }
+}
+
+fn resolve_name(repo: &mut Repo, name: &str) -> Result<ObjectId> {
+    // Like git, full hashes win over references, but references win over abbreviated hashes
+    if name.len() == repo.object_format().hex_len() {
+        if let Ok(id) = ObjectId::from_str(name) {
+            return repo
+                .peel_to_commit(&id)?
+                .ok_or_else(|| anyhow!("{} is not a commit", id));
+        }
+    }
+
+    if let Some(ref_name) = repo.expand_reference_name(name) {
+        return repo.find_reference_name_commit_id(&ref_name);
+    }
+
+    let prefix = match ObjectIdPrefix::from_str(&name.to_ascii_lowercase()) {
+        Ok(v) => v,
+        Err(_) => bail!("Unknown revision {}", name),
+    };
+
+    let mut commits = Vec::new();
+    for id in repo.find_objects_with_prefix(&prefix)? {
+        if let Some(commit) = repo.peel_to_commit(&id)? {
+            commits.push(commit);
+        }
+    }
+
+    match commits.len() {
+        0 => bail!("Unknown revision {}", name),
+        1 => Ok(commits.pop().expect("Commit list empty")),
+        _ => bail!("Short object ID {} is ambiguous", name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::git::test_util::git;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(
+            "main".parse::<Revision>()?,
+            Revision {
+                name: "main".into(),
+                reflog: None,
+                navigation: vec![],
+            }
+        );
+
+        assert_eq!(
+            "origin/main@{u}~2^2^^0^{commit}".parse::<Revision>()?,
+            Revision {
+                name: "origin/main".into(),
+                reflog: Some(ReflogSelector::Upstream),
+                navigation: vec![
+                    Navigation::Ancestor(2),
+                    Navigation::Parent(2),
+                    Navigation::Parent(1),
+                    Navigation::Parent(0),
+                ],
+            }
+        );
+
+        assert_eq!(
+            "@~".parse::<Revision>()?,
+            Revision {
+                name: "HEAD".into(),
+                reflog: None,
+                navigation: vec![Navigation::Ancestor(1)],
+            }
+        );
+
+        assert_eq!(
+            "@{12}".parse::<Revision>()?,
+            Revision {
+                name: "".into(),
+                reflog: Some(ReflogSelector::Entry(12)),
+                navigation: vec![],
+            }
+        );
+
+        assert!("".parse::<Revision>().is_err());
+        assert!("~2".parse::<Revision>().is_err());
+        assert!("main@{yesterday}".parse::<Revision>().is_err());
+        assert!("main@{u".parse::<Revision>().is_err());
+        assert!("main^{tree}".parse::<Revision>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rev_parse() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let dir = tmp_dir.path();
+
+        git(dir, &["init", "-b", "main"])?;
+        for i in 0..3 {
+            git(
+                dir,
+                &["commit", "--allow-empty", "-m", &format!("main {}", i)],
+            )?;
+        }
+        git(dir, &["checkout", "-b", "topic", "HEAD~1"])?;
+        git(dir, &["commit", "--allow-empty", "-m", "topic"])?;
+        git(dir, &["branch", "--set-upstream-to", "main"])?;
+        git(dir, &["merge", "--no-ff", "-m", "merge", "main"])?;
+        // Pack everything that exists so far so that both packed and loose objects are looked up
+        git(dir, &["gc", "--quiet"])?;
+        git(dir, &["commit", "--allow-empty", "-m", "loose"])?;
+        let short_packed = git(dir, &["rev-parse", "--short=7", "HEAD~1"])?;
+        let short_loose = git(dir, &["rev-parse", "--short=7", "HEAD"])?;
+
+        let mut repo = Repo::new(dir.to_path_buf(), false)?;
+
+        for expression in [
+            "HEAD",
+            "@",
+            "main",
+            "refs/heads/main",
+            "topic~2",
+            "HEAD~1^2",
+            "HEAD~1^2~1",
+            "HEAD^^",
+            "HEAD^0",
+            "@{u}",
+            "topic@{upstream}~1",
+            "HEAD@{u}",
+            "HEAD@{upstream}~1",
+            "HEAD@{1}",
+            "@{2}",
+            &short_packed,
+            &format!("{}^2", short_packed),
+            &format!("{}^", short_loose),
+        ] {
+            let expected = git(dir, &["rev-parse", "--verify", expression])?;
+            assert_eq!(
+                rev_parse(&mut repo, expression)?.to_string(),
+                expected,
+                "{}",
+                expression
+            );
+        }
+
+        assert!(rev_parse(&mut repo, "main~10").is_err());
+        assert!(rev_parse(&mut repo, "HEAD^3").is_err());
+        assert!(rev_parse(&mut repo, "does-not-exist").is_err());
+        assert!(rev_parse(&mut repo, "main@{u}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rev_parse_sha256() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let dir = tmp_dir.path();
+
+        git(dir, &["init", "--object-format=sha256", "-b", "main"])?;
+        for i in 0..2 {
+            git(
+                dir,
+                &["commit", "--allow-empty", "-m", &format!("main {}", i)],
+            )?;
+        }
+        git(dir, &["tag", "-a", "-m", "tag", "v1", "HEAD~1"])?;
+        git(dir, &["checkout", "-b", "topic"])?;
+        git(dir, &["branch", "--set-upstream-to", "main"])?;
+        git(dir, &["commit", "--allow-empty", "-m", "topic"])?;
+        let head = git(dir, &["rev-parse", "HEAD"])?;
+        let tag_object = git(dir, &["rev-parse", "v1"])?;
+
+        let mut repo = Repo::new(dir.to_path_buf(), false)?;
+
+        for expression in [
+            "HEAD",
+            "v1",
+            "topic~1",
+            "@{u}",
+            "HEAD@{1}",
+            head.as_str(),
+            &format!("{}^", head),
+            tag_object.as_str(),
+            &head[..10],
+        ] {
+            let expected = git(
+                dir,
+                &["rev-parse", "--verify", &format!("{expression}^{{commit}}")],
+            )?;
+            assert_eq!(
+                rev_parse(&mut repo, expression)?.to_string(),
+                expected,
+                "{}",
+                expression
+            );
+        }
+
+        Ok(())
+    }
+}
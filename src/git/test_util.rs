@@ -0,0 +1,69 @@
+//! Helpers for tests that set up repos with the git command line
+
+use crate::git::ObjectId;
+
+use anyhow::{bail, Context, Result};
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+fn git_command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new("git");
+    command
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=spit", "-c", "user.email=spit@example.com"])
+        .args(args);
+    command
+}
+
+fn check_output(args: &[&str], output: std::process::Output) -> Result<String> {
+    if !output.status.success() {
+        bail!(
+            "git {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Run git in dir, returns its trimmed output
+pub(crate) fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    check_output(args, git_command(dir, args).output()?)
+}
+
+/// Run git in dir with input written to its stdin, e.g. for fast-import or hash-object --stdin
+pub(crate) fn git_with_input(dir: &Path, args: &[&str], input: &[u8]) -> Result<String> {
+    let mut child = git_command(dir, args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().context("No stdin")?.write_all(input)?;
+
+    check_output(args, child.wait_with_output()?)
+}
+
+/// Create an empty commit on top of HEAD
+pub(crate) fn commit(dir: &Path, message: &str) -> Result<ObjectId> {
+    git(dir, &["commit", "-q", "--allow-empty", "-m", message])?;
+    git(dir, &["rev-parse", "HEAD"])?.parse()
+}
+
+/// Create an empty commit on top of HEAD, authored and committed at timestamp
+pub(crate) fn commit_at(dir: &Path, message: &str, timestamp: i64) -> Result<ObjectId> {
+    let date = format!("@{} +0000", timestamp);
+    let args = ["commit", "-q", "--allow-empty", "-m", message];
+    let output = git_command(dir, &args)
+        .env("GIT_AUTHOR_DATE", &date)
+        .env("GIT_COMMITTER_DATE", &date)
+        .output()?;
+    check_output(&args, output)?;
+
+    git(dir, &["rev-parse", "HEAD"])?.parse()
+}
@@ -1,18 +1,24 @@
 use crate::{
-    app::RepoState,
+    app::{RepoState, UpstreamStatus},
     git::{
+        commandline,
         graph::{Edge, GraphPoint},
-        Commit, HistoryGraph, Identifier, ObjectId, ReferenceId,
+        Commit, Description, GraphPrepend, HistoryGraph, Identifier, ObjectId, ReferenceId,
+    },
+    gui::{
+        reference_color, reference_underline, try_set_clipboard, upstream_status_text,
+        SearchAction, SearchBar,
     },
-    gui::{reference_color, reference_underline, try_set_clipboard, SearchAction, SearchBar},
     util::Cache,
 };
 
+use anyhow::{bail, Result};
 use clipboard::ClipboardContext;
 use eframe::egui::{
-    text::LayoutJob, Button, Frame, Label, Layout, Pos2, Rect, Response, ScrollArea, Sense, Stroke,
-    TextFormat, TextStyle, Ui, Vec2, Widget, WidgetText,
+    text::LayoutJob, Align, Button, Frame, Label, Layout, Pos2, Rect, Response, ScrollArea, Sense,
+    Shape, Stroke, TextEdit, TextFormat, TextStyle, Ui, Vec2, Widget, WidgetText,
 };
+use log::warn;
 
 use std::{collections::HashMap, ops::Range, sync::Arc};
 
@@ -142,6 +148,24 @@ fn render_commit_node(
     }
 }
 
+/// Dashed stub below a commit whose parents were cut off by a shallow clone
+fn render_truncated_history(ui: &mut Ui, node_pos: &GraphPoint, converter: &PositionConverter) {
+    let stroke = Stroke::new(
+        ui.style().visuals.widgets.open.fg_stroke.width,
+        ui.style().visuals.warn_fg_color,
+    );
+    let x = converter.graph_x_to_ui_x(node_pos.x);
+    let y = converter.graph_y_to_ui_y(node_pos.y);
+    let stub_end = converter.graph_y_to_ui_y(node_pos.y + 1);
+
+    ui.painter().extend(Shape::dashed_line(
+        &[Pos2::new(x, y), Pos2::new(x, stub_end)],
+        stroke,
+        2.0,
+        2.0,
+    ));
+}
+
 fn build_branch_id_lookup(state: &RepoState) -> HashMap<ObjectId, Vec<ReferenceId>> {
     let mut ret: HashMap<ObjectId, Vec<ReferenceId>> = HashMap::new();
     for branch in &state.references {
@@ -209,16 +233,42 @@ fn generate_search_prev(
     )
 }
 
+/// Uncached commits just outside of the visible rows, so that they are available before they are
+/// scrolled to
+fn commits_to_prefetch(
+    commit_graph: &HistoryGraph,
+    row_range: &Range<usize>,
+    commit_cache: &Cache<ObjectId, Commit>,
+) -> Vec<ObjectId> {
+    // Well below the GUI's commit cache size so that prefetching does not evict visible rows
+    const PREFETCH_ROWS: usize = 200;
+
+    let before = row_range.start.saturating_sub(PREFETCH_ROWS)..row_range.start;
+    let after = row_range.end..usize::min(row_range.end + PREFETCH_ROWS, commit_graph.nodes.len());
+
+    // Rows closest to the viewport first
+    commit_graph.nodes[after]
+        .iter()
+        .chain(commit_graph.nodes[before].iter().rev())
+        .map(|node| &node.id)
+        .filter(|id| commit_cache.get(id).is_none())
+        .cloned()
+        .collect()
+}
+
 #[allow(clippy::too_many_arguments)]
 fn render_commit_graph(
     row_range: Range<usize>,
     commit_graph: &HistoryGraph,
+    spanning_edges: &[Edge],
     ui: &mut Ui,
     row_height: f32,
     selected_commit: &mut SelectedItem,
     next_selected_commit: &mut SelectedItem,
     commit_cache: &Cache<ObjectId, Commit>,
+    descriptions: Option<&Cache<ObjectId, Option<Description>>>,
     repo_state: &RepoState,
+    upstreams: &HashMap<ReferenceId, UpstreamStatus>,
     actions: &mut Vec<CommitLogAction>,
     clipboard: &mut ClipboardContext,
 ) {
@@ -248,7 +298,14 @@ fn render_commit_graph(
     }
     *next_selected_commit = SelectedItem::None;
 
-    let max_edge_x = render_edges(ui, &commit_graph.edges, &converter, &row_range);
+    let max_edge_x = [
+        &commit_graph.edges,
+        &commit_graph.open_edges,
+        spanning_edges,
+    ]
+    .into_iter()
+    .map(|edges| render_edges(ui, edges, &converter, &row_range))
+    .fold(0, i32::max);
     let text_rect = converter.text_rect(max_edge_x);
     let mut text_ui = ui.child_ui(text_rect, Layout::default());
 
@@ -266,8 +323,16 @@ fn render_commit_graph(
         ),
     ));
 
+    let prefetch = commits_to_prefetch(commit_graph, &row_range, commit_cache);
+    let mut missing = Vec::new();
+    let mut undescribed = Vec::new();
+
     let branch_id_lookup = build_branch_id_lookup(repo_state);
     for node in &commit_graph.nodes[row_range] {
+        let history_truncated = repo_state.shallow.contains(&node.id);
+        if history_truncated {
+            render_truncated_history(ui, &node.position, &converter);
+        }
         render_commit_node(ui, &node.position, &converter, true);
 
         let mut job = LayoutJob::default();
@@ -275,6 +340,19 @@ fn render_commit_graph(
         let font = style.text_styles[&TextStyle::Body].clone();
         let mut node_branches = Vec::new();
 
+        let commit = commit_cache.get(&node.id);
+        let short_id = commit.map(|c| c.short_id.as_str()).unwrap_or("...");
+        job.append(
+            short_id,
+            0.0,
+            TextFormat::simple(font.clone(), style.visuals.weak_text_color()),
+        );
+        job.append(
+            " ",
+            0.0,
+            TextFormat::simple(font.clone(), style.visuals.text_color()),
+        );
+
         if let Some(ids) = branch_id_lookup.get(&node.id) {
             for id in ids {
                 node_branches.push(id);
@@ -288,6 +366,13 @@ fn render_commit_graph(
                 }
 
                 job.append(&name, 0.0, textformat);
+                if let Some(status) = upstream_status_text(id, upstreams) {
+                    job.append(
+                        &format!(" {status}"),
+                        0.0,
+                        TextFormat::simple(font.clone(), style.visuals.weak_text_color()),
+                    );
+                }
                 job.append(
                     " ",
                     0.0,
@@ -296,7 +381,42 @@ fn render_commit_graph(
             }
         }
 
-        let message = match commit_cache.get(&node.id) {
+        if let Some(descriptions) = descriptions {
+            let description = match descriptions.get(&node.id) {
+                Some(Some(description)) => Some(description.to_string()),
+                Some(None) => None,
+                None => {
+                    undescribed.push(node.id.clone());
+                    None
+                }
+            };
+
+            if let Some(description) = description {
+                job.append(
+                    &format!("({description}) "),
+                    0.0,
+                    TextFormat::simple(font.clone(), style.visuals.weak_text_color()),
+                );
+            }
+        }
+
+        if history_truncated {
+            job.append(
+                "[history truncated] ",
+                0.0,
+                TextFormat::simple(font.clone(), style.visuals.warn_fg_color),
+            );
+        }
+
+        if repo_state.replaced.contains(&node.id) {
+            job.append(
+                "[replaced] ",
+                0.0,
+                TextFormat::simple(font.clone(), style.visuals.warn_fg_color),
+            );
+        }
+
+        let message = match commit {
             Some(v) => v
                 .message
                 .split('\n')
@@ -304,8 +424,8 @@ fn render_commit_graph(
                 .map(|v| v.to_string())
                 .unwrap_or_else(|| v.message.clone()),
             None => {
-                actions.push(CommitLogAction::FetchCommit(node.id.clone()));
-                node.id.to_string()
+                missing.push(node.id.clone());
+                String::new()
             }
         };
 
@@ -337,8 +457,21 @@ fn render_commit_graph(
                 }),
             );
 
+            // Both the full and abbreviated hash are offered for text based actions
+            let hashes_and_all_refs = [
+                Some(node.id.to_string()),
+                commit.map(|c| c.short_id.clone()),
+            ]
+            .into_iter()
+            .flatten()
+            .chain(all_refs.clone().map(|x| x.to_string()));
+
             let local_refs = node_branches.iter().filter_map(|x| match x {
-                ReferenceId::LocalBranch(_) | ReferenceId::Tag(_) => Some((*x).clone()),
+                ReferenceId::LocalBranch(_) | ReferenceId::Tag(_) | ReferenceId::Other(_)
+                    if commandline::can_delete(x) =>
+                {
+                    Some((*x).clone())
+                }
                 _ => None,
             });
 
@@ -364,27 +497,69 @@ fn render_commit_graph(
                 ui.close_menu();
             }
 
+            if history_truncated && add_no_wrap_button(ui, "Deepen history").clicked() {
+                actions.push(CommitLogAction::Deepen);
+                ui.close_menu();
+            }
+
             ui.separator();
 
-            if let Some(identifier) = add_submenu(ui, "Copy", hash_and_all_refs.clone()) {
-                try_set_clipboard(clipboard, identifier.to_string());
+            if let Some(identifier) = add_submenu(ui, "Copy", hashes_and_all_refs.clone()) {
+                try_set_clipboard(clipboard, identifier);
             }
 
             if let Some(identifier) =
-                add_submenu(ui, "Append to command", hash_and_all_refs.clone())
+                add_submenu(ui, "Append to command", hashes_and_all_refs.clone())
             {
-                actions.push(CommitLogAction::Append(identifier.to_string()));
+                actions.push(CommitLogAction::Append(identifier));
             }
 
             if let Some(identifier) = add_submenu(
                 ui,
                 "Append to command (with space)",
-                hash_and_all_refs.clone(),
+                hashes_and_all_refs.clone(),
             ) {
                 actions.push(CommitLogAction::Append(format!("{} ", identifier)));
             }
         });
     }
+
+    if !missing.is_empty() || !prefetch.is_empty() {
+        actions.push(CommitLogAction::FetchCommits {
+            visible: missing,
+            prefetch,
+        });
+    }
+
+    if !undescribed.is_empty() {
+        actions.push(CommitLogAction::DescribeCommits(undescribed));
+    }
+}
+
+/// Text box for jumping to a revision expression (e.g. HEAD~3 or an abbreviated hash). Returns
+/// true when the user asked to go to the entered revision
+fn render_go_to_bar(ui: &mut Ui, go_to_string: &mut String) -> bool {
+    ui.allocate_ui_with_layout(
+        Vec2::new(ui.available_width(), ui.spacing().interact_size.y),
+        Layout::right_to_left(Align::Center),
+        |ui| {
+            let go_response = ui.button("go");
+            let text_response = TextEdit::singleline(go_to_string)
+                .desired_width(ui.available_width())
+                .hint_text("go to revision")
+                .show(ui)
+                .response;
+
+            let enter_pressed =
+                text_response.lost_focus() && ui.input(|i| i.key_pressed(eframe::egui::Key::Enter));
+            if enter_pressed {
+                text_response.request_focus();
+            }
+
+            enter_pressed || go_response.clicked()
+        },
+    )
+    .inner
 }
 
 // Question mark hurts readability here IMO
@@ -414,17 +589,25 @@ where
 }
 
 pub(super) enum CommitLogAction {
-    FetchCommit(ObjectId),
+    FetchCommits {
+        visible: Vec<ObjectId>,
+        prefetch: Vec<ObjectId>,
+    },
+    /// Visible commits whose nearest tag is not known yet
+    DescribeCommits(Vec<ObjectId>),
     Checkout(Identifier),
     DeleteReference(ReferenceId),
     CherryPick(ObjectId),
     Merge(Identifier),
     Append(String),
     DiffTool(ObjectId),
+    /// Offer to fetch more history of a shallow clone
+    Deepen,
     Search {
         commit_list: Vec<ObjectId>,
         search_string: String,
     },
+    GoTo(String),
 }
 
 #[derive(Clone, PartialEq)]
@@ -444,9 +627,15 @@ impl Default for SelectedItem {
 pub(super) struct CommitLog {
     repo_state: Arc<RepoState>,
     commit_graph: Option<HistoryGraph>,
+    /// Edges of appended chunks that start above the chunk they are in. Kept apart from the
+    /// graph's edges so that new edges can be appended without breaking their order
+    spanning_edges: Vec<Edge>,
     selected_commit: SelectedItem,
     next_selected_commit: SelectedItem,
     search_string: String,
+    go_to_string: String,
+    /// Show the nearest tag next to each commit
+    show_descriptions: bool,
 }
 
 impl CommitLog {
@@ -457,7 +646,41 @@ impl CommitLog {
     pub(super) fn update_graph(&mut self, mut commit_graph: HistoryGraph) {
         // Sort the start positions in increasing order
         commit_graph.edges.sort_by(|a, b| a.a.y.cmp(&b.a.y));
+        commit_graph.open_edges.sort_by_key(|edge| edge.a.y);
         self.commit_graph = Some(commit_graph);
+        self.spanning_edges.clear();
+    }
+
+    pub(super) fn extend_graph(&mut self, mut chunk: HistoryGraph) {
+        let Some(commit_graph) = &mut self.commit_graph else {
+            warn!("Received graph chunk without a graph");
+            return;
+        };
+
+        // Edges that start in this chunk sort after all existing edges. The few lines that were
+        // still open at the end of the last chunk start earlier
+        let first_row = i32::try_from(commit_graph.nodes.len()).unwrap_or(i32::MAX);
+        chunk.edges.sort_by_key(|edge| edge.a.y);
+        let num_spanning = chunk.edges.partition_point(|edge| edge.a.y < first_row);
+        self.spanning_edges
+            .extend(chunk.edges.drain(..num_spanning));
+        self.spanning_edges.sort_by_key(|edge| edge.a.y);
+        chunk.open_edges.sort_by_key(|edge| edge.a.y);
+
+        commit_graph.extend(chunk);
+    }
+
+    pub(super) fn prepend_graph(&mut self, prepend: GraphPrepend) -> Result<()> {
+        let Some(mut commit_graph) = self.commit_graph.take() else {
+            bail!("Received new rows without a graph");
+        };
+
+        // The graph is left as it was if the rows do not fit
+        commit_graph.edges.append(&mut self.spanning_edges);
+        let ret = commit_graph.prepend(prepend);
+        self.update_graph(commit_graph);
+
+        ret
     }
 
     pub(super) fn search_finished(&mut self, id: Option<ObjectId>) {
@@ -467,9 +690,14 @@ impl CommitLog {
         }
     }
 
+    pub(super) fn go_to_finished(&mut self, id: ObjectId) {
+        self.next_selected_commit = SelectedItem::Object(id);
+    }
+
     pub(super) fn reset(&mut self) {
         self.repo_state = Default::default();
         self.commit_graph = Default::default();
+        self.spanning_edges = Default::default();
         self.selected_commit = Default::default();
     }
 
@@ -488,9 +716,13 @@ impl CommitLog {
         &mut self,
         ui: &mut Ui,
         commit_cache: &Cache<ObjectId, Commit>,
+        descriptions: &Cache<ObjectId, Option<Description>>,
+        upstreams: &HashMap<ReferenceId, UpstreamStatus>,
         clipboard: &mut ClipboardContext,
     ) -> Vec<CommitLogAction> {
+        let go_to_requested = render_go_to_bar(ui, &mut self.go_to_string);
         let search_action = SearchBar::new(&mut self.search_string).show(ui);
+        ui.checkbox(&mut self.show_descriptions, "Show nearest tags");
 
         let commit_graph = match &self.commit_graph {
             Some(v) => v,
@@ -502,6 +734,10 @@ impl CommitLog {
         }
 
         let mut actions = Vec::new();
+        if go_to_requested && !self.go_to_string.trim().is_empty() {
+            actions.push(CommitLogAction::GoTo(self.go_to_string.trim().to_string()));
+        }
+
         match search_action {
             SearchAction::Next => actions.push(generate_search_next(
                 commit_graph,
@@ -550,12 +786,15 @@ impl CommitLog {
                         render_commit_graph(
                             row_range,
                             commit_graph,
+                            &self.spanning_edges,
                             ui,
                             row_height,
                             &mut self.selected_commit,
                             &mut self.next_selected_commit,
                             commit_cache,
+                            self.show_descriptions.then_some(descriptions),
                             &self.repo_state,
+                            upstreams,
                             &mut actions,
                             clipboard,
                         );
@@ -1,15 +1,17 @@
 use crate::{
     app::{DiffRequest, RepoState},
-    git::{Commit, Diff, DiffMetadata, DiffTarget, ObjectId},
+    git::{
+        Commit, Description, Diff, DiffMetadata, DiffTarget, ObjectId, ReferenceId, UnfetchedFile,
+    },
     util::Cache,
 };
 
-use eframe::egui::{ScrollArea, TextEdit, TextStyle, Ui, Widget};
+use eframe::egui::{ComboBox, ScrollArea, TextEdit, TextStyle, Ui, Widget};
 
 use spiff::widget::{self as spiff_widget, search_bar_wrapped, SearchBar, SearchBarAction};
 use spiff::DiffOptions;
 
-use super::commit_log::SelectedItem;
+use super::{commit_log::SelectedItem, reference_richtext};
 
 use std::sync::Arc;
 
@@ -21,7 +23,20 @@ struct ProcessedDiffOffset {
 
 pub(super) enum CommitViewAction {
     RequestDiff(Vec<DiffRequest>),
-    None,
+    RequestContainingRefs(ObjectId),
+    SelectReference(ReferenceId),
+    RequestMergeBase { a: ObjectId, b: ObjectId },
+}
+
+/// What the selected commit is diffed against
+enum DiffBase {
+    Parent,
+    /// Merge-base of the selected commit and diff_base_ref, like git diff X...commit
+    MergeBase(ObjectId),
+    /// Merge-base has not been received yet
+    Pending,
+    /// The selected commit has no history in common with diff_base_ref
+    Unrelated,
 }
 
 #[derive(Default)]
@@ -33,8 +48,17 @@ pub(super) struct CommitView {
     last_received_diff: Vec<DiffMetadata>,
     diff_options: DiffOptions,
     diff_views: Vec<spiff_widget::DiffView>,
+    /// Files left out of each diff view because their content is not available locally
+    unfetched_files: Vec<Vec<UnfetchedFile>>,
     search_bar: SearchBar,
     search_query: String,
+    /// Commit that containing_refs was last requested for
+    requested_containing_refs: Option<ObjectId>,
+    containing_refs: Option<(ObjectId, Result<Vec<ReferenceId>, String>)>,
+    /// Diff against the merge-base with this reference instead of the parent
+    diff_base_ref: Option<ReferenceId>,
+    requested_merge_base: Option<(ObjectId, ObjectId)>,
+    merge_base: Option<(ObjectId, ObjectId, Option<ObjectId>)>,
 }
 
 impl CommitView {
@@ -45,6 +69,12 @@ impl CommitView {
     pub(super) fn reset(&mut self) {
         self.last_requested_diff = Vec::new();
         self.diff_views = Vec::new();
+        self.unfetched_files = Vec::new();
+        self.requested_containing_refs = None;
+        self.containing_refs = None;
+        self.diff_base_ref = None;
+        self.requested_merge_base = None;
+        self.merge_base = None;
     }
 
     pub(super) fn notify_workdir_updated(&mut self) {
@@ -55,16 +85,44 @@ impl CommitView {
         if self.repo_state.index != repo_state.index {
             self.index_has_changed = true;
         }
+        if self.repo_state.references != repo_state.references {
+            // Old results stay up until the new ones arrive
+            self.requested_containing_refs = None;
+        }
+        if let Some(diff_base_ref) = &self.diff_base_ref {
+            if !repo_state.references.iter().any(|r| &r.id == diff_base_ref) {
+                self.diff_base_ref = None;
+            }
+        }
         self.repo_state = repo_state;
     }
 
+    pub(super) fn update_containing_refs(
+        &mut self,
+        id: ObjectId,
+        references: Result<Vec<ReferenceId>, String>,
+    ) {
+        self.containing_refs = Some((id, references));
+    }
+
+    pub(super) fn update_merge_base(
+        &mut self,
+        a: ObjectId,
+        b: ObjectId,
+        merge_base: Option<ObjectId>,
+    ) {
+        self.merge_base = Some((a, b, merge_base));
+    }
+
     pub(super) fn update_diffs(&mut self, diffs: Vec<Diff>) {
         self.diff_views.clear();
+        self.unfetched_files.clear();
         self.last_received_diff.clear();
 
         for diff in diffs {
             self.diff_views
                 .push(spiff_widget::DiffView::new(diff.diff.processed_diffs));
+            self.unfetched_files.push(diff.unfetched);
             self.last_received_diff.push(diff.metadata);
         }
     }
@@ -73,32 +131,65 @@ impl CommitView {
         &mut self,
         ui: &mut Ui,
         cached_commits: &Cache<ObjectId, Commit>,
+        descriptions: &Cache<ObjectId, Option<Description>>,
         selected_commit: &SelectedItem,
-    ) -> CommitViewAction {
+    ) -> Vec<CommitViewAction> {
         let force_open = match spiff_widget::show_header(&mut self.diff_options, ui) {
             spiff_widget::HeaderAction::ExpandAll => Some(true),
             spiff_widget::HeaderAction::CollapseAll => Some(false),
             _ => None,
         };
 
-        let mut action = CommitViewAction::None;
+        let mut actions = Vec::new();
 
         let selected_commit = match selected_commit {
             SelectedItem::None => {
                 ui.allocate_space(ui.available_size());
-                return CommitViewAction::None;
+                return actions;
             }
             _ => selected_commit,
         };
 
+        if let SelectedItem::Object(id) = selected_commit {
+            if self.requested_containing_refs.as_ref() != Some(id) {
+                self.requested_containing_refs = Some(id.clone());
+                actions.push(CommitViewAction::RequestContainingRefs(id.clone()));
+            }
+
+            if let Some(reference) = self.show_containing_refs(ui, id) {
+                actions.push(CommitViewAction::SelectReference(reference));
+            }
+        }
+
+        let diff_base = match selected_commit {
+            SelectedItem::Object(id) => {
+                let diff_base = self.diff_base(id, &mut actions);
+                self.show_diff_base_selector(ui, &diff_base);
+                diff_base
+            }
+            SelectedItem::Index | SelectedItem::None => DiffBase::Parent,
+        };
+
         if !self.diff_views.is_empty() {
-            let headers = gen_commit_headers(selected_commit, cached_commits);
+            let merge_base = match (&diff_base, &self.diff_base_ref) {
+                (DiffBase::MergeBase(base), Some(reference)) => Some((reference, base)),
+                _ => None,
+            };
+            let headers =
+                gen_commit_headers(selected_commit, cached_commits, descriptions, merge_base);
 
             let num_diff_views = self.diff_views.len();
 
             if num_diff_views == 1 {
                 let action = search_bar_wrapped(&mut self.search_bar, ui, |ui, jump_idx| {
-                    render_diffs(ui, jump_idx, &headers, &mut self.diff_views, force_open);
+                    render_diffs(
+                        ui,
+                        jump_idx,
+                        &headers,
+                        &self.unfetched_files,
+                        &mut self.diff_views,
+                        force_open,
+                    );
                 })
                 .action;
 
@@ -109,7 +200,14 @@ impl CommitView {
                     SearchBarAction::Jump | SearchBarAction::None => (),
                 }
             } else {
-                render_diffs(ui, None, &headers, &mut self.diff_views, force_open);
+                render_diffs(
+                    ui,
+                    None,
+                    &headers,
+                    &self.unfetched_files,
+                    &mut self.diff_views,
+                    force_open,
+                );
             }
         } else {
             ui.allocate_space(ui.available_size());
@@ -117,6 +215,7 @@ impl CommitView {
 
         let requests = construct_diff_requests(
             selected_commit,
+            &diff_base,
             &self.diff_options,
             cached_commits,
             &self.search_query,
@@ -156,13 +255,120 @@ impl CommitView {
             }
 
             if !requests.is_empty() {
-                action = CommitViewAction::RequestDiff(requests);
+                actions.push(CommitViewAction::RequestDiff(requests));
             }
             self.index_has_changed = false;
             self.workdir_has_changed = false;
         }
 
-        action
+        actions
+    }
+
+    /// Requests the merge-base with diff_base_ref when it is not known yet
+    fn diff_base(&mut self, id: &ObjectId, actions: &mut Vec<CommitViewAction>) -> DiffBase {
+        let Some(diff_base_ref) = &self.diff_base_ref else {
+            return DiffBase::Parent;
+        };
+
+        let Some(head) = self
+            .repo_state
+            .references
+            .iter()
+            .find(|reference| &reference.id == diff_base_ref)
+            .map(|reference| reference.head.clone())
+        else {
+            return DiffBase::Parent;
+        };
+
+        let key = (head, id.clone());
+        if self.requested_merge_base.as_ref() != Some(&key) {
+            self.requested_merge_base = Some(key.clone());
+            actions.push(CommitViewAction::RequestMergeBase {
+                a: key.0.clone(),
+                b: key.1.clone(),
+            });
+        }
+
+        match &self.merge_base {
+            Some((a, b, merge_base)) if (a, b) == (&key.0, &key.1) => match merge_base {
+                Some(merge_base) => DiffBase::MergeBase(merge_base.clone()),
+                None => DiffBase::Unrelated,
+            },
+            _ => DiffBase::Pending,
+        }
+    }
+
+    fn show_diff_base_selector(&mut self, ui: &mut Ui, diff_base: &DiffBase) {
+        ui.horizontal(|ui| {
+            ui.label("Diff against:");
+
+            let selected_text = match &self.diff_base_ref {
+                Some(reference) => format!("merge-base with {reference}"),
+                None => "parent".to_string(),
+            };
+            ComboBox::from_id_source("diff_base")
+                .selected_text(selected_text)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.diff_base_ref, None, "parent");
+                    for reference in &self.repo_state.references {
+                        let text = reference_richtext(&reference.id, &self.repo_state);
+                        ui.selectable_value(
+                            &mut self.diff_base_ref,
+                            Some(reference.id.clone()),
+                            text,
+                        );
+                    }
+                });
+
+            match diff_base {
+                DiffBase::Pending => {
+                    ui.spinner();
+                }
+                DiffBase::Unrelated => {
+                    ui.weak("no common history");
+                }
+                DiffBase::Parent | DiffBase::MergeBase(_) => (),
+            }
+        });
+    }
+
+    /// Lists the references that contain id. Returns the reference that was clicked, if any
+    fn show_containing_refs(&self, ui: &mut Ui, id: &ObjectId) -> Option<ReferenceId> {
+        let mut clicked = None;
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Contained in:");
+
+            let references = match &self.containing_refs {
+                Some((containing_id, Ok(references))) if containing_id == id => references,
+                Some((containing_id, Err(e))) if containing_id == id => {
+                    ui.colored_label(ui.visuals().error_fg_color, "failed")
+                        .on_hover_text(e);
+                    return;
+                }
+                _ => {
+                    ui.spinner();
+                    return;
+                }
+            };
+
+            if references.is_empty() {
+                ui.weak("no branches or tags");
+            }
+
+            for reference in references {
+                let text = reference_richtext(reference, &self.repo_state);
+                if ui
+                    .link(text)
+                    .on_hover_text("Select in the sidebar")
+                    .clicked()
+                {
+                    clicked = Some(reference.clone());
+                }
+            }
+        });
+
+        clicked
     }
 }
 
@@ -170,17 +376,36 @@ fn render_diffs(
     ui: &mut Ui,
     jump_idx: Option<(usize, usize)>,
     headers: &[String],
+    unfetched_files: &[Vec<UnfetchedFile>],
     diff_views: &mut [spiff_widget::DiffView],
     force_open: Option<bool>,
 ) {
     ScrollArea::vertical().auto_shrink(false).show(ui, |ui| {
-        for (view, header) in diff_views.iter_mut().zip(headers) {
+        for ((view, header), unfetched) in diff_views.iter_mut().zip(headers).zip(unfetched_files) {
             TextEdit::multiline(&mut header.as_str())
                 .font(TextStyle::Monospace)
                 .desired_rows(1)
                 .desired_width(ui.available_width())
                 .ui(ui);
 
+            for file in unfetched {
+                ui.horizontal_wrapped(|ui| match (&file.remote, &file.fetch_command) {
+                    (Some(remote), Some(fetch_command)) => {
+                        ui.label(format!(
+                            "{}: content not fetched from {}, fetch it with",
+                            file.path, remote
+                        ));
+                        ui.code(fetch_command);
+                    }
+                    _ => {
+                        ui.label(format!(
+                            "{}: content not fetched, no promisor remote is configured",
+                            file.path
+                        ));
+                    }
+                });
+            }
+
             view.show(ui, jump_idx, force_open);
         }
     });
@@ -206,6 +431,7 @@ fn received_diffs_match_request_targets(req: &[DiffRequest], received: &[DiffMet
 
 fn construct_diff_requests(
     selected_item: &SelectedItem,
+    diff_base: &DiffBase,
     options: &DiffOptions,
     commit_cache: &Cache<ObjectId, Commit>,
     search_query: &str,
@@ -218,19 +444,24 @@ fn construct_diff_requests(
 
     let pairs = match selected_item {
         SelectedItem::Object(id) => {
-            let commit = match commit_cache.get(id) {
-                Some(v) => v,
-                None => return Vec::new(),
-            };
-
-            let parent = match commit.metadata.parents.first() {
-                // FIXME: Choose which parent to diff to
-                // FIXME: Support initial commit
-                // FIXME: Support range of commits
-                Some(v) => v,
-                None => return Vec::new(),
+            let from = match diff_base {
+                DiffBase::Parent => {
+                    let commit = match commit_cache.get(id) {
+                        Some(v) => v,
+                        None => return Vec::new(),
+                    };
+
+                    match commit.metadata.parents.first() {
+                        // FIXME: Choose which parent to diff to
+                        // FIXME: Support initial commit
+                        Some(v) => v,
+                        None => return Vec::new(),
+                    }
+                }
+                DiffBase::MergeBase(merge_base) => merge_base,
+                DiffBase::Pending | DiffBase::Unrelated => return Vec::new(),
             };
-            let from = DiffTarget::Object(parent.clone());
+            let from = DiffTarget::Object(from.clone());
             let to = DiffTarget::Object(id.clone());
             vec![Pair { from, to }]
         }
@@ -267,6 +498,8 @@ fn construct_diff_requests(
 fn gen_commit_headers(
     selected_item: &SelectedItem,
     cached_commits: &Cache<ObjectId, Commit>,
+    descriptions: &Cache<ObjectId, Option<Description>>,
+    merge_base: Option<(&ReferenceId, &ObjectId)>,
 ) -> Vec<String> {
     match selected_item {
         SelectedItem::Index => {
@@ -276,7 +509,12 @@ fn gen_commit_headers(
                 "Untracked files".to_string(),
             ]
         }
-        SelectedItem::Object(id) => vec![gen_commit_header_for_object(id, cached_commits)],
+        SelectedItem::Object(id) => vec![gen_commit_header_for_object(
+            id,
+            cached_commits,
+            descriptions,
+            merge_base,
+        )],
         SelectedItem::None => panic!("no selected item"),
     }
 }
@@ -284,21 +522,40 @@ fn gen_commit_headers(
 fn gen_commit_header_for_object(
     selected_commit: &ObjectId,
     cached_commits: &Cache<ObjectId, Commit>,
+    descriptions: &Cache<ObjectId, Option<Description>>,
+    merge_base: Option<(&ReferenceId, &ObjectId)>,
 ) -> String {
+    let description = match descriptions.get(selected_commit) {
+        Some(Some(description)) => description.to_string(),
+        Some(None) => "no tag".to_string(),
+        None => "...".to_string(),
+    };
+
+    let diff_base = match merge_base {
+        Some((reference, merge_base)) => format!("merge-base with {reference}: {merge_base}\n"),
+        None => String::new(),
+    };
+
     cached_commits
         .get(selected_commit)
         .map(|commit| {
             format!(
                 "id: {}\n\
+                    full id: {}\n\
                     author: {}\n\
                     author timestamp: {}\n\
                     committer timestamp: {}\n\
+                    describe: {}\n\
+                    {}\
                     \n\
                     {}",
+                commit.short_id,
                 commit.metadata.id,
                 commit.author,
                 commit.metadata.author_timestamp,
                 commit.metadata.committer_timestamp,
+                description,
+                diff_base,
                 commit.message
             )
         })
@@ -13,8 +13,8 @@ use download_dialog::DownloadDialog;
 use sidebar::{Sidebar, SidebarAction};
 
 use crate::{
-    app::{AppEvent, AppRequest, RepoState, ViewState},
-    git::{Commit, Identifier, ObjectId, ReferenceId},
+    app::{AppEvent, AppRequest, RepoState, RequestPriority, UpstreamStatus, ViewState},
+    git::{commandline, Commit, Description, Identifier, ObjectId, ReferenceId},
     util::Cache,
 };
 
@@ -27,7 +27,7 @@ use eframe::{
 use log::{debug, error, warn};
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     path::PathBuf,
     sync::{
         mpsc::{Receiver, Sender},
@@ -40,12 +40,19 @@ struct GuiInner {
     output: Vec<String>,
     git_command: String,
     show_console: bool,
-    outgoing_requests: HashSet<ObjectId>,
+    outgoing_requests: HashMap<ObjectId, RequestPriority>,
+    /// Commits the app could not load, not requested again until the repo changes
+    failed_requests: HashSet<ObjectId>,
     repo_state: Arc<RepoState>,
     view_state: ViewState,
     pending_view_state: ViewState,
     last_requsted_view_state: ViewState,
     commit_cache: Cache<ObjectId, Commit>,
+    /// Nearest tag of commits, None for commits without a tag in their history
+    descriptions: Cache<ObjectId, Option<Description>>,
+    outgoing_description_requests: HashSet<ObjectId>,
+    /// Ahead/behind counts of local branches, these arrive after the repo state they belong to
+    upstreams: HashMap<ReferenceId, UpstreamStatus>,
     commit_view: CommitView,
     commit_log: CommitLog,
     sidebar: Sidebar,
@@ -62,12 +69,16 @@ impl GuiInner {
             output: Vec::new(),
             git_command: String::new(),
             show_console: true,
-            outgoing_requests: HashSet::new(),
+            outgoing_requests: HashMap::new(),
+            failed_requests: HashSet::new(),
             repo_state: Default::default(),
             view_state: Default::default(),
             pending_view_state: Default::default(),
             last_requsted_view_state: Default::default(),
             commit_cache: Cache::new(Self::MAX_CACHED_COMMITS),
+            descriptions: Cache::new(Self::MAX_CACHED_COMMITS),
+            outgoing_description_requests: HashSet::new(),
+            upstreams: HashMap::new(),
             commit_view: CommitView::new(),
             commit_log: Default::default(),
             sidebar: Sidebar::new(),
@@ -79,12 +90,16 @@ impl GuiInner {
 
     fn reset(&mut self) {
         self.git_command = String::new();
-        self.outgoing_requests = HashSet::new();
+        self.outgoing_requests = HashMap::new();
+        self.failed_requests = HashSet::new();
         self.repo_state = Default::default();
         self.view_state = Default::default();
         self.pending_view_state = Default::default();
         self.last_requsted_view_state = Default::default();
         self.commit_cache = Cache::new(Self::MAX_CACHED_COMMITS);
+        self.descriptions = Cache::new(Self::MAX_CACHED_COMMITS);
+        self.outgoing_description_requests = HashSet::new();
+        self.upstreams = HashMap::new();
         self.download_dialog.reset();
         self.commit_view.reset();
         self.commit_log.reset();
@@ -96,13 +111,25 @@ impl GuiInner {
                 // FIXME: Rolling buffer
                 self.output.push(s);
             }
-            AppEvent::CommitFetched { repo, commit } => {
+            AppEvent::CommitsFetched {
+                repo,
+                commits,
+                failed,
+            } => {
                 let current_repo_is_same = self.repo_state.repo == repo;
                 if current_repo_is_same {
-                    self.outgoing_requests.remove(&commit.metadata.id);
-                    self.commit_cache.push(commit.metadata.id.clone(), commit);
+                    for commit in commits {
+                        self.outgoing_requests.remove(&commit.metadata.id);
+                        self.commit_cache.push(commit.metadata.id.clone(), commit);
+                    }
+                    for (id, e) in failed {
+                        self.outgoing_requests.remove(&id);
+                        self.output
+                            .push(format!("Failed to load commit {}: {}", id, e));
+                        self.failed_requests.insert(id);
+                    }
                 } else {
-                    warn!("Dropping commit in gui: {}", commit.metadata.id);
+                    warn!("Dropping {} commits in gui", commits.len());
                 }
             }
             AppEvent::DiffsFetched { repo, diffs } => {
@@ -110,16 +137,78 @@ impl GuiInner {
                     self.commit_view.update_diffs(diffs);
                 }
             }
+            AppEvent::CommitsDescribed { repo, descriptions } => {
+                if self.repo_state.repo == repo {
+                    for (id, description) in descriptions {
+                        self.outgoing_description_requests.remove(&id);
+                        self.descriptions.push(id, description);
+                    }
+                }
+            }
+            AppEvent::UpstreamStatusesFetched { repo, upstreams } => {
+                if self.repo_state.repo == repo {
+                    self.upstreams = upstreams;
+                }
+            }
+            AppEvent::CommitAuthorsFetched { repo, authors } => {
+                if self.repo_state.repo == repo {
+                    self.sidebar.update_authors(authors);
+                }
+            }
+            AppEvent::MergeBaseFound {
+                repo,
+                a,
+                b,
+                merge_base,
+            } => {
+                if self.repo_state.repo == repo {
+                    self.commit_view.update_merge_base(a, b, merge_base);
+                }
+            }
+            AppEvent::ContainingRefsFetched {
+                repo,
+                id,
+                references,
+            } => {
+                if self.repo_state.repo == repo {
+                    self.commit_view.update_containing_refs(id, references);
+                }
+            }
             AppEvent::CommitGraphFetched(view_state, graph) => {
                 self.view_state = view_state;
                 self.commit_log.update_graph(graph);
             }
+            AppEvent::CommitGraphExtended(view_state, chunk) => {
+                if self.view_state == view_state {
+                    self.commit_log.extend_graph(chunk);
+                } else {
+                    debug!("Dropping graph chunk for stale view state");
+                }
+            }
+            AppEvent::CommitGraphPrepended(view_state, prepend) => {
+                if self.view_state != view_state {
+                    debug!("Dropping prepended rows for stale view state");
+                } else if let Err(e) = self.commit_log.prepend_graph(prepend) {
+                    error!("Failed to add new rows to graph: {:?}", e);
+                }
+            }
             AppEvent::SearchFinished {
                 viewer_id: _,
                 matched_id,
             } => {
                 self.commit_log.search_finished(matched_id);
             }
+            AppEvent::RevisionResolved { viewer_id: _, id } => {
+                self.commit_log.go_to_finished(id);
+            }
+            AppEvent::RepoOpened {
+                repo_state,
+                view_state,
+            } => {
+                self.reset();
+                self.pending_view_state = view_state;
+                self.update_repo_state(repo_state);
+            }
             AppEvent::RepoStateUpdated(repo_state) => {
                 if self.repo_state.repo != repo_state.repo {
                     self.reset();
@@ -127,24 +216,7 @@ impl GuiInner {
                         FromIterator::from_iter([ReferenceId::head()]);
                 }
 
-                let repo_state = Arc::new(repo_state);
-                self.pending_view_state.update_with_repo_state(&repo_state);
-                self.view_state.update_with_repo_state(&repo_state);
-                self.sidebar.update_with_repo_state(Arc::clone(&repo_state));
-                self.commit_log
-                    .update_with_repo_state(Arc::clone(&repo_state));
-                self.commit_view
-                    .update_with_repo_state(Arc::clone(&repo_state));
-
-                if repo_state.repo != self.repo_state.repo {
-                    self.download_dialog.update_remote_state(Default::default());
-                }
-
-                if *self.repo_state != *repo_state {
-                    self.repo_state = repo_state;
-                    // Reset requested view state to force a re-request
-                    self.last_requsted_view_state = Default::default();
-                }
+                self.update_repo_state(repo_state);
             }
             AppEvent::WorkdirUpdated => {
                 self.commit_view.notify_workdir_updated();
@@ -161,9 +233,61 @@ impl GuiInner {
         }
     }
 
+    fn update_repo_state(&mut self, repo_state: RepoState) {
+        let repo_state = Arc::new(repo_state);
+        self.pending_view_state.update_with_repo_state(&repo_state);
+        self.view_state.update_with_repo_state(&repo_state);
+        self.sidebar.update_with_repo_state(Arc::clone(&repo_state));
+        self.commit_log
+            .update_with_repo_state(Arc::clone(&repo_state));
+        self.commit_view
+            .update_with_repo_state(Arc::clone(&repo_state));
+
+        if repo_state.repo != self.repo_state.repo {
+            self.download_dialog.update_remote_state(Default::default());
+        }
+
+        let tags = |repo_state: &RepoState| {
+            repo_state
+                .references
+                .iter()
+                .filter(|reference| matches!(reference.id, ReferenceId::Tag(_)))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        // Replace refs and grafts change the parents of the replaced commits, and the commits
+        // that have those in their history describe differently
+        let history_rewritten = repo_state.replace_refs != self.repo_state.replace_refs
+            || repo_state.replaced != self.repo_state.replaced;
+        if history_rewritten {
+            self.commit_cache = Cache::new(Self::MAX_CACHED_COMMITS);
+            self.outgoing_requests.clear();
+        }
+
+        if history_rewritten || tags(&repo_state) != tags(&self.repo_state) {
+            self.descriptions = Cache::new(Self::MAX_CACHED_COMMITS);
+            self.outgoing_description_requests.clear();
+        }
+
+        if *self.repo_state != *repo_state {
+            self.repo_state = repo_state;
+            // Reset requested view state to force a re-request
+            self.last_requsted_view_state = Default::default();
+            // Commits may have become readable, e.g. after a fetch
+            self.failed_requests.clear();
+
+            let res = self.tx.send(AppRequest::GetUpstreamStatuses {
+                expected_repo: self.repo_state.repo.clone(),
+            });
+            if let Err(e) = res {
+                error!("Failed to request upstream statuses: {}", e);
+            }
+        }
+    }
+
     fn open_repo(&mut self, repo: PathBuf) {
         self.tx
-            .send(AppRequest::OpenRepo(repo))
+            .send(AppRequest::OpenRepo(repo, Default::default()))
             .expect("App handle invalid");
     }
 
@@ -189,19 +313,59 @@ impl GuiInner {
         Ok(())
     }
 
-    fn request_commit(&mut self, id: ObjectId) -> Result<()> {
-        if !self.outgoing_requests.contains(&id) {
-            debug!("Requesting commit {}", id);
+    fn request_commits(&mut self, ids: Vec<ObjectId>, priority: RequestPriority) -> Result<()> {
+        // Re-send requests that have become more urgent, the app de-duplicates them
+        let ids = ids
+            .into_iter()
+            .filter(|id| !self.failed_requests.contains(id))
+            .filter(|id| match self.outgoing_requests.get(id) {
+                Some(requested_priority) => *requested_priority < priority,
+                None => true,
+            })
+            .collect::<Vec<_>>();
 
-            self.tx
-                .send(AppRequest::GetCommit {
-                    expected_repo: self.repo_state.repo.clone(),
-                    id: id.clone(),
-                })
-                .context("Failed to request commit")?;
+        if ids.is_empty() {
+            return Ok(());
+        }
 
-            self.outgoing_requests.insert(id);
+        debug!("Requesting {} commits ({:?})", ids.len(), priority);
+        for id in &ids {
+            self.outgoing_requests.insert(id.clone(), priority);
         }
+
+        self.tx
+            .send(AppRequest::GetCommits {
+                expected_repo: self.repo_state.repo.clone(),
+                ids,
+                priority,
+            })
+            .context("Failed to request commits")?;
+
+        Ok(())
+    }
+
+    fn request_descriptions(&mut self, ids: Vec<ObjectId>) -> Result<()> {
+        let ids = ids
+            .into_iter()
+            .filter(|id| {
+                self.descriptions.get(id).is_none()
+                    && !self.outgoing_description_requests.contains(id)
+            })
+            .collect::<Vec<_>>();
+
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        self.outgoing_description_requests
+            .extend(ids.iter().cloned());
+        self.tx
+            .send(AppRequest::DescribeCommits {
+                expected_repo: self.repo_state.repo.clone(),
+                ids,
+            })
+            .context("Failed to request descriptions")?;
+
         Ok(())
     }
 
@@ -219,8 +383,12 @@ impl GuiInner {
     ) -> Result<()> {
         for action in actions {
             match action {
-                commit_log::CommitLogAction::FetchCommit(id) => {
-                    self.request_commit(id)?;
+                commit_log::CommitLogAction::FetchCommits { visible, prefetch } => {
+                    self.request_commits(visible, RequestPriority::Normal)?;
+                    self.request_commits(prefetch, RequestPriority::Background)?;
+                }
+                commit_log::CommitLogAction::DescribeCommits(ids) => {
+                    self.request_descriptions(ids)?;
                 }
                 commit_log::CommitLogAction::Checkout(id) => {
                     self.request_checkout(id)?;
@@ -240,6 +408,12 @@ impl GuiInner {
                         .send(AppRequest::DiffTool(id))
                         .context("Failed to send delete request")?;
                 }
+                commit_log::CommitLogAction::Deepen => {
+                    // Left in the console rather than run so that the depth can be adjusted
+                    const DEEPEN_COMMITS: usize = 1000;
+                    self.git_command = commandline::deepen(DEEPEN_COMMITS);
+                    self.show_console = true;
+                }
                 commit_log::CommitLogAction::Merge(id) => {
                     self.tx
                         .send(AppRequest::Merge((*self.repo_state).clone(), id))
@@ -261,6 +435,15 @@ impl GuiInner {
                         })
                         .context("Failed to request search")?;
                 }
+                commit_log::CommitLogAction::GoTo(revision) => {
+                    self.tx
+                        .send(AppRequest::ResolveRevision {
+                            expected_repo: self.repo_state.repo.clone(),
+                            viewer_id: "GUI".into(),
+                            revision,
+                        })
+                        .context("Failed to request revision")?;
+                }
             }
         }
 
@@ -280,21 +463,50 @@ impl GuiInner {
         }
 
         let selected_commit = selected_commit.clone();
-        self.request_commit(selected_commit)
+        self.request_commits(vec![selected_commit], RequestPriority::UserVisible)
             .context("Failed to request selected commit")?;
 
         Ok(())
     }
 
-    fn handle_commit_view_action(&mut self, action: CommitViewAction) -> Result<()> {
-        match action {
-            CommitViewAction::RequestDiff(reqs) => {
-                self.tx.send(AppRequest::GetDiffs {
-                    expected_repo: self.repo_state.repo.clone(),
-                    reqs,
-                })?;
+    fn ensure_selected_commit_described(&mut self) -> Result<()> {
+        let selected_commit = match self.commit_log.selected_commit() {
+            SelectedItem::Object(v) => v.clone(),
+            SelectedItem::Index | SelectedItem::None => return Ok(()),
+        };
+
+        self.descriptions.pin(selected_commit.clone());
+        self.request_descriptions(vec![selected_commit])
+            .context("Failed to request description of selected commit")
+    }
+
+    fn handle_commit_view_actions(&mut self, actions: Vec<CommitViewAction>) -> Result<()> {
+        for action in actions {
+            match action {
+                CommitViewAction::RequestDiff(reqs) => {
+                    self.tx.send(AppRequest::GetDiffs {
+                        expected_repo: self.repo_state.repo.clone(),
+                        viewer_id: "GUI".into(),
+                        reqs,
+                    })?;
+                }
+                CommitViewAction::RequestContainingRefs(id) => {
+                    self.tx.send(AppRequest::GetContainingRefs {
+                        expected_repo: self.repo_state.repo.clone(),
+                        id,
+                    })?;
+                }
+                CommitViewAction::SelectReference(id) => {
+                    self.pending_view_state.selected_references.insert(id);
+                }
+                CommitViewAction::RequestMergeBase { a, b } => {
+                    self.tx.send(AppRequest::GetMergeBase {
+                        expected_repo: self.repo_state.repo.clone(),
+                        a,
+                        b,
+                    })?;
+                }
             }
-            CommitViewAction::None => (),
         }
 
         Ok(())
@@ -341,13 +553,17 @@ impl GuiInner {
             }
         }
 
-        let commit_view_action = egui::TopBottomPanel::bottom("commit_view_panel")
+        let commit_view_actions = egui::TopBottomPanel::bottom("commit_view_panel")
             .default_height(ctx.available_rect().height() / 2.0)
             .resizable(true)
             .min_height(100.0)
             .show(ctx, |ui| {
-                self.commit_view
-                    .show(ui, &self.commit_cache, self.commit_log.selected_commit())
+                self.commit_view.show(
+                    ui,
+                    &self.commit_cache,
+                    &self.descriptions,
+                    self.commit_log.selected_commit(),
+                )
             })
             .inner;
 
@@ -358,6 +574,7 @@ impl GuiInner {
                     ui,
                     &self.view_state,
                     &mut self.pending_view_state,
+                    &self.upstreams,
                     &mut self.clipboard,
                 )
             })
@@ -365,8 +582,13 @@ impl GuiInner {
 
         let commit_log_actions = egui::CentralPanel::default()
             .show(ctx, |ui| -> Vec<commit_log::CommitLogAction> {
-                self.commit_log
-                    .show(ui, &self.commit_cache, &mut self.clipboard)
+                self.commit_log.show(
+                    ui,
+                    &self.commit_cache,
+                    &self.descriptions,
+                    &self.upstreams,
+                    &mut self.clipboard,
+                )
             })
             .inner;
 
@@ -388,11 +610,36 @@ impl GuiInner {
                     .send(AppRequest::Delete((*self.repo_state).clone(), id))
                     .context("Failed to send delete request")?;
             }
+            SidebarAction::SetReplaceRefs(enabled) => {
+                self.tx
+                    .send(AppRequest::SetReplaceRefs {
+                        expected_repo: self.repo_state.repo.clone(),
+                        enabled,
+                    })
+                    .context("Failed to send replace refs request")?;
+            }
+            SidebarAction::SetRefPresets(presets) => {
+                self.tx
+                    .send(AppRequest::SetRefPresets {
+                        expected_repo: self.repo_state.repo.clone(),
+                        presets,
+                    })
+                    .context("Failed to send ref presets")?;
+            }
+            SidebarAction::RequestAuthors(ids) => {
+                self.tx
+                    .send(AppRequest::GetCommitAuthors {
+                        expected_repo: self.repo_state.repo.clone(),
+                        ids,
+                    })
+                    .context("Failed to request authors")?;
+            }
             SidebarAction::None => (),
         }
 
         self.ensure_selected_commit_in_cache()?;
-        self.handle_commit_view_action(commit_view_action)?;
+        self.ensure_selected_commit_described()?;
+        self.handle_commit_view_actions(commit_view_actions)?;
         self.handle_commit_log_actions(commit_log_actions)?;
         self.request_pending_view_state()?;
 
@@ -547,6 +794,21 @@ fn reference_richtext(id: &ReferenceId, repo_state: &RepoState) -> RichText {
     }
 }
 
+/// Ahead/behind counts against the upstream of id as "↑3 ↓5", None when there is nothing to show
+fn upstream_status_text(
+    id: &ReferenceId,
+    upstreams: &HashMap<ReferenceId, UpstreamStatus>,
+) -> Option<String> {
+    let status = upstreams.get(id)?;
+    let text = match (status.ahead, status.behind) {
+        (0, 0) => return None,
+        (ahead, 0) => format!("↑{ahead}"),
+        (0, behind) => format!("↓{behind}"),
+        (ahead, behind) => format!("↑{ahead} ↓{behind}"),
+    };
+    Some(text)
+}
+
 fn reference_underline(id: &ReferenceId, repo_state: &RepoState) -> bool {
     repo_state.head == *id
 }
@@ -557,6 +819,7 @@ fn reference_color(id: &ReferenceId) -> Color32 {
         ReferenceId::LocalBranch(_) => Color32::LIGHT_GREEN,
         ReferenceId::RemoteBranch(_) => Color32::LIGHT_RED,
         ReferenceId::Tag(_) => Color32::LIGHT_YELLOW,
+        ReferenceId::Other(_) => Color32::LIGHT_GRAY,
         ReferenceId::Unknown => Color32::RED,
     }
 }
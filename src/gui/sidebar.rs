@@ -1,26 +1,91 @@
 use crate::{
-    app::{RemoteState, RepoState, ViewState},
-    git::{Reference, ReferenceId, SortType},
-    gui::{reference_richtext, tristate_checkbox::TristateCheckbox, try_set_clipboard},
+    app::{RemoteState, RepoState, UpstreamStatus, ViewState},
+    git::{commandline, ObjectId, RefFilterMode, RefPreset, Reference, ReferenceId, SortType},
+    gui::{
+        reference_color, reference_richtext, reference_underline,
+        tristate_checkbox::TristateCheckbox, try_set_clipboard, upstream_status_text,
+    },
+    util::glob_match,
 };
 
 use clipboard::ClipboardContext;
-use eframe::egui::{ComboBox, ScrollArea, TextEdit, Ui, Widget};
+use eframe::egui::{
+    collapsing_header::CollapsingState, Button, Checkbox, CollapsingHeader, ComboBox, RichText,
+    ScrollArea, TextEdit, Ui, Widget,
+};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
 
-use std::{collections::BTreeSet, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
 
 pub(super) enum SidebarAction {
     Checkout(ReferenceId),
     Delete(ReferenceId),
+    SetReplaceRefs(bool),
+    SetRefPresets(Vec<RefPreset>),
+    RequestAuthors(Vec<ObjectId>),
     None,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum RefSortType {
+    #[default]
+    Name,
+    CommitterTimestamp,
+    AuthorTimestamp,
+}
+
+enum RefFilter {
+    Substring(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+impl RefFilter {
+    fn new(mode: RefFilterMode, text: &str) -> Result<RefFilter, regex::Error> {
+        let filter = match mode {
+            RefFilterMode::Substring => RefFilter::Substring(text.to_string()),
+            // Nothing typed yet shows everything, like the other modes
+            RefFilterMode::Glob if text.is_empty() => RefFilter::Substring(String::new()),
+            RefFilterMode::Glob => RefFilter::Glob(text.to_string()),
+            RefFilterMode::Regex => RefFilter::Regex(Regex::new(text)?),
+        };
+
+        Ok(filter)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            RefFilter::Substring(s) => name.contains(s.as_str()),
+            RefFilter::Glob(pattern) => glob_match(pattern, name),
+            RefFilter::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
 #[derive(Default)]
 pub(super) struct Sidebar {
     repo_state: Arc<RepoState>,
     remote_state: RemoteState,
     filter_text: String,
+    filter_mode: RefFilterMode,
+    /// Set when filter_text is not a valid pattern for filter_mode
+    filter_error: Option<String>,
+    preset_name: String,
     filtered_refs: BTreeSet<ReferenceId>,
+    ref_tree: RefTreeNode,
+    ref_sort_type: RefSortType,
+    reference_heads: HashMap<ReferenceId, ObjectId>,
+    /// Authors of ref heads, only fetched while refs are sorted by author date
+    authors: HashMap<ObjectId, String>,
+    requested_authors: HashSet<ObjectId>,
+    /// Opens every group on the next frame so that filter matches are visible
+    expand_groups: bool,
 }
 
 impl Sidebar {
@@ -32,13 +97,73 @@ impl Sidebar {
         self.repo_state = repo_state;
         if self.repo_state.repo != self.remote_state.repo {
             self.remote_state = Default::default();
+            self.authors.clear();
+            self.requested_authors.clear();
         }
         self.update_filters();
     }
 
+    pub(super) fn update_authors(&mut self, authors: Vec<(ObjectId, String)>) {
+        for (id, author) in authors {
+            self.requested_authors.remove(&id);
+            self.authors.insert(id, author);
+        }
+    }
+
+    /// Heads whose author is needed for the current ref order but not known yet
+    fn missing_authors(&self) -> Vec<ObjectId> {
+        if self.ref_sort_type != RefSortType::AuthorTimestamp {
+            return Vec::new();
+        }
+
+        self.reference_heads
+            .values()
+            .filter(|head| !self.authors.contains_key(*head))
+            .filter(|head| !self.requested_authors.contains(*head))
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
     pub(super) fn update_filters(&mut self) {
-        self.filtered_refs =
-            filter_references(&self.filter_text, &self.repo_state.references).collect();
+        match RefFilter::new(self.filter_mode, &self.filter_text) {
+            Ok(filter) => {
+                self.filter_error = None;
+                self.filtered_refs =
+                    filter_references(&filter, &self.repo_state.references).collect();
+            }
+            Err(e) => {
+                self.filter_error = Some(e.to_string());
+                self.filtered_refs.clear();
+            }
+        }
+        self.ref_tree = build_ref_tree(&self.filtered_refs);
+
+        self.reference_heads = self
+            .repo_state
+            .references
+            .iter()
+            .map(|reference| (reference.id.clone(), reference.head.clone()))
+            .collect();
+
+        let repo_state = &self.repo_state;
+        let heads = &self.reference_heads;
+        let ref_sort_type = self.ref_sort_type;
+        let timestamp = |id: &ReferenceId| {
+            let commit = repo_state.reference_commits.get(heads.get(id)?)?;
+            match ref_sort_type {
+                RefSortType::Name => None,
+                RefSortType::CommitterTimestamp => Some(commit.committer_timestamp),
+                RefSortType::AuthorTimestamp => Some(commit.author_timestamp),
+            }
+        };
+        if ref_sort_type != RefSortType::Name {
+            // Keep the kinds in a fixed order, only what is inside of them moves around
+            for group in self.ref_tree.groups_mut() {
+                sort_ref_tree(group, &timestamp);
+            }
+        }
     }
 
     pub(super) fn show(
@@ -46,6 +171,7 @@ impl Sidebar {
         ui: &mut Ui,
         view_state: &ViewState,
         pending_view_state: &mut ViewState,
+        upstreams: &HashMap<ReferenceId, UpstreamStatus>,
         clipboard: &mut ClipboardContext,
     ) -> SidebarAction {
         ComboBox::from_label("Sort Type")
@@ -63,19 +189,86 @@ impl Sidebar {
                 );
             });
 
-        ui.separator();
+        let mut action = SidebarAction::None;
 
-        if TextEdit::singleline(&mut self.filter_text)
-            .desired_width(ui.available_width())
-            .hint_text("Branch filter")
-            .show(ui)
-            .response
+        let mut replace_refs = self.repo_state.replace_refs;
+        if ui
+            .checkbox(&mut replace_refs, "Apply replace refs")
+            .on_hover_text(
+                "Show history as rewritten by refs/replace, like git without --no-replace-objects",
+            )
             .changed()
         {
-            self.update_filters()
+            action = SidebarAction::SetReplaceRefs(replace_refs);
         }
 
-        let mut action = SidebarAction::None;
+        show_view_filters(ui, pending_view_state, &self.repo_state);
+
+        ui.separator();
+
+        let ref_sort_type = self.ref_sort_type;
+        ComboBox::from_label("Ref Order")
+            .selected_text(ref_sort_type_label(&ref_sort_type))
+            .show_ui(ui, |ui| {
+                for sort_type in [
+                    RefSortType::Name,
+                    RefSortType::CommitterTimestamp,
+                    RefSortType::AuthorTimestamp,
+                ] {
+                    ui.selectable_value(
+                        &mut self.ref_sort_type,
+                        sort_type,
+                        ref_sort_type_label(&sort_type),
+                    );
+                }
+            });
+        if ref_sort_type != self.ref_sort_type {
+            self.update_filters();
+        }
+
+        let filter_changed = ui
+            .horizontal(|ui| {
+                let filter_mode = self.filter_mode;
+                ComboBox::from_id_source("ref_filter_mode")
+                    .width(60.0)
+                    .selected_text(ref_filter_mode_label(&filter_mode))
+                    .show_ui(ui, |ui| {
+                        for mode in [
+                            RefFilterMode::Substring,
+                            RefFilterMode::Glob,
+                            RefFilterMode::Regex,
+                        ] {
+                            ui.selectable_value(
+                                &mut self.filter_mode,
+                                mode,
+                                ref_filter_mode_label(&mode),
+                            );
+                        }
+                    });
+
+                let text_changed = TextEdit::singleline(&mut self.filter_text)
+                    .desired_width(ui.available_width())
+                    .hint_text(match self.filter_mode {
+                        RefFilterMode::Substring => "Branch filter",
+                        RefFilterMode::Glob => "Branch filter, e.g. release/*",
+                        RefFilterMode::Regex => "Branch filter, e.g. ^v[0-9]+",
+                    })
+                    .show(ui)
+                    .response
+                    .changed();
+
+                text_changed || filter_mode != self.filter_mode
+            })
+            .inner;
+
+        if filter_changed {
+            self.update_filters();
+            self.expand_groups = !self.filter_text.is_empty();
+        }
+
+        if let Some(e) = &self.filter_error {
+            ui.colored_label(ui.visuals().error_fg_color, e);
+        }
 
         ui.horizontal(|ui| {
             if ui.button("All").clicked() {
@@ -96,54 +289,448 @@ impl Sidebar {
             }
         });
 
+        if let Some(presets) = self.show_presets(ui, pending_view_state) {
+            action = SidebarAction::SetRefPresets(presets);
+        }
+
+        let expand_all = std::mem::take(&mut self.expand_groups);
         ScrollArea::vertical()
             .auto_shrink([false, false])
             .show(ui, |ui| {
-                for id in self.filtered_refs.iter() {
-                    let real_state = view_state.selected_references.contains(id);
-                    let mut selected = pending_view_state.selected_references.contains(id);
-
-                    let text = reference_richtext(id, &self.repo_state);
-
-                    let response = TristateCheckbox::new(&real_state, &mut selected, text).ui(ui);
-                    if response.clicked() {
-                        if selected {
-                            pending_view_state.selected_references.insert(id.clone());
-                        } else {
-                            pending_view_state.selected_references.remove(id);
-                        }
+                let mut ctx = RefTreeContext {
+                    repo_state: &self.repo_state,
+                    reference_heads: &self.reference_heads,
+                    view_state,
+                    pending_view_state,
+                    clipboard,
+                    action: &mut action,
+                    expand_all,
+                    sort_type: self.ref_sort_type,
+                    authors: &self.authors,
+                    upstreams,
+                };
+                show_ref_tree_node(ui, &self.ref_tree, "", &mut ctx);
+            });
+
+        if matches!(action, SidebarAction::None) {
+            let missing_authors = self.missing_authors();
+            if !missing_authors.is_empty() {
+                self.requested_authors
+                    .extend(missing_authors.iter().cloned());
+                action = SidebarAction::RequestAuthors(missing_authors);
+            }
+        }
+
+        action
+    }
+
+    /// Returns the new list of presets if it was modified
+    fn show_presets(
+        &mut self,
+        ui: &mut Ui,
+        pending_view_state: &mut ViewState,
+    ) -> Option<Vec<RefPreset>> {
+        let mut new_presets = None;
+
+        CollapsingHeader::new("Presets").show(ui, |ui| {
+            let presets = &self.repo_state.ref_presets;
+            for (idx, preset) in presets.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let hover_text = format!(
+                        "{}: {}",
+                        ref_filter_mode_label(&preset.mode),
+                        preset.pattern
+                    );
+                    let filter = RefFilter::new(preset.mode, &preset.pattern);
+                    let clicked = ui
+                        .add_enabled(filter.is_ok(), Button::new(&preset.name))
+                        .on_hover_text(hover_text)
+                        .clicked();
+                    if let (true, Ok(filter)) = (clicked, filter) {
+                        pending_view_state.selected_references =
+                            filter_references(&filter, &self.repo_state.references).collect();
                     }
-                    response.context_menu(|ui| {
-                        if ui.button("Copy").clicked() {
-                            try_set_clipboard(clipboard, id.to_string());
-                            ui.close_menu();
-                        }
 
-                        if ui.button("Checkout").clicked() {
-                            action = SidebarAction::Checkout(id.clone());
-                            ui.close_menu();
-                        }
+                    if ui.small_button("Delete").clicked() {
+                        let mut presets = presets.clone();
+                        presets.remove(idx);
+                        new_presets = Some(presets);
+                    }
+                });
+            }
 
-                        ui.separator();
+            ui.horizontal(|ui| {
+                let name = self.preset_name.trim();
+                let save_clicked = ui
+                    .add_enabled(
+                        !name.is_empty() && self.filter_error.is_none(),
+                        Button::new("Save filter"),
+                    )
+                    .on_hover_text(
+                        "Save the branch filter, replacing a preset of the same name. The preset \
+                         selects every ref that matches the filter",
+                    )
+                    .clicked();
+                if save_clicked {
+                    let preset = RefPreset {
+                        name: name.to_string(),
+                        mode: self.filter_mode,
+                        pattern: self.filter_text.clone(),
+                    };
+                    let mut presets = presets.clone();
+                    match presets.iter_mut().find(|p| p.name == preset.name) {
+                        Some(existing) => *existing = preset,
+                        None => presets.push(preset),
+                    }
+                    new_presets = Some(presets);
+                    self.preset_name.clear();
+                }
 
-                        if ui.button("Delete").clicked() {
-                            action = SidebarAction::Delete(id.clone());
-                            ui.close_menu();
-                        }
+                TextEdit::singleline(&mut self.preset_name)
+                    .desired_width(ui.available_width())
+                    .hint_text("Preset name")
+                    .show(ui);
+            });
+        });
+
+        new_presets
+    }
+}
+
+/// References grouped by kind and by `/`-separated path segments
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RefTreeNode {
+    /// References directly in this folder and sub folders in display order, with the label to
+    /// show for them
+    entries: Vec<(String, RefTreeEntry)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RefTreeEntry {
+    Ref(ReferenceId),
+    Group(RefTreeNode),
+}
+
+impl RefTreeNode {
+    fn insert(&mut self, path: &str, id: ReferenceId) {
+        match path.split_once('/') {
+            Some((group, rest)) => {
+                // Input is sorted, so a matching group is usually the most recent one
+                let idx = self
+                    .entries
+                    .iter()
+                    .rposition(|(name, entry)| {
+                        name == group && matches!(entry, RefTreeEntry::Group(_))
+                    })
+                    .unwrap_or_else(|| {
+                        let group_entry = RefTreeEntry::Group(Default::default());
+                        self.entries.push((group.to_string(), group_entry));
+                        self.entries.len() - 1
                     });
+                if let RefTreeEntry::Group(node) = &mut self.entries[idx].1 {
+                    node.insert(rest, id);
                 }
+            }
+            None => self.entries.push((path.to_string(), RefTreeEntry::Ref(id))),
+        }
+    }
+
+    /// Put references before sub folders, keeping the name order within each
+    fn sort_refs_first(&mut self) {
+        self.entries
+            .sort_by_key(|(_, entry)| matches!(entry, RefTreeEntry::Group(_)));
+        for node in self.groups_mut() {
+            node.sort_refs_first();
+        }
+    }
+
+    fn groups_mut(&mut self) -> impl Iterator<Item = &mut RefTreeNode> {
+        self.entries
+            .iter_mut()
+            .filter_map(|(_, entry)| match entry {
+                RefTreeEntry::Group(node) => Some(node),
+                RefTreeEntry::Ref(_) => None,
+            })
+    }
+
+    fn ids(&self) -> Box<dyn Iterator<Item = &ReferenceId> + '_> {
+        Box::new(self.entries.iter().flat_map(|(_, entry)| match entry {
+            RefTreeEntry::Ref(id) => Box::new(std::iter::once(id)),
+            RefTreeEntry::Group(node) => node.ids(),
+        }))
+    }
+}
+
+fn build_ref_tree(references: &BTreeSet<ReferenceId>) -> RefTreeNode {
+    let mut root = RefTreeNode::default();
+    for id in references {
+        let name = id.to_string();
+        match reference_category(id) {
+            Some(category) => root.insert(&format!("{category}/{name}"), id.clone()),
+            // Symbolic refs like HEAD are few and live at the top level
+            None => root.entries.push((name, RefTreeEntry::Ref(id.clone()))),
+        }
+    }
+    root.sort_refs_first();
+    root
+}
+
+/// Orders references and folders newest first, where a folder is as new as its newest
+/// reference. Ties keep their order
+fn sort_ref_tree(
+    node: &mut RefTreeNode,
+    timestamp: &impl Fn(&ReferenceId) -> Option<DateTime<Utc>>,
+) -> Option<DateTime<Utc>> {
+    let mut entries = std::mem::take(&mut node.entries)
+        .into_iter()
+        .map(|(name, mut entry)| {
+            let newest = match &mut entry {
+                RefTreeEntry::Ref(id) => timestamp(id),
+                RefTreeEntry::Group(group) => sort_ref_tree(group, timestamp),
+            };
+            (newest, name, entry)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(newest, _, _)| Reverse(*newest));
+
+    let newest = entries.first().and_then(|(newest, _, _)| *newest);
+    node.entries = entries
+        .into_iter()
+        .map(|(_, name, entry)| (name, entry))
+        .collect();
+
+    newest
+}
+
+struct RefTreeContext<'a> {
+    repo_state: &'a RepoState,
+    reference_heads: &'a HashMap<ReferenceId, ObjectId>,
+    view_state: &'a ViewState,
+    pending_view_state: &'a mut ViewState,
+    clipboard: &'a mut ClipboardContext,
+    action: &'a mut SidebarAction,
+    expand_all: bool,
+    sort_type: RefSortType,
+    authors: &'a HashMap<ObjectId, String>,
+    upstreams: &'a HashMap<ReferenceId, UpstreamStatus>,
+}
+
+fn show_ref_tree_node(ui: &mut Ui, node: &RefTreeNode, path: &str, ctx: &mut RefTreeContext) {
+    for (name, entry) in &node.entries {
+        match entry {
+            RefTreeEntry::Ref(id) => show_reference(ui, name, id, ctx),
+            RefTreeEntry::Group(child) => show_ref_group(ui, name, child, path, ctx),
+        }
+    }
+}
+
+fn show_ref_group(
+    ui: &mut Ui,
+    name: &str,
+    child: &RefTreeNode,
+    path: &str,
+    ctx: &mut RefTreeContext,
+) {
+    let child_path = format!("{path}/{name}");
+    let mut state = CollapsingState::load_with_default_open(
+        ui.ctx(),
+        ui.make_persistent_id(&child_path),
+        path.is_empty(),
+    );
+    if ctx.expand_all {
+        state.set_open(true);
+    }
+
+    state
+        .show_header(ui, |ui| {
+            let selected_references = &mut ctx.pending_view_state.selected_references;
+            let (num_refs, num_selected) = child.ids().fold((0, 0), |(total, selected), id| {
+                (
+                    total + 1,
+                    selected + selected_references.contains(id) as usize,
+                )
             });
+            let mut all_selected = num_refs == num_selected;
+            let partially_selected = num_selected != 0 && !all_selected;
+            let text = format!("{name} ({num_refs})");
+            let text = if path.is_empty() {
+                RichText::new(text).strong()
+            } else {
+                RichText::new(text)
+            };
 
-        action
+            if Checkbox::new(&mut all_selected, text)
+                .indeterminate(partially_selected)
+                .ui(ui)
+                .clicked()
+            {
+                if all_selected {
+                    selected_references.extend(child.ids().cloned());
+                } else {
+                    for id in child.ids() {
+                        selected_references.remove(id);
+                    }
+                }
+            }
+        })
+        .body(|ui| show_ref_tree_node(ui, child, &child_path, ctx));
+}
+
+fn show_reference(ui: &mut Ui, label: &str, id: &ReferenceId, ctx: &mut RefTreeContext) {
+    let real_state = ctx.view_state.selected_references.contains(id);
+    let mut selected = ctx.pending_view_state.selected_references.contains(id);
+
+    let mut text = RichText::new(label).color(reference_color(id));
+    if reference_underline(id, ctx.repo_state) {
+        text = text.underline();
+    }
+
+    let commit = ctx
+        .reference_heads
+        .get(id)
+        .and_then(|head| ctx.repo_state.reference_commits.get(head));
+
+    let response = ui
+        .horizontal(|ui| {
+            let response = TristateCheckbox::new(&real_state, &mut selected, text).ui(ui);
+            if let Some(status) = upstream_status_text(id, ctx.upstreams) {
+                let upstream = &ctx.upstreams[id].upstream;
+                ui.label(status)
+                    .on_hover_text(format!("Compared to {upstream}"));
+            }
+            if let Some(commit) = commit {
+                let timestamp = match ctx.sort_type {
+                    RefSortType::AuthorTimestamp => commit.author_timestamp,
+                    RefSortType::Name | RefSortType::CommitterTimestamp => {
+                        commit.committer_timestamp
+                    }
+                };
+                let mut details = format_relative_time(Utc::now(), timestamp);
+                let author = ctx
+                    .reference_heads
+                    .get(id)
+                    .and_then(|head| ctx.authors.get(head));
+                if let Some(author) = author.filter(|author| !author.is_empty()) {
+                    details = format!("{details}, {author}");
+                }
+                ui.weak(details);
+            }
+            response
+        })
+        .inner
+        .on_hover_text(id.to_string());
+    if response.clicked() {
+        if selected {
+            ctx.pending_view_state
+                .selected_references
+                .insert(id.clone());
+        } else {
+            ctx.pending_view_state.selected_references.remove(id);
+        }
+    }
+    response.context_menu(|ui| {
+        if ui.button("Copy").clicked() {
+            try_set_clipboard(ctx.clipboard, id.to_string());
+            ui.close_menu();
+        }
+
+        if ui.button("Checkout").clicked() {
+            *ctx.action = SidebarAction::Checkout(id.clone());
+            ui.close_menu();
+        }
+
+        ui.menu_button("Show commits unique to this vs", |ui| {
+            if let Some(base) = show_unique_commits_menu(ui, id, ctx.repo_state, ctx.upstreams) {
+                // Same as git log base..id
+                ctx.pending_view_state.selected_references = HashSet::from([id.clone()]);
+                ctx.pending_view_state.excluded_references = HashSet::from([base]);
+                ui.close_menu();
+            }
+        });
+
+        if commandline::can_delete(id) {
+            ui.separator();
+
+            if ui.button("Delete").clicked() {
+                *ctx.action = SidebarAction::Delete(id.clone());
+                ui.close_menu();
+            }
+        }
+    });
+}
+
+/// Branches to compare id against, its upstream first. Returns the one that was clicked
+fn show_unique_commits_menu(
+    ui: &mut Ui,
+    id: &ReferenceId,
+    repo_state: &RepoState,
+    upstreams: &HashMap<ReferenceId, UpstreamStatus>,
+) -> Option<ReferenceId> {
+    let upstream = upstreams.get(id).map(|status| &status.upstream);
+    let branches = repo_state.references.iter().map(|r| &r.id).filter(|other| {
+        *other != id
+            && Some(*other) != upstream
+            && matches!(
+                other,
+                ReferenceId::LocalBranch(_) | ReferenceId::RemoteBranch(_)
+            )
+    });
+
+    let mut clicked = None;
+    ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+        for other in upstream.into_iter().chain(branches) {
+            let text = reference_richtext(other, repo_state);
+            if Button::new(text).wrap(false).ui(ui).clicked() {
+                clicked = Some(other.clone());
+            }
+        }
+    });
+
+    clicked
+}
+
+/// Shows the parts of the view state that are not editable from the reference list, with an
+/// option to clear them
+fn show_view_filters(ui: &mut Ui, view_state: &mut ViewState, repo_state: &RepoState) {
+    if !view_state.excluded_references.is_empty() {
+        let mut excluded = view_state
+            .excluded_references
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        excluded.sort();
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Excluding:");
+            for id in &excluded {
+                ui.label(reference_richtext(id, repo_state));
+            }
+
+            if ui.button("Clear").clicked() {
+                view_state.excluded_references.clear();
+            }
+        });
+    }
+
+    let mut clear_path_filter = false;
+    if let Some(path) = &view_state.path_filter {
+        ui.horizontal_wrapped(|ui| {
+            ui.label(format!("Path: {}", path.display()));
+            clear_path_filter = ui.button("Clear").clicked();
+        });
+    }
+
+    if clear_path_filter {
+        view_state.path_filter = None;
     }
 }
 
 fn filter_references<'a>(
-    filter: &'a str,
+    filter: &'a RefFilter,
     references: &'a [Reference],
 ) -> impl Iterator<Item = ReferenceId> + 'a {
     references.iter().filter_map(move |x| {
-        if x.id.to_string().contains(filter) {
+        if filter.matches(&x.id.to_string()) {
             Some(x.id.clone())
         } else {
             None
@@ -151,6 +738,55 @@ fn filter_references<'a>(
     })
 }
 
+fn reference_category(id: &ReferenceId) -> Option<&'static str> {
+    match id {
+        ReferenceId::LocalBranch(_) => Some("Branches"),
+        ReferenceId::RemoteBranch(_) => Some("Remotes"),
+        ReferenceId::Tag(_) => Some("Tags"),
+        ReferenceId::Other(_) => Some("Other"),
+        ReferenceId::Symbolic(_) | ReferenceId::Unknown => None,
+    }
+}
+
+fn ref_filter_mode_label(mode: &RefFilterMode) -> &str {
+    match mode {
+        RefFilterMode::Substring => "Text",
+        RefFilterMode::Glob => "Glob",
+        RefFilterMode::Regex => "Regex",
+    }
+}
+
+fn ref_sort_type_label(sort_type: &RefSortType) -> &str {
+    match sort_type {
+        RefSortType::Name => "Name",
+        RefSortType::CommitterTimestamp => "Committer date",
+        RefSortType::AuthorTimestamp => "Author date",
+    }
+}
+
+/// Coarse "3 days ago" style age of timestamp
+fn format_relative_time(now: DateTime<Utc>, timestamp: DateTime<Utc>) -> String {
+    const UNITS: [(i64, &str); 6] = [
+        (365 * 24 * 60 * 60, "year"),
+        (30 * 24 * 60 * 60, "month"),
+        (7 * 24 * 60 * 60, "week"),
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+    ];
+
+    let seconds = (now - timestamp).num_seconds();
+    for (unit_seconds, unit) in UNITS {
+        let count = seconds / unit_seconds;
+        if count > 0 {
+            let plural = if count == 1 { "" } else { "s" };
+            return format!("{count} {unit}{plural} ago");
+        }
+    }
+
+    "just now".to_string()
+}
+
 fn sort_type_label(sort_type: &SortType) -> &str {
     match sort_type {
         SortType::CommitterTimestamp => "Committer",
@@ -179,21 +815,209 @@ mod test {
             },
         ];
 
-        assert_eq!(filter_references("test", &branches).next(), None);
+        let substring = |text| RefFilter::new(RefFilterMode::Substring, text).unwrap();
         assert_eq!(
-            filter_references("HE", &branches).collect::<Vec<_>>(),
+            filter_references(&substring("test"), &branches).next(),
+            None
+        );
+        assert_eq!(
+            filter_references(&substring("HE"), &branches).collect::<Vec<_>>(),
             vec![ReferenceId::Symbolic("HEAD".into())]
         );
         assert_eq!(
-            filter_references("_", &branches).collect::<Vec<_>>(),
+            filter_references(&substring("_"), &branches).collect::<Vec<_>>(),
             vec![
                 ReferenceId::LocalBranch("local_branch".into()),
                 ReferenceId::RemoteBranch("origin/remote_branch".into())
             ]
         );
         assert_eq!(
-            filter_references("llocal_branch", &branches).collect::<Vec<_>>(),
+            filter_references(&substring("llocal_branch"), &branches).collect::<Vec<_>>(),
             vec![]
         );
     }
+
+    #[test]
+    fn test_pattern_filtering() -> Result<(), regex::Error> {
+        let branches = [
+            "release/1.0",
+            "release/2.0",
+            "origin/release/1.0",
+            "release-notes",
+            "v1.0",
+            "v10",
+        ]
+        .map(|name| Reference {
+            id: ReferenceId::LocalBranch(name.into()),
+            head: "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap(),
+        });
+
+        let filtered = |mode, text| -> Result<Vec<String>, regex::Error> {
+            let filter = RefFilter::new(mode, text)?;
+            Ok(filter_references(&filter, &branches)
+                .map(|id| id.to_string())
+                .collect())
+        };
+
+        assert_eq!(
+            filtered(RefFilterMode::Glob, "release/*")?,
+            ["release/1.0", "release/2.0"]
+        );
+        assert_eq!(
+            filtered(RefFilterMode::Glob, "*release?*")?,
+            [
+                "release/1.0",
+                "release/2.0",
+                "origin/release/1.0",
+                "release-notes"
+            ]
+        );
+        // . is literal in a glob
+        assert_eq!(filtered(RefFilterMode::Glob, "v1.?")?, ["v1.0"]);
+        assert_eq!(filtered(RefFilterMode::Glob, "")?.len(), branches.len());
+        assert_eq!(filtered(RefFilterMode::Regex, "^v1")?, ["v1.0", "v10"]);
+        assert_eq!(
+            filtered(RefFilterMode::Regex, r"\d\.0$")?,
+            ["release/1.0", "release/2.0", "origin/release/1.0", "v1.0"]
+        );
+        assert!(RefFilter::new(RefFilterMode::Regex, "release/(").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_tree() {
+        let references = [
+            ReferenceId::head(),
+            ReferenceId::LocalBranch("feature/a".into()),
+            ReferenceId::LocalBranch("feature/b".into()),
+            ReferenceId::LocalBranch("main".into()),
+            ReferenceId::RemoteBranch("origin/feature/a".into()),
+            ReferenceId::RemoteBranch("upstream/main".into()),
+            ReferenceId::Tag("v1".into()),
+        ]
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+        let node = |groups: Vec<(&str, RefTreeNode)>, refs: Vec<(&str, ReferenceId)>| RefTreeNode {
+            entries: refs
+                .into_iter()
+                .map(|(label, id)| (label.to_string(), RefTreeEntry::Ref(id)))
+                .chain(
+                    groups
+                        .into_iter()
+                        .map(|(name, node)| (name.to_string(), RefTreeEntry::Group(node))),
+                )
+                .collect(),
+        };
+        let leaf = |label: &str, id: ReferenceId| node(vec![], vec![(label, id)]);
+
+        let mut tree = build_ref_tree(&references);
+        assert_eq!(
+            tree,
+            node(
+                vec![
+                    (
+                        "Branches",
+                        node(
+                            vec![(
+                                "feature",
+                                node(
+                                    vec![],
+                                    vec![
+                                        ("a", ReferenceId::LocalBranch("feature/a".into())),
+                                        ("b", ReferenceId::LocalBranch("feature/b".into())),
+                                    ]
+                                )
+                            )],
+                            vec![("main", ReferenceId::LocalBranch("main".into()))]
+                        )
+                    ),
+                    (
+                        "Remotes",
+                        node(
+                            vec![
+                                (
+                                    "origin",
+                                    node(
+                                        vec![(
+                                            "feature",
+                                            leaf(
+                                                "a",
+                                                ReferenceId::RemoteBranch(
+                                                    "origin/feature/a".into()
+                                                )
+                                            )
+                                        )],
+                                        vec![]
+                                    )
+                                ),
+                                (
+                                    "upstream",
+                                    leaf("main", ReferenceId::RemoteBranch("upstream/main".into()))
+                                ),
+                            ],
+                            vec![]
+                        )
+                    ),
+                    ("Tags", leaf("v1", ReferenceId::Tag("v1".into()))),
+                ],
+                vec![("HEAD", ReferenceId::head())]
+            )
+        );
+
+        let remotes = tree.groups_mut().nth(1).unwrap();
+        assert_eq!(remotes.ids().count(), 2);
+    }
+
+    #[test]
+    fn test_sort_ref_tree() {
+        let references = [
+            ReferenceId::LocalBranch("feature/a".into()),
+            ReferenceId::LocalBranch("feature/b".into()),
+            ReferenceId::LocalBranch("main".into()),
+            ReferenceId::LocalBranch("unknown".into()),
+        ]
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+        let timestamp = |id: &ReferenceId| {
+            let seconds = match id.to_string().as_str() {
+                "feature/a" => 1,
+                "feature/b" => 3,
+                "main" => 2,
+                _ => return None,
+            };
+            DateTime::from_timestamp(seconds, 0)
+        };
+
+        let mut tree = build_ref_tree(&references);
+        let branches = tree.groups_mut().next().unwrap();
+        assert_eq!(
+            sort_ref_tree(branches, &timestamp),
+            DateTime::from_timestamp(3, 0)
+        );
+        assert_eq!(
+            branches.ids().cloned().collect::<Vec<_>>(),
+            [
+                ReferenceId::LocalBranch("feature/b".into()),
+                ReferenceId::LocalBranch("feature/a".into()),
+                ReferenceId::LocalBranch("main".into()),
+                ReferenceId::LocalBranch("unknown".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_relative_time() {
+        let now = DateTime::from_timestamp(1_000_000_000, 0).unwrap();
+        let ago = |seconds| format_relative_time(now, now - chrono::Duration::seconds(seconds));
+
+        assert_eq!(ago(-5), "just now");
+        assert_eq!(ago(59), "just now");
+        assert_eq!(ago(60), "1 minute ago");
+        assert_eq!(ago(3 * 60 * 60 + 5), "3 hours ago");
+        assert_eq!(ago(24 * 60 * 60), "1 day ago");
+        assert_eq!(ago(15 * 24 * 60 * 60), "2 weeks ago");
+        assert_eq!(ago(400 * 24 * 60 * 60), "1 year ago");
+    }
 }
@@ -0,0 +1,52 @@
+use std::{fmt, sync::Arc};
+
+/// Checked by long running operations to find out if their result is still wanted
+#[derive(Clone)]
+pub(crate) struct CancellationToken {
+    is_cancelled: Arc<dyn Fn() -> bool + Send + Sync>,
+}
+
+impl CancellationToken {
+    /// Number of cheap work items (e.g. commits walked) to process between checks
+    pub(crate) const CHECK_INTERVAL: usize = 4096;
+
+    pub(crate) fn new<F>(is_cancelled: F) -> CancellationToken
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        CancellationToken {
+            is_cancelled: Arc::new(is_cancelled),
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        (self.is_cancelled)()
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            return Err(Cancelled);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for CancellationToken {
+    /// A token that is never cancelled
+    fn default() -> Self {
+        CancellationToken::new(|| false)
+    }
+}
+
+/// Error returned when an operation stopped early because its CancellationToken was cancelled
+#[derive(Debug)]
+pub(crate) struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
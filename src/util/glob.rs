@@ -0,0 +1,80 @@
+/// Minimal glob matcher for reference names. Supports `*` (any sequence of characters, including
+/// `/`) and `?` (any single character). Everything else is matched literally
+pub(crate) fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let s = s.as_bytes();
+
+    let mut pattern_idx = 0;
+    let mut s_idx = 0;
+    // Position of the last seen star in the pattern, and the position in s that it is currently
+    // assumed to have consumed up to. Used to backtrack when a literal match fails
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s_idx < s.len() {
+        match pattern.get(pattern_idx) {
+            Some(b'*') => {
+                backtrack = Some((pattern_idx, s_idx));
+                pattern_idx += 1;
+                continue;
+            }
+            Some(b'?') => {
+                pattern_idx += 1;
+                s_idx += 1;
+                continue;
+            }
+            Some(c) if *c == s[s_idx] => {
+                pattern_idx += 1;
+                s_idx += 1;
+                continue;
+            }
+            _ => (),
+        }
+
+        match &mut backtrack {
+            Some((star_idx, consumed)) => {
+                *consumed += 1;
+                pattern_idx = *star_idx + 1;
+                s_idx = *consumed;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[pattern_idx..].iter().all(|c| *c == b'*')
+}
+
+pub(crate) fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_literal() {
+        assert!(glob_match("main", "main"));
+        assert!(!glob_match("main", "main2"));
+        assert!(!glob_match("main2", "main"));
+        assert!(glob_match("", ""));
+    }
+
+    #[test]
+    fn test_star() {
+        assert!(glob_match("feature/*", "feature/a"));
+        assert!(glob_match("feature/*", "feature/a/b"));
+        assert!(glob_match("feature/*", "feature/"));
+        assert!(!glob_match("feature/*", "features/a"));
+        assert!(glob_match("*/main", "origin/main"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn test_question_mark() {
+        assert!(glob_match("v1.?", "v1.2"));
+        assert!(!glob_match("v1.?", "v1.20"));
+        assert!(glob_match("v1.?*", "v1.20"));
+    }
+}
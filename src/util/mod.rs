@@ -1,5 +1,9 @@
 mod cache;
+mod cancellation;
+mod glob;
 mod timer;
 
 pub(crate) use cache::Cache;
+pub(crate) use cancellation::{CancellationToken, Cancelled};
+pub(crate) use glob::{glob_match, is_glob};
 pub(crate) use timer::Timer;